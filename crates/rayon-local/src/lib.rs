@@ -220,6 +220,41 @@ impl<S> RayonLocal<S> {
         Self { thread_locals }
     }
 
+    /// Create a new `RayonLocal` with a single value, which [`RayonLocal::get_local`] returns on
+    /// every thread, rayon worker or not.
+    ///
+    /// Which value a rayon worker gets usually depends on which worker rayon picked, so this is
+    /// for tests which need that to be deterministic.
+    ///
+    /// # Safety
+    /// Every thread shares the value, so it must only ever be accessed from one thread at a time,
+    /// e.g. never with [`locals::RayonIterExt::with_locals`]. Otherwise two threads may get a
+    /// mutable reference to the same value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rayon_local::RayonLocal;
+    ///
+    /// // SAFETY: only the worker below accesses the value, while this thread waits for it
+    /// let local = unsafe { RayonLocal::single(1) };
+    /// assert_eq!(local.idx(), 0);
+    ///
+    /// let on_worker = rayon::scope(|_| *local.get_local());
+    /// assert_eq!(on_worker, 1);
+    /// ```
+    pub unsafe fn single(value: S) -> Self {
+        let thread_locals = Box::new([UnsafeCell::new(value)]);
+
+        Self { thread_locals }
+    }
+
+    /// Whether this was created with [`RayonLocal::single`].
+    #[must_use]
+    pub fn is_single(&self) -> bool {
+        self.thread_locals.len() == 1
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &S> {
         self.get_all().iter()
     }
@@ -228,6 +263,51 @@ impl<S> RayonLocal<S> {
         self.get_all_mut().iter_mut()
     }
 
+    /// Folds every thread-local value into an accumulator, starting with `init`, in index order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rayon_local::RayonLocal;
+    ///
+    /// let queued = RayonLocal::init_with_index(|idx| vec![0_u8; idx]);
+    /// let total = queued.fold_all(0, |total, queue| total + queue.len());
+    ///
+    /// // there is one value for every rayon thread and one for the main thread
+    /// let threads = rayon::current_num_threads();
+    /// assert_eq!(total, threads * (threads + 1) / 2);
+    /// ```
+    pub fn fold_all<T>(&self, init: T, f: impl FnMut(T, &S) -> T) -> T {
+        self.iter().fold(init, f)
+    }
+
+    /// Maps every thread-local value with `map` and combines the results with `reduce`.
+    ///
+    /// This runs on the calling thread for now, but `reduce` should be associative and `map`
+    /// should not depend on the order it is called in, so this can be parallelized without
+    /// changing the result.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rayon_local::RayonLocal;
+    ///
+    /// let high_water_marks = RayonLocal::init_with_index(|idx| idx * 10);
+    /// let highest = high_water_marks.map_reduce(|&mark| mark, usize::max);
+    ///
+    /// assert_eq!(highest, rayon::current_num_threads() * 10);
+    /// ```
+    #[expect(
+        clippy::missing_panics_doc,
+        reason = "there is always a value for the main thread"
+    )]
+    pub fn map_reduce<T>(&self, map: impl Fn(&S) -> T, reduce: impl Fn(T, T) -> T) -> T {
+        self.iter()
+            .map(map)
+            .reduce(reduce)
+            .expect("there is always a value for the main thread")
+    }
+
     unsafe fn get_ref(&self) -> RayonRef<S> {
         let locals = &*self.thread_locals;
         let idx = self.idx();
@@ -262,6 +342,10 @@ impl<S> RayonLocal<S> {
 
     #[must_use]
     pub fn idx(&self) -> usize {
+        if self.is_single() {
+            return 0;
+        }
+
         // this is so the main thread will still have a place to put data
         // todo: priority—this is currently unsafe in the situation where there is another thread beyond the main thread
         let index = rayon::current_thread_index().unwrap_or(self.thread_locals.len() - 1);
@@ -311,6 +395,48 @@ impl<S> RayonLocal<S> {
         unsafe { core::slice::from_raw_parts(start_ptr.cast(), len) }
     }
 
+    /// Run `f` on every thread-local value from the thread which owns it, so core-affine data
+    /// (i.e., per-core buffers) is never touched from another core.
+    ///
+    /// Each rayon worker receives only the value at its own [`rayon::current_thread_index`], and
+    /// the value reserved for the main thread is processed on the calling thread afterwards.
+    /// `f` must only touch the value it is given; reaching into the values of other indices
+    /// defeats the point of this method.
+    ///
+    /// If this was created with [`RayonLocal::single`], its value is processed on the calling
+    /// thread.
+    ///
+    /// # Panics
+    /// If called from a thread inside the rayon thread pool.
+    pub fn par_for_each_mut<F>(&mut self, f: F)
+    where
+        S: Send,
+        F: Fn(usize, &mut S) + Sync,
+    {
+        assert!(
+            rayon::current_thread_index().is_none(),
+            "par_for_each_mut must be called from outside the rayon thread pool"
+        );
+
+        if self.is_single() {
+            f(0, self.thread_locals[0].get_mut());
+            return;
+        }
+
+        let this: &Self = self;
+
+        rayon::broadcast(|ctx| {
+            let idx = ctx.index();
+            // SAFETY: every worker has a unique index, so no two threads get the same value, and
+            // we have `&mut self` so nothing else can access them
+            let local = unsafe { &mut *this.thread_locals[idx].get() };
+            f(idx, local);
+        });
+
+        let main_idx = self.thread_locals.len() - 1;
+        f(main_idx, self.thread_locals[main_idx].get_mut());
+    }
+
     // /// Get a mutable reference to one thread-local value, using a round-robin
     // pub fn get_round_robin(&mut self) -> &mut S {
     //     let index = self.idx;
@@ -351,6 +477,40 @@ mod tests {
         assert_eq!(sum, (0..100).sum());
     }
 
+    #[test]
+    fn test_par_for_each_mut_runs_on_owner() {
+        let mut local = RayonLocal::<Option<usize>>::init_with_defaults();
+        let main_idx = rayon::current_num_threads();
+
+        local.par_for_each_mut(|idx, value| {
+            let expected = if idx == main_idx { None } else { Some(idx) };
+            assert_eq!(rayon::current_thread_index(), expected);
+            *value = Some(idx);
+        });
+
+        let visited: Vec<_> = local.iter().copied().collect();
+        let expected: Vec<_> = (0..=main_idx).map(Some).collect();
+        assert_eq!(visited, expected);
+    }
+
+    #[test]
+    fn test_single() {
+        // SAFETY: the value is only accessed by one thread at a time
+        let mut local = unsafe { RayonLocal::single(0_usize) };
+        assert_eq!(local.get_all().len(), 1);
+
+        // every thread gets the same value
+        assert_eq!(local.idx(), 0);
+        let indices = rayon::broadcast(|_| local.idx());
+        assert!(indices.iter().all(|&idx| idx == 0));
+
+        local.par_for_each_mut(|idx, value| {
+            assert_eq!(rayon::current_thread_index(), None);
+            *value = idx + 1;
+        });
+        assert_eq!(local[0], 1);
+    }
+
     // #[test]
     // fn test_get_all_locals() {
     //     let mut local = RayonLocal::<i32>::init();
@@ -22,8 +22,8 @@ where
 }
 
 impl<'a, A> Locals<'a> for &'a mut RayonLocal<A> {
-    type Output<I>     = (RayonRef<'a, A>, I)
-
+    type Output<I>
+        = (RayonRef<'a, A>, I)
     where
         I: Send + 'a,
         Self: 'a;
@@ -39,8 +39,8 @@ impl<'a, A> Locals<'a> for &'a mut RayonLocal<A> {
 }
 
 impl<'a, A, B> Locals<'a> for (&'a mut RayonLocal<A>, &'a mut RayonLocal<B>) {
-    type Output<I>     = (RayonRef<'a, A>, RayonRef<'a, B>, I)
-
+    type Output<I>
+        = (RayonRef<'a, A>, RayonRef<'a, B>, I)
     where
         I: Send + 'a,
         Self: 'a;
@@ -62,8 +62,8 @@ impl<'a, A, B, C> Locals<'a>
         &'a mut RayonLocal<C>,
     )
 {
-    type Output<I>     = (RayonRef<'a, A>, RayonRef<'a, B>, RayonRef<'a, C>, I)
-
+    type Output<I>
+        = (RayonRef<'a, A>, RayonRef<'a, B>, RayonRef<'a, C>, I)
     where
         I: Send + 'a,
         Self: 'a;
@@ -91,8 +91,14 @@ impl<'a, A, B, C, D> Locals<'a>
         &'a mut RayonLocal<D>,
     )
 {
-    type Output<I>     = (RayonRef<'a, A>, RayonRef<'a, B>, RayonRef<'a, C>, RayonRef<'a, D>, I)
-
+    type Output<I>
+        = (
+        RayonRef<'a, A>,
+        RayonRef<'a, B>,
+        RayonRef<'a, C>,
+        RayonRef<'a, D>,
+        I,
+    )
     where
         I: Send + 'a,
         Self: 'a;
@@ -0,0 +1,98 @@
+//! Compares encoding a batch of packets with [`Packets::append_many`] against calling
+//! [`Packets::append`] in a loop.
+
+use common::NoopServer;
+use divan::Bencher;
+use evenio::prelude::*;
+use libdeflater::CompressionLvl;
+use server::{
+    event::Scratches,
+    net::{Broadcast, Compose, Compressors, IoBufs, MIN_S2C_BUFFER_SIZE},
+};
+use valence_protocol::{packets::play, CompressionThreshold};
+
+mod common;
+
+fn main() {
+    divan::main();
+}
+
+const PACKET_COUNTS: &[usize] = &[10_000];
+
+#[derive(Event)]
+struct AppendLoop {
+    count: usize,
+}
+
+#[derive(Event)]
+struct AppendMany {
+    count: usize,
+}
+
+fn handle_append_loop(
+    r: Receiver<AppendLoop>,
+    mut broadcast: Single<&mut Broadcast>,
+    compose: Compose,
+) {
+    broadcast.clear();
+    compose.bufs.release_sent([]);
+
+    for id in 0..r.event.count {
+        let pkt = play::KeepAliveS2c { id: id as i64 };
+        broadcast.append(&pkt, &compose).unwrap();
+    }
+}
+
+fn handle_append_many(
+    r: Receiver<AppendMany>,
+    mut broadcast: Single<&mut Broadcast>,
+    compose: Compose,
+) {
+    broadcast.clear();
+    compose.bufs.release_sent([]);
+
+    let pkts = (0..r.event.count).map(|id| play::KeepAliveS2c { id: id as i64 });
+    broadcast.append_many(pkts, &compose).unwrap();
+}
+
+fn world() -> World {
+    let mut world = World::new();
+
+    let io = IoBufs::init(
+        CompressionThreshold(256),
+        MIN_S2C_BUFFER_SIZE * 4,
+        &mut NoopServer,
+    )
+    .unwrap();
+
+    let id = world.spawn();
+    world.insert(id, io);
+
+    let id = world.spawn();
+    world.insert(id, Compressors::new(CompressionLvl::new(6).unwrap()));
+
+    let id = world.spawn();
+    world.insert(id, Scratches::default());
+
+    let id = world.spawn();
+    world.insert(id, Broadcast::default());
+
+    world.add_handler(handle_append_loop);
+    world.add_handler(handle_append_many);
+
+    world
+}
+
+#[divan::bench(args = PACKET_COUNTS)]
+fn append_loop(b: Bencher, count: usize) {
+    let mut world = world();
+    b.counter(count)
+        .bench_local(|| world.send(AppendLoop { count }));
+}
+
+#[divan::bench(args = PACKET_COUNTS)]
+fn append_many(b: Bencher, count: usize) {
+    let mut world = world();
+    b.counter(count)
+        .bench_local(|| world.send(AppendMany { count }));
+}
@@ -0,0 +1,59 @@
+//! Measures queueing a broadcast for 500 players with [`Packets::extend`].
+//!
+//! The counter is the number of writes which end up queued for all players combined, so any
+//! writes merged by [`Packets::extend`] show up as fewer items per iteration.
+
+use divan::{counter::ItemsCount, Bencher};
+use server::net::{IoBuf, Packets, MIN_S2C_BUFFER_SIZE};
+use valence_protocol::CompressionThreshold;
+
+fn main() {
+    divan::main();
+}
+
+const PLAYER_COUNTS: &[usize] = &[500];
+
+/// Every player is sent one packet of their own, followed by one packet which is broadcast to
+/// everyone, like a system which acknowledges each player and broadcasts what they did.
+fn setup(players: usize) -> (IoBuf, Vec<Packets>, Packets) {
+    let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+    let broadcast = Packets::default();
+
+    let packets = (0..players)
+        .map(|_| {
+            let packets = Packets::default();
+            packets.append_raw(&[0; 16], &mut buf).unwrap();
+            broadcast.append_raw(&[1; 32], &mut buf).unwrap();
+            packets
+        })
+        .collect();
+
+    (buf, packets, broadcast)
+}
+
+fn extend_all(packets: &mut [Packets], broadcast: &Packets) {
+    for pkts in packets {
+        pkts.extend(broadcast);
+    }
+}
+
+fn writes(packets: &mut [Packets]) -> usize {
+    packets
+        .iter_mut()
+        .flat_map(|pkts| pkts.get_write_mut().iter())
+        .map(std::collections::VecDeque::len)
+        .sum()
+}
+
+#[divan::bench(args = PLAYER_COUNTS)]
+fn extend_from_broadcast(b: Bencher, players: usize) {
+    let (_buf, mut packets, broadcast) = setup(players);
+    extend_all(&mut packets, &broadcast);
+
+    b.counter(ItemsCount::new(writes(&mut packets)))
+        .with_inputs(|| setup(players))
+        .bench_local_values(|(buf, mut packets, broadcast)| {
+            extend_all(&mut packets, &broadcast);
+            (buf, packets)
+        });
+}
@@ -0,0 +1,80 @@
+//! Helpers shared by the benchmarks.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use libc::iovec;
+use server::{
+    global::Global,
+    net::{
+        BufferPool, Fd, FlushResult, RefreshItems, ServerDef, ServerEvent, SocketOpts,
+        DEFAULT_MAX_CONNECTIONS,
+    },
+};
+
+/// A [`ServerDef`] which does nothing so the rings can be registered without a socket.
+pub struct NoopServer;
+
+impl ServerDef for NoopServer {
+    fn new_multi_with_socket_opts(
+        _addresses: &[SocketAddr],
+        _opts: &SocketOpts,
+    ) -> anyhow::Result<Self> {
+        Ok(Self)
+    }
+
+    fn local_addrs(&self) -> &[SocketAddr] {
+        &[]
+    }
+
+    fn drain(&mut self, _f: impl FnMut(ServerEvent)) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn allocate_buffers(&mut self, _pool: Arc<BufferPool>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    unsafe fn allocate_buffers_raw(&mut self, _buffers: &[iovec]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn replace_s2c_buffers(&mut self, _pool: Arc<BufferPool>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn resize_buffers(&mut self, _count: usize, _f: impl FnMut(ServerEvent)) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn write_all<'a>(
+        &mut self,
+        _global: &mut Global,
+        _writers: impl Iterator<Item = RefreshItems<'a>>,
+    ) -> FlushResult {
+        FlushResult::default()
+    }
+
+    fn close_after_send(&mut self, _fd: Fd) {}
+
+    fn connected_fds(&self) -> impl Iterator<Item = Fd> + '_ {
+        std::iter::empty()
+    }
+
+    fn connection_count(&self) -> usize {
+        0
+    }
+
+    fn max_connections(&self) -> usize {
+        DEFAULT_MAX_CONNECTIONS
+    }
+
+    fn peer_addr(&self, _fd: Fd) -> Option<SocketAddr> {
+        None
+    }
+
+    fn submit_events(&mut self) {}
+
+    fn shutdown(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! Compares always compressing packets above the threshold against a [`CompressionPolicy`] which
+//! skips packets that barely compress.
+//!
+//! The chunk-like workload mixes packets which compress well with packets that are already
+//! densely packed.
+
+use std::io::Write;
+
+use bytes::BytesMut;
+use divan::{counter::BytesCount, Bencher};
+use libdeflater::CompressionLvl;
+use server::{
+    event::Scratch,
+    net::{encoder::PacketEncoder, CompressionPolicy},
+};
+use valence_protocol::{CompressionThreshold, Encode, Packet, PacketSide, PacketState};
+
+fn main() {
+    divan::main();
+}
+
+/// The size of the payload of each packet, roughly a chunk section with its light data.
+const PAYLOAD_LEN: usize = 8 * 1024;
+const PACKET_COUNT: usize = 64;
+
+#[derive(Debug)]
+struct Payload(Vec<u8>);
+
+impl Packet for Payload {
+    const ID: i32 = 0;
+    const NAME: &'static str = "Payload";
+    const SIDE: PacketSide = PacketSide::Clientbound;
+    const STATE: PacketState = PacketState::Play;
+}
+
+impl Encode for Payload {
+    fn encode(&self, mut w: impl Write) -> anyhow::Result<()> {
+        w.write_all(&self.0)?;
+        Ok(())
+    }
+}
+
+/// Every other packet is made of a few repeated block states, and the rest is noise which does
+/// not compress.
+fn chunk_heavy() -> Vec<Payload> {
+    let mut rng = fastrand::Rng::with_seed(0);
+
+    (0..PACKET_COUNT)
+        .map(|i| {
+            let payload = if i % 2 == 0 {
+                std::iter::repeat_with(|| rng.u8(..4))
+                    .take(PAYLOAD_LEN)
+                    .collect()
+            } else {
+                std::iter::repeat_with(|| rng.u8(..))
+                    .take(PAYLOAD_LEN)
+                    .collect()
+            };
+
+            Payload(payload)
+        })
+        .collect()
+}
+
+fn bench_policy(b: Bencher, policy: CompressionPolicy) {
+    let pkts = chunk_heavy();
+
+    let mut enc = PacketEncoder::new(CompressionThreshold(256));
+    enc.set_compression_policy(policy);
+
+    let mut scratch = Scratch::new();
+    let mut compressor = libdeflater::Compressor::new(CompressionLvl::new(6).unwrap());
+    let mut buf = BytesMut::new();
+
+    b.counter(BytesCount::new(PACKET_COUNT * PAYLOAD_LEN))
+        .bench_local(|| {
+            buf.clear();
+
+            for pkt in &pkts {
+                enc.append_packet(pkt, &mut buf, &mut scratch, &mut compressor)
+                    .unwrap();
+            }
+        });
+}
+
+#[divan::bench]
+fn always_compress(b: Bencher) {
+    bench_policy(b, CompressionPolicy::ALWAYS);
+}
+
+#[divan::bench]
+fn skip_incompressible(b: Bencher) {
+    bench_policy(b, CompressionPolicy::min_savings(0.1));
+}
@@ -0,0 +1,250 @@
+//! Measures the compress and append pipeline which every tick goes through:
+//!
+//! - `append`: one packet appended with [`Packets::append`] at different compression thresholds,
+//!   for a chunk-sized packet and a small entity move packet.
+//! - `broadcast`: one packet sent to many players, either encoded once into the [`Broadcast`] and
+//!   copied into every player with [`Packets::extend`], or encoded for every player.
+//! - `push`: queueing writes which are contiguous in the ring, so they are merged, against writes
+//!   which are not.
+
+use std::io::Write;
+
+use common::NoopServer;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use evenio::prelude::*;
+use libdeflater::CompressionLvl;
+use server::{
+    event::Scratches,
+    net::{Broadcast, Compose, Compressors, IoBuf, IoBufs, Packets, MIN_S2C_BUFFER_SIZE},
+};
+use valence_protocol::{
+    packets::play, CompressionThreshold, Encode, Packet, PacketSide, PacketState, VarInt,
+};
+
+mod common;
+
+const THRESHOLDS: [i32; 3] = [0, 64, 256];
+const PLAYER_COUNTS: [usize; 3] = [10, 100, 1000];
+
+/// The size of a chunk packet in a world with little variation, like a superflat or ocean.
+const CHUNK_LEN: usize = 16 * 1024;
+
+/// The number of writes queued in each iteration of the `push` group.
+const WRITES: usize = 64;
+
+/// A movement of a single entity, which is most of what is sent every tick.
+const ENTITY_MOVE: play::MoveRelativeS2c = play::MoveRelativeS2c {
+    entity_id: VarInt(1),
+    delta: [16, 0, -8],
+    on_ground: true,
+};
+
+/// A packet with a chunk-sized payload.
+#[derive(Debug)]
+struct ChunkBlob(Vec<u8>);
+
+impl Packet for ChunkBlob {
+    const ID: i32 = 0;
+    const NAME: &'static str = "ChunkBlob";
+    const SIDE: PacketSide = PacketSide::Clientbound;
+    const STATE: PacketState = PacketState::Play;
+}
+
+impl Encode for ChunkBlob {
+    fn encode(&self, mut w: impl Write) -> anyhow::Result<()> {
+        w.write_all(&self.0)?;
+        Ok(())
+    }
+}
+
+/// Palette indices of a chunk with 16 block states, which compress about as well as real chunks.
+fn chunk_blob() -> ChunkBlob {
+    let mut rng = fastrand::Rng::with_seed(7);
+    ChunkBlob((0..CHUNK_LEN).map(|_| rng.u8(0..16)).collect())
+}
+
+#[derive(Component)]
+struct Players(Vec<Packets>);
+
+#[derive(Event)]
+struct AppendChunk<'a> {
+    pkt: &'a ChunkBlob,
+}
+
+#[derive(Event)]
+struct AppendMove {
+    pkt: play::MoveRelativeS2c,
+}
+
+#[derive(Event)]
+struct BroadcastMove {
+    pkt: play::MoveRelativeS2c,
+}
+
+#[derive(Event)]
+struct AppendMoveToEach {
+    pkt: play::MoveRelativeS2c,
+}
+
+/// Clears everything which was queued so the rings never fill up.
+fn reset(broadcast: &mut Broadcast, players: &mut Players, compose: &Compose) {
+    broadcast.clear();
+    players.0.iter_mut().for_each(Packets::clear);
+    compose.bufs.release_sent([]);
+}
+
+fn handle_append_chunk(
+    r: Receiver<AppendChunk>,
+    mut broadcast: Single<&mut Broadcast>,
+    mut players: Single<&mut Players>,
+    compose: Compose,
+) {
+    reset(&mut broadcast, &mut players, &compose);
+    broadcast.append(r.event.pkt, &compose).unwrap();
+}
+
+fn handle_append_move(
+    r: Receiver<AppendMove>,
+    mut broadcast: Single<&mut Broadcast>,
+    mut players: Single<&mut Players>,
+    compose: Compose,
+) {
+    reset(&mut broadcast, &mut players, &compose);
+    broadcast.append(&r.event.pkt, &compose).unwrap();
+}
+
+fn handle_broadcast_move(
+    r: Receiver<BroadcastMove>,
+    mut broadcast: Single<&mut Broadcast>,
+    mut players: Single<&mut Players>,
+    compose: Compose,
+) {
+    reset(&mut broadcast, &mut players, &compose);
+    broadcast.append(&r.event.pkt, &compose).unwrap();
+
+    for packets in &mut players.0 {
+        packets.extend(&broadcast);
+    }
+}
+
+fn handle_append_move_to_each(
+    r: Receiver<AppendMoveToEach>,
+    mut broadcast: Single<&mut Broadcast>,
+    mut players: Single<&mut Players>,
+    compose: Compose,
+) {
+    reset(&mut broadcast, &mut players, &compose);
+
+    for packets in &players.0 {
+        packets.append(&r.event.pkt, &compose).unwrap();
+    }
+}
+
+fn world(threshold: i32, players: usize) -> World {
+    let mut world = World::new();
+
+    let io = IoBufs::init(
+        CompressionThreshold(threshold),
+        MIN_S2C_BUFFER_SIZE * 4,
+        &mut NoopServer,
+    )
+    .unwrap();
+
+    let id = world.spawn();
+    world.insert(id, io);
+
+    let id = world.spawn();
+    world.insert(id, Compressors::new(CompressionLvl::new(6).unwrap()));
+
+    let id = world.spawn();
+    world.insert(id, Scratches::default());
+
+    let id = world.spawn();
+    world.insert(id, Broadcast::default());
+
+    let id = world.spawn();
+    world.insert(
+        id,
+        Players((0..players).map(|_| Packets::default()).collect()),
+    );
+
+    world.add_handler(handle_append_chunk);
+    world.add_handler(handle_append_move);
+    world.add_handler(handle_broadcast_move);
+    world.add_handler(handle_append_move_to_each);
+
+    world
+}
+
+fn append(c: &mut Criterion) {
+    let chunk = chunk_blob();
+    let mut group = c.benchmark_group("append");
+
+    for threshold in THRESHOLDS {
+        let mut world = world(threshold, 0);
+
+        group.throughput(Throughput::Bytes(CHUNK_LEN as u64));
+        group.bench_with_input(BenchmarkId::new("chunk", threshold), &chunk, |b, pkt| {
+            b.iter(|| world.send(AppendChunk { pkt }));
+        });
+
+        group.throughput(Throughput::Elements(1));
+        group.bench_function(BenchmarkId::new("entity_move", threshold), |b| {
+            b.iter(|| world.send(AppendMove { pkt: ENTITY_MOVE }));
+        });
+    }
+
+    group.finish();
+}
+
+fn broadcast(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broadcast");
+
+    for players in PLAYER_COUNTS {
+        let mut world = world(256, players);
+
+        group.throughput(Throughput::Elements(players as u64));
+        group.bench_function(BenchmarkId::new("encode_once", players), |b| {
+            b.iter(|| world.send(BroadcastMove { pkt: ENTITY_MOVE }));
+        });
+        group.bench_function(BenchmarkId::new("encode_each", players), |b| {
+            b.iter(|| world.send(AppendMoveToEach { pkt: ENTITY_MOVE }));
+        });
+    }
+
+    group.finish();
+}
+
+/// Queues [`WRITES`] small writes, going round-robin over `packets`. With a single [`Packets`],
+/// every write starts where the last one ended and is merged into it. With more, none are.
+fn push_writes(buf: &mut IoBuf, packets: &mut [Packets]) {
+    packets.iter_mut().for_each(Packets::clear);
+
+    let ring = buf.buf_mut();
+    let position = ring.position();
+    ring.release_until(position);
+
+    for (pkts, _) in packets.iter().cycle().zip(0..WRITES) {
+        pkts.append_raw(&[0; 32], buf).unwrap();
+    }
+}
+
+fn push(c: &mut Criterion) {
+    let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+    let mut packets = [Packets::default(), Packets::default()];
+
+    let mut group = c.benchmark_group("push");
+    group.throughput(Throughput::Elements(WRITES as u64));
+
+    group.bench_function("contiguous", |b| {
+        b.iter(|| push_writes(&mut buf, &mut packets[..1]));
+    });
+    group.bench_function("non_contiguous", |b| {
+        b.iter(|| push_writes(&mut buf, &mut packets));
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, append, broadcast, push);
+criterion_main!(benches);
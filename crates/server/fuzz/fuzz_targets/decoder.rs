@@ -0,0 +1,120 @@
+//! Feeds arbitrary bytes to a [`PacketDecoder`] the way they arrive from a connection.
+//!
+//! The input starts with two bytes which pick the compression threshold and where the rest of the
+//! input is split into two reads, so frames which arrive partially are buffered. The rest is
+//! decoded both with [`PacketDecoder::try_next_packet`] and with
+//! [`PacketDecoder::decode_frames`] followed by [`PacketDecoder::decompress_frame`], which have to
+//! agree on every packet until one of them fails.
+//!
+//! Run with `cargo fuzz run decoder` from `crates/server`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use server::{event::Scratch, net::PacketDecoder};
+use valence_protocol::{CompressionThreshold, Decode, VarInt};
+
+/// The thresholds the first byte of the input picks from: no compression, compressing
+/// everything, and two realistic thresholds.
+const THRESHOLDS: [i32; 4] = [-1, 0, 64, 256];
+
+/// A packet ID and the body after it.
+type Packet = (i32, Vec<u8>);
+
+fuzz_target!(|input: &[u8]| {
+    let [threshold, split, data @ ..] = input else {
+        return;
+    };
+
+    let threshold = THRESHOLDS[usize::from(*threshold) % THRESHOLDS.len()];
+    let threshold = CompressionThreshold(threshold);
+
+    let split = usize::from(*split) * data.len() / usize::from(u8::MAX);
+    let reads = [&data[..split], &data[split..]];
+
+    let mut scratch = Scratch::new();
+    let capacity = scratch.capacity();
+
+    let packets = try_next_packet(threshold, reads, &mut scratch);
+    let frames = decode_frames(threshold, reads, &mut scratch);
+
+    let len = packets.len().min(frames.len());
+    assert_eq!(packets[..len], frames[..len]);
+
+    // nothing was decompressed into more than the `MAX_PACKET_SIZE` bytes the scratch buffer
+    // starts with
+    assert_eq!(scratch.capacity(), capacity);
+    assert_eq!(scratch.grow_count(), 0);
+});
+
+/// Decodes `reads` with [`PacketDecoder::try_next_packet`] until it fails.
+fn try_next_packet(
+    threshold: CompressionThreshold,
+    reads: [&[u8]; 2],
+    scratch: &mut Scratch,
+) -> Vec<Packet> {
+    let mut decoder = PacketDecoder::new();
+    decoder.set_compression(threshold);
+
+    let mut packets = Vec::new();
+
+    for read in reads {
+        decoder.queue_slice(read);
+
+        loop {
+            match decoder.try_next_packet(scratch) {
+                Ok(Some(packet)) => packets.push((packet.id, packet.body.to_vec())),
+                Ok(None) => break,
+                Err(_) => return packets,
+            }
+        }
+    }
+
+    packets
+}
+
+/// Decodes `reads` with [`PacketDecoder::decode_frames`] and
+/// [`PacketDecoder::decompress_frame`] until either fails.
+fn decode_frames(
+    threshold: CompressionThreshold,
+    reads: [&[u8]; 2],
+    scratch: &mut Scratch,
+) -> Vec<Packet> {
+    let mut decoder = PacketDecoder::new();
+    decoder.set_compression(threshold);
+
+    let mut packets = Vec::new();
+
+    for read in reads {
+        let mut frames = Vec::new();
+        let mut failed = false;
+
+        for frame in decoder.decode_frames(read) {
+            match frame {
+                Ok(frame) => frames.push(frame.into_owned()),
+                Err(_) => {
+                    failed = true;
+                    break;
+                }
+            }
+        }
+
+        for frame in &frames {
+            let Ok(mut data) = decoder.decompress_frame(frame, scratch) else {
+                return packets;
+            };
+
+            let Ok(id) = VarInt::decode(&mut data) else {
+                return packets;
+            };
+
+            packets.push((id.0, data.to_vec()));
+        }
+
+        if failed {
+            return packets;
+        }
+    }
+
+    packets
+}
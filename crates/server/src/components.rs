@@ -1,7 +1,7 @@
 use std::time::Instant;
 
 use bvh::aabb::Aabb;
-use derive_more::{Deref, Display, From};
+use derive_more::{Deref, DerefMut, Display, From};
 use evenio::component::Component;
 use glam::Vec3;
 use valence_protocol::ChunkPos;
@@ -10,6 +10,7 @@ use valence_server::entity::EntityKind;
 use crate::{
     components::vitals::{Absorption, Regeneration},
     global::Global,
+    net::{ForwardedPlayer, PacketQueue},
 };
 
 pub mod chunks;
@@ -38,6 +39,18 @@ pub enum LoginState {
     Handshake,
     Status,
     Login,
+    /// Waiting for a Velocity proxy to forward the player. See [`crate::net::VelocityForwarding`].
+    Forwarding {
+        /// The username the client sent, which the forwarded one replaces.
+        username: Box<str>,
+    },
+    /// Finished logging in, and waiting in the [`crate::singleton::login_queue::LoginQueue`] to
+    /// be let into the world.
+    Queued {
+        username: Box<str>,
+        /// The player a proxy forwarded, if [`crate::global::Global::velocity`] is set.
+        forwarded: Option<ForwardedPlayer>,
+    },
     TransitioningPlay {
         // todo: remove this is a hack
         packets_to_transition: usize,
@@ -187,6 +200,11 @@ pub struct LastSentChunk {
     pub chunk: ChunkPos,
 }
 
+/// The chunks which came into view of a player but have not been sent yet. A few of them are sent
+/// every tick, so a player who just joined or moved far does not stall the tick.
+#[derive(Component, Debug, Default, Deref, DerefMut)]
+pub struct PendingChunks(pub PacketQueue<ChunkPos>);
+
 pub const PLAYER_SPAWN_POSITION: Vec3 = Vec3::new(-464.0, -16.0, -60.0);
 
 impl FullEntityPose {
@@ -1,32 +1,39 @@
 use std::{borrow::Cow, collections::HashMap, io::Write};
 
 use anyhow::Context;
-use bytes::{Bytes, BytesMut};
 use evenio::component::Component;
 use fxhash::FxBuildHasher;
 use itertools::Itertools;
-use rayon_local::RayonLocal;
 use tracing::instrument;
 use valence_generated::block::BlockState;
 use valence_nbt::{compound, List};
-use valence_protocol::{packets::play, ChunkPos, Encode, FixedArray};
+use valence_protocol::{packets::play, BlockPos, ChunkPos, Encode, FixedArray};
 use valence_registry::{BiomeRegistry, RegistryIdx};
-use valence_server::layer::chunk::{bit_width, BiomeContainer, BlockStateContainer, UnloadedChunk};
+use valence_server::layer::chunk::{
+    bit_width, BiomeContainer, BlockStateContainer, Chunk, UnloadedChunk,
+};
 
-use crate::{bits::BitStorage, blocks::AnvilFolder, chunk::heightmap, net, net::Compose};
+use crate::{
+    bits::BitStorage,
+    blocks::AnvilFolder,
+    chunk::heightmap,
+    net::{CachedChunkPacket, Compose},
+};
+
+/// The lowest Y coordinate of a chunk, which is the bottom of its first section.
+const MIN_Y: i32 = -64;
 
 #[derive(Debug)]
 pub struct LoadedChunk {
-    // inner: UnloadedChunk,
-    pub raw: Bytes,
+    /// The chunk with every edit made through [`Chunks::set_block`], which `raw` is encoded from
+    inner: UnloadedChunk,
+    pub raw: CachedChunkPacket,
 }
 
 #[derive(Debug, Component)]
 pub struct Chunks {
     loader: parking_lot::Mutex<AnvilFolder>,
 
-    bytes: RayonLocal<BytesMut>,
-
     // todo: impl more efficient (probably lru) cache
     cache: parking_lot::RwLock<HashMap<ChunkPos, LoadedChunk, FxBuildHasher>>,
 }
@@ -36,7 +43,6 @@ impl Chunks {
         let loader = AnvilFolder::new(registry).context("failed to get anvil data")?;
         Ok(Self {
             loader: loader.into(),
-            bytes: RayonLocal::default(),
             cache: HashMap::with_hasher(FxBuildHasher::default()).into(),
         })
     }
@@ -59,7 +65,11 @@ impl Chunks {
     //     )))
     // }
 
-    pub fn get(&self, pos: ChunkPos, compose: &Compose) -> anyhow::Result<Option<Bytes>> {
+    pub fn get(
+        &self,
+        pos: ChunkPos,
+        compose: &Compose,
+    ) -> anyhow::Result<Option<CachedChunkPacket>> {
         {
             let cache = self.cache.read();
             if let Some(result) = cache.get(&pos) {
@@ -81,44 +91,71 @@ impl Chunks {
             return Ok(None);
         };
 
-        let bufs = compose.bufs.get_local();
-        let mut bufs = bufs.borrow_mut();
-        let enc = bufs.enc_mut();
-
-        let bytes = self.bytes.get_local_raw();
-        let bytes = unsafe { &mut *bytes.get() };
-
         let chunk = chunk.chunk;
 
-        let bytes_in_packet = encode_chunk_packet(&chunk, pos, bytes, compose, enc)?;
-
-        let Some(bytes_in_packet) = bytes_in_packet else {
-            return Ok(None);
-        };
-
-        let bytes_in_packet = bytes_in_packet.freeze();
+        let bytes_in_packet = encode_chunk_packet(&chunk, pos, compose)?;
 
         {
             // write
             let mut cache = self.cache.write();
-            cache.insert(pos, LoadedChunk {
-                // inner: chunk,
+            cache.entry(pos).or_insert_with(|| LoadedChunk {
+                inner: chunk,
                 raw: bytes_in_packet.clone(),
             });
         }
 
         Ok(Some(bytes_in_packet))
     }
+
+    /// Sets the block at `pos` in the loaded chunk, and encodes its cached packet again, so
+    /// players who load the chunk afterwards see the change. The chunk is loaded first if it is
+    /// not yet, since loading it later would read the block from the world files again. Blocks
+    /// outside of the world are ignored.
+    pub fn set_block(
+        &self,
+        pos: BlockPos,
+        state: BlockState,
+        compose: &Compose,
+    ) -> anyhow::Result<()> {
+        let chunk_pos = ChunkPos::from(pos);
+
+        if self.get(chunk_pos, compose)?.is_none() {
+            return Ok(());
+        }
+
+        let mut cache = self.cache.write();
+        let chunk = cache
+            .get_mut(&chunk_pos)
+            .context("loaded chunks are never removed")?;
+
+        let Ok(y) = u32::try_from(pos.y - MIN_Y) else {
+            return Ok(());
+        };
+
+        if y >= chunk.inner.height() {
+            return Ok(());
+        }
+
+        let x = pos.x.rem_euclid(16).unsigned_abs();
+        let z = pos.z.rem_euclid(16).unsigned_abs();
+
+        if chunk.inner.set_block_state(x, y, z, state) == state {
+            return Ok(());
+        }
+
+        // a chunk which was already sent is not changed by this, since `raw` is reference counted
+        chunk.raw = encode_chunk_packet(&chunk.inner, chunk_pos, compose)?;
+
+        Ok(())
+    }
 }
 
 #[instrument(skip_all, level = "trace", fields(location = ?location))]
 fn encode_chunk_packet(
     chunk: &UnloadedChunk,
     location: ChunkPos,
-    buf: &mut BytesMut,
     compose: &Compose,
-    encoder: &net::encoder::PacketEncoder,
-) -> anyhow::Result<Option<BytesMut>> {
+) -> anyhow::Result<CachedChunkPacket> {
     let section_count = 384 / 16_usize;
     let dimension_height = 384;
 
@@ -162,15 +199,9 @@ fn encode_chunk_packet(
         block_light_arrays: Cow::Borrowed(&[]),
     };
 
-    let mut scratch = compose.scratch.get_local().borrow_mut();
-    let mut compressor = compose.compressor.get_local().borrow_mut();
-
-    let scratch = &mut *scratch;
-    let compressor = &mut *compressor;
-
-    let result = encoder.append_packet(&pkt, buf, scratch, compressor)?;
+    let result = compose.cache_chunk(&pkt)?;
 
-    Ok(Some(result))
+    Ok(result)
 }
 
 fn write_block_states(states: &BlockStateContainer, writer: &mut impl Write) -> anyhow::Result<()> {
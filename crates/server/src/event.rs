@@ -1,4 +1,4 @@
-use std::{alloc::Allocator, cell::RefCell, fmt::Debug};
+use std::{alloc::Allocator, cell::RefCell, fmt::Debug, time::Duration};
 
 use bumpalo::Bump;
 use derive_more::{Deref, DerefMut};
@@ -12,7 +12,7 @@ use valence_text::Text;
 
 use crate::{
     components::FullEntityPose,
-    net::{Server, MAX_PACKET_SIZE},
+    net::{ForwardedPlayer, Server, MAX_PACKET_SIZE},
     util::player_skin::PlayerSkin,
 };
 
@@ -39,6 +39,10 @@ pub struct PlayerInit {
     /// The name of the player i.e., `Emerald_Explorer`.
     pub username: Box<str>,
     pub pose: FullEntityPose,
+
+    /// Who a Velocity proxy says the player is, if [`crate::global::Global::velocity`] is set.
+    /// `username` is already the forwarded one.
+    pub forwarded: Option<ForwardedPlayer>,
 }
 
 /// Sent whenever a player joins the server.
@@ -59,6 +63,14 @@ pub struct KickPlayer {
     pub reason: String,
 }
 
+/// An event which warns every player with `reason`, and disconnects them and shuts the server
+/// down after `grace`. See [`crate::Hyperion::announce_and_drain`].
+#[derive(Event)]
+pub struct AnnounceDrain {
+    pub reason: Text,
+    pub grace: Duration,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[repr(i32)]
 pub enum Pose {
@@ -164,13 +176,33 @@ pub struct Stats<'a, 'b> {
 #[derive(Debug)]
 pub struct Scratch<A: Allocator = std::alloc::Global> {
     inner: Vec<u8, A>,
+    /// The capacity of `inner` the last time it was obtained.
+    capacity: usize,
+    /// See [`Scratch::grow_count`].
+    grow_count: u64,
 }
 
 impl Scratch {
     #[must_use]
     pub fn new() -> Self {
-        let inner = Vec::with_capacity(MAX_PACKET_SIZE);
-        Self { inner }
+        Self::from(std::alloc::Global)
+    }
+}
+
+impl<A: Allocator> Scratch<A> {
+    /// The current capacity of the buffer in bytes.
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    /// The number of times the buffer had to grow beyond its previous capacity. Growth is noticed
+    /// the next time the buffer is obtained, so this lags behind by one use.
+    ///
+    /// If this keeps increasing, the initial capacity of [`MAX_PACKET_SIZE`] is too small.
+    #[must_use]
+    pub const fn grow_count(&self) -> u64 {
+        self.grow_count
     }
 }
 
@@ -200,17 +232,30 @@ unsafe impl<A: Allocator + Debug> ScratchBuffer for Scratch<A> {
     type Allocator = A;
 
     fn obtain(&mut self) -> &mut Vec<u8, Self::Allocator> {
+        let capacity = self.inner.capacity();
+        if capacity > self.capacity {
+            self.capacity = capacity;
+            self.grow_count += 1;
+        }
+
         self.inner.clear();
         &mut self.inner
     }
 }
 
+/// A [`Scratch`] which lives in the [`Bump`] of a tick. The bump is created at the start of every
+/// tick and dropped at the end of it, so growing one of these does not free the old allocation
+/// until the tick is over.
 pub type BumpScratch<'a> = Scratch<&'a Bump>;
 
 impl<A: Allocator> From<A> for Scratch<A> {
     fn from(allocator: A) -> Self {
+        let inner = Vec::with_capacity_in(MAX_PACKET_SIZE, allocator);
+
         Self {
-            inner: Vec::with_capacity_in(MAX_PACKET_SIZE, allocator),
+            capacity: inner.capacity(),
+            inner,
+            grow_count: 0,
         }
     }
 }
@@ -265,7 +310,23 @@ pub struct Scratches {
     inner: RayonLocal<RefCell<Scratch>>,
 }
 
+impl Scratches {
+    /// The capacity of the scratch buffer of each core. See [`Scratch::capacity`].
+    pub fn capacities(&self) -> impl Iterator<Item = usize> + '_ {
+        self.inner.iter().map(|scratch| scratch.borrow().capacity())
+    }
+
+    /// How often the scratch buffer of each core grew. See [`Scratch::grow_count`].
+    pub fn grow_counts(&self) -> impl Iterator<Item = u64> + '_ {
+        self.inner
+            .iter()
+            .map(|scratch| scratch.borrow().grow_count())
+    }
+}
+
 // todo: why need two life times?
+/// Sent once per tick. `bump` and `scratch` are recreated for every tick, so anything allocated in
+/// them is freed when the tick ends.
 #[derive(Event)]
 pub struct Gametick<'a, 'b> {
     pub bump: &'a RayonLocal<Bump>,
@@ -284,3 +345,27 @@ pub struct SetPlayerSkin {
     pub target: EntityId,
     pub skin: PlayerSkin,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scratch_grow_count() {
+        let mut scratch = Scratch::new();
+        assert!(scratch.capacity() >= MAX_PACKET_SIZE);
+
+        scratch.obtain().extend_from_slice(&[0; 16]);
+        scratch.obtain();
+        assert_eq!(scratch.grow_count(), 0);
+
+        let capacity = scratch.capacity();
+        scratch.obtain().resize(capacity + 1, 0);
+        assert_eq!(scratch.grow_count(), 0);
+
+        // the growth is noticed the next time the buffer is used
+        scratch.obtain();
+        assert_eq!(scratch.grow_count(), 1);
+        assert!(scratch.capacity() > capacity);
+    }
+}
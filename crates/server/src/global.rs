@@ -1,5 +1,6 @@
 //! Defined the [`Global`] struct which is used to store global data which defines a [`crate::Hyperion`]
 use std::{
+    collections::VecDeque,
     sync::{atomic::AtomicU32, Arc},
     time::Duration,
 };
@@ -8,6 +9,13 @@ use evenio::component::Component;
 use libdeflater::CompressionLvl;
 use valence_protocol::CompressionThreshold;
 
+#[cfg(feature = "record")]
+use crate::net::Recorder;
+use crate::net::{
+    encoder::PacketWriteInfo, BandwidthLimiter, CompressionBackend, Drain, Fd, FlushPolicy,
+    HandshakeConfig, StatusResponse, VelocityForwarding, DEFAULT_MAX_CONNECTIONS,
+};
+
 /// Shared data that is shared between the ECS framework and the IO thread.
 pub struct Shared {
     /// realistically, we will never have more than 2^32 = 4,294,967,296 players
@@ -15,6 +23,8 @@ pub struct Shared {
     /// The compression level to use for the server.
     pub compression_threshold: CompressionThreshold,
     pub compression_level: CompressionLvl,
+    /// What every connection is compressed with, in both directions. See [`CompressionBackend`].
+    pub compression_backend: CompressionBackend,
 }
 
 /// See [`crate::global`].
@@ -32,6 +42,64 @@ pub struct Global {
     pub shared: Arc<Shared>,
 
     pub keep_alive_timeout: Duration,
+
+    /// When queued packets are sent. See [`FlushPolicy`].
+    pub flush_policy: FlushPolicy,
+
+    /// How many bytes may be written per tick. See [`BandwidthLimiter`].
+    pub bandwidth: BandwidthLimiter,
+
+    /// Whether clients before 1.7 get a status response to their server list ping. Otherwise,
+    /// the ping is decoded like a modern handshake and the connection fails.
+    pub legacy_ping: bool,
+
+    /// Which handshakes are accepted. Connections which send any other handshake are closed
+    /// without a response.
+    pub handshake: HandshakeConfig,
+
+    /// Whether players are forwarded by a Velocity proxy with this secret. Their UUID, username,
+    /// and skin are then taken from the proxy, and clients which do not connect through it are
+    /// disconnected at login.
+    pub velocity: Option<VelocityForwarding>,
+
+    /// Whether packets are sent without compression, no matter how large they are. This is for
+    /// benchmarks and LAN servers, where compression is pure overhead.
+    ///
+    /// Clients are told at login with a `LoginCompressionS2c` threshold of -1, so this has to be
+    /// set before any player logs in; players who negotiated compression cannot read the
+    /// uncompressed packets. Encoders pick it up at the end of each tick.
+    pub disable_compression: bool,
+
+    /// Whether the packets encoded every tick are counted by packet ID. See
+    /// [`crate::net::NetMetrics::by_packet_type`].
+    pub track_packet_types: bool,
+
+    /// Whether each connection is pinned to a core when it is accepted, so all of its packets are
+    /// appended to that core's ring. See [`crate::net::Packets::pin_to_core`] for the tradeoff.
+    pub pin_connections: bool,
+
+    /// What is shown in the server list. Legacy pings get its description and maximum number of
+    /// players.
+    pub status: StatusResponse,
+
+    /// Connections which log in while there are more than this many are disconnected with a
+    /// "server full" message. This is set to [`crate::net::ServerDef::max_connections`] when the
+    /// game starts.
+    pub max_connections: usize,
+
+    /// Connections which finished logging in wait in the
+    /// [`crate::singleton::login_queue::LoginQueue`], and at most this many of them join the
+    /// world per tick, so a join storm after a restart is spread over many ticks. If this is
+    /// [`None`], every connection joins as soon as it logged in.
+    pub logins_per_tick: Option<usize>,
+
+    /// The drain which is in progress, if the server is restarting. Nobody can log in while it is
+    /// set. See [`crate::Hyperion::announce_and_drain`].
+    pub drain: Option<Drain>,
+
+    /// The connections whose writes are recorded. See [`Recorder`].
+    #[cfg(feature = "record")]
+    pub recorder: Recorder,
 }
 
 impl Global {
@@ -41,6 +109,69 @@ impl Global {
             max_hurt_resistant_time: 20, // actually kinda like 10 vanilla mc is weird
             shared,
             keep_alive_timeout: Duration::from_secs(20),
+            flush_policy: FlushPolicy::default(),
+            bandwidth: BandwidthLimiter::default(),
+            legacy_ping: true,
+            handshake: HandshakeConfig::default(),
+            velocity: None,
+            disable_compression: false,
+            track_packet_types: false,
+            pin_connections: true,
+            status: StatusResponse::default(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            logins_per_tick: None,
+            drain: None,
+            #[cfg(feature = "record")]
+            recorder: Recorder::default(),
+        }
+    }
+
+    /// The threshold clients are told to use at login, which is -1 if
+    /// [`Global::disable_compression`] is set.
+    #[must_use]
+    pub fn compression_threshold(&self) -> CompressionThreshold {
+        if self.disable_compression {
+            CompressionThreshold(-1)
+        } else {
+            self.shared.compression_threshold
         }
     }
+
+    /// Passes the writes to `fd` in `queue` which [`Global::bandwidth`] allows in this tick to
+    /// `f`, which hands them to the OS. With the `record` feature, they are also recorded if `fd`
+    /// is being recorded.
+    ///
+    /// With the `debug_checksums` feature, the checksum of every write is verified first. A
+    /// mismatch is logged, and panics in debug builds.
+    pub fn drain_writes(
+        &mut self,
+        #[cfg_attr(
+            not(any(feature = "record", feature = "debug_checksums")),
+            expect(unused_variables, reason = "only recording and checksums need the fd")
+        )]
+        fd: Fd,
+        queue: &mut VecDeque<PacketWriteInfo>,
+        mut f: impl FnMut(PacketWriteInfo),
+    ) {
+        #[cfg(feature = "record")]
+        let recorder = &mut self.recorder;
+
+        self.bandwidth.drain_allowed(queue, |elem| {
+            // SAFETY: the bytes are in a ring which is still valid
+            #[cfg(feature = "debug_checksums")]
+            if let Err(err) = unsafe { elem.verify_checksum() } {
+                tracing::error!("corrupted write to {fd:?}: {err}");
+
+                if cfg!(debug_assertions) {
+                    panic!("corrupted write to {fd:?}: {err}");
+                }
+            }
+
+            // SAFETY: the bytes are in a ring which is not overwritten until this write completes
+            #[cfg(feature = "record")]
+            recorder.record(fd, unsafe { elem.as_slice() });
+
+            f(elem);
+        });
+    }
 }
@@ -39,17 +39,21 @@ use signal_hook::iterator::Signals;
 use singleton::bounding_box;
 use spin::Lazy;
 use tracing::{error, info, instrument, trace, warn};
-use valence_protocol::CompressionThreshold;
+use valence_protocol::{text::Text, CompressionThreshold};
 pub use valence_server;
 
 use crate::{
     components::{chunks::Chunks, Vitals},
     event::{BumpScratch, Egress, Gametick, Scratches, Stats},
     global::Global,
-    net::{Broadcast, Compressors, IoBufs, Server, ServerDef, S2C_BUFFER_SIZE},
+    net::{
+        Broadcast, CompressionBackend, Compressors, IoBufs, IoBufsConfig, NetMetrics,
+        PacketFilters, Server, ServerDef, SocketOpts, S2C_BUFFER_SIZE,
+    },
     singleton::{
-        fd_lookup::FdLookup, player_aabb_lookup::PlayerBoundingBoxes,
-        player_id_lookup::EntityIdLookup, player_uuid_lookup::PlayerUuidLookup,
+        fd_activity::FdActivity, fd_lookup::FdLookup, login_queue::LoginQueue,
+        player_aabb_lookup::PlayerBoundingBoxes, player_id_lookup::EntityIdLookup,
+        player_uuid_lookup::PlayerUuidLookup,
     },
     system::{generate_biome_registry, generate_ingress_events},
 };
@@ -170,6 +174,17 @@ fn set_memlock_limit(limit: u64) -> anyhow::Result<()> {
     }
 }
 
+/// Options for [`Hyperion::init_with_config`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HyperionConfig {
+    /// The options set on the listener and every connection, like `SO_REUSEPORT` to run several
+    /// processes on the same port. See [`SocketOpts`].
+    pub socket_opts: SocketOpts,
+    /// Touches every page of the S2C rings at startup. Off by default. See
+    /// [`IoBufsConfig::prefault`].
+    pub prefault: bool,
+}
+
 /// The central [`Hyperion`] struct which owns and manages the entire server.
 pub struct Hyperion {
     /// The shared state between the ECS framework and the I/O thread.
@@ -182,6 +197,8 @@ pub struct Hyperion {
     last_ms_per_tick: VecDeque<f64>,
     /// The tick of the game. This is incremented every 50 ms.
     tick_on: u64,
+    /// The most completions which are handled per tick. See [`Hyperion::set_drain_budget`].
+    drain_budget: usize,
 
     server: Server,
 }
@@ -208,6 +225,26 @@ impl Hyperion {
         // TODO
     }
 
+    /// Restarts the server gracefully: every player is warned with `reason`, and after `grace`
+    /// they are disconnected with it. Once their disconnect packets were sent, the game loop
+    /// stops and the server shuts down. See [`net::Drain`].
+    ///
+    /// Logins are rejected with `reason` as soon as the drain starts. Drains which are announced
+    /// while one is in progress are ignored.
+    pub fn announce_and_drain(&mut self, reason: Text, grace: Duration) {
+        self.world.send(event::AnnounceDrain { reason, grace });
+    }
+
+    /// Handles at most `max_events` IO completions at the start of each tick, so a burst of them
+    /// cannot make the tick take too long. The rest is handled by the next ticks, in order. See
+    /// [`ServerDef::drain_budget`].
+    ///
+    /// Every completion is handled each tick by default. A budget which is too small for the
+    /// number of connections delays their packets by more and more ticks.
+    pub fn set_drain_budget(&mut self, max_events: usize) {
+        self.drain_budget = max_events;
+    }
+
     pub fn init(address: impl ToSocketAddrs + Send + Sync + 'static) -> anyhow::Result<Self> {
         Self::init_with(address, |_| {})
     }
@@ -215,6 +252,15 @@ impl Hyperion {
     pub fn init_with(
         address: impl ToSocketAddrs + Send + Sync + 'static,
         handlers: impl FnOnce(&mut World) + Send + Sync + 'static,
+    ) -> anyhow::Result<Self> {
+        Self::init_with_config(address, HyperionConfig::default(), handlers)
+    }
+
+    /// Like [`Hyperion::init_with`], but with the options in `config`.
+    pub fn init_with_config(
+        address: impl ToSocketAddrs + Send + Sync + 'static,
+        config: HyperionConfig,
+        handlers: impl FnOnce(&mut World) + Send + Sync + 'static,
     ) -> anyhow::Result<Self> {
         // Denormals (numbers very close to 0) are flushed to zero because doing computations on them
         // is slow.
@@ -230,14 +276,20 @@ impl Hyperion {
             .build_global()
             .context("failed to build thread pool")?;
 
-        no_denormals::no_denormals(|| Self::init_with_helper(address, handlers))
+        no_denormals::no_denormals(|| Self::init_with_helper(address, config, handlers))
     }
 
     /// Initialize the server.
     fn init_with_helper(
         address: impl ToSocketAddrs + Send + Sync + 'static,
+        config: HyperionConfig,
         handlers: impl FnOnce(&mut World) + Send + Sync + 'static,
     ) -> anyhow::Result<Self> {
+        let HyperionConfig {
+            socket_opts,
+            prefault,
+        } = config;
+
         // 10k players * 2 file handles / player  = 20,000. We can probably get away with 16,384 file handles
         adjust_file_descriptor_limits(32_768).context("failed to set file limits")?;
 
@@ -270,6 +322,7 @@ impl Hyperion {
             compression_threshold: CompressionThreshold(256),
             compression_level: CompressionLvl::new(12)
                 .map_err(|_| anyhow::anyhow!("failed to create compression level"))?,
+            compression_backend: CompressionBackend::Zlib,
         });
 
         let mut world = World::new();
@@ -277,20 +330,34 @@ impl Hyperion {
         handlers(&mut world);
 
         let compressor_id = world.spawn();
-        world.insert(compressor_id, Compressors::new(shared.compression_level));
+        let compressors =
+            Compressors::new(shared.compression_level).with_backend(shared.compression_backend);
+        world.insert(compressor_id, compressors);
 
-        let mut server_def = Server::new(address)?;
+        let mut server_def = Server::new_with_socket_opts(address, &socket_opts)?;
 
         let io_id = world.spawn();
 
-        let io = IoBufs::init(shared.compression_threshold, &mut server_def);
+        let io = IoBufs::init_with_config(
+            shared.compression_threshold,
+            S2C_BUFFER_SIZE,
+            IoBufsConfig {
+                prefault,
+                ..IoBufsConfig::default()
+            },
+            &mut server_def,
+        )?;
 
         world.insert(io_id, io);
 
         world.add_handler(system::ingress::add_player);
         world.add_handler(system::ingress::remove_player);
+        world.add_handler(system::ingress::connection_error);
         world.add_handler(system::ingress::recv_data);
         world.add_handler(system::ingress::sent_data);
+        world.add_handler(system::ingress::completion_overflow);
+        world.add_handler(system::ingress::accepts_rejected);
+        world.add_handler(system::ingress::admit_queued);
 
         world.add_handler(system::send_chunk_updates);
         world.add_handler(system::init_player);
@@ -327,11 +394,16 @@ impl Hyperion {
         world.add_handler(system::egress);
 
         world.add_handler(system::keep_alive);
+        world.add_handler(system::disconnect_idle);
+        world.add_handler(system::announce_drain);
+        world.add_handler(system::drain);
         world.add_handler(system::stats_message);
         world.add_handler(system::kill_all);
 
         let global = world.spawn();
-        world.insert(global, Global::new(shared.clone()));
+        let mut global_data = Global::new(shared.clone());
+        global_data.max_connections = server_def.max_connections();
+        world.insert(global, global_data);
 
         let scratches = world.spawn();
         world.insert(scratches, Scratches::default());
@@ -356,15 +428,28 @@ impl Hyperion {
         let fd_lookup = world.spawn();
         world.insert(fd_lookup, FdLookup::default());
 
+        let fd_activity = world.spawn();
+        world.insert(fd_activity, FdActivity::default());
+
+        let login_queue = world.spawn();
+        world.insert(login_queue, LoginQueue::default());
+
         let broadcast = world.spawn();
         world.insert(broadcast, Broadcast::default());
 
+        let net_metrics = world.spawn();
+        world.insert(net_metrics, NetMetrics::default());
+
+        let packet_filters = world.spawn();
+        world.insert(packet_filters, PacketFilters::default());
+
         let mut game = Self {
             shared,
             world,
             last_ticks: VecDeque::default(),
             last_ms_per_tick: VecDeque::default(),
             tick_on: 0,
+            drain_budget: usize::MAX,
             server: server_def,
         };
 
@@ -410,6 +495,10 @@ impl Hyperion {
                 spin_sleep::sleep(wait_duration);
             }
         }
+
+        if let Err(err) = self.server.shutdown() {
+            error!("failed to shutdown server: {err}");
+        }
     }
 
     /// Run one tick of the game loop.
@@ -437,7 +526,12 @@ impl Hyperion {
         let bump = RayonLocal::init(bumpalo::Bump::new);
         let mut scratch = bump.map_ref(event::Scratch::from);
 
-        generate_ingress_events(&mut self.world, &mut self.server, &mut scratch);
+        generate_ingress_events(
+            &mut self.world,
+            &mut self.server,
+            &mut scratch,
+            self.drain_budget,
+        );
 
         tracing::span!(tracing::Level::TRACE, "gametick").in_scope(|| {
             self.world.send(Gametick {
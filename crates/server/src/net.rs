@@ -1,40 +1,120 @@
 //! All the networking related code.
 
 use std::{
-    cell::RefCell,
+    borrow::Cow,
+    cell::{Cell, RefCell},
     collections::VecDeque,
     hash::Hash,
-    net::ToSocketAddrs,
-    sync::{atomic, atomic::AtomicUsize},
+    iter,
+    net::{SocketAddr, ToSocketAddrs},
+    os::fd::RawFd,
+    sync::{
+        atomic,
+        atomic::{AtomicBool, AtomicI32, AtomicUsize},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use derive_more::{Deref, DerefMut, From};
+use anyhow::{anyhow, ensure, Context};
+use bytes::{Bytes, BytesMut};
+use derive_more::{Deref, DerefMut};
 use evenio::{fetch::Single, handler::HandlerParam, prelude::Component};
+use fxhash::FxHashSet;
 use libc::iovec;
 use libdeflater::CompressionLvl;
+use parking_lot::{Mutex, MutexGuard};
+use serde::Serialize;
+use socket2::{Domain, SockRef, Socket, Type};
 use tracing::{debug, trace};
-use valence_protocol::CompressionThreshold;
+use valence_protocol::{
+    packets::{
+        login::{LoginCompressionS2c, LoginDisconnectS2c},
+        play::{BundleSplitterS2c, ChunkDataS2c, DisconnectS2c},
+    },
+    text::Text,
+    ChunkPos, CompressionThreshold, PacketState, VarInt,
+};
 
-use crate::{global::Global, net::encoder::PacketWriteInfo, singleton::ring::Ring};
+use crate::{
+    components::LoginState,
+    global::Global,
+    net::{compression::CompressorFactory, encoder::PacketWriteInfo},
+    singleton::ring::Ring,
+};
 
 #[cfg(target_os = "linux")]
 mod linux;
+#[cfg(target_os = "linux")]
+pub use linux::{LinuxServerConfig, SubmitStrategy};
 
 #[cfg(not(target_os = "linux"))]
 mod generic;
 
+/// A connection of a [`ServerDef`].
+///
+/// On Linux, this is a slot of the fixed file table of the `io_uring`, which the next accepted
+/// connection may get once the connection is closed. Each `Fd` also holds the generation of its
+/// slot, so one which was kept around after its connection was closed never refers to the
+/// connection which got the slot next. Check [`ServerDef::is_connected`] before using an `Fd`
+/// from an earlier tick.
 #[derive(Debug, Copy, Clone, Component, PartialEq, Eq, Hash)]
 pub struct Fd(
     #[cfg(target_os = "linux")] linux::Fixed,
+    #[cfg(target_os = "linux")] u32,
     #[cfg(not(target_os = "linux"))] usize,
 );
 
 #[allow(unused, reason = "these are used on linux")]
 pub enum ServerEvent<'a> {
-    AddPlayer { fd: Fd },
-    RemovePlayer { fd: Fd },
-    RecvData { fd: Fd, data: &'a [u8] },
-    SentData { fd: Fd },
+    /// A connection was accepted by the listener at index `listener` of
+    /// [`ServerDef::local_addrs`], which tells the address family it came in on.
+    AddPlayer {
+        fd: Fd,
+        listener: usize,
+    },
+    RemovePlayer {
+        fd: Fd,
+    },
+    RecvData {
+        fd: Fd,
+        data: &'a [u8],
+    },
+    /// Like [`ServerEvent::RecvData`], but the handler owns the buffer the data was received
+    /// into, so it can be decoded after the drain callback returned. See [`RecvBuffer`] for how
+    /// long it should be held.
+    ///
+    /// This is only emitted by the `io_uring` server on Linux, and only if
+    /// [`LinuxServerConfig::zero_copy_recv`] is set, instead of [`ServerEvent::RecvData`].
+    RecvBuffer {
+        fd: Fd,
+        buffer: RecvBuffer,
+    },
+    SentData {
+        fd: Fd,
+    },
+    /// An IO operation on `fd` failed. If the connection is closed because of this, a
+    /// [`ServerEvent::RemovePlayer`] for the same `fd` follows, so `fd` can still be looked up
+    /// when this is handled.
+    Error {
+        fd: Fd,
+        error: std::io::Error,
+    },
+    /// The kernel dropped `dropped` completions because the completion queue was full. Events of
+    /// some connections were lost, so for example [`Packets::number_sending`] may never reach zero
+    /// for them again. The server should shed load, like by accepting fewer connections.
+    ///
+    /// This is only emitted by the `io_uring` server on Linux.
+    CompletionOverflow {
+        dropped: u32,
+    },
+    /// `count` accepted connections were closed right away because they were over the
+    /// [`AcceptPolicy`] of the server, or [`ServerDef::max_connections`] and
+    /// [`FULL_CONNECTION_HEADROOM`] more connections were already open. No
+    /// [`ServerEvent::AddPlayer`] was emitted for them.
+    AcceptsRejected {
+        count: u32,
+    },
 }
 
 pub struct Server {
@@ -44,39 +124,129 @@ pub struct Server {
     server: generic::GenericServer,
 }
 
+#[cfg(target_os = "linux")]
+impl Server {
+    /// Like [`ServerDef::new`], but with options specific to the `io_uring` server.
+    pub fn new_with_config(
+        address: impl ToSocketAddrs,
+        config: LinuxServerConfig,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            server: linux::LinuxServer::new_with_config(address, config)?,
+        })
+    }
+
+    /// Like [`ServerDef::new_multi`], but with options specific to the `io_uring` server.
+    pub fn new_multi_with_config(
+        addresses: &[SocketAddr],
+        config: LinuxServerConfig,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            server: linux::LinuxServer::new_multi_with_config(addresses, config)?,
+        })
+    }
+}
+
+impl Server {
+    /// An fd which becomes readable once [`Server::drain_ready`] has events to handle, so the
+    /// server can be driven by an external poller like a Tokio runtime.
+    ///
+    /// This is only available with the `io_uring` server on Linux and [`None`] elsewhere.
+    #[must_use]
+    pub fn registration_fd(&self) -> Option<RawFd> {
+        #[cfg(target_os = "linux")]
+        {
+            Some(self.server.registration_fd())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            None
+        }
+    }
+
+    /// Handles the events which are ready without waiting for more. See
+    /// [`Server::registration_fd`].
+    ///
+    /// # Errors
+    /// [`std::io::ErrorKind::Unsupported`] if the server is not the `io_uring` server on Linux.
+    #[allow(unused, reason = "this has to do with cross-platform code")]
+    pub fn drain_ready(&mut self, f: impl FnMut(ServerEvent)) -> std::io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            self.server.drain_ready(f)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "drain_ready is only supported by the io_uring server",
+            ))
+        }
+    }
+}
+
 impl ServerDef for Server {
     #[allow(unused, reason = "this has to do with cross-platform code")]
-    fn new(address: impl ToSocketAddrs) -> anyhow::Result<Self>
+    fn new_multi_with_socket_opts(
+        addresses: &[SocketAddr],
+        opts: &SocketOpts,
+    ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
         #[cfg(target_os = "linux")]
         {
             Ok(Self {
-                server: linux::LinuxServer::new(address)?,
+                server: linux::LinuxServer::new_multi_with_socket_opts(addresses, opts)?,
             })
         }
         #[cfg(not(target_os = "linux"))]
         {
             Ok(Self {
-                server: generic::GenericServer::new(address)?,
+                server: generic::GenericServer::new_multi_with_socket_opts(addresses, opts)?,
             })
         }
     }
 
+    fn local_addrs(&self) -> &[SocketAddr] {
+        self.server.local_addrs()
+    }
+
     fn drain(&mut self, f: impl FnMut(ServerEvent)) -> std::io::Result<()> {
         self.server.drain(f)
     }
 
-    fn allocate_buffers(&mut self, buffers: &[iovec]) {
-        for (idx, elem) in buffers.iter().enumerate() {
-            let ptr = elem.iov_base as *const u8;
-            let len = elem.iov_len;
-            let len_readable = humansize::SizeFormatter::new(len, humansize::BINARY);
-            debug!("buffer {idx} {ptr:?} of len {len} = {len_readable}");
-        }
+    fn drain_budget(
+        &mut self,
+        max_events: usize,
+        f: impl FnMut(ServerEvent),
+    ) -> std::io::Result<bool> {
+        self.server.drain_budget(max_events, f)
+    }
+
+    fn allocate_buffers(&mut self, pool: Arc<BufferPool>) -> anyhow::Result<()> {
+        let buffers = pool.iovecs();
+        validate_buffers(&buffers)?;
+        log_buffers(&buffers);
+
+        self.server.allocate_buffers(pool)
+    }
+
+    unsafe fn allocate_buffers_raw(&mut self, buffers: &[iovec]) -> anyhow::Result<()> {
+        validate_buffers(buffers)?;
+        log_buffers(buffers);
+
+        self.server.allocate_buffers_raw(buffers)
+    }
+
+    fn replace_s2c_buffers(&mut self, pool: Arc<BufferPool>) -> anyhow::Result<()> {
+        log_buffers(&pool.iovecs());
+
+        self.server.replace_s2c_buffers(pool)
+    }
 
-        self.server.allocate_buffers(buffers);
+    fn resize_buffers(&mut self, count: usize, f: impl FnMut(ServerEvent)) -> anyhow::Result<()> {
+        self.server.resize_buffers(count, f)
     }
 
     /// Impl with local sends BEFORE broadcasting
@@ -84,13 +254,230 @@ impl ServerDef for Server {
         &mut self,
         global: &mut Global,
         writers: impl Iterator<Item = RefreshItems<'a>>,
-    ) {
-        self.server.write_all(global, writers);
+    ) -> FlushResult {
+        self.server.write_all(global, writers)
+    }
+
+    fn close_after_send(&mut self, fd: Fd) {
+        self.server.close_after_send(fd);
+    }
+
+    fn connected_fds(&self) -> impl Iterator<Item = Fd> + '_ {
+        self.server.connected_fds()
+    }
+
+    fn is_connected(&self, fd: Fd) -> bool {
+        self.server.is_connected(fd)
+    }
+
+    fn connection_count(&self) -> usize {
+        self.server.connection_count()
+    }
+
+    fn max_connections(&self) -> usize {
+        self.server.max_connections()
+    }
+
+    fn peer_addr(&self, fd: Fd) -> Option<SocketAddr> {
+        self.server.peer_addr(fd)
     }
 
     fn submit_events(&mut self) {
         self.server.submit_events();
     }
+
+    fn shutdown(&mut self) -> std::io::Result<()> {
+        self.server.shutdown()
+    }
+}
+
+/// The [`RayonLocal`] slot of the current thread. This is recorded on the tracing spans of the
+/// servers so they can be split up per core.
+pub(crate) fn core_index() -> usize {
+    rayon::current_thread_index().unwrap_or_else(rayon::current_num_threads)
+}
+
+fn log_buffers(buffers: &[iovec]) {
+    for (idx, elem) in buffers.iter().enumerate() {
+        let ptr = elem.iov_base as *const u8;
+        let len = elem.iov_len;
+        let len_readable = humansize::SizeFormatter::new(len, humansize::BINARY);
+        debug!("buffer {idx} {ptr:?} of len {len} = {len_readable}");
+    }
+}
+
+/// Registering no buffers or an empty buffer would leave writes with nothing to point into, so
+/// this is rejected up front instead of failing on the first write.
+fn validate_buffers(buffers: &[iovec]) -> anyhow::Result<()> {
+    ensure!(!buffers.is_empty(), "no buffers to allocate");
+
+    for (idx, elem) in buffers.iter().enumerate() {
+        ensure!(elem.iov_len != 0, "buffer {idx} is empty");
+        ensure!(!elem.iov_base.is_null(), "buffer {idx} is null");
+    }
+
+    Ok(())
+}
+
+/// Checks the buffers which replace the `registered` ones in
+/// [`ServerDef::replace_s2c_buffers`]. The [`IoBuf`] of every core writes from the buffer at its
+/// index, so there must not be fewer buffers than before, or the writes of some cores would point
+/// into no buffer.
+fn validate_resize(registered: usize, new_buffers: &[iovec]) -> anyhow::Result<()> {
+    validate_buffers(new_buffers)?;
+
+    ensure!(
+        new_buffers.len() >= registered,
+        "cannot shrink from {registered} to {} buffers, since every registered buffer can be in \
+         use by a core",
+        new_buffers.len()
+    );
+
+    Ok(())
+}
+
+/// The backlog of every listening socket.
+const LISTEN_BACKLOG: libc::c_int = 128;
+
+/// Socket options which are set on every listener before it starts listening, and on every
+/// connection it accepts. See [`ServerDef::new_multi_with_socket_opts`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketOpts {
+    /// Sets `SO_REUSEPORT`, so several processes can listen on the same port. On Linux, the
+    /// kernel then spreads the connections it accepts across them, so a server can be scaled out
+    /// by starting more processes. Other platforms let the processes bind, but may not balance
+    /// between them. This is a no-op on platforms which are not Unix. This defaults to `false`.
+    pub reuseport: bool,
+    /// Sets `SO_REUSEADDR`, so the server can listen again right after it restarted while
+    /// connections of the previous process are still in `TIME_WAIT`. This defaults to `true`.
+    pub reuseaddr: bool,
+    /// Sets `SO_BINDTODEVICE`, so only connections which arrive on the network interface with
+    /// this name, like `eth0`, are accepted. This needs `CAP_NET_RAW` on kernels older than 5.7.
+    ///
+    /// This is only supported on Linux, and a no-op with a warning on the generic server other
+    /// platforms use. This defaults to [`None`].
+    pub bind_device: Option<String>,
+    /// Sets `TCP_NODELAY` on every connection, which disables Nagle's algorithm.
+    ///
+    /// Nagle's algorithm holds small writes back until everything sent before them was
+    /// acknowledged, so they go out together. That saves headers on slow links, but delays
+    /// latency-sensitive packets like keep alives and movement by up to a round trip. The server
+    /// already coalesces everything a connection is sent in a tick into few writes, so this
+    /// defaults to `true`.
+    pub nodelay: bool,
+    /// Sets `SO_SNDBUF`, the number of bytes the kernel buffers for every connection before a
+    /// write has to wait.
+    ///
+    /// A larger buffer keeps more bytes in flight, which raises the throughput of bursts like the
+    /// chunks of a player who just joined on links with a high round-trip time, but costs kernel
+    /// memory for every connection. Whatever the kernel buffers is also queued ahead of packets
+    /// appended later, so there is little point in making this larger than the S2C ring of a
+    /// connection. The kernel doubles the size for its bookkeeping and caps it at
+    /// `net.core.wmem_max`. This defaults to [`None`], which keeps the kernel default and its
+    /// autotuning.
+    pub send_buffer_size: Option<usize>,
+    /// Sets `SO_RCVBUF`, the number of bytes the kernel buffers for every connection before the
+    /// server reads them. Like [`SocketOpts::send_buffer_size`], larger buffers trade kernel
+    /// memory for throughput, and the size is doubled and capped at `net.core.rmem_max`. This
+    /// defaults to [`None`].
+    ///
+    /// The receive window of a connection is negotiated when it is accepted, so this is also set
+    /// on the listener, which every connection inherits it from.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOpts {
+    fn default() -> Self {
+        Self {
+            reuseport: false,
+            reuseaddr: true,
+            bind_device: None,
+            nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+/// Sets the options of `opts` which apply to each connection on `socket`. This is called on every
+/// accepted connection, and on listeners so connections inherit the options before they are
+/// accepted.
+fn set_connection_opts(socket: SockRef<'_>, opts: &SocketOpts) -> std::io::Result<()> {
+    socket.set_nodelay(opts.nodelay)?;
+
+    if let Some(size) = opts.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+
+    if let Some(size) = opts.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+
+    Ok(())
+}
+
+/// Creates a non-blocking listener for every address in `addresses`. See
+/// [`ServerDef::new_multi`].
+fn bind_listeners(addresses: &[SocketAddr], opts: &SocketOpts) -> anyhow::Result<Vec<Socket>> {
+    ensure!(!addresses.is_empty(), "no addresses specified");
+
+    addresses
+        .iter()
+        .map(|&address| {
+            let v6_only = address.is_ipv6()
+                && addresses
+                    .iter()
+                    .any(|other| other.is_ipv4() && other.port() == address.port());
+
+            bind_listener(address, v6_only, opts)
+                .with_context(|| format!("failed to listen on {address}"))
+        })
+        .collect()
+}
+
+fn bind_listener(address: SocketAddr, v6_only: bool, opts: &SocketOpts) -> std::io::Result<Socket> {
+    let listener = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+
+    if address.is_ipv6() {
+        listener.set_only_v6(v6_only)?;
+    }
+
+    listener.set_reuse_address(opts.reuseaddr)?;
+
+    #[cfg(unix)]
+    listener.set_reuse_port(opts.reuseport)?;
+
+    if let Some(device) = &opts.bind_device {
+        #[cfg(target_os = "linux")]
+        listener.bind_device(Some(device.as_bytes()))?;
+
+        #[cfg(not(target_os = "linux"))]
+        tracing::warn!(
+            "binding to {device} is only supported on Linux, so connections from every interface \
+             are accepted"
+        );
+    }
+
+    set_connection_opts(SockRef::from(&listener), opts)?;
+
+    listener.set_nonblocking(true)?;
+    listener.bind(&address.into())?;
+    listener.listen(LISTEN_BACKLOG)?;
+
+    Ok(listener)
+}
+
+/// The addresses `listeners` are bound to.
+fn local_addrs(listeners: &[Socket]) -> anyhow::Result<Vec<SocketAddr>> {
+    listeners
+        .iter()
+        .map(|listener| {
+            listener
+                .local_addr()?
+                .as_socket()
+                .context("listener is not bound to an IP address")
+        })
+        .collect()
 }
 
 #[allow(unused, reason = "this is used on linux")]
@@ -99,411 +486,4517 @@ pub struct RefreshItems<'a> {
     pub fd: Fd,
 }
 
-pub trait ServerDef {
-    fn new(address: impl ToSocketAddrs) -> anyhow::Result<Self>
-    where
-        Self: Sized;
-    fn drain(&mut self, f: impl FnMut(ServerEvent)) -> std::io::Result<()>;
+/// What [`ServerDef::write_all`] handed to the OS.
+///
+/// The bytes were submitted, which does not mean they were sent. Each write completes later with
+/// a [`ServerEvent::SentData`] on a drain, or fails with a [`ServerEvent::Error`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct FlushResult {
+    /// The connections at least one write was submitted for.
+    pub fds_written: usize,
+    /// The bytes of all submitted writes together.
+    pub bytes_submitted: usize,
+    /// The number of submitted writes. Packets which are next to each other in a ring are
+    /// coalesced into one write, so this is usually much lower than the number of packets.
+    pub iovecs: usize,
+}
 
-    // todo:make unsafe
-    fn allocate_buffers(&mut self, buffers: &[iovec]);
+/// The writes which [`Packets::swap`] took out of a connection, so they can be sent while the
+/// packets of the next tick are appended to the connection.
+///
+/// # Ownership
+///
+/// A snapshot owns the [`PacketWriteInfo`]s, not the bytes they point to. Those stay in the
+/// [`Ring`] of the core which encoded them, and the [`Packets`] the snapshot was taken from keeps
+/// them from being overwritten: [`Packets::oldest_unsent`] includes them until
+/// [`Packets::set_successfully_sent`] confirmed every write of the snapshot, which is what the
+/// ingress system does for each [`ServerEvent::SentData`]. The snapshot itself can be dropped as
+/// soon as it is empty.
+///
+/// This means a snapshot must not outlive the [`Packets`] it was taken from: once the player is
+/// removed, nothing keeps the bytes it points to from being overwritten, and writing them would
+/// send whatever the ring holds by then. This is not enforced by a lifetime, since the snapshot
+/// has to be in flight while packets are appended to the [`Packets`]. A snapshot has to be:
+///
+/// - passed to [`ServerDef::write_all`] with [`PacketsSnapshot::refresh_items`] before the rings
+///   are released at the end of the tick, and dropped with its [`Packets`] at the latest.
+/// - given back with [`Packets::restore`] if writes are left in it, like the ones the
+///   [`BandwidthLimiter`] deferred. Dropping a snapshot with writes left means
+///   [`Packets::number_sending`] never reaches zero, so the connection is never sent to again.
+///
+/// Only one snapshot of a connection can be in flight, since [`Packets::swap`] has to wait for
+/// [`Packets::can_send`] like [`Packets::prepare_for_send`].
+#[derive(Debug, Default)]
+pub struct PacketsSnapshot {
+    writes: RayonLocal<VecDeque<PacketWriteInfo>>,
+}
 
-    fn write_all<'a>(
-        &mut self,
-        global: &mut Global,
-        writers: impl Iterator<Item = RefreshItems<'a>>,
-    );
+impl PacketsSnapshot {
+    /// The writes to `fd` in the form [`ServerDef::write_all`] takes them. Writes which the server
+    /// passed to the OS are removed from the snapshot.
+    pub fn refresh_items(&mut self, fd: Fd) -> RefreshItems<'_> {
+        RefreshItems {
+            write: &mut self.writes,
+            fd,
+        }
+    }
 
-    fn submit_events(&mut self);
+    /// The number of writes which were not sent yet.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.writes
+            .fold_all(0, |count, writes| count + writes.len())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.writes.iter().all(VecDeque::is_empty)
+    }
 }
 
-struct NotImplemented;
+/// The [`ServerDef::max_connections`] of servers which are not configured otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 32_000;
+
+/// The number of connections past [`ServerDef::max_connections`] which are still accepted, so
+/// they can be shown the status or told that the server is full instead of being dropped.
+pub const FULL_CONNECTION_HEADROOM: usize = 256;
+
+/// Set this to fail the tests which need `io_uring` where it is not available, instead of
+/// skipping them.
+#[cfg(test)]
+pub(crate) const REQUIRE_IO_URING: &str = "HYPERION_REQUIRE_IO_URING";
+
+pub trait ServerDef {
+    /// Listens on the first address `address` resolves to. See [`ServerDef::new_multi`].
+    fn new(address: impl ToSocketAddrs) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let Some(address) = address.to_socket_addrs()?.next() else {
+            anyhow::bail!("no addresses specified")
+        };
+
+        Self::new_multi(&[address])
+    }
 
-impl ServerDef for NotImplemented {
-    fn new(_address: impl ToSocketAddrs) -> anyhow::Result<Self>
+    /// Listens on every address in `addresses`, and accepts connections from all of them.
+    ///
+    /// IPv6 listeners are dual-stack, so `[::]:25565` also accepts IPv4 connections. The exception
+    /// is when there is also an IPv4 address with the same port, since the two would conflict.
+    fn new_multi(addresses: &[SocketAddr]) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        unimplemented!("not implemented; use Linux")
+        Self::new_multi_with_socket_opts(addresses, &SocketOpts::default())
     }
 
-    fn drain(&mut self, _f: impl FnMut(ServerEvent)) -> std::io::Result<()> {
-        unimplemented!("not implemented; use Linux")
+    /// Like [`ServerDef::new`], but sets `opts` on the listener before it starts listening.
+    fn new_with_socket_opts(address: impl ToSocketAddrs, opts: &SocketOpts) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let Some(address) = address.to_socket_addrs()?.next() else {
+            anyhow::bail!("no addresses specified")
+        };
+
+        Self::new_multi_with_socket_opts(&[address], opts)
     }
 
-    fn allocate_buffers(&mut self, _buffers: &[iovec]) {
-        unimplemented!("not implemented; use Linux")
+    /// Like [`ServerDef::new_multi`], but sets `opts` on every listener before it starts
+    /// listening.
+    fn new_multi_with_socket_opts(
+        addresses: &[SocketAddr],
+        opts: &SocketOpts,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized;
+
+    /// The addresses the server is listening on, in the order they were passed to
+    /// [`ServerDef::new_multi`]. Unlike the addresses passed in, these have the actual ports if
+    /// port 0 was used.
+    fn local_addrs(&self) -> &[SocketAddr];
+
+    fn drain(&mut self, f: impl FnMut(ServerEvent)) -> std::io::Result<()>;
+
+    /// Like [`ServerDef::drain`], but handles at most `max_events` completions, so a burst of
+    /// completions cannot take up a whole tick. Returns whether completions are left, which the
+    /// next drain handles first, in the order they arrived.
+    ///
+    /// On Linux, the budget counts `io_uring` completions. A completion can emit more than one
+    /// event, like an error and the removal of the player, and those are never split across
+    /// drains.
+    ///
+    /// Servers which cannot stop partway handle everything and return `false`.
+    fn drain_budget(
+        &mut self,
+        max_events: usize,
+        f: impl FnMut(ServerEvent),
+    ) -> std::io::Result<bool> {
+        let _ = max_events;
+        self.drain(f)?;
+        Ok(false)
     }
 
+    /// Registers the S2C buffers which [`ServerDef::write_all`] writes from. The index of each
+    /// buffer is the index of the [`IoBuf`] which owns it.
+    ///
+    /// The server keeps `pool` alive, so the buffers stay valid for as long as the server might
+    /// read from them.
+    ///
+    /// # Errors
+    /// If `pool` is empty or the buffers could not be registered.
+    fn allocate_buffers(&mut self, pool: Arc<BufferPool>) -> anyhow::Result<()>;
+
+    /// Like [`ServerDef::allocate_buffers`], but for buffers which are not owned by a
+    /// [`BufferPool`].
+    ///
+    /// # Safety
+    /// Every buffer must stay valid, and must not be accessed other than through the rings, until
+    /// the server is dropped.
+    ///
+    /// # Errors
+    /// If `buffers` is empty, any buffer is empty, or the buffers could not be registered.
+    unsafe fn allocate_buffers_raw(&mut self, buffers: &[iovec]) -> anyhow::Result<()>;
+
+    /// Replaces the registered S2C buffers with the buffers of `pool` without recreating the
+    /// server, like after the [`BufferPool`] was outgrown. Like with
+    /// [`ServerDef::allocate_buffers`], the index of each buffer is the index of the [`IoBuf`]
+    /// which owns it, so there must be at least as many buffers as before.
+    ///
+    /// With `io_uring`, a write refers to its buffer by index, so the old buffers can only be
+    /// unregistered once nothing refers to them. Between two ticks, that means:
+    ///
+    /// 1. Drain until [`ServerEvent::SentData`] was emitted for every write passed to
+    ///    [`ServerDef::write_all`], so none is in flight. This is checked.
+    /// 2. Call this, which unregisters the old buffers and registers the new ones. Nothing can be
+    ///    submitted in between, since this takes `&mut self`. If the new buffers cannot be
+    ///    registered, the old ones are registered again.
+    /// 3. Call [`IoBufs::move_to_pool`] before anything is appended again.
+    ///
+    /// The generic server copies the bytes out of the rings when they are written, so it only
+    /// swaps the buffers it checks writes against.
+    ///
+    /// # Errors
+    /// If `pool` is empty, has fewer buffers than are registered, writes are still in flight, or
+    /// the buffers could not be registered.
+    fn replace_s2c_buffers(&mut self, pool: Arc<BufferPool>) -> anyhow::Result<()>;
+
+    /// Resizes the C2S buffers the kernel receives into to `count` buffers, like when many more
+    /// players joined than the buffers were sized for. This does nothing on servers which do not
+    /// provide buffers to the kernel.
+    ///
+    /// With `io_uring`, every connection has a recv in flight which picks its buffers from the
+    /// provided-buffer ring, so the ring can only be replaced once none is. This cancels the recvs
+    /// and drains until all of them completed, passing the events of the drain to `f`, then
+    /// replaces the ring and requests the recvs again. Connections accepted in the meantime get
+    /// their recv once the new ring is registered. Nothing is lost, since the data is left in the
+    /// socket until it is received again.
+    ///
+    /// Buffers which are held through a [`RecvBuffer`] stay valid, and are dropped with the old
+    /// ring instead of being given back to the new one.
+    ///
+    /// # Errors
+    /// If `count` is not a power of two of at most 32768, or the new ring could not be
+    /// registered, in which case a ring of the old size is registered again.
+    fn resize_buffers(&mut self, count: usize, f: impl FnMut(ServerEvent)) -> anyhow::Result<()>;
+
+    /// Submits the queued writes of every connection in `writers` which the [`BandwidthLimiter`]
+    /// of `global` allows, and returns what was submitted.
+    ///
+    /// With `io_uring`, the writes are only pushed to the submission queue here, and are passed
+    /// to the kernel by [`ServerDef::submit_events`]. [`ServerDef::flush`] does both.
     fn write_all<'a>(
         &mut self,
-        _global: &mut Global,
-        _writers: impl Iterator<Item = RefreshItems<'a>>,
-    ) {
-        unimplemented!("not implemented; use Linux")
+        global: &mut Global,
+        writers: impl Iterator<Item = RefreshItems<'a>>,
+    ) -> FlushResult;
+
+    /// [`ServerDef::write_all`] followed by [`ServerDef::submit_events`], for callers which do
+    /// not need to do anything in between. The result counts bytes which were submitted, not
+    /// sent; see [`FlushResult`].
+    fn flush<'a>(
+        &mut self,
+        global: &mut Global,
+        writers: impl Iterator<Item = RefreshItems<'a>>,
+    ) -> FlushResult {
+        let result = self.write_all(global, writers);
+        self.submit_events();
+        result
     }
 
-    fn submit_events(&mut self) {
-        unimplemented!("not implemented; use Linux")
+    /// Closes `fd` once every write which has already been passed to [`ServerDef::write_all`] for
+    /// it has completed. A [`ServerEvent::RemovePlayer`] for `fd` is emitted on a later drain.
+    fn close_after_send(&mut self, fd: Fd);
+
+    /// Every connection which has been added and not removed or closed yet.
+    fn connected_fds(&self) -> impl Iterator<Item = Fd> + '_;
+
+    /// Whether `fd` is one of [`ServerDef::connected_fds`]. This is `false` for an `fd` whose
+    /// connection was closed, even if another connection got its slot since.
+    ///
+    /// [`ServerDef::write_all`] skips the writes to such an `fd`, and
+    /// [`ServerDef::close_after_send`] ignores it.
+    fn is_connected(&self, fd: Fd) -> bool {
+        self.connected_fds().any(|connected| connected == fd)
     }
+
+    /// The number of connections [`ServerDef::connected_fds`] returns.
+    fn connection_count(&self) -> usize;
+
+    /// The most connections the game keeps. Once there are more, new connections can still ask
+    /// for the status, but they are disconnected with a "server full" message when they log in.
+    ///
+    /// So that they can be told, up to [`FULL_CONNECTION_HEADROOM`] connections past this are
+    /// accepted. Past that, connections are closed as soon as they are accepted and counted with
+    /// [`ServerEvent::AcceptsRejected`].
+    fn max_connections(&self) -> usize;
+
+    /// The address of the peer of `fd`, from when it was accepted. This is [`None`] once `fd` has
+    /// been closed, or if the OS did not report an address.
+    fn peer_addr(&self, fd: Fd) -> Option<SocketAddr>;
+
+    fn submit_events(&mut self);
+
+    /// Flushes all queued writes, waits for them to complete, and closes every connection.
+    ///
+    /// Events which occur while shutting down are discarded. No more events should be drained
+    /// after this is called.
+    fn shutdown(&mut self) -> std::io::Result<()>;
 }
 
 /// The Minecraft protocol version this library currently targets.
-pub const PROTOCOL_VERSION: i32 = 763;
+pub const PROTOCOL_VERSION: i32 = ProtocolVersion::CURRENT.protocol();
+
+/// The maximum length of a packet, not counting its length prefix.
+///
+/// Vanilla reads the length prefix as a VarInt of at most 3 bytes, so the largest length it can
+/// hold is 2^21 - 1. [`valence_protocol::MAX_PACKET_SIZE`] is 2^21, which is one more: valence
+/// uses it as an inclusive bound for the packets it reads, but a packet of exactly 2^21 bytes
+/// needs a 4 byte prefix and is rejected by vanilla clients.
+pub const MAX_PACKET_SIZE: usize = 0x001F_FFFF;
 
-// todo: this is one off.. why?
-// pub const MAX_PACKET_SIZE: usize = 0x001F_FFFF;
-/// The maximum number of bytes that can be sent in a single packet.
-pub const MAX_PACKET_SIZE: usize = valence_protocol::MAX_PACKET_SIZE as usize;
+/// The maximum size of the length prefix of a packet. See [`MAX_PACKET_SIZE`].
+pub const MAX_PACKET_LEN_SIZE: usize = 3;
+
+/// The maximum number of bytes a single encoded packet takes up, including its length prefix.
+pub const MAX_ENCODED_PACKET_SIZE: usize = MAX_PACKET_SIZE + MAX_PACKET_LEN_SIZE;
 
 /// The stringified name of the Minecraft version this library currently
 /// targets.
-pub const MINECRAFT_VERSION: &str = "1.20.1";
+pub const MINECRAFT_VERSION: &str = ProtocolVersion::CURRENT.name();
 
+mod accept_limit;
+#[cfg(feature = "tokio")]
+mod async_server;
+mod compression;
 mod decoder;
+mod disconnect;
+mod drain;
 pub mod encoder;
+mod encryption;
+mod filter;
+mod handshake;
+mod legacy_ping;
+mod metrics;
+#[cfg(any(test, feature = "testing"))]
+mod mock;
+mod plugin_message;
+mod protocol;
+mod queue;
+#[cfg(feature = "record")]
+mod record;
+mod recv_buffer;
+mod registry;
+mod status;
+mod velocity;
 
-pub use decoder::PacketDecoder;
+pub use accept_limit::{AcceptPolicy, AcceptRate};
+#[cfg(feature = "tokio")]
+pub use async_server::{AsyncServer, AsyncServerHandle, OwnedServerEvent};
+#[cfg(feature = "zstd")]
+pub use compression::ZstdCompressor;
+pub use compression::{CompressionBackend, PacketCompressor};
+pub use decoder::{DecodeError, Frames, PacketDecoder};
+pub use disconnect::DisconnectReason;
+pub use drain::{Drain, DrainStep, FLUSH_TIMEOUT};
+pub use encoder::{encode_to_vec, AppendError, CompressionPolicy, Priority};
+pub use encryption::{PacketDecryptor, PacketEncryptor};
+pub use filter::{FilterAction, PacketFilter, PacketFilters};
+pub use handshake::{
+    decode_handshake, Handshake, HandshakeConfig, HandshakeError, MAX_ADDRESS_LEN,
+};
+pub use legacy_ping::{LegacyPing, LegacyStatus};
+#[cfg(any(test, feature = "testing"))]
+pub use mock::MockServer;
+pub use plugin_message::{plugin_message, MAX_PLUGIN_MESSAGE_LEN};
+pub use protocol::ProtocolVersion;
+pub use queue::{PacketQueue, DEFAULT_ENCODE_BUDGET};
 use rayon_local::RayonLocal;
+#[cfg(feature = "record")]
+pub use record::{Recorder, Replay, ReplayDecoder};
+pub use recv_buffer::RecvBuffer;
+pub use registry::PacketRegistry;
+pub use status::{SamplePlayer, StatusResponse, FAVICON_SIZE};
+pub use velocity::{ForwardedPlayer, VelocityForwarding};
 
+pub use self::metrics::{CoreMetrics, NetMetrics};
+pub use crate::singleton::ring::{Buf, BufferPool, HugePages, RingMode, VecBuf};
 use crate::{
-    event::Scratches, net::encoder::append_packet_without_compression,
-    singleton::ring::register_rings,
+    event::{Scratch, ScratchBuffer, Scratches},
+    net::{decoder::frame_len, encoder::encode_uncompressed_into},
+    singleton::ring::Buf,
 };
 
-// 128 MiB * num_cores
+/// The default size of each per-core S2C [`Ring`] buffer. In total, this is 128 MiB * `num_cores`.
 pub const S2C_BUFFER_SIZE: usize = 1024 * 1024 * 128;
 
+/// The smallest S2C buffer size that can be passed to [`IoBuf::new`].
+///
+/// Every encode reserves a contiguous [`MAX_ENCODED_PACKET_SIZE`] region of the [`Ring`], so
+/// anything smaller would not be able to hold a single maximum-sized packet. The [`Ring`] does not
+/// require its size to be a power of two.
+pub const MIN_S2C_BUFFER_SIZE: usize = MAX_ENCODED_PACKET_SIZE;
+
 #[derive(Debug)]
 pub struct IoBuf {
     /// The encoding buffer and logic
     enc: encoder::PacketEncoder,
     buf: Ring,
     index: usize,
+    /// Reused by [`Compose::encoded_len`] so measuring does not touch `buf`
+    measure: Vec<u8>,
+    metrics: CoreMetrics,
 }
 
 #[derive(Component, Deref, DerefMut)]
 pub struct Compressors {
-    compressors: RayonLocal<RefCell<libdeflater::Compressor>>,
+    #[deref]
+    #[deref_mut]
+    compressors: RayonLocal<RefCell<Box<dyn PacketCompressor>>>,
+    /// Creates the compressors in `compressors`
+    factory: CompressorFactory,
+    /// The level set with [`Compressors::set_level`]
+    level: AtomicI32,
+    /// The level each compressor in `compressors` was created with
+    local_levels: RayonLocal<Cell<i32>>,
+    /// The compressors for [`CompressionBackend::Zstd`], which keep the level they were created
+    /// with
+    #[cfg(feature = "zstd")]
+    zstd: RayonLocal<RefCell<Box<dyn PacketCompressor>>>,
+    /// See [`Compressors::with_backend`].
+    backend: CompressionBackend,
 }
 
 impl Compressors {
+    /// Creates a [`libdeflater::Compressor`] for each core.
     #[must_use]
     pub fn new(level: CompressionLvl) -> Self {
-        Self {
-            compressors: RayonLocal::init(|| libdeflater::Compressor::new(level).into()),
-        }
-    }
-}
-
-#[derive(Component, Debug, Deref, DerefMut)]
-pub struct IoBufs {
-    locals: RayonLocal<RefCell<IoBuf>>,
-}
-
-impl IoBufs {
-    pub fn init(threshold: CompressionThreshold, server_def: &mut impl ServerDef) -> Self {
-        let mut locals = RayonLocal::init_with_index(|i| IoBuf::new(threshold, i));
-
-        let rings = locals.get_all_mut().iter_mut().map(IoBuf::buf_mut);
-        register_rings(server_def, rings);
-
-        let locals = locals.map(RefCell::new);
-
-        Self { locals }
+        Self::with_factory(level, libdeflater::Compressor::new)
     }
-}
 
-impl IoBuf {
+    /// Creates a compressor for each core with `factory`, which is called again with the new
+    /// level whenever it changes.
+    ///
+    /// `factory` is only used for [`CompressionBackend::Zlib`].
+    ///
+    /// # Panics
+    /// With the `zstd` feature, if a zstd context cannot be created.
     #[must_use]
-    pub fn new(threshold: CompressionThreshold, index: usize) -> Self {
+    pub fn with_factory<C: PacketCompressor + 'static>(
+        level: CompressionLvl,
+        factory: impl Fn(CompressionLvl) -> C + Send + Sync + 'static,
+    ) -> Self {
+        let factory: CompressorFactory = Box::new(move |level| Box::new(factory(level)));
+
         Self {
-            enc: encoder::PacketEncoder::new(threshold),
-            buf: Ring::new(S2C_BUFFER_SIZE),
-            index,
+            compressors: RayonLocal::init(|| factory(level).into()),
+            factory,
+            level: AtomicI32::new(level.into()),
+            local_levels: RayonLocal::init(|| Cell::new(level.into())),
+            #[cfg(feature = "zstd")]
+            zstd: RayonLocal::init(|| {
+                let zstd =
+                    ZstdCompressor::new(level.into()).expect("failed to create zstd context");
+                RefCell::new(Box::new(zstd))
+            }),
+            backend: CompressionBackend::Zlib,
         }
     }
 
+    /// Compresses every packet with `backend` instead of zlib.
     #[must_use]
-    pub const fn enc(&self) -> &encoder::PacketEncoder {
-        &self.enc
+    pub fn with_backend(mut self, backend: CompressionBackend) -> Self {
+        self.backend = backend;
+        self
     }
 
-    pub fn enc_mut(&mut self) -> &mut encoder::PacketEncoder {
-        &mut self.enc
+    #[must_use]
+    pub const fn backend(&self) -> CompressionBackend {
+        self.backend
     }
 
+    /// The level new compressors are created with.
     #[must_use]
-    pub const fn index(&self) -> usize {
-        self.index
+    #[allow(clippy::missing_panics_doc, reason = "the level is always valid")]
+    pub fn level(&self) -> CompressionLvl {
+        CompressionLvl::new(self.level.load(atomic::Ordering::Relaxed))
+            .expect("the level was valid when it was set")
     }
 
-    pub fn buf_mut(&mut self) -> &mut Ring {
-        &mut self.buf
+    /// Changes the compression level at runtime, e.g. to lower it under CPU pressure.
+    ///
+    /// A compressor cannot change its level, so each core creates a new one the next time it
+    /// borrows its compressor through [`Compressors::get_local`]. Appends which are
+    /// already in progress on other cores keep using the old level until then.
+    pub fn set_level(&self, level: CompressionLvl) {
+        self.level.store(level.into(), atomic::Ordering::Relaxed);
     }
-}
 
-#[derive(HandlerParam, Copy, Clone)]
-pub struct Compose<'a> {
-    pub bufs: Single<'a, &'static IoBufs>,
-    pub compressor: Single<'a, &'static Compressors>,
-    pub scratch: Single<'a, &'static Scratches>,
-}
+    /// The compressor of the current thread for [`Compressors::backend`]. A zlib compressor is
+    /// recreated first if the level was changed with [`Compressors::set_level`] since it was
+    /// created.
+    pub fn get_local(&self) -> &RefCell<Box<dyn PacketCompressor>> {
+        #[cfg(feature = "zstd")]
+        if self.backend == CompressionBackend::Zstd {
+            return self.zstd.get_local();
+        }
 
-/// This is useful for the ECS so we can use Single<&mut Broadcast> instead of having to use a marker struct
-#[derive(Component, From, Deref, DerefMut, Default)]
-pub struct Broadcast(Packets);
+        let compressor = self.compressors.get_local();
 
-/// Stores indices of packets
-#[derive(Component, Default)]
-pub struct Packets {
-    to_write: RayonLocal<VecDeque<PacketWriteInfo>>,
-    number_sending: AtomicUsize,
+        let level = self.level.load(atomic::Ordering::Relaxed);
+        let local_level = self.local_levels.get_local();
+
+        if local_level.get() != level {
+            // if it is borrowed further up the stack, the new level is used the next time
+            if let Ok(mut compressor) = compressor.try_borrow_mut() {
+                *compressor = (self.factory)(self.level());
+                local_level.set(level);
+            }
+        }
+
+        compressor
+    }
 }
 
-impl Packets {
-    pub fn extend(&mut self, other: &Self) {
-        let this = self.to_write.iter_mut();
-        let other = other.to_write.iter();
+/// The [`IoBuf`] of every core.
+///
+/// Each [`IoBuf`] is behind a [`Mutex`] rather than a [`RefCell`], since connections pinned with
+/// [`Packets::pin_to_core`] are appended to from whichever core runs the handler. Without
+/// pinning, every core only locks its own [`IoBuf`], so the lock is never contended.
+#[derive(Component, Debug, Deref, DerefMut)]
+pub struct IoBufs {
+    #[deref]
+    #[deref_mut]
+    locals: RayonLocal<Mutex<IoBuf>>,
+    /// See [`IoBufs::track_packet_types`].
+    track_packet_types: AtomicBool,
+}
 
-        for (this, other) in this.zip(other) {
-            this.extend(other);
-        }
+/// Options for [`IoBufs::init_with_config`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoBufsConfig {
+    /// What the rings do when a write does not fit. See [`IoBufs::init_with_mode`].
+    pub mode: RingMode,
+    /// Touches every page of the rings before they are used, from the core which owns each ring.
+    /// See [`Ring::prefault`].
+    ///
+    /// The OS maps the pages of a fresh allocation lazily, so without this every 4 KiB page
+    /// stalls the core the first time a packet is written into it. This moves that cost to
+    /// startup instead. The rings are prefaulted in parallel, so the startup cost does not grow
+    /// with the number of cores.
+    ///
+    /// On Linux, registering the rings with `io_uring` pins and therefore maps their pages anyway,
+    /// but from a single thread. Prefaulting first maps each ring on the NUMA node of its core.
+    pub prefault: bool,
+    /// Backs the rings with huge pages if they are available. See [`HugePages`].
+    pub huge_pages: HugePages,
+}
+
+impl IoBufs {
+    /// Creates one [`IoBuf`] per core, each backed by a [`Ring`] of `buffer_size` bytes, and
+    /// registers the rings with `server_def`.
+    ///
+    /// See [`IoBuf::new`] for the constraints on `buffer_size`.
+    ///
+    /// # Panics
+    /// If [`PacketRegistry::validate`] finds packets with the wrong IDs for [`PROTOCOL_VERSION`].
+    pub fn init(
+        threshold: CompressionThreshold,
+        buffer_size: usize,
+        server_def: &mut impl ServerDef,
+    ) -> anyhow::Result<Self> {
+        Self::init_with_mode(threshold, buffer_size, RingMode::Fixed, server_def)
     }
 
-    pub fn get_write_mut(&mut self) -> &mut RayonLocal<VecDeque<PacketWriteInfo>> {
-        &mut self.to_write
+    /// Like [`IoBufs::init`], but the rings handle writes which do not fit according to `mode`.
+    pub fn init_with_mode(
+        threshold: CompressionThreshold,
+        buffer_size: usize,
+        mode: RingMode,
+        server_def: &mut impl ServerDef,
+    ) -> anyhow::Result<Self> {
+        let config = IoBufsConfig {
+            mode,
+            ..IoBufsConfig::default()
+        };
+
+        Self::init_with_config(threshold, buffer_size, config, server_def)
     }
 
-    #[must_use]
-    pub fn can_send(&self) -> bool {
-        if self.number_sending.load(atomic::Ordering::Relaxed) != 0 {
-            return false;
+    /// Like [`IoBufs::init`], but with the options in `config`.
+    pub fn init_with_config(
+        threshold: CompressionThreshold,
+        buffer_size: usize,
+        config: IoBufsConfig,
+        server_def: &mut impl ServerDef,
+    ) -> anyhow::Result<Self> {
+        let IoBufsConfig {
+            mode,
+            prefault,
+            huge_pages,
+        } = config;
+
+        PacketRegistry::server().validate();
+
+        let pool = Arc::new(BufferPool::with_huge_pages(
+            rayon_local::count(),
+            mode.buffer_len(buffer_size),
+            huge_pages,
+        ));
+
+        let locals = RayonLocal::init_with_index(|i| {
+            let ring = Ring::from_pool_with_mode(pool.clone(), i, buffer_size, mode);
+            Mutex::new(IoBuf::with_ring(threshold, ring, i))
+        });
+
+        let mut bufs = Self {
+            locals,
+            track_packet_types: AtomicBool::new(false),
+        };
+
+        if prefault {
+            let start = Instant::now();
+            bufs.par_for_each_mut(|buf| buf.buf.prefault());
+            debug!("prefaulted the S2C rings in {:?}", start.elapsed());
         }
 
-        self.to_write.iter().any(|x| !x.is_empty())
+        server_def.allocate_buffers(pool)?;
+
+        Ok(bufs)
     }
 
-    pub fn set_successfully_sent(&self, d_count: usize) {
-        debug_assert!(
-            self.number_sending.load(atomic::Ordering::Relaxed) > 0,
-            "somehow number sending is 0 even though we just marked a successful send"
+    /// Moves the [`Ring`] of every core to its buffer in `pool`, once
+    /// [`ServerDef::replace_s2c_buffers`] registered `pool` in place of the buffers the rings
+    /// were backed by. Each ring keeps its capacity and [`RingMode`], and starts out empty. The
+    /// old buffers are freed once the last ring is moved.
+    ///
+    /// # Errors
+    /// If any ring still has bytes which were not released, since they may still be queued or
+    /// in flight, or if `pool` has too few or too small buffers for the rings. Nothing is moved
+    /// then.
+    pub fn move_to_pool(&mut self, pool: &Arc<BufferPool>) -> anyhow::Result<()> {
+        ensure!(
+            pool.len() >= self.locals.iter().count(),
+            "{} buffers are too few for {} rings",
+            pool.len(),
+            self.locals.iter().count()
         );
 
-        self.number_sending
-            .fetch_sub(d_count, atomic::Ordering::Relaxed);
-    }
+        for buf in self.locals.iter_mut() {
+            let buf = buf.get_mut();
+            let ring = &buf.buf;
 
-    pub fn prepare_for_send(&mut self) -> usize {
-        debug_assert!(
-            self.number_sending.load(atomic::Ordering::Relaxed) == 0,
-            "number sending is not 0 even though we are preparing for send"
-        );
-        let count = self.to_write.iter().map(VecDeque::len).sum();
-        self.number_sending = AtomicUsize::new(count);
-        count
+            ensure!(
+                ring.pending() == 0,
+                "the ring of core {} still has {} bytes which were not released",
+                buf.index,
+                ring.pending()
+            );
+
+            let needed = ring.mode().buffer_len(ring.capacity());
+            ensure!(
+                needed <= pool.buffer_len(),
+                "the ring of core {} needs buffers of {needed} bytes, but they are {} bytes",
+                buf.index,
+                pool.buffer_len()
+            );
+        }
+
+        for buf in self.locals.iter_mut() {
+            let buf = buf.get_mut();
+            let (capacity, mode) = (buf.buf.capacity(), buf.buf.mode());
+            buf.buf = Ring::from_pool_with_mode(pool.clone(), buf.index, capacity, mode);
+        }
+
+        Ok(())
     }
 
-    pub fn clear(&mut self) {
-        self.to_write.iter_mut().for_each(VecDeque::clear);
+    /// See [`encoder::PacketEncoder::set_compression_disabled`]. This is applied to the encoder of
+    /// every core.
+    ///
+    /// This blocks while the [`IoBuf`] of any core is locked.
+    pub fn set_compression_disabled(&self, disabled: bool) {
+        for buf in self.locals.iter() {
+            buf.lock().enc.set_compression_disabled(disabled);
+        }
     }
 
-    fn push(&self, writer: PacketWriteInfo, buf: &IoBuf) {
-        let idx = buf.index();
-        let to_write = unsafe { &mut *self.to_write.get_raw(idx).get() };
+    /// Whether every core counts the packets it encodes by type for
+    /// [`NetMetrics::by_packet_type`]. This costs a hash map lookup per packet, so it is off by
+    /// default.
+    ///
+    /// This blocks while the [`IoBuf`] of any core is locked, so it should only be called when
+    /// `enabled` differs from [`IoBufs::track_packet_types`].
+    pub fn set_track_packet_types(&self, enabled: bool) {
+        self.track_packet_types
+            .store(enabled, atomic::Ordering::Relaxed);
+
+        for buf in self.locals.iter() {
+            buf.lock().metrics.track_packet_types = enabled;
+        }
+    }
+
+    /// The value of the last [`IoBufs::set_track_packet_types`].
+    #[must_use]
+    pub fn track_packet_types(&self) -> bool {
+        self.track_packet_types.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Runs `f` on every [`IoBuf`] from the core which owns it. The [`Ring`] buffers are
+    /// core-affine, so touching them from another core causes cache lines to bounce between
+    /// cores.
+    pub fn par_for_each_mut(&mut self, f: impl Fn(&mut IoBuf) + Sync) {
+        self.locals.par_for_each_mut(|_, buf| f(buf.get_mut()));
+    }
+
+    /// Releases the bytes of every [`Ring`] which none of `packets` still refer to, so they can
+    /// be overwritten. Without this, appending eventually fails with [`AppendError::RingFull`].
+    pub fn release_sent<'a>(&self, packets: impl IntoIterator<Item = &'a Packets>) {
+        let mut oldest_unsent = vec![None; self.locals.iter().count()];
 
-        if let Some(last) = to_write.back_mut() {
-            let start_pointer_if_contiguous = unsafe { last.start_ptr.add(last.len as usize) };
-            if start_pointer_if_contiguous == writer.start_ptr {
-                last.len += writer.len;
-                return;
+        for pkts in packets {
+            for (idx, oldest_unsent) in oldest_unsent.iter_mut().enumerate() {
+                *oldest_unsent = oldest(*oldest_unsent, pkts.oldest_unsent(idx));
             }
         }
 
-        to_write.push_back(writer);
+        for (buf, oldest_unsent) in self.locals.iter().zip(oldest_unsent) {
+            let mut buf = buf.lock();
+            let ring = &mut buf.buf;
+            let position = oldest_unsent.unwrap_or_else(|| ring.position());
+
+            ring.release_until(position);
+
+            trace!(
+                "ring has {} pending bytes with a high water mark of {}",
+                ring.pending(),
+                ring.high_water_mark()
+            );
+        }
+    }
+
+    /// The state of every core's [`IoBuf`] for debugging, like from an admin command or a panic
+    /// hook. Cores whose [`IoBuf`] is currently locked are skipped.
+    ///
+    /// This must not be called while packets are appended to `broadcast`.
+    #[must_use]
+    pub fn debug_snapshot(
+        &self,
+        broadcast: &Broadcast,
+        compressors: &Compressors,
+    ) -> Vec<CoreBufState> {
+        let broadcast_writes = broadcast.packets.to_write.iter();
+        let broadcast_high = broadcast.packets.high.iter();
+
+        self.locals
+            .iter()
+            .zip(broadcast_writes.zip(broadcast_high))
+            .zip(compressors.local_levels.iter())
+            .enumerate()
+            .filter_map(|(core, ((buf, (to_write, high)), level))| {
+                let Some(buf) = buf.try_lock() else {
+                    debug!("skipping core {core} in snapshot because its buffer is locked");
+                    return None;
+                };
+
+                let ring = &buf.buf;
+
+                Some(CoreBufState {
+                    core,
+                    capacity: ring.capacity(),
+                    pending: ring.pending(),
+                    high_water_mark: ring.high_water_mark(),
+                    head: ring.head(),
+                    broadcast_writes: to_write.len() + high.len(),
+                    compression_level: level.get(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The state of the [`IoBuf`] of one core. See [`IoBufs::debug_snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CoreBufState {
+    pub core: usize,
+    /// See [`Ring::capacity`].
+    pub capacity: usize,
+    /// The bytes in the ring which have not been sent yet. See [`Ring::pending`].
+    pub pending: usize,
+    /// See [`Ring::high_water_mark`].
+    pub high_water_mark: usize,
+    /// The offset in the ring the next packet is written at.
+    pub head: usize,
+    /// The number of writes from this core queued in the [`Broadcast`].
+    pub broadcast_writes: usize,
+    /// The level of this core's zlib compressor.
+    pub compression_level: i32,
+}
+
+impl IoBuf {
+    /// Creates a new [`IoBuf`] backed by a [`Ring`] of `buffer_size` bytes.
+    ///
+    /// [`S2C_BUFFER_SIZE`] is a sensible default for production servers. Small test servers can
+    /// use something much smaller, but `buffer_size` must be at least [`MIN_S2C_BUFFER_SIZE`].
+    #[must_use]
+    pub fn new(threshold: CompressionThreshold, buffer_size: usize, index: usize) -> Self {
+        Self::with_ring(threshold, Ring::new(buffer_size), index)
+    }
+
+    fn with_ring(threshold: CompressionThreshold, buf: Ring, index: usize) -> Self {
+        let buffer_size = buf.capacity();
+
+        debug_assert!(
+            buffer_size >= MIN_S2C_BUFFER_SIZE,
+            "S2C buffer size of {buffer_size} is smaller than the minimum of {MIN_S2C_BUFFER_SIZE}"
+        );
+
+        Self {
+            enc: encoder::PacketEncoder::new(threshold),
+            buf,
+            index,
+            measure: Vec::new(),
+            metrics: CoreMetrics::default(),
+        }
+    }
+
+    #[must_use]
+    pub const fn enc(&self) -> &encoder::PacketEncoder {
+        &self.enc
+    }
+
+    pub fn enc_mut(&mut self) -> &mut encoder::PacketEncoder {
+        &mut self.enc
+    }
+
+    /// Sets the compression threshold to `threshold` until the returned guard is dropped. The
+    /// previous threshold is restored even if encoding fails or panics, so it cannot leak into
+    /// the packets appended afterwards.
+    pub fn with_compression(&mut self, threshold: CompressionThreshold) -> CompressionGuard<'_> {
+        let previous = self.enc.set_compression(threshold);
+
+        CompressionGuard {
+            buf: self,
+            previous,
+        }
+    }
+
+    #[must_use]
+    pub const fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn buf_mut(&mut self) -> &mut Ring {
+        &mut self.buf
+    }
+
+    #[must_use]
+    pub const fn metrics(&self) -> &CoreMetrics {
+        &self.metrics
     }
 
-    pub fn append_pre_compression_packet<P>(&self, pkt: &P, buf: &mut IoBuf) -> anyhow::Result<()>
+    /// Encodes `pkt` into the ring, and counts it towards the bytes of its packet type.
+    fn append_packet<P>(
+        &mut self,
+        pkt: &P,
+        scratch: &mut impl ScratchBuffer,
+        compressor: &mut (impl PacketCompressor + ?Sized),
+    ) -> Result<PacketWriteInfo, AppendError>
     where
         P: valence_protocol::Packet + valence_protocol::Encode,
     {
-        let compression = buf.enc.compression_threshold();
-        // none
-        buf.enc.set_compression(CompressionThreshold::DEFAULT);
+        let info = self
+            .enc
+            .append_packet(pkt, &mut self.buf, scratch, compressor)?;
 
-        let result = append_packet_without_compression(pkt, &mut buf.buf)?;
+        self.metrics.record_packet_type(P::ID, info.len as usize);
 
-        trace!("without compression: {result:?}");
+        Ok(info)
+    }
 
-        self.push(result, buf);
+    /// Copies the bytes of `pkt` into the ring, and counts it towards the bytes of its packet
+    /// type.
+    fn append_precompressed(
+        &mut self,
+        pkt: &PrecompressedPacket,
+    ) -> Result<PacketWriteInfo, AppendError> {
+        let data = pkt.as_bytes();
+
+        self.buf.get_contiguous(data.len())?.copy_from_slice(data);
+        let info = self.buf.advance(data.len());
+
+        self.metrics.record_packet_type(pkt.id, data.len());
+
+        Ok(info)
+    }
+}
+
+#[derive(HandlerParam, Copy, Clone)]
+pub struct Compose<'a> {
+    pub bufs: Single<'a, &'static IoBufs>,
+    pub compressor: Single<'a, &'static Compressors>,
+    pub scratch: Single<'a, &'static Scratches>,
+}
 
-        // reset
-        buf.enc.set_compression(compression);
+impl Compose<'_> {
+    /// Runs `f` with the [`IoBuf`], scratch buffer, and compressor of the current thread.
+    fn with_locals<T>(
+        &self,
+        f: impl FnOnce(&mut IoBuf, &mut Scratch, &mut dyn PacketCompressor) -> T,
+    ) -> T {
+        let mut buf = self.bufs.get_local().lock();
+        let mut scratch = self.scratch.get_local().borrow_mut();
+        let mut compressor = self.compressor.get_local().borrow_mut();
+
+        f(&mut *buf, &mut *scratch, &mut **compressor)
+    }
+
+    /// Like [`Compose::with_locals`], but with the [`IoBuf`] of [`Compose::buf_of`] `packets`.
+    /// The scratch buffer and compressor are still the ones of the current thread.
+    fn with_locals_of<T>(
+        &self,
+        packets: &Packets,
+        f: impl FnOnce(&mut IoBuf, &mut Scratch, &mut dyn PacketCompressor) -> T,
+    ) -> T {
+        let mut buf = self.buf_of(packets);
+        let mut scratch = self.scratch.get_local().borrow_mut();
+        let mut compressor = self.compressor.get_local().borrow_mut();
+
+        f(&mut *buf, &mut *scratch, &mut **compressor)
+    }
+
+    /// Appends `pkt` for `packets` to the [`IoBuf`] of [`Packets::ordered_core`] instead of the
+    /// one of the current thread, so it is sent after every packet which was appended this way
+    /// before, no matter which core appended it. See the ordering rules of [`Packets`].
+    ///
+    /// Connections which are not pinned all share the [`IoBuf`] of core 0 for this, so only use
+    /// it for sequences which rely on their order.
+    pub fn ordered_append<P>(&self, packets: &Packets, pkt: &P) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        let mut buf = self.buf_for(packets.ordered_core());
+        let mut scratch = self.scratch.get_local().borrow_mut();
+        let mut compressor = self.compressor.get_local().borrow_mut();
+
+        packets.append_to(pkt, None, &mut buf, &mut *scratch, &mut **compressor)?;
 
         Ok(())
     }
 
-    pub fn append<P>(&self, pkt: &P, compose: &Compose) -> anyhow::Result<()>
+    /// Sends the packets which `f` appends to the [`Bundle`] for `packets` as a bundle: between
+    /// two [`BundleSplitterS2c`] delimiters, which make the client handle all of them in the same
+    /// tick, like spawning an entity together with its metadata and equipment.
+    ///
+    /// The [`IoBuf`] of [`Compose::buf_of`] `packets` is locked until `f` returns, and the
+    /// bundle is only queued once its end delimiter was encoded, so nothing else for the
+    /// connection can end up between the delimiters. A bundle which does not fit before the end
+    /// of the [`Ring`] is queued as two writes, which are still sent back to back.
+    /// [`Priority::High`] packets which are appended later are sent before the whole bundle,
+    /// never inside it.
+    ///
+    /// If `f` or an append fails, nothing of the bundle is queued, since a client which only
+    /// received the start delimiter would hold back every packet after it.
+    pub fn bundle(
+        &self,
+        packets: &Packets,
+        f: impl FnOnce(&mut Bundle<'_>) -> Result<(), AppendError>,
+    ) -> Result<(), AppendError> {
+        self.with_locals_of(packets, |buf, scratch, compressor| {
+            packets.append_bundle(buf, scratch, compressor, f)
+        })
+    }
+
+    /// Locks the [`IoBuf`] of the core at `index` until the guard is dropped.
+    ///
+    /// Another core may be appending to it at the same time, so this blocks until it is done.
+    /// Never lock a second [`IoBuf`] while holding the guard, since two cores doing that in
+    /// opposite order deadlock.
+    ///
+    /// # Panics
+    /// If there is no core at `index`.
+    pub fn buf_for(&self, index: usize) -> MutexGuard<'_, IoBuf> {
+        self.bufs.get_all()[index].lock()
+    }
+
+    /// Locks the [`IoBuf`] packets for `packets` are appended to: the core the connection is
+    /// pinned to with [`Packets::pin_to_core`], or the core of the current thread if it is not
+    /// pinned. See [`Compose::buf_for`].
+    pub fn buf_of(&self, packets: &Packets) -> MutexGuard<'_, IoBuf> {
+        match packets.core {
+            Some(index) => self.buf_for(index),
+            None => self.bufs.get_local().lock(),
+        }
+    }
+}
+
+impl Compose<'_> {
+    /// Encodes and compresses `pkt` a single time so it can be sent to many connections with
+    /// [`Packets::append_precompressed`].
+    pub fn encode_once<P>(&self, pkt: &P) -> Result<PrecompressedPacket, AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        self.with_locals(|buf, scratch, compressor| {
+            PrecompressedPacket::encode(pkt, &buf.enc, scratch, compressor)
+        })
+    }
+
+    /// Encodes and compresses the chunk in `pkt` a single time. The result can be sent to any
+    /// number of viewers with [`Broadcast::send_cached`] or [`Packets::append_precompressed`] until
+    /// a block in the chunk changes.
+    ///
+    /// A chunk with many detailed sections can be longer than [`MAX_PACKET_SIZE`], which fails with
+    /// [`AppendError::TooLarge`]. Such a chunk can still be sent in several packets:
+    ///
+    /// 1. Cache the chunk with the sections which do not fit replaced by sections of a single
+    ///    block, like air, whose palettes take up a few bytes.
+    /// 2. After it, send every replaced section as a `ChunkDeltaUpdateS2c` which sets each of its
+    ///    non-air blocks. A section has 4096 blocks, so this always fits.
+    ///
+    /// Clients handle packets in the order they arrive, so the placeholders are replaced before
+    /// anything else can change the chunk.
+    pub fn cache_chunk(&self, pkt: &ChunkDataS2c<'_>) -> Result<CachedChunkPacket, AppendError> {
+        Ok(CachedChunkPacket {
+            pos: pkt.pos,
+            pkt: self.encode_once(pkt)?,
+        })
+    }
+
+    /// The number of bytes [`Packets::append`] would write for `pkt`, including the length prefix
+    /// and compression. The packet is encoded into a separate buffer, so the [`Ring`] is not
+    /// touched.
+    pub fn encoded_len<P>(&self, pkt: &P) -> anyhow::Result<usize>
     where
         P: valence_protocol::Packet + valence_protocol::Encode,
     {
-        let buf = compose.bufs.get_local();
+        let len = self
+            .with_locals(|buf, scratch, compressor| encoded_len(pkt, buf, scratch, compressor))?;
 
-        let scratch = compose.scratch.get_local();
-        let mut scratch = scratch.borrow_mut();
+        Ok(len)
+    }
 
-        let compressor = compose.compressor.get_local();
-        let mut compressor = compressor.borrow_mut();
+    /// Queues a plugin message sending `data` on `channel` for the connection of `packets`. See
+    /// [`plugin_message`] for what is rejected.
+    pub fn send_plugin_message(
+        &self,
+        packets: &Packets,
+        channel: &str,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let pkt = plugin_message(channel, data)?;
+        packets.append(&pkt, self)?;
 
-        let mut buf = buf.borrow_mut();
-        let buf = &mut *buf;
+        Ok(())
+    }
 
-        let result = buf
-            .enc
-            .append_packet(pkt, &mut buf.buf, &mut *scratch, &mut compressor)?;
+    /// Queues a disconnect packet with `reason` for the connection of `packets`, and closes the
+    /// connection as soon as it has been sent instead of waiting for the client to time out.
+    ///
+    /// The packet depends on `state`. During login, compression has not been negotiated yet, so a
+    /// [`LoginDisconnectS2c`] is encoded without compression. Connections which are still in the
+    /// handshake or status state cannot be sent a reason, so they are only closed.
+    ///
+    /// `state` is set to [`LoginState::Terminate`].
+    pub fn disconnect(
+        &self,
+        packets: &mut Packets,
+        state: &mut LoginState,
+        reason: &Text,
+    ) -> Result<(), AppendError> {
+        let reason = Cow::Borrowed(reason);
+
+        match *state {
+            LoginState::Handshake | LoginState::Status | LoginState::Terminate => {}
+            LoginState::Login | LoginState::Forwarding { .. } | LoginState::Queued { .. } => {
+                let pkt = LoginDisconnectS2c { reason };
+                self.with_locals_of(packets, |buf, _, _| {
+                    packets.append_pre_compression_packet(&pkt, buf)
+                })?;
+            }
+            LoginState::TransitioningPlay { .. } | LoginState::Play => {
+                packets.append(&DisconnectS2c { reason }, self)?;
+            }
+        }
+
+        *state = LoginState::Terminate;
+        packets.close_after_send();
+
+        Ok(())
+    }
+
+    /// Like [`Compose::disconnect`], but the message is picked by `reason`, and the disconnect
+    /// is logged and counted towards [`NetMetrics::by_disconnect_reason`] under
+    /// [`DisconnectReason::kind`]. Prefer this over [`Compose::disconnect`].
+    pub fn disconnect_with_reason(
+        &self,
+        packets: &mut Packets,
+        state: &mut LoginState,
+        reason: &DisconnectReason,
+    ) -> Result<(), AppendError> {
+        debug!("disconnecting a connection in {state:?}: {}", reason.kind());
+
+        self.buf_of(packets).metrics().record_disconnect(reason);
+        self.disconnect(packets, state, &reason.message())
+    }
+}
+
+/// The most packets vanilla clients accept between the delimiters of a bundle. Larger bundles
+/// disconnect them.
+pub const MAX_BUNDLE_PACKETS: usize = 4096;
+
+/// The packets of a bundle which is being appended with [`Compose::bundle`]. They are encoded
+/// right away, but only queued once the bundle is complete.
+pub struct Bundle<'a> {
+    buf: &'a mut IoBuf,
+    scratch: &'a mut Scratch,
+    compressor: &'a mut dyn PacketCompressor,
+    writes: Vec<PacketWriteInfo>,
+}
+
+impl Bundle<'_> {
+    /// Appends `pkt` after the packets which are already in the bundle.
+    ///
+    /// Fails once the bundle holds [`MAX_BUNDLE_PACKETS`] packets.
+    pub fn append<P>(&mut self, pkt: &P) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        if self.writes.len() >= MAX_BUNDLE_PACKETS {
+            return Err(
+                anyhow!("bundles cannot hold more than {MAX_BUNDLE_PACKETS} packets").into(),
+            );
+        }
+
+        let info = self.buf.append_packet(pkt, self.scratch, self.compressor)?;
+        self.writes.push(info);
 
-        self.push(result, buf);
         Ok(())
     }
 
-    pub fn append_raw(&self, data: &[u8], buf: &mut IoBuf) {
-        let start_ptr = buf.buf.append(data);
+    /// The number of packets in the bundle, not counting its delimiters.
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+fn encoded_len<P>(
+    pkt: &P,
+    buf: &mut IoBuf,
+    scratch: &mut impl ScratchBuffer,
+    compressor: &mut (impl PacketCompressor + ?Sized),
+) -> Result<usize, AppendError>
+where
+    P: valence_protocol::Packet + valence_protocol::Encode,
+{
+    let IoBuf { enc, measure, .. } = buf;
+    enc.append_packet(pkt, &mut Measure(measure), scratch, compressor)
+}
+
+/// A [`Buf`] which only reports the length of what was written so its storage can be reused.
+struct Measure<'a>(&'a mut Vec<u8>);
+
+impl Buf for Measure<'_> {
+    type Output = usize;
+
+    fn get_contiguous(&mut self, len: usize) -> Result<&mut [u8], AppendError> {
+        self.0.resize(len, 0);
+        Ok(&mut self.0[..len])
+    }
+
+    fn advance(&mut self, len: usize) -> Self::Output {
+        len
+    }
+}
+
+/// A packet which has already been encoded and compressed with the server-wide compression
+/// threshold. See [`Compose::encode_once`].
+#[derive(Debug, Clone)]
+pub struct PrecompressedPacket {
+    /// The ID of the packet, for [`NetMetrics::by_packet_type`].
+    id: i32,
+    data: Bytes,
+}
+
+impl PrecompressedPacket {
+    fn encode<P>(
+        pkt: &P,
+        enc: &encoder::PacketEncoder,
+        scratch: &mut impl ScratchBuffer,
+        compressor: &mut (impl PacketCompressor + ?Sized),
+    ) -> Result<Self, AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        let mut buf = BytesMut::new();
+        let data = enc.append_packet(pkt, &mut buf, scratch, compressor)?;
+
+        Ok(Self {
+            id: P::ID,
+            data: data.freeze(),
+        })
+    }
+
+    /// The encoded bytes, including the length prefix.
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// A [`ChunkDataS2c`] which has already been encoded and compressed. See [`Compose::cache_chunk`].
+///
+/// The bytes are stored once. Each core copies them into its own [`Ring`] when they are sent, since
+/// a connection can only be sent bytes from the rings of its [`Packets`].
+#[derive(Debug, Clone, Deref)]
+pub struct CachedChunkPacket {
+    pos: ChunkPos,
+    #[deref]
+    pkt: PrecompressedPacket,
+}
+
+impl CachedChunkPacket {
+    /// The position of the cached chunk.
+    #[must_use]
+    pub const fn pos(&self) -> ChunkPos {
+        self.pos
+    }
+}
+
+/// When the egress system passes the packets queued for a connection to the server.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Sends queued packets every tick. This is the default.
+    #[default]
+    Immediate,
+    /// Holds packets back so more of them are sent in a single write, until either `max_bytes`
+    /// are queued or the oldest queued packet has waited for `max_delay`.
+    ///
+    /// This is checked once per tick, so `max_delay` is effectively rounded up to a whole number
+    /// of ticks. Packets which should not wait, like keep alives, can skip the wait with
+    /// [`Packets::flush_now`].
+    Coalesce {
+        max_delay: Duration,
+        max_bytes: usize,
+    },
+}
+
+impl FlushPolicy {
+    /// Whether the packets queued in `packets` should be sent at `now`.
+    #[must_use]
+    pub fn should_flush(self, packets: &Packets, now: Instant) -> bool {
+        match self {
+            Self::Immediate => true,
+            Self::Coalesce {
+                max_delay,
+                max_bytes,
+            } => {
+                packets.flush_now.load(atomic::Ordering::Relaxed)
+                    || packets.queued_bytes.load(atomic::Ordering::Relaxed) >= max_bytes
+                    || packets
+                        .first_queued_at()
+                        .is_some_and(|at| now.saturating_duration_since(at) >= max_delay)
+            }
+        }
+    }
+}
+
+/// The most bytes [`Packets::encrypt_pending`] copies at once. A write larger than the room left in
+/// the [`Ring`] is encrypted in several parts.
+const ENCRYPT_CHUNK_SIZE: usize = 16 * 1024;
+
+/// A budget for the bytes written to all connections in one tick, so a huge broadcast cannot
+/// queue more than the network interface can send.
+///
+/// [`ServerDef::write_all`] asks the limiter before passing each write to the OS. Essential writes
+/// are always sent, but once the budget is used up, [`Priority::Cosmetic`] writes are left queued
+/// and sent in a later tick. The write which uses up the budget may exceed it, so a cosmetic
+/// write larger than the limit is still sent once nothing else was written in a tick. Encrypted
+/// connections are never deferred, since [`Packets::encrypt_pending`] turns their queued writes
+/// into essential ones.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct BandwidthLimiter {
+    limit: Option<usize>,
+    used: usize,
+}
+
+impl BandwidthLimiter {
+    /// Creates a limiter which allows `limit` bytes per tick. [`None`] means there is no limit,
+    /// which is the default.
+    #[must_use]
+    pub const fn new(limit: Option<usize>) -> Self {
+        Self { limit, used: 0 }
+    }
+
+    /// The number of bytes which may be written per tick.
+    #[must_use]
+    pub const fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// The number of bytes written in the current tick. This is measured even without a limit.
+    #[must_use]
+    pub const fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Resets [`BandwidthLimiter::used`]. The egress system calls this once per tick before
+    /// writing.
+    pub fn start_tick(&mut self) {
+        self.used = 0;
+    }
 
-        let writer = PacketWriteInfo {
-            start_ptr,
-            len: data.len() as u32,
+    /// Whether `writer` may be written in this tick, counting it towards the budget if so.
+    pub fn allow(&mut self, writer: &PacketWriteInfo) -> bool {
+        let allowed = match writer.priority {
+            Priority::High | Priority::Essential => true,
+            Priority::Cosmetic => self.limit.is_none_or(|limit| self.used < limit),
         };
 
-        self.push(writer, buf);
+        if allowed {
+            self.used += writer.len as usize;
+        }
+
+        allowed
+    }
+
+    /// Removes the writes which may be written in this tick from `queue` and passes them to `f`
+    /// in order. Deferred writes stay in `queue`.
+    ///
+    /// Once a cosmetic write is deferred, the cosmetic writes after it are deferred as well, so
+    /// cosmetic packets are not reordered among themselves. Essential writes behind a deferred
+    /// cosmetic write are still passed to `f`, so they overtake it and are sent before it.
+    pub fn drain_allowed(
+        &mut self,
+        queue: &mut VecDeque<PacketWriteInfo>,
+        mut f: impl FnMut(PacketWriteInfo),
+    ) {
+        let mut deferring = false;
+
+        queue.retain(|writer| {
+            let cosmetic = writer.priority == Priority::Cosmetic;
+
+            if (cosmetic && deferring) || !self.allow(writer) {
+                deferring = true;
+                return true;
+            }
+
+            f(*writer);
+            false
+        });
+    }
+}
+
+/// A packet appended with [`Packets::append_tracked`].
+#[derive(Debug, Copy, Clone)]
+pub struct TrackedWrite {
+    /// The core whose [`Ring`] holds the bytes.
+    pub core: usize,
+    /// The bytes of the packet, including its length prefix. The packet may have been merged into
+    /// the write queued before it, so the bytes can be sent together with other packets.
+    pub info: PacketWriteInfo,
+}
+
+impl TrackedWrite {
+    /// The number of bytes the packet takes up once encoded.
+    #[must_use]
+    pub const fn encoded_len(&self) -> usize {
+        self.info.len as usize
+    }
+}
+
+/// A write which is queued for a connection. Unlike [`PacketWriteInfo`], this does not point to
+/// the bytes, so it can be inspected without any unsafe code.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct QueuedWrite {
+    /// The core whose [`Ring`] holds the bytes.
+    pub core: usize,
+    /// Where the write starts in everything queued for the connection, in the order it is sent.
+    pub offset: usize,
+    /// The length of the write in bytes.
+    pub len: u32,
+}
+
+/// This is useful for the ECS so we can use Single<&mut Broadcast> instead of having to use a marker struct
+///
+/// Writes in here do not belong to any connection. The egress system copies them into the
+/// [`Packets`] of every player and clears them in the same tick, so there is never anything to
+/// prune when a connection goes away.
+///
+/// Packets are encoded once and every player refers to the same bytes in the [`Ring`], including
+/// for packets appended with [`Broadcast::append_filtered`], [`Broadcast::append_except`] or
+/// [`Broadcast::append_to_cores`].
+/// Those are queued like every other broadcast, and [`Broadcast::extend_into`] cuts them out of
+/// the writes of players who should not get them, so the order of all broadcasts is kept.
+#[derive(Component, Deref, DerefMut, Default)]
+pub struct Broadcast {
+    #[deref]
+    #[deref_mut]
+    packets: Packets,
+    /// The writes on each core which only some connections get, with who gets them
+    filtered: RayonLocal<Vec<(PacketWriteInfo, Audience)>>,
+}
+
+/// Who gets a write in [`Broadcast::filtered`].
+enum Audience {
+    /// See [`Broadcast::append_filtered`].
+    Viewers(ViewerSet),
+    /// Every connection but this one. See [`Broadcast::append_except`].
+    Except(Fd),
+    /// The connections pinned to the core of the ring the write is in. See
+    /// [`Broadcast::append_to_cores`].
+    PinnedToCore,
+}
+
+impl Audience {
+    /// Whether `fd`, which is pinned to `pinned`, gets a write in the ring of `core`.
+    fn includes(&self, fd: Fd, pinned: Option<usize>, core: usize) -> bool {
+        match self {
+            Self::Viewers(viewers) => viewers.contains(fd),
+            Self::Except(except) => fd != *except,
+            Self::PinnedToCore => pinned == Some(core),
+        }
+    }
+}
+
+/// The connections a packet appended with [`Broadcast::append_filtered`] is sent to.
+///
+/// The connections are shared between clones, so [`Broadcast`] can keep the set of every packet
+/// without copying it. Inserting into a set which was cloned copies it once.
+#[derive(Debug, Clone, Default)]
+pub struct ViewerSet {
+    fds: Arc<FxHashSet<Fd>>,
+}
+
+impl ViewerSet {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, fd: Fd) {
+        Arc::make_mut(&mut self.fds).insert(fd);
+    }
+
+    #[must_use]
+    pub fn contains(&self, fd: Fd) -> bool {
+        self.fds.contains(&fd)
+    }
+}
+
+impl FromIterator<Fd> for ViewerSet {
+    fn from_iter<T: IntoIterator<Item = Fd>>(iter: T) -> Self {
+        Self {
+            fds: Arc::new(iter.into_iter().collect()),
+        }
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use bumpalo::Bump;
-//     use valence_protocol::{packets::login::LoginHelloC2s, Bounded};
-//
-//     use super::*;
-//     use crate::events::Scratch;
-//
-//     #[test]
-//     fn test_append_pre_compression_packet() {
-//         let mut buf = IoBuf::new(
-//             CompressionThreshold::DEFAULT,
-//             CompressionLvl::new(4).unwrap(),
-//         );
-//         let mut packets = Packets::default();
-//
-//         let pkt = LoginHelloC2s {
-//             username: Bounded::default(),
-//             profile_id: None,
-//         };
-//
-//         let bump = Bump::new();
-//         let mut scratch = Scratch::from(&bump);
-//
-//         packets
-//             .append_pre_compression_packet(&pkt, &mut buf)
-//             .unwrap();
-//
-//         assert_eq!(packets.get_write().len(), 1);
-//
-//         let len = packets.get_write()[0].len;
-//
-//         assert_eq!(len, 4); // Packet length for an empty LoginHelloC2s
-//     }
-//     #[test]
-//     fn test_append_packet() {
-//         let mut buf = IoBuf::new(
-//             CompressionThreshold::DEFAULT,
-//             CompressionLvl::new(4).unwrap(),
-//         );
-//         let mut packets = Packets::default();
-//
-//         let pkt = LoginHelloC2s {
-//             username: Bounded::default(),
-//             profile_id: None,
-//         };
-//
-//         let bump = Bump::new();
-//         let mut scratch = Scratch::from(&bump);
-//         packets.append(&pkt, &mut buf, &mut scratch).unwrap();
-//
-//         assert_eq!(packets.get_write().len(), 1);
-//         let len = packets.get_write()[0].len;
-//         assert_eq!(len, 4); // Packet length for an empty LoginHelloC2s
-//     }
-//
-//     #[test]
-//     fn test_append_raw() {
-//         let mut buf = IoBuf::new(
-//             CompressionThreshold::DEFAULT,
-//             CompressionLvl::new(4).unwrap(),
-//         );
-//         let mut packets = Packets::default();
-//
-//         let data = b"Hello, world!";
-//         packets.append_raw(data, &mut buf);
-//
-//         assert_eq!(packets.get_write().len(), 1);
-//
-//         let len = packets.get_write()[0].len;
-//         assert_eq!(len, data.len() as u32);
-//     }
-//
-//     #[test]
-//     fn test_clear() {
-//         let mut buf = IoBuf::new(
-//             CompressionThreshold::DEFAULT,
-//             CompressionLvl::new(4).unwrap(),
-//         );
-//         let mut packets = Packets::default();
-//
-//         let pkt = LoginHelloC2s {
-//             username: Bounded::default(),
-//             profile_id: None,
-//         };
-//
-//         let bump = Bump::new();
-//         let mut scratch = Scratch::from(&bump);
-//
-//         packets.append(&pkt, &mut buf, &mut scratch).unwrap();
-//         assert_eq!(packets.get_write().len(), 1);
-//
-//         packets.clear();
-//         assert_eq!(packets.get_write().len(), 0);
-//     }
-//
-//     #[test]
-//     fn test_contiguous_packets() {
-//         let mut buf = IoBuf::new(
-//             CompressionThreshold::DEFAULT,
-//             CompressionLvl::new(4).unwrap(),
-//         );
-//         let mut packets = Packets::default();
-//
-//         let pkt1 = LoginHelloC2s {
-//             username: Bounded::default(),
-//             profile_id: None,
-//         };
-//         let pkt2 = LoginHelloC2s {
-//             username: Bounded::default(),
-//             profile_id: None,
-//         };
-//
-//         let bump = Bump::new();
-//         let mut scratch = Scratch::from(&bump);
-//
-//         packets
-//             .append_pre_compression_packet(&pkt1, &mut buf, &mut scratch)
-//             .unwrap();
-//         packets
-//             .append_pre_compression_packet(&pkt2, &mut buf, &mut scratch)
-//             .unwrap();
-//
-//         assert_eq!(packets.get_write().len(), 1);
-//
-//         let len = packets.get_write()[0].len;
-//         assert_eq!(len, 8); // Combined length of both packets
-//     }
-// }
+impl Broadcast {
+    /// Like [`Packets::append`], but only sends `pkt` to the players in `viewers`.
+    pub fn append_filtered<P>(
+        &self,
+        pkt: &P,
+        compose: &Compose,
+        viewers: &ViewerSet,
+    ) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        compose.with_locals(|buf, scratch, compressor| {
+            self.append_filtered_to(pkt, viewers, buf, scratch, compressor)
+        })
+    }
+
+    fn append_filtered_to<P>(
+        &self,
+        pkt: &P,
+        viewers: &ViewerSet,
+        buf: &mut IoBuf,
+        scratch: &mut impl ScratchBuffer,
+        compressor: &mut (impl PacketCompressor + ?Sized),
+    ) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        let info = buf.append_packet(pkt, scratch, compressor)?;
+
+        self.push_filtered(info, Audience::Viewers(viewers.clone()), buf);
+
+        Ok(())
+    }
+
+    /// Like [`Packets::append`], but sends `pkt` to every player except `exclude`, like the player
+    /// whose action `pkt` shows, which the client already predicted.
+    ///
+    /// `pkt` is queued on the current core like every other broadcast, so it keeps its place
+    /// among them. Pinning does not matter: whichever core `exclude` is pinned to, it is left out
+    /// when [`Broadcast::extend_into`] copies the broadcasts into its [`Packets`].
+    pub fn append_except<P>(
+        &self,
+        pkt: &P,
+        compose: &Compose,
+        exclude: Fd,
+    ) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        compose.with_locals(|buf, scratch, compressor| {
+            self.append_except_to(pkt, exclude, buf, scratch, compressor)
+        })
+    }
+
+    fn append_except_to<P>(
+        &self,
+        pkt: &P,
+        exclude: Fd,
+        buf: &mut IoBuf,
+        scratch: &mut impl ScratchBuffer,
+        compressor: &mut (impl PacketCompressor + ?Sized),
+    ) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        let info = buf.append_packet(pkt, scratch, compressor)?;
+
+        self.push_filtered(info, Audience::Except(exclude), buf);
+
+        Ok(())
+    }
+
+    /// Sends a cached chunk to the players in `viewers`. The bytes are copied into the [`Ring`] of
+    /// the current core once, no matter how many viewers there are.
+    pub fn send_cached(
+        &self,
+        pkt: &CachedChunkPacket,
+        compose: &Compose,
+        viewers: &ViewerSet,
+    ) -> Result<(), AppendError> {
+        compose.with_locals(|buf, _, _| self.send_cached_to(pkt, viewers, buf))
+    }
+
+    fn send_cached_to(
+        &self,
+        pkt: &CachedChunkPacket,
+        viewers: &ViewerSet,
+        buf: &mut IoBuf,
+    ) -> Result<(), AppendError> {
+        let info = buf.append_precompressed(pkt)?;
+
+        self.push_filtered(info, Audience::Viewers(viewers.clone()), buf);
+
+        Ok(())
+    }
+
+    /// Like [`Packets::append`], but only sends `pkt` to the connections pinned to one of `cores`
+    /// with [`Packets::pin_to_core`], like the players of a shard of the world which is simulated
+    /// on those cores.
+    ///
+    /// `pkt` is encoded a single time, and the bytes are copied into the [`Ring`] of each core in
+    /// `cores`, once per core even if it is listed more than once. The rings of the other cores
+    /// are not touched. Connections which are not pinned belong to no core, so they never get
+    /// `pkt`; this is only useful with [`crate::global::Global::pin_connections`].
+    ///
+    /// # Panics
+    /// If there is no core at one of `cores`.
+    pub fn append_to_cores<P>(
+        &self,
+        pkt: &P,
+        compose: &Compose,
+        cores: &[usize],
+    ) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        if cores.is_empty() {
+            return Ok(());
+        }
+
+        let pkt = compose.encode_once(pkt)?;
+
+        for (i, &core) in cores.iter().enumerate() {
+            if cores.iter().take(i).any(|&other| other == core) {
+                continue;
+            }
+
+            self.append_to_core(&pkt, &mut compose.buf_for(core))?;
+        }
+
+        Ok(())
+    }
+
+    fn append_to_core(
+        &self,
+        pkt: &PrecompressedPacket,
+        buf: &mut IoBuf,
+    ) -> Result<(), AppendError> {
+        let info = buf.append_precompressed(pkt)?;
+
+        self.push_filtered(info, Audience::PinnedToCore, buf);
+
+        Ok(())
+    }
+
+    fn push_filtered(&self, info: PacketWriteInfo, audience: Audience, buf: &IoBuf) {
+        self.packets.push(info, buf);
+
+        // SAFETY: like the queues of `Packets`, the list of a core is only touched while its
+        // `IoBuf` is locked
+        let filtered = unsafe { &mut *self.filtered.get_raw(buf.index()).get() };
+        filtered.push((info, audience));
+    }
+
+    /// Queues every broadcast which the connection `fd` can see after the packets queued in
+    /// `packets`. Without [`Broadcast::append_filtered`], [`Broadcast::append_except`] and
+    /// [`Broadcast::append_to_cores`], this is the same as [`Packets::extend`].
+    pub fn extend_into(&self, packets: &mut Packets, fd: Fd) {
+        if self.filtered.iter().all(Vec::is_empty) {
+            packets.extend(&self.packets);
+            return;
+        }
+
+        let pinned = packets.core();
+        let hidden = self.filtered.iter().enumerate().map(|(core, filtered)| {
+            filtered
+                .iter()
+                .filter(move |(_, audience)| !audience.includes(fd, pinned, core))
+                .map(|(info, _)| info)
+        });
+
+        packets.extend_except(&self.packets, hidden);
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.filtered.iter_mut().for_each(Vec::clear);
+    }
+}
+
+/// Stores indices of packets
+///
+/// # Ordering
+///
+/// Every core has its own queue of writes for the connection, so packets are only sent in the
+/// order they were appended as far as these rules go:
+///
+/// - Packets appended to the same [`IoBuf`] are sent in the order they were appended, since it is
+///   locked while appending. [`Priority::High`] packets are the exception and are sent before
+///   the packets of lower priority which are already queued.
+/// - When the connection is sent to, the queue of core 0 is sent first, then the one of core 1
+///   and so on. Packets appended to different [`IoBuf`]s in the same tick are sent in the order of
+///   their cores, even if one handler ran strictly after the other.
+/// - [`Broadcast`] packets are queued by the egress system, after the packets appended to the
+///   same core in that tick.
+/// - Everything sent in a tick is written before anything sent in a later tick, since a
+///   connection is only sent to once its previous writes completed. Cosmetic packets deferred by
+///   the [`BandwidthLimiter`] are the exception, as later essential packets can overtake them.
+///
+/// Packets which must arrive in a certain order therefore have to be appended to the same
+/// [`IoBuf`]. This holds for every [`Packets::append`] to a connection which is pinned with
+/// [`Packets::pin_to_core`]. For other connections, use [`Compose::ordered_append`] for every
+/// packet of the sequence.
+#[derive(Component, Default)]
+pub struct Packets {
+    to_write: RayonLocal<VecDeque<PacketWriteInfo>>,
+    /// The [`Priority::High`] writes of each core, which are moved in front of `to_write` by
+    /// [`Packets::queue_high_first`] before it is sent.
+    high: RayonLocal<VecDeque<PacketWriteInfo>>,
+    number_sending: AtomicUsize,
+
+    /// The cipher for this connection, if encryption is enabled.
+    encryption: Option<PacketEncryptor>,
+
+    /// The number of pending bytes which were queued before encryption was enabled and must be
+    /// sent as plaintext.
+    plaintext_len: usize,
+
+    /// Writes which [`Packets::encrypt_pending`] held back because the ring had no room for their
+    /// encrypted copies. They are queued again by [`Packets::requeue_deferred`].
+    unencrypted: RayonLocal<VecDeque<PacketWriteInfo>>,
+
+    /// The [`Ring::position`] of the oldest queued byte in each ring.
+    queued_since: RayonLocal<Option<u64>>,
+    /// The [`Ring::position`] of the oldest byte in each ring which is currently being sent.
+    sending_since: RayonLocal<Option<u64>>,
+
+    /// The number of bytes in `to_write`.
+    queued_bytes: AtomicUsize,
+    /// The number of bytes which are currently being sent.
+    sending_bytes: usize,
+    /// See [`Packets::set_max_queued_bytes`].
+    max_queued_bytes: Option<usize>,
+    /// See [`Packets::close_after_send`].
+    close_after_send: bool,
+    /// Whether [`Packets::send_set_compression`] was called.
+    compression_negotiated: bool,
+    /// See [`Packets::pin_to_core`].
+    core: Option<usize>,
+    /// When the first packet which is still queued was queued on each core. See
+    /// [`FlushPolicy::Coalesce`].
+    queued_at: RayonLocal<Option<Instant>>,
+    /// See [`Packets::flush_now`].
+    flush_now: AtomicBool,
+    /// Whether [`Packets::prepare_for_send`] was called and [`Packets::requeue_deferred`] was not
+    /// called since.
+    prepared: bool,
+}
+
+impl Packets {
+    /// Packets which keep a single queue instead of one per core, built on
+    /// [`RayonLocal::single`]. Only [`IoBuf`]s with index 0 may be appended to them.
+    ///
+    /// Every write lands in the same queue no matter which thread appended it, so which writes
+    /// are coalesced is deterministic. This is for tests.
+    ///
+    /// # Safety
+    /// See [`RayonLocal::single`]: the packets must only ever be appended to from one thread at a
+    /// time.
+    #[must_use]
+    pub unsafe fn single_threaded() -> Self {
+        Self {
+            to_write: RayonLocal::single(VecDeque::new()),
+            high: RayonLocal::single(VecDeque::new()),
+            unencrypted: RayonLocal::single(VecDeque::new()),
+            queued_since: RayonLocal::single(None),
+            sending_since: RayonLocal::single(None),
+            queued_at: RayonLocal::single(None),
+            ..Self::default()
+        }
+    }
+
+    /// Queues everything queued in `other` after the packets of this connection.
+    ///
+    /// Both usually share the same [`Ring`], so a write from `other` which starts right where the
+    /// last queued write ends is merged into it, which saves a write syscall or SQE. Writes to
+    /// different connections can never be merged, since each one goes to its own socket.
+    pub fn extend(&mut self, other: &Self) {
+        self.extend_except(other, iter::repeat(iter::empty()));
+    }
+
+    /// Like [`Packets::extend`], but leaves out the writes which `hidden` yields for each core.
+    /// The writes of a core are filtered lazily, so nothing is allocated per connection.
+    fn extend_except<'a, H>(&mut self, other: &Self, hidden: impl IntoIterator<Item = H>)
+    where
+        H: Iterator<Item = &'a PacketWriteInfo> + Clone,
+    {
+        let this = self.to_write.iter_mut().zip(self.high.iter_mut());
+        let other_writes = other.to_write.iter().zip(other.high.iter());
+        let mut queued_bytes = 0;
+
+        for (((this, this_high), (other, other_high)), hidden) in this.zip(other_writes).zip(hidden)
+        {
+            for &writer in queued(other, other_high) {
+                queued_bytes += push_visible(this_high, this, writer, hidden.clone());
+            }
+        }
+
+        let this = self.queued_since.iter_mut();
+        let other_queued_since = other.queued_since.iter();
+
+        for (this, other) in this.zip(other_queued_since) {
+            *this = oldest(*this, *other);
+        }
+
+        let this = self.queued_at.iter_mut();
+        let other_queued_at = other.queued_at.iter();
+
+        for (this, other) in this.zip(other_queued_at) {
+            *this = match (*this, *other) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+
+        if other.flush_now.load(atomic::Ordering::Relaxed) {
+            *self.flush_now.get_mut() = true;
+        }
+
+        *self.queued_bytes.get_mut() += queued_bytes;
+    }
+
+    pub fn get_write_mut(&mut self) -> &mut RayonLocal<VecDeque<PacketWriteInfo>> {
+        self.queue_high_first();
+        &mut self.to_write
+    }
+
+    /// Moves the [`Priority::High`] writes of each core in front of the other writes which are
+    /// queued, behind the high priority writes which are already there. Keeping them apart until
+    /// the queue is read means a high priority write does not shift the whole queue.
+    fn queue_high_first(&mut self) {
+        for (high, to_write) in self.high.iter_mut().zip(self.to_write.iter_mut()) {
+            if high.is_empty() {
+                continue;
+            }
+
+            let front = to_write
+                .iter()
+                .take_while(|info| info.priority == Priority::High)
+                .count();
+
+            if front == 0 {
+                while let Some(info) = high.pop_back() {
+                    to_write.push_front(info);
+                }
+            } else {
+                let rest = to_write.split_off(front);
+                to_write.append(high);
+                to_write.extend(rest);
+            }
+        }
+    }
+
+    /// Every write which is queued and not being sent yet, in the order it will be sent. This is
+    /// meant for debugging, e.g. to look at the queue depth of each core.
+    pub fn iter(&self) -> impl Iterator<Item = QueuedWrite> + '_ {
+        let mut offset = 0;
+
+        self.to_write
+            .iter()
+            .zip(self.high.iter())
+            .enumerate()
+            .flat_map(|(core, (to_write, high))| {
+                queued(to_write, high).map(move |info| (core, info.len))
+            })
+            .map(move |(core, len)| {
+                let write = QueuedWrite { core, offset, len };
+                offset += len as usize;
+                write
+            })
+    }
+
+    /// The number of bytes in every write [`Packets::iter`] returns.
+    #[must_use]
+    pub fn total_len(&self) -> usize {
+        self.to_write
+            .iter()
+            .chain(self.high.iter())
+            .flatten()
+            .map(|info| info.len as usize)
+            .sum()
+    }
+
+    #[must_use]
+    pub fn can_send(&self) -> bool {
+        if self.number_sending.load(atomic::Ordering::Relaxed) != 0 {
+            return false;
+        }
+
+        self.has_queued()
+    }
+
+    /// Whether any write is queued on any core.
+    fn has_queued(&self) -> bool {
+        self.to_write
+            .iter()
+            .chain(self.high.iter())
+            .any(|x| !x.is_empty())
+    }
+
+    /// The number of writes to this connection which have not completed yet.
+    #[must_use]
+    pub fn number_sending(&self) -> usize {
+        self.number_sending.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_successfully_sent(&self, d_count: usize) {
+        debug_assert!(
+            self.number_sending.load(atomic::Ordering::Relaxed) > 0,
+            "somehow number sending is 0 even though we just marked a successful send"
+        );
+
+        self.number_sending
+            .fetch_sub(d_count, atomic::Ordering::Relaxed);
+    }
+
+    pub fn prepare_for_send(&mut self) -> usize {
+        debug_assert!(
+            self.number_sending.load(atomic::Ordering::Relaxed) == 0,
+            "number sending is not 0 even though we are preparing for send"
+        );
+        self.queue_high_first();
+
+        let count = self
+            .to_write
+            .fold_all(0, |count, to_write| count + to_write.len());
+        self.number_sending = AtomicUsize::new(count);
+
+        let sending = self.sending_since.iter_mut();
+        let queued = self.queued_since.iter_mut();
+
+        for (sending, queued) in sending.zip(queued) {
+            *sending = queued.take();
+        }
+
+        self.sending_bytes = std::mem::take(self.queued_bytes.get_mut());
+
+        self.queued_at.iter_mut().for_each(|at| *at = None);
+        *self.flush_now.get_mut() = false;
+        self.prepared = true;
+
+        count
+    }
+
+    /// Takes back the writes which [`ServerDef::write_all`] left queued because of the
+    /// [`BandwidthLimiter`], so they are sent on the next tick. This must be called after every
+    /// [`ServerDef::write_all`] and does nothing if this connection was not sent to.
+    pub fn requeue_deferred(&mut self) {
+        if !std::mem::take(&mut self.prepared) {
+            return;
+        }
+
+        let mut count = 0;
+        let mut bytes = 0;
+
+        let unencrypted = self.unencrypted.iter_mut();
+
+        for (core, (to_write, unencrypted)) in self.to_write.iter_mut().zip(unencrypted).enumerate()
+        {
+            if to_write.is_empty() && unencrypted.is_empty() {
+                continue;
+            }
+
+            // held back writes were not prepared, so they are not counted as sending
+            count += to_write.len();
+            bytes += to_write.iter().map(|info| info.len as usize).sum::<usize>();
+            bytes += unencrypted
+                .iter()
+                .map(|info| info.len as usize)
+                .sum::<usize>();
+            to_write.append(unencrypted);
+
+            // the deferred bytes are somewhere after the oldest byte which was prepared
+            self.queued_since[core] = self.sending_since[core];
+            self.queued_at[core].get_or_insert_with(Instant::now);
+        }
+
+        if bytes == 0 {
+            return;
+        }
+
+        *self.number_sending.get_mut() -= count;
+        self.sending_bytes -= bytes;
+        *self.queued_bytes.get_mut() += bytes;
+        *self.flush_now.get_mut() = true;
+    }
+
+    /// Takes everything which is queued to be sent, and leaves an empty queue for the packets of
+    /// the next tick. This is [`Packets::prepare_for_send`] for callers which flush separately
+    /// from appending; see [`PacketsSnapshot`] for how long the writes stay valid. Encrypted
+    /// connections need [`Packets::encrypt_pending`] first.
+    ///
+    /// This must only be called if [`Packets::can_send`], i.e. no earlier snapshot is in flight.
+    /// The snapshot must not outlive `self`.
+    pub fn swap(&mut self) -> PacketsSnapshot {
+        self.prepare_for_send();
+
+        // writes which are left in the snapshot come back through `restore` instead
+        self.prepared = false;
+
+        // keeps the slots of `to_write`, which may be fewer than `RayonLocal::default` has, like
+        // with `Packets::single_threaded`
+        let empty = self.to_write.map_ref(|_| VecDeque::new());
+
+        PacketsSnapshot {
+            writes: std::mem::replace(&mut self.to_write, empty),
+        }
+    }
+
+    /// Queues the writes which are left in `snapshot` again, before anything appended since
+    /// [`Packets::swap`], so they are sent on the next tick. This is the
+    /// [`Packets::requeue_deferred`] of snapshots.
+    pub fn restore(&mut self, mut snapshot: PacketsSnapshot) {
+        let mut count = 0;
+        let mut bytes = 0;
+
+        for (core, left) in snapshot.writes.iter_mut().enumerate() {
+            let unencrypted = &mut self.unencrypted[core];
+
+            if left.is_empty() && unencrypted.is_empty() {
+                continue;
+            }
+
+            let mut left = std::mem::take(left);
+
+            // held back writes were not prepared, so they are not counted as sending
+            count += left.len();
+            bytes += left.iter().map(|info| info.len as usize).sum::<usize>();
+            bytes += unencrypted
+                .iter()
+                .map(|info| info.len as usize)
+                .sum::<usize>();
+            left.append(unencrypted);
+
+            let to_write = &mut self.to_write[core];
+            left.append(to_write);
+            *to_write = left;
+
+            // the left writes are somewhere after the oldest byte which was taken
+            self.queued_since[core] = oldest(self.sending_since[core], self.queued_since[core]);
+            self.queued_at[core].get_or_insert_with(Instant::now);
+        }
+
+        if bytes == 0 {
+            return;
+        }
+
+        *self.number_sending.get_mut() -= count;
+        self.sending_bytes -= bytes;
+        *self.queued_bytes.get_mut() += bytes;
+        *self.flush_now.get_mut() = true;
+    }
+
+    /// Drops every write which is queued but was not passed to the server yet, for example because
+    /// the connection failed and will be removed.
+    ///
+    /// Writes which are already in flight are kept: they still complete through
+    /// [`Packets::set_successfully_sent`], and their bytes must not be released while the server
+    /// may still read them. [`Packets::can_send`] stays `false` until they complete, so
+    /// [`Packets::prepare_for_send`] is not called with writes in flight.
+    pub fn drop_pending(&mut self) {
+        self.clear();
+        self.plaintext_len = 0;
+    }
+
+    pub fn clear(&mut self) {
+        self.to_write.iter_mut().for_each(VecDeque::clear);
+        self.high.iter_mut().for_each(VecDeque::clear);
+        self.unencrypted.iter_mut().for_each(VecDeque::clear);
+        self.queued_since.iter_mut().for_each(|since| *since = None);
+        self.queued_at.iter_mut().for_each(|at| *at = None);
+        *self.queued_bytes.get_mut() = 0;
+        *self.flush_now.get_mut() = false;
+    }
+
+    /// Sends everything which is queued on the next tick, even if the [`FlushPolicy`] would hold
+    /// it back. Use this after appending packets which should not wait, like keep alives.
+    pub fn flush_now(&self) {
+        self.flush_now.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// When the oldest packet which is still queued was queued.
+    fn first_queued_at(&self) -> Option<Instant> {
+        self.queued_at.iter().flatten().min().copied()
+    }
+
+    /// The number of bytes which are queued for or currently being sent to this connection.
+    #[must_use]
+    pub fn queued_bytes(&self) -> usize {
+        let queued = self.queued_bytes.load(atomic::Ordering::Relaxed);
+
+        if self.number_sending.load(atomic::Ordering::Relaxed) == 0 {
+            return queued;
+        }
+
+        queued + self.sending_bytes
+    }
+
+    /// Whether more than `limit` bytes are queued for or being sent to this connection. A
+    /// saturated connection is not keeping up, so non-essential packets should be dropped
+    /// instead of piling up in the [`Ring`].
+    #[must_use]
+    pub fn is_saturated(&self, limit: usize) -> bool {
+        self.queued_bytes() > limit
+    }
+
+    /// Sets the limit [`Packets::append_droppable`] uses to decide whether this connection is
+    /// saturated. [`None`] means there is no limit, which is the default.
+    pub fn set_max_queued_bytes(&mut self, limit: Option<usize>) {
+        self.max_queued_bytes = limit;
+    }
+
+    /// Appends every packet for this connection to the [`IoBuf`] of the core at `index`, no
+    /// matter which core runs the handler. Broadcasts are not affected, except that the
+    /// connection gets the packets of [`Broadcast::append_to_cores`] which target `index`.
+    ///
+    /// This keeps the writes of a connection in a single ring, so consecutive packets are merged
+    /// into fewer writes and the server submits one queue per connection instead of one per core.
+    /// The cost is that cores write into each other's rings, which moves cache lines between
+    /// cores, and that appends for connections pinned to the same core wait for each other.
+    pub fn pin_to_core(&mut self, index: usize) {
+        self.core = Some(index);
+    }
+
+    /// The core this connection is pinned to. See [`Packets::pin_to_core`].
+    #[must_use]
+    pub const fn core(&self) -> Option<usize> {
+        self.core
+    }
+
+    /// The core [`Compose::ordered_append`] appends to: the core this connection is pinned to, or
+    /// core 0 if it is not pinned.
+    #[must_use]
+    pub const fn ordered_core(&self) -> usize {
+        match self.core {
+            Some(core) => core,
+            None => 0,
+        }
+    }
+
+    /// Closes this connection once everything which is queued for it has been sent. The egress
+    /// system does the closing, so nothing appended after this tick is sent.
+    pub fn close_after_send(&mut self) {
+        self.close_after_send = true;
+    }
+
+    /// Whether [`Packets::close_after_send`] was called and everything has been passed to the
+    /// server, so the connection can be closed now. This only returns `true` once.
+    pub fn take_close(&mut self) -> bool {
+        if !self.close_after_send || self.has_queued() {
+            return false;
+        }
+
+        self.close_after_send = false;
+        true
+    }
+
+    /// The [`Ring::position`] of the oldest byte in the ring of core `index` which has not been
+    /// sent to this connection yet.
+    #[must_use]
+    pub fn oldest_unsent(&self, index: usize) -> Option<u64> {
+        let queued = self.queued_since[index];
+
+        if self.number_sending.load(atomic::Ordering::Relaxed) == 0 {
+            return queued;
+        }
+
+        oldest(self.sending_since[index], queued)
+    }
+
+    fn push(&self, writer: PacketWriteInfo, buf: &IoBuf) {
+        let idx = buf.index();
+        let to_write = unsafe { &mut *self.to_write.get_raw(idx).get() };
+        let high = unsafe { &mut *self.high.get_raw(idx).get() };
+        let queued_since = unsafe { &mut *self.queued_since.get_raw(idx).get() };
+        let queued_at = unsafe { &mut *self.queued_at.get_raw(idx).get() };
+
+        queued_since.get_or_insert_with(|| buf.buf.position_of_last(&writer));
+        queued_at.get_or_insert_with(Instant::now);
+
+        self.queued_bytes
+            .fetch_add(writer.len as usize, atomic::Ordering::Relaxed);
+
+        buf.metrics.record_append(writer.len as usize);
+
+        #[cfg(feature = "metrics")]
+        if let Some(merged) = push_coalesced(high, to_write, writer) {
+            buf.metrics.record_coalesce(merged);
+        }
+
+        #[cfg(not(feature = "metrics"))]
+        push_coalesced(high, to_write, writer);
+    }
+
+    /// Encrypts everything sent to this connection from now on with AES-128-CFB8.
+    ///
+    /// The cipher lives here rather than on the [`encoder::PacketEncoder`] because the encoder
+    /// and its [`Ring`] are shared by every connection on a core, while the cipher state is
+    /// specific to one connection. Packets which are already queued are still sent as plaintext.
+    ///
+    /// # Panics
+    /// If encryption is already enabled.
+    pub fn enable_encryption(&mut self, shared_secret: &[u8; 16]) {
+        assert!(self.encryption.is_none(), "encryption is already enabled");
+
+        self.queue_high_first();
+
+        // packets appended from now on must not be sent before the plaintext, even if they have a
+        // high priority
+        self.to_write
+            .iter_mut()
+            .flatten()
+            .for_each(|info| info.priority = Priority::High);
+
+        self.plaintext_len = self
+            .to_write
+            .iter()
+            .flatten()
+            .map(|info| info.len as usize)
+            .sum();
+
+        self.encryption = Some(PacketEncryptor::new(shared_secret));
+    }
+
+    /// The number of bytes which were queued before [`Packets::enable_encryption`] and are still
+    /// to be sent as plaintext.
+    #[must_use]
+    pub const fn plaintext_len(&self) -> usize {
+        self.plaintext_len
+    }
+
+    /// Tells the client to use `threshold` with a `LoginCompressionS2c`, and makes `decoder`
+    /// expect compressed packets from it. This is the last packet of the login which is sent
+    /// without compression, so every packet appended afterwards is compressed.
+    ///
+    /// `threshold` has to be the one the encoders compress with, which is
+    /// [`Global::compression_threshold`], since clients reject compressed packets which are
+    /// shorter than the threshold they were told.
+    ///
+    /// # Panics
+    /// If compression was already negotiated.
+    pub fn send_set_compression(
+        &mut self,
+        threshold: CompressionThreshold,
+        buf: &mut IoBuf,
+        decoder: &mut PacketDecoder,
+    ) -> Result<(), AppendError> {
+        assert!(
+            !self.compression_negotiated,
+            "compression was already negotiated"
+        );
+
+        let pkt = LoginCompressionS2c {
+            threshold: VarInt(threshold.0),
+        };
+        self.append_pre_compression_packet(&pkt, buf)?;
+
+        self.compression_negotiated = true;
+        decoder.set_compression(threshold);
+
+        Ok(())
+    }
+
+    /// Whether [`Packets::send_set_compression`] was called, so packets can no longer be appended
+    /// with [`Packets::append_pre_compression_packet`].
+    #[must_use]
+    pub const fn is_compression_negotiated(&self) -> bool {
+        self.compression_negotiated
+    }
+
+    /// If encryption is enabled, copies the queued writes into `buf` in send order and encrypts
+    /// the copies. This must be called exactly once for the packets of each send, right before
+    /// [`Packets::prepare_for_send`].
+    ///
+    /// The writes are copied in chunks of at most [`ENCRYPT_CHUNK_SIZE`] bytes, so a backlog which
+    /// is larger than the room left in the [`Ring`] is encrypted over several sends. The writes
+    /// which do not fit are held back, since nothing may be sent before the writes in front of it
+    /// are encrypted, and are queued again by [`Packets::requeue_deferred`].
+    ///
+    /// # Errors
+    /// [`AppendError::RingFull`] if not even the first write fits. Nothing is dequeued in that
+    /// case, so this can be retried later.
+    pub fn encrypt_pending(&mut self, buf: &mut IoBuf) -> Result<(), AppendError> {
+        let Some(encryption) = &mut self.encryption else {
+            return Ok(());
+        };
+
+        debug_assert!(
+            self.unencrypted.iter().all(VecDeque::is_empty),
+            "held back writes were not requeued since the last send"
+        );
+
+        self.queue_high_first();
+
+        let mut encrypted = VecDeque::new();
+        let mut encrypted_since = None;
+
+        'cores: for to_write in self.to_write.iter_mut() {
+            while let Some(front) = to_write.front_mut() {
+                let PacketWriteInfo { start_ptr, len, .. } = *front;
+                let len = (len as usize).min(ENCRYPT_CHUNK_SIZE);
+
+                let dst = match buf.buf.get_contiguous(len) {
+                    Ok(dst) => dst,
+                    Err(err) if encrypted.is_empty() => return Err(err),
+                    Err(_) => break 'cores,
+                };
+
+                // SAFETY: `start_ptr` points to at least `len` bytes in one of the rings which have
+                // not been sent yet, so the ring did not hand them out again as part of `dst`.
+                unsafe { std::ptr::copy(start_ptr, dst.as_mut_ptr(), len) };
+
+                // the first `plaintext_len` bytes were queued before encryption was enabled
+                let skip = self.plaintext_len.min(len);
+                self.plaintext_len -= skip;
+                encryption.encrypt(&mut dst[skip..]);
+
+                let info = buf.buf.advance(len);
+                encrypted_since.get_or_insert_with(|| buf.buf.position_of_last(&info));
+                push_merged(&mut encrypted, info);
+
+                if len == front.len as usize {
+                    to_write.pop_front();
+                    continue;
+                }
+
+                // SAFETY: `len` is less than the length of the write
+                front.start_ptr = unsafe { start_ptr.add(len) };
+                front.len -= len as u32;
+
+                #[cfg(feature = "debug_checksums")]
+                {
+                    // SAFETY: the rest of the write is still in its ring
+                    front.checksum =
+                        unsafe { PacketWriteInfo::checksum_of(front.start_ptr, front.len) };
+                }
+            }
+        }
+
+        let unencrypted = self.unencrypted.iter_mut();
+
+        for ((to_write, unencrypted), since) in self
+            .to_write
+            .iter_mut()
+            .zip(unencrypted)
+            .zip(self.queued_since.iter_mut())
+        {
+            // the held back bytes of other cores stay pinned where they were, since their rings
+            // are not locked here
+            if to_write.is_empty() {
+                *since = None;
+            } else {
+                std::mem::swap(to_write, unencrypted);
+            }
+        }
+
+        // the encrypted copies are in the same ring as the held back bytes of this core, so those
+        // must not pin the room the copies need
+        let held = self.unencrypted[buf.index()]
+            .iter()
+            .map(|info| buf.buf.position_of(info))
+            .min();
+
+        self.queued_since[buf.index()] = oldest(held, encrypted_since);
+        self.to_write[buf.index()] = encrypted;
+
+        Ok(())
+    }
+
+    /// Appends `pkt` without compression, for the packets which are sent before
+    /// [`Packets::send_set_compression`].
+    ///
+    /// # Errors
+    /// [`AppendError::Encode`] if compression was already negotiated, since the client would read
+    /// the packet as a compressed one, or if `pkt` is a play packet, which is only sent after
+    /// compression was negotiated.
+    pub fn append_pre_compression_packet<P>(
+        &self,
+        pkt: &P,
+        buf: &mut IoBuf,
+    ) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        if self.compression_negotiated {
+            return Err(anyhow!("compression was already negotiated with the client").into());
+        }
+
+        if matches!(P::STATE, PacketState::Play) {
+            return Err(
+                anyhow!("{} is only sent after compression was negotiated", P::NAME).into(),
+            );
+        }
+
+        let mut buf = buf.with_compression(CompressionThreshold::DEFAULT);
+
+        let result = encode_uncompressed_into(pkt, &mut buf.buf)?;
+        buf.metrics.record_packet_type(P::ID, result.len as usize);
+
+        trace!("without compression: {result:?}");
+
+        self.push(result, &mut buf);
+
+        Ok(())
+    }
+
+    pub fn append<P>(&self, pkt: &P, compose: &Compose) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        self.append_with_threshold(pkt, None, compose)
+    }
+
+    /// Like [`Packets::append`], but returns where `pkt` was written, so the caller can attribute
+    /// the bytes to it, like for metrics.
+    pub fn append_tracked<P>(&self, pkt: &P, compose: &Compose) -> Result<TrackedWrite, AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        compose.with_locals_of(self, |buf, scratch, compressor| {
+            let info = self.append_to(pkt, None, buf, scratch, compressor)?;
+
+            Ok(TrackedWrite {
+                core: buf.index(),
+                info,
+            })
+        })
+    }
+
+    /// Like [`Packets::append`], but skips `pkt` if this connection is saturated according to
+    /// [`Packets::set_max_queued_bytes`]. Use this for packets which the client can do without,
+    /// like particles.
+    ///
+    /// Returns whether `pkt` was appended.
+    pub fn append_droppable<P>(&self, pkt: &P, compose: &Compose) -> Result<bool, AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        if let Some(limit) = self.max_queued_bytes {
+            if self.is_saturated(limit) {
+                return Ok(false);
+            }
+        }
+
+        self.append(pkt, compose)?;
+
+        Ok(true)
+    }
+
+    /// Like [`Packets::append`], but marks `pkt` as [`Priority::Cosmetic`], so it can be held
+    /// back by the [`BandwidthLimiter`] when a tick sends too much.
+    pub fn append_cosmetic<P>(&self, pkt: &P, compose: &Compose) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        self.append_priority(pkt, compose, Priority::Cosmetic)
+    }
+
+    /// Like [`Packets::append`], but queues `pkt` with `prio`. [`Priority::High`] packets are
+    /// sent before the packets of lower priority which are already queued.
+    pub fn append_priority<P>(
+        &self,
+        pkt: &P,
+        compose: &Compose,
+        prio: Priority,
+    ) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        compose.with_locals_of(self, |buf, scratch, compressor| {
+            self.append_priority_to(pkt, prio, buf, scratch, compressor)
+        })
+    }
+
+    fn append_priority_to<P>(
+        &self,
+        pkt: &P,
+        prio: Priority,
+        buf: &mut IoBuf,
+        scratch: &mut impl ScratchBuffer,
+        compressor: &mut (impl PacketCompressor + ?Sized),
+    ) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        let mut result = buf.append_packet(pkt, scratch, compressor)?;
+        result.priority = prio;
+
+        self.push(result, buf);
+        Ok(())
+    }
+
+    /// Like [`Packets::append`], but encodes `pkt` with `threshold` instead of the threshold of
+    /// the shared [`IoBuf`] encoder if it is [`Some`].
+    ///
+    /// The encoder threshold is restored afterwards, even if encoding fails.
+    pub fn append_with_threshold<P>(
+        &self,
+        pkt: &P,
+        threshold: Option<CompressionThreshold>,
+        compose: &Compose,
+    ) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        compose.with_locals_of(self, |buf, scratch, compressor| {
+            self.append_to(pkt, threshold, buf, scratch, compressor)?;
+            Ok(())
+        })
+    }
+
+    /// Encodes every packet in `pkts`, borrowing the local [`IoBuf`], scratch buffer, and
+    /// compressor only once for the entire batch instead of once per packet.
+    ///
+    /// If a packet fails to encode, the packets before it are still queued.
+    pub fn append_many<P, I>(&self, pkts: I, compose: &Compose) -> Result<(), AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+        I: IntoIterator<Item = P>,
+    {
+        compose.with_locals_of(self, |buf, scratch, compressor| {
+            for pkt in pkts {
+                self.append_to(&pkt, None, buf, scratch, compressor)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// See [`Compose::bundle`].
+    fn append_bundle(
+        &self,
+        buf: &mut IoBuf,
+        scratch: &mut Scratch,
+        compressor: &mut dyn PacketCompressor,
+        f: impl FnOnce(&mut Bundle<'_>) -> Result<(), AppendError>,
+    ) -> Result<(), AppendError> {
+        let start = buf.append_packet(&BundleSplitterS2c, scratch, compressor)?;
+
+        let mut bundle = Bundle {
+            buf,
+            scratch,
+            compressor,
+            writes: Vec::new(),
+        };
+        f(&mut bundle)?;
+
+        let Bundle {
+            buf,
+            scratch,
+            compressor,
+            writes,
+        } = bundle;
+        let end = buf.append_packet(&BundleSplitterS2c, scratch, compressor)?;
+
+        for info in iter::once(start).chain(writes).chain(iter::once(end)) {
+            self.push(info, buf);
+        }
+
+        Ok(())
+    }
+
+    fn append_to<P>(
+        &self,
+        pkt: &P,
+        threshold: Option<CompressionThreshold>,
+        buf: &mut IoBuf,
+        scratch: &mut impl ScratchBuffer,
+        compressor: &mut (impl PacketCompressor + ?Sized),
+    ) -> Result<PacketWriteInfo, AppendError>
+    where
+        P: valence_protocol::Packet + valence_protocol::Encode,
+    {
+        let append = |buf: &mut IoBuf| -> Result<PacketWriteInfo, AppendError> {
+            let result = buf.append_packet(pkt, scratch, compressor)?;
+
+            self.push(result, buf);
+            Ok(result)
+        };
+
+        match threshold {
+            Some(threshold) => append(&mut buf.with_compression(threshold)),
+            None => append(buf),
+        }
+    }
+
+    /// Copies the bytes of a [`PrecompressedPacket`] into the [`Ring`] of `buf`. This copy is the
+    /// only per-connection cost.
+    pub fn append_precompressed(
+        &self,
+        pkt: &PrecompressedPacket,
+        buf: &mut IoBuf,
+    ) -> Result<(), AppendError> {
+        let info = buf.append_precompressed(pkt)?;
+
+        self.push(info, buf);
+
+        Ok(())
+    }
+
+    /// Copies `data` into the [`Ring`] of `buf` exactly as it is. Nothing is added: no length
+    /// prefix, no compression framing, and nothing is checked. It is encrypted like everything
+    /// else if the connection is.
+    ///
+    /// This is for bytes which are not framed like packets, like the response to a
+    /// [`LegacyPing`], and for packets which were framed by this server ahead of time. For
+    /// packets framed by another server, use [`Packets::append_framed_raw`].
+    pub fn append_raw(&self, data: &[u8], buf: &mut IoBuf) -> Result<(), AppendError> {
+        buf.buf.get_contiguous(data.len())?.copy_from_slice(data);
+        let writer = buf.buf.advance(data.len());
+
+        self.push(writer, buf);
+
+        Ok(())
+    }
+
+    /// Copies packets which are already framed, like packets proxied from another server, into
+    /// the [`Ring`] of `buf` byte for byte.
+    ///
+    /// `fully_framed` has to be a sequence of whole frames as they are sent over the wire: each
+    /// one starts with its length prefix and, if the connection has compression enabled, has the
+    /// data length and compressed body of that threshold. Unlike [`Packets::append_raw`], this
+    /// checks that every length prefix is valid and that the last frame ends where
+    /// `fully_framed` ends, since a single cut off frame would make the client misread every
+    /// packet after it. The compression framing is not checked.
+    ///
+    /// Nothing is appended if the check fails.
+    pub fn append_framed_raw(
+        &self,
+        fully_framed: &[u8],
+        buf: &mut IoBuf,
+    ) -> Result<(), AppendError> {
+        let mut rest = fully_framed;
+
+        while !rest.is_empty() {
+            let (prefix_len, len) = frame_len(rest)
+                .map_err(anyhow::Error::from)?
+                .context("the length prefix of the last frame is cut off")?;
+
+            let end = prefix_len + len;
+            if end > rest.len() {
+                let left = rest.len();
+                return Err(anyhow!(
+                    "the last frame is {end} bytes long, but only {left} are left"
+                )
+                .into());
+            }
+
+            rest = &rest[end..];
+        }
+
+        self.append_raw(fully_framed, buf)
+    }
+}
+
+/// Pushes `writer` to `high` if it has [`Priority::High`] and to `to_write` otherwise, like
+/// [`push_merged`]. The high priority writes are moved to the front by
+/// [`Packets::queue_high_first`], so the server sends them first.
+fn push_coalesced(
+    high: &mut VecDeque<PacketWriteInfo>,
+    to_write: &mut VecDeque<PacketWriteInfo>,
+    writer: PacketWriteInfo,
+) -> Option<bool> {
+    if writer.priority == Priority::High {
+        push_merged(high, writer)
+    } else {
+        push_merged(to_write, writer)
+    }
+}
+
+/// The writes of a core in the `to_write` and `high` queues of [`Packets`], in the order they
+/// will be sent after [`Packets::queue_high_first`].
+fn queued<'a>(
+    to_write: &'a VecDeque<PacketWriteInfo>,
+    high: &'a VecDeque<PacketWriteInfo>,
+) -> impl Iterator<Item = &'a PacketWriteInfo> {
+    let front = to_write
+        .iter()
+        .take_while(|info| info.priority == Priority::High)
+        .count();
+
+    to_write
+        .range(..front)
+        .chain(high)
+        .chain(to_write.range(front..))
+}
+
+/// Pushes `writer`, merging it into the last element if it starts right where the last one ends.
+///
+/// Returns whether `writer` was merged, or [`None`] if there was no write to merge it into.
+fn push_merged(queue: &mut VecDeque<PacketWriteInfo>, writer: PacketWriteInfo) -> Option<bool> {
+    let merged = queue.back_mut().map(|last| try_merge(last, writer));
+
+    if merged != Some(true) {
+        queue.push_back(writer);
+    }
+
+    merged
+}
+
+/// Merges `writer` into `last` if it starts right where `last` ends.
+///
+/// Both must come from the same [`Ring::generation`]. Otherwise the ring wrapped in between, and
+/// the bytes after `last` may have been overwritten even if the pointers line up. They must also
+/// have the same [`Priority`], so merging never moves bytes into another priority class.
+fn try_merge(last: &mut PacketWriteInfo, writer: PacketWriteInfo) -> bool {
+    let same_generation = last.generation == writer.generation;
+    let same_priority = last.priority == writer.priority;
+    let start_pointer_if_contiguous = last.start_ptr.wrapping_add(last.len as usize);
+
+    if same_generation && same_priority && start_pointer_if_contiguous == writer.start_ptr {
+        #[cfg(feature = "debug_checksums")]
+        {
+            last.checksum = last.combined_checksum(&writer);
+        }
+
+        last.len += writer.len;
+        return true;
+    }
+
+    false
+}
+
+/// Pushes the parts of `writer` which are not covered by a write in `hidden`, returning the number
+/// of bytes pushed.
+fn push_visible<'a>(
+    high: &mut VecDeque<PacketWriteInfo>,
+    to_write: &mut VecDeque<PacketWriteInfo>,
+    writer: PacketWriteInfo,
+    hidden: impl Iterator<Item = &'a PacketWriteInfo>,
+) -> usize {
+    let start = writer.start_ptr as usize;
+    let end = start + writer.len as usize;
+    let mut cursor = start;
+    let mut pushed = 0;
+
+    let mut push = |from: usize, to: usize| {
+        if from < to {
+            let start_ptr = writer.start_ptr.wrapping_add(from - start);
+            let len = (to - from) as u32;
+
+            let part = PacketWriteInfo {
+                start_ptr,
+                len,
+                generation: writer.generation,
+                priority: writer.priority,
+                // a part of a write cannot be derived from its checksum, so this only catches
+                // corruption after the split
+                // SAFETY: the part is within the bytes of `writer`, which are still queued
+                #[cfg(feature = "debug_checksums")]
+                checksum: unsafe { PacketWriteInfo::checksum_of(start_ptr, len) },
+            };
+            push_coalesced(high, to_write, part);
+            pushed += to - from;
+        }
+    };
+
+    for info in hidden {
+        let hidden_start = info.start_ptr as usize;
+        let hidden_end = hidden_start + info.len as usize;
+
+        // writes are only merged within a generation, so this must be one of the merged writes
+        if info.generation != writer.generation || hidden_start < cursor || hidden_end > end {
+            continue;
+        }
+
+        push(cursor, hidden_start);
+        cursor = hidden_end;
+    }
+
+    push(cursor, end);
+
+    pushed
+}
+
+/// The older of two [`Ring::position`]s.
+fn oldest(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// An [`IoBuf`] whose compression threshold is changed until this is dropped. See
+/// [`IoBuf::with_compression`].
+pub struct CompressionGuard<'a> {
+    buf: &'a mut IoBuf,
+    previous: CompressionThreshold,
+}
+
+impl CompressionGuard<'_> {
+    /// The threshold which is restored when this is dropped.
+    #[must_use]
+    pub const fn previous(&self) -> CompressionThreshold {
+        self.previous
+    }
+}
+
+impl std::ops::Deref for CompressionGuard<'_> {
+    type Target = IoBuf;
+
+    fn deref(&self) -> &Self::Target {
+        self.buf
+    }
+}
+
+impl std::ops::DerefMut for CompressionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buf
+    }
+}
+
+impl Drop for CompressionGuard<'_> {
+    fn drop(&mut self) {
+        self.buf.enc.set_compression(self.previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use valence_protocol::{
+        packets::login::LoginHelloC2s, text::IntoText, Bounded, Decode, Encode, Packet, PacketSide,
+        PacketState, VarInt,
+    };
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct FailingPkt;
+
+    impl Packet for FailingPkt {
+        const ID: i32 = 0;
+        const NAME: &'static str = "FailingPkt";
+        const SIDE: PacketSide = PacketSide::Clientbound;
+        const STATE: PacketState = PacketState::Login;
+    }
+
+    impl Encode for FailingPkt {
+        fn encode(&self, _w: impl Write) -> anyhow::Result<()> {
+            anyhow::bail!("this packet always fails to encode")
+        }
+    }
+
+    #[test]
+    fn test_failing_append_restores_threshold() {
+        let threshold = CompressionThreshold(256);
+        let mut buf = IoBuf::new(threshold, MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let result = packets.append_to(
+            &FailingPkt,
+            Some(CompressionThreshold(2)),
+            &mut buf,
+            &mut scratch,
+            &mut compressor,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(buf.enc().compression_threshold(), threshold);
+        assert!(packets.to_write.iter().all(VecDeque::is_empty));
+    }
+
+    #[derive(Debug)]
+    struct PanickingPkt;
+
+    impl Packet for PanickingPkt {
+        const ID: i32 = 0;
+        const NAME: &'static str = "PanickingPkt";
+        const SIDE: PacketSide = PacketSide::Clientbound;
+        const STATE: PacketState = PacketState::Login;
+    }
+
+    impl Encode for PanickingPkt {
+        fn encode(&self, _w: impl Write) -> anyhow::Result<()> {
+            panic!("this packet always panics while encoding")
+        }
+    }
+
+    #[test]
+    fn test_panicking_append_restores_threshold() {
+        let threshold = CompressionThreshold(256);
+        let mut buf = IoBuf::new(threshold, MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            packets.append_pre_compression_packet(&PanickingPkt, &mut buf)
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(buf.enc().compression_threshold(), threshold);
+    }
+
+    #[test]
+    fn test_with_compression() {
+        let threshold = CompressionThreshold(256);
+        let mut buf = IoBuf::new(threshold, MIN_S2C_BUFFER_SIZE, 0);
+
+        {
+            let guard = buf.with_compression(CompressionThreshold(-1));
+            assert_eq!(guard.previous(), threshold);
+            assert_eq!(
+                guard.enc().compression_threshold(),
+                CompressionThreshold(-1)
+            );
+        }
+
+        assert_eq!(buf.enc().compression_threshold(), threshold);
+    }
+
+    #[derive(Debug)]
+    struct OversizedPkt;
+
+    impl Packet for OversizedPkt {
+        const ID: i32 = 0;
+        const NAME: &'static str = "OversizedPkt";
+        const SIDE: PacketSide = PacketSide::Clientbound;
+        const STATE: PacketState = PacketState::Play;
+    }
+
+    impl Encode for OversizedPkt {
+        fn encode(&self, mut w: impl Write) -> anyhow::Result<()> {
+            w.write_all(&vec![0; MAX_PACKET_SIZE])?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_append_errors_are_distinguishable() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let result =
+            packets.append_to(&OversizedPkt, None, &mut buf, &mut scratch, &mut compressor);
+        assert!(matches!(
+            result,
+            Err(AppendError::TooLarge { size }) if size == MAX_PACKET_SIZE + 1
+        ));
+
+        let result = packets.append_to(&FailingPkt, None, &mut buf, &mut scratch, &mut compressor);
+        assert!(matches!(result, Err(AppendError::Encode(_))));
+
+        // existing anyhow call sites keep working
+        let err: anyhow::Error = AppendError::TooLarge { size: 0 }.into();
+        assert!(err.downcast_ref::<AppendError>().is_some());
+    }
+
+    #[test]
+    fn test_max_packet_size() {
+        assert_eq!(
+            VarInt(MAX_PACKET_SIZE as i32).written_size(),
+            MAX_PACKET_LEN_SIZE
+        );
+        assert!(VarInt(MAX_PACKET_SIZE as i32 + 1).written_size() > MAX_PACKET_LEN_SIZE);
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        // random bytes are not worth compressing, so packets above the threshold are sent
+        // uncompressed with an extra data length byte
+        let mut compressed = encoder::PacketEncoder::new(CompressionThreshold(256));
+        compressed.set_compression_policy(CompressionPolicy::min_savings(0.1));
+        let uncompressed = encoder::PacketEncoder::new(CompressionThreshold(-1));
+
+        let mut rng = fastrand::Rng::with_seed(7);
+
+        for (enc, overhead) in [(&uncompressed, 1), (&compressed, 2)] {
+            for len in [MAX_PACKET_SIZE - 1, MAX_PACKET_SIZE, MAX_PACKET_SIZE + 1] {
+                let pkt = BytesPkt(
+                    std::iter::repeat_with(|| rng.u8(..))
+                        .take(len - overhead)
+                        .collect(),
+                );
+                let result = PrecompressedPacket::encode(&pkt, enc, &mut scratch, &mut compressor);
+
+                if len > MAX_PACKET_SIZE {
+                    assert!(matches!(
+                        result,
+                        Err(AppendError::TooLarge { size }) if size == len
+                    ));
+                    continue;
+                }
+
+                let encoded = result.unwrap();
+                let mut bytes = encoded.as_bytes();
+                assert_eq!(VarInt::decode(&mut bytes).unwrap().0, len as i32);
+                assert_eq!(bytes.len(), len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_append_precompressed_matches_append() {
+        let mut buf = IoBuf::new(CompressionThreshold(2), MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let pkt = valence_protocol::packets::play::KeepAliveS2c { id: 1234 };
+
+        let precompressed =
+            PrecompressedPacket::encode(&pkt, buf.enc(), &mut scratch, &mut compressor).unwrap();
+
+        packets
+            .append_to(&pkt, None, &mut buf, &mut scratch, &mut compressor)
+            .unwrap();
+        packets
+            .append_precompressed(&precompressed, &mut buf)
+            .unwrap();
+
+        // both are written contiguously, so they are coalesced into one write
+        let write = packets.to_write[0][0];
+        let bytes = unsafe { write.as_slice() };
+
+        let (appended, copied) = bytes.split_at(bytes.len() / 2);
+        assert_eq!(appended, precompressed.as_bytes());
+        assert_eq!(copied, precompressed.as_bytes());
+    }
+
+    #[test]
+    fn test_validate_buffers() {
+        assert!(validate_buffers(&[]).is_err());
+
+        let mut data = [0_u8; 16];
+
+        let empty = iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: 0,
+        };
+        assert!(validate_buffers(&[empty]).is_err());
+
+        let valid = iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: data.len(),
+        };
+        assert!(validate_buffers(&[valid]).is_ok());
+        assert!(validate_buffers(&[valid, empty]).is_err());
+    }
+
+    #[test]
+    fn test_encoded_len_matches_append() {
+        for threshold in [CompressionThreshold(-1), CompressionThreshold(2)] {
+            let mut buf = IoBuf::new(threshold, MIN_S2C_BUFFER_SIZE, 0);
+            let packets = Packets::default();
+
+            let mut scratch = Scratch::new();
+            let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+            let pkt = valence_protocol::packets::play::GameMessageS2c {
+                chat: "hello world hello world hello world".into_cow_text(),
+                overlay: false,
+            };
+
+            let len = encoded_len(&pkt, &mut buf, &mut scratch, &mut compressor).unwrap();
+            assert_eq!(buf.buf.position(), 0);
+
+            let info = packets
+                .append_to(&pkt, None, &mut buf, &mut scratch, &mut compressor)
+                .unwrap();
+            assert_eq!(packets.queued_bytes(), len);
+
+            // the info is only for this packet, even though it is merged into the last write
+            let second = packets
+                .append_to(&pkt, None, &mut buf, &mut scratch, &mut compressor)
+                .unwrap();
+            assert_eq!({ second.len } as usize, len);
+            assert_eq!({ second.start_ptr }, { info.start_ptr }.wrapping_add(len));
+            assert_eq!(packets.to_write.iter().map(VecDeque::len).sum::<usize>(), 1);
+        }
+    }
+
+    #[test]
+    fn test_append_raw_past_capacity() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+
+        let data = vec![1; MIN_S2C_BUFFER_SIZE / 2 + 1];
+
+        packets.append_raw(&data, &mut buf).unwrap();
+        assert!(matches!(
+            packets.append_raw(&data, &mut buf),
+            Err(AppendError::RingFull)
+        ));
+        assert_eq!(packets.oldest_unsent(0), Some(0));
+
+        // the first packet is still being sent
+        let mut packets = packets;
+        packets.prepare_for_send();
+        packets.clear();
+        assert_eq!(packets.oldest_unsent(0), Some(0));
+
+        packets.set_successfully_sent(1);
+        assert_eq!(packets.oldest_unsent(0), None);
+
+        buf.buf.release_until(buf.buf.position());
+        packets.append_raw(&data, &mut buf).unwrap();
+    }
+
+    #[test]
+    fn test_queued_bytes() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        packets.append_raw(&[0; 10], &mut buf).unwrap();
+        packets.append_raw(&[0; 20], &mut buf).unwrap();
+        assert_eq!(packets.queued_bytes(), 30);
+        assert!(packets.is_saturated(29));
+        assert!(!packets.is_saturated(30));
+
+        // bytes which are being sent still count
+        packets.prepare_for_send();
+        packets.clear();
+        packets.append_raw(&[0; 5], &mut buf).unwrap();
+        assert_eq!(packets.queued_bytes(), 35);
+
+        packets.set_successfully_sent(1);
+        assert_eq!(packets.queued_bytes(), 5);
+    }
+
+    #[test]
+    fn test_append_framed_raw() {
+        use valence_protocol::packets::play::KeepAliveS2c;
+
+        // packets framed by another server, with its own compression threshold
+        let enc = encoder::PacketEncoder::new(CompressionThreshold(0));
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let mut proxied = VecBuf::new();
+        for id in 0..3 {
+            enc.append_packet(
+                &KeepAliveS2c { id },
+                &mut proxied,
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap();
+        }
+
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        packets.append_framed_raw(&proxied, &mut buf).unwrap();
+        assert_eq!(send(&mut packets).1, *proxied);
+
+        // a cut off frame is rejected without appending anything
+        let cut = &proxied[..proxied.len() - 1];
+        assert!(packets.append_framed_raw(cut, &mut buf).is_err());
+        assert!(packets.append_framed_raw(&[0x80], &mut buf).is_err());
+        assert_eq!(packets.total_len(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_coalesce_metrics() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let a = Packets::default();
+        let b = Packets::default();
+
+        // the first write of a queue has nothing to merge with
+        a.append_raw(&[1; 10], &mut buf).unwrap();
+        a.append_raw(&[1; 10], &mut buf).unwrap();
+        b.append_raw(&[2; 10], &mut buf).unwrap();
+
+        // b was appended in between, so this cannot be merged
+        a.append_raw(&[1; 10], &mut buf).unwrap();
+
+        assert_eq!(buf.metrics().coalesce_hits(), 1);
+        assert_eq!(buf.metrics().coalesce_misses(), 1);
+        assert_eq!(a.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_disconnect_metrics() {
+        let buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+
+        buf.metrics().record_disconnect(&DisconnectReason::TimedOut);
+        buf.metrics().record_disconnect(&DisconnectReason::TimedOut);
+        buf.metrics()
+            .record_disconnect(&DisconnectReason::Kicked("Cheating".into_text()));
+
+        let disconnects = buf.metrics().disconnects();
+        assert_eq!(disconnects[DisconnectReason::TimedOut.kind_index()], 2);
+        assert_eq!(disconnects[DisconnectReason::ServerFull.kind_index()], 0);
+        assert_eq!(disconnects.iter().sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn test_packet_type_tracking() {
+        use valence_protocol::packets::play::KeepAliveS2c;
+
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let pkt = KeepAliveS2c { id: 1 };
+        let len = encoded_len(&pkt, &mut buf, &mut scratch, &mut compressor).unwrap();
+
+        // nothing is counted unless tracking is enabled
+        packets
+            .append_to(&pkt, None, &mut buf, &mut scratch, &mut compressor)
+            .unwrap();
+        assert!(buf.metrics.packet_types.is_empty());
+
+        buf.metrics.track_packet_types = true;
+
+        for _ in 0..2 {
+            packets
+                .append_to(&pkt, None, &mut buf, &mut scratch, &mut compressor)
+                .unwrap();
+        }
+        packets.append_raw(b"raw", &mut buf).unwrap();
+
+        // precompressed packets are counted when they are appended, not when they are encoded
+        let precompressed =
+            PrecompressedPacket::encode(&pkt, buf.enc(), &mut scratch, &mut compressor).unwrap();
+        assert_eq!(buf.metrics.packet_types[&KeepAliveS2c::ID].0, 2);
+        packets
+            .append_precompressed(&precompressed, &mut buf)
+            .unwrap();
+
+        assert_eq!(buf.metrics.packet_types.len(), 1);
+        assert_eq!(
+            buf.metrics.packet_types[&KeepAliveS2c::ID],
+            (3, 3 * len as u64)
+        );
+    }
+
+    #[test]
+    fn test_failing_pre_compression_append_restores_threshold() {
+        let threshold = CompressionThreshold(256);
+        let mut buf = IoBuf::new(threshold, MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+
+        let result = packets.append_pre_compression_packet(&FailingPkt, &mut buf);
+
+        assert!(result.is_err());
+        assert_eq!(buf.enc().compression_threshold(), threshold);
+    }
+
+    #[test]
+    fn test_extend_coalesces() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+        let other = Packets::default();
+        let broadcast = Packets::default();
+
+        packets.append_raw(&[0; 10], &mut buf).unwrap();
+        broadcast.append_raw(&[1; 20], &mut buf).unwrap();
+        other.append_raw(&[2; 30], &mut buf).unwrap();
+        broadcast.append_raw(&[3; 40], &mut buf).unwrap();
+
+        packets.extend(&broadcast);
+
+        // the first write of `broadcast` starts right where the write of `packets` ends
+        assert_eq!(packets.to_write[0].len(), 2);
+        assert_eq!(packets.queued_bytes(), 70);
+
+        let (count, written) = send(&mut packets);
+        assert_eq!(count, 2);
+        assert_eq!(&written[..10], &[0; 10]);
+        assert_eq!(&written[10..30], &[1; 20]);
+        assert_eq!(&written[30..], &[3; 40]);
+
+        // when nothing is in between, the seam between both sets is merged as well
+        let mut packets = Packets::default();
+        let contiguous = Packets::default();
+
+        packets.append_raw(&[4; 10], &mut buf).unwrap();
+        contiguous.append_raw(&[5; 20], &mut buf).unwrap();
+        contiguous.append_raw(&[6; 30], &mut buf).unwrap();
+        assert_eq!(contiguous.to_write[0].len(), 1);
+
+        packets.extend(&contiguous);
+        assert_eq!(packets.to_write[0].len(), 1);
+        assert_eq!(packets.queued_bytes(), 60);
+
+        let (count, written) = send(&mut packets);
+        assert_eq!(count, 1);
+        assert_eq!(&written[..10], &[4; 10]);
+        assert_eq!(&written[10..30], &[5; 20]);
+        assert_eq!(&written[30..], &[6; 30]);
+    }
+
+    #[cfg(feature = "debug_checksums")]
+    #[test]
+    fn test_debug_checksums() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        // SAFETY: the packets are only used by this test
+        let mut packets = unsafe { Packets::single_threaded() };
+
+        packets.append_raw(b"hello ", &mut buf).unwrap();
+        packets.append_raw(b"world", &mut buf).unwrap();
+
+        // the merged write has the checksum of both
+        let write = packets.get_write_mut()[0][0];
+        assert_eq!({ write.len }, 11);
+        assert_eq!({ write.checksum }, crc32fast::hash(b"hello world"));
+        unsafe { write.verify_checksum() }.unwrap();
+
+        // something clobbers the bytes before they are sent
+        unsafe { write.start_ptr.cast_mut().write(b'j') };
+        assert!(unsafe { write.verify_checksum() }.is_err());
+    }
+
+    #[test]
+    fn test_no_coalescing_across_wrap() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let capacity = buf.buf.capacity();
+        let mut packets = Packets::default();
+        let other = Packets::default();
+
+        packets.append_raw(&[1; 16], &mut buf).unwrap();
+
+        // pretend everything was sent so the ring wraps back over the queued write
+        buf.buf.release_until(buf.buf.position());
+        other.append_raw(&vec![2; capacity - 16], &mut buf).unwrap();
+        buf.buf.release_until(buf.buf.position());
+        other.append_raw(&[3; 16], &mut buf).unwrap();
+
+        // this starts right where the first write ends, but in the next generation
+        packets.append_raw(&[4; 16], &mut buf).unwrap();
+
+        let [first, second] = [packets.to_write[0][0], packets.to_write[0][1]];
+        assert_eq!(packets.to_write[0].len(), 2);
+        assert_eq!(first.start_ptr.wrapping_add(first.len as usize), {
+            second.start_ptr
+        });
+        assert_ne!({ first.generation }, { second.generation });
+    }
+
+    #[derive(Debug)]
+    struct BytesPkt(Vec<u8>);
+
+    impl Packet for BytesPkt {
+        const ID: i32 = 0;
+        const NAME: &'static str = "BytesPkt";
+        const SIDE: PacketSide = PacketSide::Clientbound;
+        const STATE: PacketState = PacketState::Play;
+    }
+
+    impl Encode for BytesPkt {
+        fn encode(&self, mut w: impl Write) -> anyhow::Result<()> {
+            w.write_all(&self.0)?;
+            Ok(())
+        }
+    }
+
+    /// The data length of an encoded packet, which is 0 if it was sent uncompressed.
+    fn data_len(encoded: &PrecompressedPacket) -> i32 {
+        let mut bytes = encoded.as_bytes();
+        VarInt::decode(&mut bytes).unwrap();
+        VarInt::decode(&mut bytes).unwrap().0
+    }
+
+    #[test]
+    fn test_compression_policy() {
+        let mut enc = encoder::PacketEncoder::new(CompressionThreshold(2));
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let mut rng = fastrand::Rng::with_seed(7);
+        let random = BytesPkt(std::iter::repeat_with(|| rng.u8(..)).take(4096).collect());
+        let zeros = BytesPkt(vec![0; 4096]);
+
+        let mut encode = |enc: &encoder::PacketEncoder, pkt: &BytesPkt| {
+            let encoded =
+                PrecompressedPacket::encode(pkt, enc, &mut scratch, &mut compressor).unwrap();
+            data_len(&encoded)
+        };
+
+        assert_ne!(encode(&enc, &random), 0);
+
+        enc.set_compression_policy(CompressionPolicy::min_savings(0.1));
+        assert_eq!(encode(&enc, &random), 0);
+        assert_ne!(encode(&enc, &zeros), 0);
+    }
+
+    #[test]
+    fn test_compressor_factory() {
+        let created = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let compressors = Compressors::with_factory(CompressionLvl::new(6).unwrap(), {
+            let created = created.clone();
+            move |level| {
+                created.lock().unwrap().push(i32::from(level));
+                libdeflater::Compressor::new(level)
+            }
+        });
+
+        let enc = encoder::PacketEncoder::new(CompressionThreshold(2));
+        let mut scratch = Scratch::new();
+        let pkt = BytesPkt(vec![0; 4096]);
+
+        let encode = |scratch: &mut Scratch| {
+            let mut compressor = compressors.get_local().borrow_mut();
+            PrecompressedPacket::encode(&pkt, &enc, scratch, &mut **compressor).unwrap()
+        };
+
+        assert_ne!(data_len(&encode(&mut scratch)), 0);
+
+        // the factory is called again with the new level
+        compressors.set_level(CompressionLvl::new(1).unwrap());
+        assert_ne!(data_len(&encode(&mut scratch)), 0);
+        assert!(created.lock().unwrap().ends_with(&[1]));
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_zstd_roundtrip() {
+        let threshold = CompressionThreshold(2);
+        let enc = encoder::PacketEncoder::new(threshold);
+        let mut scratch = Scratch::new();
+        let mut compressor = ZstdCompressor::new(0).unwrap();
+
+        let pkt = BytesPkt(b"hello world ".repeat(64));
+        let encoded =
+            PrecompressedPacket::encode(&pkt, &enc, &mut scratch, &mut compressor).unwrap();
+        assert_eq!(data_len(&encoded), 1 + pkt.0.len());
+
+        // zlib cannot inflate it
+        let mut decoder = PacketDecoder::new();
+        decoder.set_compression(threshold);
+        decoder.queue_slice(encoded.as_bytes());
+        assert!(decoder.try_next_packet(&mut scratch).is_err());
+
+        let mut decoder = PacketDecoder::new();
+        decoder.set_compression(threshold);
+        decoder.set_compression_backend(CompressionBackend::Zstd);
+        decoder.queue_slice(encoded.as_bytes());
+
+        let frame = decoder.try_next_packet(&mut scratch).unwrap().unwrap();
+        assert_eq!(frame.id, BytesPkt::ID);
+        assert_eq!(&frame.body[..], &pkt.0[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_zstd_broadcast() {
+        let threshold = CompressionThreshold(2);
+        let mut buf = IoBuf::new(threshold, MIN_S2C_BUFFER_SIZE, 0);
+        let broadcast = Broadcast::default();
+
+        let mut server = MockServer::default();
+        let fd = server.connect();
+
+        let compressors =
+            Compressors::new(CompressionLvl::default()).with_backend(CompressionBackend::Zstd);
+        let mut scratch = Scratch::new();
+
+        // broadcasts are compressed once for every connection, so they use the server's backend
+        let pkt = BytesPkt(b"hello world ".repeat(64));
+        broadcast
+            .append_filtered_to(
+                &pkt,
+                &[fd].into_iter().collect(),
+                &mut buf,
+                &mut scratch,
+                &mut **compressors.get_local().borrow_mut(),
+            )
+            .unwrap();
+
+        let mut packets = Packets::default();
+        broadcast.extend_into(&mut packets, fd);
+        server.send(fd, &mut packets);
+
+        let mut decoder = PacketDecoder::new();
+        decoder.set_compression(threshold);
+        decoder.set_compression_backend(CompressionBackend::Zstd);
+        decoder.queue_slice(&server.take_written(fd));
+
+        let frame = decoder.try_next_packet(&mut scratch).unwrap().unwrap();
+        assert_eq!(frame.id, BytesPkt::ID);
+        assert_eq!(&frame.body[..], &pkt.0[..]);
+    }
+
+    #[test]
+    fn test_set_compression_level() {
+        let compressors = Compressors::new(CompressionLvl::new(6).unwrap());
+        let data = [b"hello world ".as_slice(); 64].concat();
+
+        let compress = || {
+            let mut compressor = compressors.get_local().borrow_mut();
+            let mut out = vec![0; data.len() * 2];
+            let len = compressor.compress(&data, &mut out).unwrap();
+            out.truncate(len);
+            out
+        };
+
+        let before = compress();
+
+        compressors.set_level(CompressionLvl::new(0).unwrap());
+        assert_eq!(i32::from(compressors.level()), 0);
+
+        // level 0 stores the data without compressing it
+        let after = compress();
+        assert!(after.len() > before.len());
+        assert!(after.len() > data.len());
+    }
+
+    #[test]
+    fn test_flush_policy() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        let policy = FlushPolicy::Coalesce {
+            max_delay: Duration::from_millis(100),
+            max_bytes: 64,
+        };
+
+        let start = Instant::now();
+        assert!(!policy.should_flush(&packets, start));
+
+        packets.append_raw(&[0; 32], &mut buf).unwrap();
+        assert!(!policy.should_flush(&packets, start));
+        assert!(policy.should_flush(&packets, start + Duration::from_millis(200)));
+        assert!(FlushPolicy::Immediate.should_flush(&packets, start));
+
+        packets.append_raw(&[0; 32], &mut buf).unwrap();
+        assert!(policy.should_flush(&packets, start));
+
+        packets.prepare_for_send();
+        packets.set_successfully_sent(1);
+
+        packets.append_raw(&[0; 8], &mut buf).unwrap();
+        assert!(!policy.should_flush(&packets, start));
+
+        packets.flush_now();
+        assert!(policy.should_flush(&packets, start));
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+        let other = Packets::default();
+
+        packets.append_raw(&[0; 10], &mut buf).unwrap();
+        other.append_raw(&[1; 20], &mut buf).unwrap();
+        packets.append_raw(&[2; 30], &mut buf).unwrap();
+
+        assert_eq!(packets.iter().collect::<Vec<_>>(), [
+            QueuedWrite {
+                core: 0,
+                offset: 0,
+                len: 10
+            },
+            QueuedWrite {
+                core: 0,
+                offset: 10,
+                len: 30
+            },
+        ]);
+        assert_eq!(packets.total_len(), 40);
+        assert_eq!(Packets::default().iter().count(), 0);
+    }
+
+    #[test]
+    fn test_drop_pending() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        packets.append_raw(&[1; 10], &mut buf).unwrap();
+        let count = packets.prepare_for_send();
+        packets.get_write_mut().iter_mut().for_each(VecDeque::clear);
+
+        packets.append_raw(&[2; 20], &mut buf).unwrap();
+        packets.flush_now();
+        packets.drop_pending();
+
+        // the write which is in flight is still tracked
+        assert_eq!(packets.queued_bytes(), 10);
+        assert_eq!(packets.number_sending(), count);
+        assert!(packets.oldest_unsent(0).is_some());
+        assert!(!packets.can_send());
+
+        // `flush_now` was dropped as well
+        let policy = FlushPolicy::Coalesce {
+            max_delay: Duration::from_secs(60),
+            max_bytes: usize::MAX,
+        };
+        assert!(!policy.should_flush(&packets, Instant::now()));
+
+        packets.set_successfully_sent(count);
+        assert_eq!(packets.queued_bytes(), 0);
+        assert_eq!(packets.oldest_unsent(0), None);
+        assert_eq!(packets.prepare_for_send(), 0);
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        packets.append_raw(&[1; 10], &mut buf).unwrap();
+        packets.append_raw(&[2; 20], &mut buf).unwrap();
+
+        let mut snapshot = packets.swap();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(packets.number_sending(), 1);
+        assert!(packets.iter().next().is_none());
+
+        // the next tick is appended while the snapshot is being sent
+        packets.append_raw(&[3; 30], &mut buf).unwrap();
+        assert_eq!(packets.queued_bytes(), 60);
+        assert!(!packets.can_send());
+
+        let mut limiter = BandwidthLimiter::default();
+        let mut sent = Vec::new();
+        let items = snapshot.refresh_items(MockServer::default().connect());
+        for queue in items.write.iter_mut() {
+            limiter.drain_allowed(queue, |info| sent.push(info.len));
+        }
+        assert_eq!(sent, [30]);
+        assert!(snapshot.is_empty());
+        packets.restore(snapshot);
+
+        // the ring stays reserved until the snapshot is confirmed
+        assert!(packets.oldest_unsent(0).is_some());
+        packets.set_successfully_sent(1);
+        assert_eq!(packets.queued_bytes(), 30);
+
+        let snapshot = packets.swap();
+        assert_eq!(snapshot.len(), 1);
+        packets.restore(snapshot);
+
+        // unsent writes are queued again before anything appended after the swap
+        packets.append_raw(&[4; 40], &mut buf).unwrap();
+        assert_eq!(packets.number_sending(), 0);
+        assert_eq!(packets.queued_bytes(), 70);
+        assert!(packets.can_send());
+        assert_eq!(packets.iter().map(|write| write.len).collect::<Vec<_>>(), [
+            70
+        ]);
+    }
+
+    #[test]
+    fn test_swap_single_threaded() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        // SAFETY: the packets are only used from this thread
+        let mut packets = unsafe { Packets::single_threaded() };
+
+        packets.append_raw(&[1; 10], &mut buf).unwrap();
+        let snapshot = packets.swap();
+        assert!(snapshot.writes.is_single());
+
+        // the queue left behind still has a single slot
+        assert!(packets.to_write.is_single());
+        packets.append_raw(&[2; 20], &mut buf).unwrap();
+        assert_eq!(packets.queued_bytes(), 20);
+
+        packets.restore(snapshot);
+        assert_eq!(packets.iter().map(|write| write.len).collect::<Vec<_>>(), [
+            10, 20
+        ]);
+    }
+
+    #[test]
+    fn test_encrypt_pending_larger_than_ring() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+        let secret = [7; 16];
+
+        packets.append_raw(b"plaintext", &mut buf).unwrap();
+        packets.enable_encryption(&secret);
+
+        // the encrypted copy of the backlog does not fit next to it
+        let data: Vec<u8> = (0..MIN_S2C_BUFFER_SIZE / 4 * 3)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        packets.append_raw(&data, &mut buf).unwrap();
+
+        let mut received = Vec::new();
+        let mut sends = 0;
+
+        while received.len() < data.len() + 9 {
+            packets.encrypt_pending(&mut buf).unwrap();
+            let count = packets.prepare_for_send();
+
+            for info in packets.get_write_mut().iter_mut().flat_map(|w| w.drain(..)) {
+                // SAFETY: the bytes stay pinned until the send is confirmed
+                let bytes =
+                    unsafe { std::slice::from_raw_parts(info.start_ptr, info.len as usize) };
+                received.extend_from_slice(bytes);
+            }
+
+            packets.requeue_deferred();
+            packets.set_successfully_sent(count);
+
+            let position = packets.oldest_unsent(0);
+            buf.buf
+                .release_until(position.unwrap_or_else(|| buf.buf.position()));
+            sends += 1;
+        }
+
+        assert!(sends > 1);
+        assert_eq!(packets.queued_bytes(), 0);
+
+        assert_eq!(&received[..9], b"plaintext");
+        PacketDecryptor::new(&secret).decrypt(&mut received[9..]);
+        assert_eq!(received[9..], data);
+    }
+
+    #[test]
+    fn test_append_filtered() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let broadcast = Broadcast::default();
+
+        let mut server = MockServer::default();
+        let viewer = server.connect();
+        let other = server.connect();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        broadcast.append_raw(&[0; 10], &mut buf).unwrap();
+        broadcast
+            .append_filtered_to(
+                &BytesPkt(vec![1; 20]),
+                &[viewer].into_iter().collect(),
+                &mut buf,
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap();
+        broadcast.append_raw(&[2; 30], &mut buf).unwrap();
+
+        let mut packets = Packets::default();
+        broadcast.extend_into(&mut packets, viewer);
+        assert_eq!(packets.total_len(), 62);
+
+        let count = server.send(viewer, &mut packets);
+        let written = server.take_written(viewer);
+        assert_eq!(count, 1);
+        assert_eq!(written.len(), 62);
+        assert_eq!(&written[12..32], &[1; 20]);
+
+        let mut packets = Packets::default();
+        broadcast.extend_into(&mut packets, other);
+        assert_eq!(packets.total_len(), 40);
+        assert_eq!(packets.queued_bytes(), 40);
+
+        let count = server.send(other, &mut packets);
+        let written = server.take_written(other);
+        assert_eq!(count, 2);
+        assert_eq!(&written[..10], &[0; 10]);
+        assert_eq!(&written[10..], &[2; 30]);
+    }
+
+    #[test]
+    fn test_append_except() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let broadcast = Broadcast::default();
+
+        let mut server = MockServer::default();
+        let fds = [server.connect(), server.connect(), server.connect()];
+        let excluded = fds[1];
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        broadcast
+            .append_except_to(
+                &BytesPkt(vec![1; 20]),
+                excluded,
+                &mut buf,
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap();
+
+        for fd in fds {
+            let mut packets = Packets::default();
+            broadcast.extend_into(&mut packets, fd);
+            server.send(fd, &mut packets);
+
+            let written = server.take_written(fd);
+            if fd == excluded {
+                assert!(written.is_empty());
+            } else {
+                assert_eq!(written.len(), 22);
+                assert_eq!(&written[2..], &[1; 20]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_append_to_cores() {
+        let mut bufs = [
+            IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0),
+            IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 1),
+        ];
+        let broadcast = Broadcast::default();
+
+        let mut server = MockServer::default();
+        let in_shard = server.connect();
+        let elsewhere = server.connect();
+        let unpinned = server.connect();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let pkt = PrecompressedPacket::encode(
+            &BytesPkt(vec![1; 20]),
+            bufs[1].enc(),
+            &mut scratch,
+            &mut compressor,
+        )
+        .unwrap();
+
+        broadcast.append_raw(&[0; 10], &mut bufs[1]).unwrap();
+        broadcast.append_to_core(&pkt, &mut bufs[1]).unwrap();
+        broadcast.append_raw(&[2; 30], &mut bufs[1]).unwrap();
+
+        // only the ring of the shard is written to
+        assert_eq!(bufs[0].buf.pending(), 0);
+
+        let mut packets = Packets::default();
+        packets.pin_to_core(1);
+        broadcast.extend_into(&mut packets, in_shard);
+        assert_eq!(packets.total_len(), 62);
+
+        let count = server.send(in_shard, &mut packets);
+        let written = server.take_written(in_shard);
+        assert_eq!(count, 1);
+        assert_eq!(&written[10..32], pkt.as_bytes());
+
+        for (fd, core) in [(elsewhere, Some(0)), (unpinned, None)] {
+            let mut packets = Packets::default();
+            if let Some(core) = core {
+                packets.pin_to_core(core);
+            }
+            broadcast.extend_into(&mut packets, fd);
+            assert_eq!(packets.total_len(), 40);
+
+            server.send(fd, &mut packets);
+            let written = server.take_written(fd);
+            assert_eq!(&written[..10], &[0; 10]);
+            assert_eq!(&written[10..], &[2; 30]);
+        }
+    }
+
+    #[test]
+    fn test_order_across_cores() {
+        let mut bufs = [
+            IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0),
+            IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 1),
+        ];
+
+        let mut server = MockServer::default();
+        let fd = server.connect();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let first = BytesPkt(vec![1; 10]);
+        let second = BytesPkt(vec![2; 10]);
+
+        // appended on core 1 and then on core 0, but core 0 is sent first
+        let mut packets = Packets::default();
+        for (pkt, buf) in [(&first, 1), (&second, 0)] {
+            packets
+                .append_to(pkt, None, &mut bufs[buf], &mut scratch, &mut compressor)
+                .unwrap();
+        }
+
+        server.send(fd, &mut packets);
+        let written = server.take_written(fd);
+        assert_eq!(written[2], 2);
+        assert_eq!(written[14], 1);
+
+        // appended to the ring of the core the connection is pinned to, both stay in order
+        let mut packets = Packets::default();
+        packets.pin_to_core(1);
+
+        for pkt in [&first, &second] {
+            let buf = &mut bufs[packets.ordered_core()];
+            packets
+                .append_to(pkt, None, buf, &mut scratch, &mut compressor)
+                .unwrap();
+        }
+
+        server.send(fd, &mut packets);
+        let written = server.take_written(fd);
+        assert_eq!(written[2], 1);
+        assert_eq!(written[14], 2);
+    }
+
+    #[test]
+    fn test_send_cached() {
+        let mut bufs = [
+            IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0),
+            IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 1),
+        ];
+        let broadcast = Broadcast::default();
+
+        let mut server = MockServer::default();
+        let viewer = server.connect();
+        let other = server.connect();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let pkt = CachedChunkPacket {
+            pos: ChunkPos::new(1, 2),
+            pkt: PrecompressedPacket::encode(
+                &BytesPkt(vec![1; 20]),
+                bufs[0].enc(),
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap(),
+        };
+        let viewers = [viewer].into_iter().collect();
+
+        // each core copies the chunk into its own ring
+        for buf in &mut bufs {
+            broadcast.send_cached_to(&pkt, &viewers, buf).unwrap();
+        }
+
+        let mut packets = Packets::default();
+        broadcast.extend_into(&mut packets, viewer);
+        assert_eq!(packets.total_len(), 44);
+
+        server.send(viewer, &mut packets);
+        let written = server.take_written(viewer);
+        assert_eq!(&written[..22], pkt.as_bytes());
+        assert_eq!(&written[22..], pkt.as_bytes());
+
+        let mut packets = Packets::default();
+        broadcast.extend_into(&mut packets, other);
+        assert_eq!(packets.total_len(), 0);
+    }
+
+    #[test]
+    fn test_debug_snapshot() {
+        let bufs = IoBufs::init(
+            CompressionThreshold(-1),
+            MIN_S2C_BUFFER_SIZE,
+            &mut MockServer::default(),
+        )
+        .unwrap();
+        let broadcast = Broadcast::default();
+        let compressors = Compressors::new(CompressionLvl::new(4).unwrap());
+
+        let locals = bufs.get_all();
+        broadcast
+            .append_raw(&[0; 10], &mut locals[0].lock())
+            .unwrap();
+
+        let snapshot = bufs.debug_snapshot(&broadcast, &compressors);
+        assert_eq!(snapshot.len(), locals.len());
+        assert_eq!(snapshot[0], CoreBufState {
+            core: 0,
+            capacity: MIN_S2C_BUFFER_SIZE,
+            pending: 10,
+            high_water_mark: 10,
+            head: 10,
+            broadcast_writes: 1,
+            compression_level: 4,
+        });
+
+        // a core which is using its buffer is skipped instead of panicking
+        let _in_use = locals[0].lock();
+        let snapshot = bufs.debug_snapshot(&broadcast, &compressors);
+        assert!(snapshot.iter().all(|state| state.core != 0));
+        assert_eq!(snapshot.len(), locals.len() - 1);
+    }
+
+    #[test]
+    fn test_move_to_pool() {
+        let mut server = MockServer::default();
+        let mut bufs =
+            IoBufs::init(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, &mut server).unwrap();
+        let cores = bufs.get_all().len();
+
+        bufs.get_all()[0].lock().buf_mut().append(&[0; 10]).unwrap();
+
+        let pool = Arc::new(BufferPool::new(cores, MIN_S2C_BUFFER_SIZE));
+        server.replace_s2c_buffers(pool.clone()).unwrap();
+
+        // the write may still be queued, so it has to be released first
+        assert!(bufs.move_to_pool(&pool).is_err());
+        assert_eq!(Arc::strong_count(&pool), 2);
+
+        let mut buf = bufs.get_all()[0].lock();
+        let position = buf.buf_mut().position();
+        buf.buf_mut().release_until(position);
+        drop(buf);
+
+        let small = Arc::new(BufferPool::new(cores, MIN_S2C_BUFFER_SIZE / 2));
+        assert!(bufs.move_to_pool(&small).is_err());
+
+        bufs.move_to_pool(&pool).unwrap();
+        assert_eq!(Arc::strong_count(&pool), cores + 2);
+
+        let mut buf = bufs.get_all()[0].lock();
+        assert_eq!(buf.buf_mut().pending(), 0);
+        assert_eq!(buf.buf_mut().capacity(), MIN_S2C_BUFFER_SIZE);
+        assert_eq!(buf.buf_mut().head(), 0);
+    }
+
+    #[test]
+    fn test_dual_stack_listener() {
+        let address = "[::]:0".parse().unwrap();
+        let Ok(listeners) = bind_listeners(&[address], &SocketOpts::default()) else {
+            eprintln!("skipping, IPv6 is not available");
+            return;
+        };
+
+        assert!(!listeners[0].only_v6().unwrap());
+
+        // IPv4 connections are accepted too
+        let port = local_addrs(&listeners).unwrap()[0].port();
+        std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    }
+
+    #[test]
+    fn test_ipv4_and_ipv6_listeners_on_same_port() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+
+        let addresses = [
+            SocketAddr::from(([0, 0, 0, 0], port)),
+            SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], port)),
+        ];
+
+        if bind_listeners(&addresses[1..], &SocketOpts::default()).is_err() {
+            eprintln!("skipping, IPv6 is not available");
+            return;
+        }
+
+        // the IPv6 listener would conflict with the IPv4 one if it were dual-stack
+        let listeners = bind_listeners(&addresses, &SocketOpts::default()).unwrap();
+        assert!(listeners[1].only_v6().unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_reuseport() {
+        let opts = SocketOpts {
+            reuseport: true,
+            ..SocketOpts::default()
+        };
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 0));
+        let first = bind_listeners(&[address], &opts).unwrap();
+        let address = local_addrs(&first).unwrap()[0];
+        assert!(first[0].reuse_port().unwrap());
+
+        // another process could listen on the same port now
+        let second = bind_listeners(&[address], &opts).unwrap();
+        assert_eq!(local_addrs(&second).unwrap()[0], address);
+
+        // but not without SO_REUSEPORT
+        assert!(bind_listeners(&[address], &SocketOpts::default()).is_err());
+    }
+
+    #[test]
+    fn test_connection_opts() {
+        let opts = SocketOpts {
+            nodelay: true,
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: Some(64 * 1024),
+            ..SocketOpts::default()
+        };
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 0));
+        let listeners = bind_listeners(&[address], &opts).unwrap();
+        let address = local_addrs(&listeners).unwrap()[0];
+        assert!(listeners[0].nodelay().unwrap());
+
+        let _client = std::net::TcpStream::connect(address).unwrap();
+        listeners[0].set_nonblocking(false).unwrap();
+        let (connection, _) = listeners[0].accept().unwrap();
+
+        // the options are set on each connection, not only inherited from the listener
+        let nagle = SocketOpts {
+            nodelay: false,
+            ..SocketOpts::default()
+        };
+        set_connection_opts(SockRef::from(&connection), &nagle).unwrap();
+        assert!(!connection.nodelay().unwrap());
+
+        set_connection_opts(SockRef::from(&connection), &opts).unwrap();
+        assert!(connection.nodelay().unwrap());
+
+        // the kernel may round the sizes up
+        assert!(connection.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(connection.recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[test]
+    fn test_bandwidth_limiter() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        packets.append_raw(&[0; 10], &mut buf).unwrap();
+        packets
+            .append_priority_to(
+                &BytesPkt(vec![1; 20]),
+                Priority::Cosmetic,
+                &mut buf,
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap();
+        packets.append_raw(&[2; 30], &mut buf).unwrap();
+
+        let mut limiter = BandwidthLimiter::new(Some(10));
+        let tick = |packets: &mut Packets, limiter: &mut BandwidthLimiter| {
+            limiter.start_tick();
+            let count = packets.prepare_for_send();
+
+            let mut sent = Vec::new();
+            for queue in packets.get_write_mut().iter_mut() {
+                limiter.drain_allowed(queue, |info| sent.push(info.len));
+            }
+
+            packets.requeue_deferred();
+            (count, sent)
+        };
+
+        // essential writes are sent even though they exceed the limit
+        assert_eq!(tick(&mut packets, &mut limiter), (3, vec![10, 30]));
+        assert_eq!(limiter.used(), 40);
+        assert_eq!(packets.number_sending(), 2);
+        assert_eq!(packets.queued_bytes(), 62);
+        assert!(!packets.can_send());
+
+        packets.set_successfully_sent(2);
+        assert_eq!(packets.queued_bytes(), 22);
+        assert!(packets.oldest_unsent(0).is_some());
+
+        assert_eq!(tick(&mut packets, &mut limiter), (1, vec![22]));
+        assert_eq!(limiter.used(), 22);
+        assert_eq!(packets.number_sending(), 1);
+
+        packets.set_successfully_sent(1);
+        assert_eq!(packets.queued_bytes(), 0);
+        assert_eq!(packets.oldest_unsent(0), None);
+    }
+
+    #[test]
+    fn test_high_priority_is_sent_first() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let mut append = |packets: &Packets, byte: u8, prio: Priority| {
+            packets
+                .append_priority_to(
+                    &BytesPkt(vec![byte; 4]),
+                    prio,
+                    &mut buf,
+                    &mut scratch,
+                    &mut compressor,
+                )
+                .unwrap();
+        };
+
+        append(&packets, 1, Priority::Essential);
+        append(&packets, 2, Priority::High);
+        append(&packets, 3, Priority::High);
+        append(&packets, 4, Priority::Essential);
+
+        // the high priority packets are contiguous in the ring, so they are merged, but not with
+        // the essential packets around them
+        assert_eq!(packets.high[0].len(), 1);
+        assert_eq!(packets.to_write[0].len(), 2);
+
+        let (count, written) = send(&mut packets);
+        assert_eq!(count, 3);
+
+        let order: Vec<u8> = written.chunks(6).map(|pkt| pkt[2]).collect();
+        assert_eq!(order, [2, 3, 1, 4]);
+    }
+
+    #[test]
+    fn test_send_set_compression() {
+        use valence_protocol::packets::play::KeepAliveS2c;
+
+        let mut buf = IoBuf::new(CompressionThreshold(256), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+        let mut decoder = PacketDecoder::default();
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        packets
+            .send_set_compression(CompressionThreshold(256), &mut buf, &mut decoder)
+            .unwrap();
+        assert!(packets.is_compression_negotiated());
+        assert_eq!(decoder.compression(), CompressionThreshold(256));
+
+        let pkt = KeepAliveS2c { id: 1234 };
+        packets
+            .append_to(&pkt, None, &mut buf, &mut scratch, &mut compressor)
+            .unwrap();
+
+        // packets can no longer be sent without compression
+        assert!(packets
+            .append_pre_compression_packet(&pkt, &mut buf)
+            .is_err());
+
+        let (_, written) = send(&mut packets);
+
+        // the LoginCompressionS2c itself is not compressed: its length, ID, and threshold
+        let mut expected = vec![3, 0x03, 0x80, 0x02];
+        // the packet after it is, but as it is below the threshold, its data length is 0
+        expected.extend([10, 0, u8::try_from(KeepAliveS2c::ID).unwrap()]);
+        expected.extend(1234_i64.to_be_bytes());
+
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "compression was already negotiated")]
+    fn test_send_set_compression_twice() {
+        let mut buf = IoBuf::new(CompressionThreshold(256), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+        let mut decoder = PacketDecoder::default();
+
+        for _ in 0..2 {
+            packets
+                .send_set_compression(CompressionThreshold(256), &mut buf, &mut decoder)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_close_after_send() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        let mut server = MockServer::default();
+        let fd = server.connect();
+
+        packets.append_raw(b"bye", &mut buf).unwrap();
+        packets.close_after_send();
+
+        // the packet has not been passed to the server yet
+        assert!(!packets.take_close());
+
+        server.send(fd, &mut packets);
+        assert!(packets.take_close());
+        assert!(!packets.take_close());
+
+        server.close_after_send(fd);
+        server.submit_events();
+        assert!(server.is_closed(fd));
+        assert_eq!(server.written(fd), Some(&b"bye"[..]));
+
+        let mut removed = false;
+        server
+            .drain(|event| {
+                if let ServerEvent::RemovePlayer { fd: removed_fd } = event {
+                    assert_eq!(removed_fd, fd);
+                    removed = true;
+                }
+            })
+            .unwrap();
+        assert!(removed);
+    }
+
+    #[test]
+    fn test_bundle() {
+        use valence_protocol::packets::play::KeepAliveS2c;
+
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        packets
+            .append_bundle(&mut buf, &mut scratch, &mut compressor, |bundle| {
+                bundle.append(&KeepAliveS2c { id: 1 })?;
+                bundle.append(&KeepAliveS2c { id: 2 })?;
+                assert_eq!(bundle.len(), 2);
+                Ok(())
+            })
+            .unwrap();
+
+        let (count, written) = send(&mut packets);
+        assert_eq!(count, 1);
+
+        let mut decoder = PacketDecoder::default();
+        decoder.queue_slice(&written);
+
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.try_next_packet(&mut scratch).unwrap() {
+            frames.push(frame);
+        }
+
+        let ids: Vec<_> = frames.iter().map(|frame| frame.id).collect();
+        assert_eq!(ids, [
+            BundleSplitterS2c::ID,
+            KeepAliveS2c::ID,
+            KeepAliveS2c::ID,
+            BundleSplitterS2c::ID
+        ]);
+
+        // the packets inside keep their order
+        let inner: Vec<_> = frames[1..3]
+            .iter()
+            .map(|frame| frame.decode::<KeepAliveS2c>().unwrap().id)
+            .collect();
+        assert_eq!(inner, [1, 2]);
+    }
+
+    #[test]
+    fn test_bundle_across_wrap() {
+        use valence_protocol::packets::play::KeepAliveS2c;
+
+        let mut buf = IoBuf::new(CompressionThreshold(-1), 4 * MIN_S2C_BUFFER_SIZE, 0);
+        let capacity = buf.buf.capacity();
+        let mut packets = Packets::default();
+        let other = Packets::default();
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let keep_alive = |id| KeepAliveS2c { id };
+        let len = encoded_len(&keep_alive(0), &mut buf, &mut scratch, &mut compressor).unwrap();
+        let delimiter =
+            encoded_len(&BundleSplitterS2c, &mut buf, &mut scratch, &mut compressor).unwrap();
+
+        // every encode reserves MAX_ENCODED_PACKET_SIZE contiguous bytes, so this leaves just
+        // enough room to encode a packet, the start delimiter, and the first packet of the bundle
+        // before the ring wraps for the second one
+        let room = MAX_ENCODED_PACKET_SIZE + len / 2 + len + delimiter;
+        other
+            .append_raw(&vec![0; capacity - room], &mut buf)
+            .unwrap();
+        buf.buf.release_until(buf.buf.position());
+
+        packets
+            .append_to(
+                &keep_alive(0),
+                None,
+                &mut buf,
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap();
+        packets
+            .append_bundle(&mut buf, &mut scratch, &mut compressor, |bundle| {
+                for id in 1..=3 {
+                    bundle.append(&keep_alive(id))?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        packets
+            .append_priority_to(
+                &keep_alive(9),
+                Priority::High,
+                &mut buf,
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap();
+
+        // the high priority packet, everything before the wrap, and everything after it
+        let (count, written) = send(&mut packets);
+        assert_eq!(count, 3);
+
+        let mut decoder = PacketDecoder::default();
+        decoder.queue_slice(&written);
+
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.try_next_packet(&mut scratch).unwrap() {
+            frames.push(frame);
+        }
+
+        // the high priority packet goes before the bundle, not inside it, and the two halves of
+        // the bundle are sent back to back
+        let order: Vec<_> = frames
+            .iter()
+            .map(|frame| match frame.id {
+                KeepAliveS2c::ID => Some(frame.decode::<KeepAliveS2c>().unwrap().id),
+                BundleSplitterS2c::ID => None,
+                id => panic!("unexpected packet {id:#x}"),
+            })
+            .collect();
+        assert_eq!(order, [
+            Some(9),
+            Some(0),
+            None,
+            Some(1),
+            Some(2),
+            Some(3),
+            None
+        ]);
+    }
+
+    #[test]
+    fn test_failed_bundle_queues_nothing() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let result = packets.append_bundle(&mut buf, &mut scratch, &mut compressor, |bundle| {
+            bundle.append(&BytesPkt(b"hello".to_vec()))?;
+            bundle.append(&FailingPkt)
+        });
+        assert!(matches!(result, Err(AppendError::Encode(_))));
+
+        // no unterminated bundle is sent
+        assert_eq!(packets.total_len(), 0);
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        let mut server = MockServer::default();
+        let fd = server.connect();
+
+        let mut global = Global::new(Arc::new(crate::global::Shared {
+            player_count: atomic::AtomicU32::new(0),
+            compression_threshold: CompressionThreshold(-1),
+            compression_level: CompressionLvl::default(),
+            compression_backend: CompressionBackend::Zlib,
+        }));
+
+        packets.append_raw(&[1; 10], &mut buf).unwrap();
+        packets.append_raw(&[2; 20], &mut buf).unwrap();
+        packets.prepare_for_send();
+
+        let items = iter::once(RefreshItems {
+            write: packets.get_write_mut(),
+            fd,
+        });
+        let result = server.flush(&mut global, items);
+
+        // both packets are in one write
+        assert_eq!(result, FlushResult {
+            fds_written: 1,
+            bytes_submitted: 30,
+            iovecs: 1,
+        });
+
+        // the write was only submitted, and completes on the next drain
+        let mut sent = 0;
+        server
+            .drain(|event| {
+                if let ServerEvent::SentData { .. } = event {
+                    sent += 1;
+                }
+            })
+            .unwrap();
+        assert_eq!(sent, 1);
+        assert_eq!(server.written(fd).unwrap().len(), 30);
+    }
+
+    /// Sends everything queued in `packets` through a [`MockServer`] and returns what it wrote.
+    fn send(packets: &mut Packets) -> (usize, Vec<u8>) {
+        let mut server = MockServer::default();
+        let fd = server.connect();
+
+        let count = server.send(fd, packets);
+        (count, server.take_written(fd))
+    }
+
+    fn login_hello() -> LoginHelloC2s<'static> {
+        LoginHelloC2s {
+            username: Bounded::default(),
+            profile_id: None,
+        }
+    }
+
+    #[test]
+    fn test_append_pre_compression_packet() {
+        let mut buf = IoBuf::new(CompressionThreshold(256), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        packets
+            .append_pre_compression_packet(&login_hello(), &mut buf)
+            .unwrap();
+
+        let (count, written) = send(&mut packets);
+        assert_eq!(count, 1);
+        assert_eq!(written.len(), 4); // packet length for an empty LoginHelloC2s
+
+        // play packets are only sent once compression was negotiated
+        let keep_alive = valence_protocol::packets::play::KeepAliveS2c { id: 1 };
+        assert!(packets
+            .append_pre_compression_packet(&keep_alive, &mut buf)
+            .is_err());
+        assert_eq!(packets.total_len(), 0);
+    }
+
+    #[test]
+    fn test_append_packet() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        packets
+            .append_to(
+                &login_hello(),
+                None,
+                &mut buf,
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap();
+
+        let (count, written) = send(&mut packets);
+        assert_eq!(count, 1);
+        assert_eq!(written.len(), 4); // packet length for an empty LoginHelloC2s
+    }
+
+    #[test]
+    fn test_append_raw() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        let data = b"Hello, world!";
+        packets.append_raw(data, &mut buf).unwrap();
+
+        let (count, written) = send(&mut packets);
+        assert_eq!(count, 1);
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        packets
+            .append_pre_compression_packet(&login_hello(), &mut buf)
+            .unwrap();
+        packets.clear();
+
+        let (count, written) = send(&mut packets);
+        assert_eq!(count, 0);
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn test_contiguous_packets() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        // SAFETY: the packets are only used by this test
+        let mut packets = unsafe { Packets::single_threaded() };
+
+        packets
+            .append_pre_compression_packet(&login_hello(), &mut buf)
+            .unwrap();
+        packets
+            .append_pre_compression_packet(&login_hello(), &mut buf)
+            .unwrap();
+
+        let (count, written) = send(&mut packets);
+        assert_eq!(count, 1);
+        assert_eq!(written.len(), 8); // combined length of both packets
+    }
+}
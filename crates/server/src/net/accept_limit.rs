@@ -0,0 +1,282 @@
+//! Rate limits for new connections, so connection spam is closed before it reaches the game.
+
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use fxhash::FxHashMap;
+
+/// Once this many source IPs are tracked, the ones which have not connected recently are
+/// forgotten so spoofed or spread out connection spam cannot grow the map forever.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// How often the source IPs are pruned at most once there are [`PRUNE_THRESHOLD`] of them.
+/// Pruning visits every bucket, so doing it on every accept would make each accept cost as much
+/// as the number of IPs while the map stays full.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How fast connections may be accepted. See [`AcceptPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcceptRate {
+    /// The number of connections which are accepted per second on average.
+    pub per_second: u32,
+    /// The number of connections which may be accepted at once after none were for a while.
+    pub burst: u32,
+}
+
+/// Limits on how fast the server accepts connections, each enforced with a token bucket.
+///
+/// Connections over a limit are closed as soon as they are accepted, so they never cause a
+/// [`crate::net::ServerEvent::AddPlayer`]. They are counted with
+/// [`crate::net::ServerEvent::AcceptsRejected`].
+///
+/// This is only applied by the `io_uring` server on Linux, where it is set with
+/// `LinuxServerConfig::accept_policy`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AcceptPolicy {
+    /// The limit for each source IP. IPv4 connections to a dual-stack listener count as their
+    /// IPv4 address.
+    pub per_ip: Option<AcceptRate>,
+    /// The limit for all connections together.
+    pub global: Option<AcceptRate>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(rate: AcceptRate, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(rate.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: AcceptRate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = elapsed
+            .mul_add(f64::from(rate.per_second), self.tokens)
+            .min(f64::from(rate.burst));
+        self.updated = now;
+    }
+
+    fn has_token(&self) -> bool {
+        self.tokens >= 1.0
+    }
+}
+
+/// Applies an [`AcceptPolicy`] to accepted connections.
+#[derive(Debug)]
+#[cfg_attr(
+    not(target_os = "linux"),
+    expect(dead_code, reason = "only the io_uring server limits accepts")
+)]
+pub(crate) struct AcceptLimiter {
+    policy: AcceptPolicy,
+    global: Option<TokenBucket>,
+    per_ip: FxHashMap<IpAddr, TokenBucket>,
+    /// When `per_ip` was last pruned. See [`PRUNE_INTERVAL`].
+    pruned: Instant,
+    /// The number of connections rejected since [`AcceptLimiter::take_rejected`] was last called
+    rejected: u32,
+}
+
+impl AcceptLimiter {
+    pub fn new(policy: AcceptPolicy) -> Self {
+        let now = Instant::now();
+
+        Self {
+            policy,
+            global: policy.global.map(|rate| TokenBucket::full(rate, now)),
+            per_ip: FxHashMap::default(),
+            pruned: now,
+            rejected: 0,
+        }
+    }
+
+    /// Whether a connection from `peer` which was accepted at `now` may be kept. A token is only
+    /// taken from the buckets if every limit allows it. Connections without a known peer only
+    /// count towards the global limit.
+    pub fn allow(&mut self, peer: Option<IpAddr>, now: Instant) -> bool {
+        let mut global = self.global.as_mut().zip(self.policy.global);
+        let mut per_ip = self.policy.per_ip.zip(peer).map(|(rate, ip)| {
+            let bucket = Self::bucket(&mut self.per_ip, &mut self.pruned, ip, rate, now);
+            (bucket, rate)
+        });
+
+        for (bucket, rate) in global.iter_mut().chain(per_ip.iter_mut()) {
+            bucket.refill(*rate, now);
+        }
+
+        let allowed = global.iter().all(|(bucket, _)| bucket.has_token())
+            && per_ip.iter().all(|(bucket, _)| bucket.has_token());
+
+        if allowed {
+            for (bucket, _) in global.into_iter().chain(per_ip) {
+                bucket.tokens -= 1.0;
+            }
+        } else {
+            self.record_rejected();
+        }
+
+        allowed
+    }
+
+    /// The bucket of `ip`, which starts out full.
+    fn bucket<'a>(
+        buckets: &'a mut FxHashMap<IpAddr, TokenBucket>,
+        pruned: &mut Instant,
+        ip: IpAddr,
+        rate: AcceptRate,
+        now: Instant,
+    ) -> &'a mut TokenBucket {
+        let ip = ip.to_canonical();
+
+        if buckets.len() >= PRUNE_THRESHOLD
+            && now.saturating_duration_since(*pruned) >= PRUNE_INTERVAL
+            && !buckets.contains_key(&ip)
+        {
+            *pruned = now;
+
+            // a bucket which would be full again is the same as no bucket
+            buckets.retain(|_, bucket| {
+                bucket.refill(rate, now);
+                bucket.tokens < f64::from(rate.burst)
+            });
+        }
+
+        buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::full(rate, now))
+    }
+
+    /// Counts a connection which was rejected for another reason than the policy, so it is
+    /// reported along with the others.
+    pub fn record_rejected(&mut self) {
+        self.rejected = self.rejected.saturating_add(1);
+    }
+
+    /// The number of connections rejected since this was last called.
+    pub fn take_rejected(&mut self) -> u32 {
+        std::mem::take(&mut self.rejected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: AcceptRate = AcceptRate {
+        per_second: 2,
+        burst: 3,
+    };
+
+    #[test]
+    fn test_per_ip_limit() {
+        let mut limiter = AcceptLimiter::new(AcceptPolicy {
+            per_ip: Some(RATE),
+            global: None,
+        });
+
+        let now = Instant::now();
+        let spammer = Some(IpAddr::from([1, 2, 3, 4]));
+
+        // the burst is allowed
+        for _ in 0..3 {
+            assert!(limiter.allow(spammer, now));
+        }
+        assert!(!limiter.allow(spammer, now));
+
+        // other IPs are not affected
+        assert!(limiter.allow(Some(IpAddr::from([5, 6, 7, 8])), now));
+
+        // neither are connections whose peer is unknown
+        assert!(limiter.allow(None, now));
+
+        // tokens come back at `per_second`
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.allow(spammer, later));
+        assert!(!limiter.allow(spammer, later));
+
+        assert_eq!(limiter.take_rejected(), 2);
+        assert_eq!(limiter.take_rejected(), 0);
+    }
+
+    #[test]
+    fn test_ipv4_mapped_addresses_share_a_bucket() {
+        let mut limiter = AcceptLimiter::new(AcceptPolicy {
+            per_ip: Some(RATE),
+            global: None,
+        });
+
+        let now = Instant::now();
+        let ipv4 = IpAddr::from([1, 2, 3, 4]);
+        let mapped = IpAddr::from([0, 0, 0, 0, 0, 0xFFFF, 0x0102, 0x0304]);
+
+        assert!(limiter.allow(Some(ipv4), now));
+        assert!(limiter.allow(Some(mapped), now));
+        assert!(limiter.allow(Some(ipv4), now));
+        assert!(!limiter.allow(Some(mapped), now));
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let mut limiter = AcceptLimiter::new(AcceptPolicy {
+            per_ip: Some(RATE),
+            global: Some(AcceptRate {
+                per_second: 1,
+                burst: 4,
+            }),
+        });
+
+        let now = Instant::now();
+        let spammer = Some(IpAddr::from([1, 2, 3, 4]));
+
+        for _ in 0..3 {
+            assert!(limiter.allow(spammer, now));
+        }
+
+        // rejected by the per-IP limit, which does not use up the global limit
+        assert!(!limiter.allow(spammer, now));
+
+        assert!(limiter.allow(Some(IpAddr::from([5, 6, 7, 8])), now));
+        assert!(!limiter.allow(Some(IpAddr::from([9, 10, 11, 12])), now));
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut limiter = AcceptLimiter::new(AcceptPolicy {
+            per_ip: Some(RATE),
+            global: None,
+        });
+
+        let now = Instant::now();
+
+        for i in 0..PRUNE_THRESHOLD as u32 {
+            assert!(limiter.allow(Some(IpAddr::from(i.to_be_bytes())), now));
+        }
+        assert_eq!(limiter.per_ip.len(), PRUNE_THRESHOLD);
+
+        // every bucket has refilled, so they are all forgotten
+        let later = now + PRUNE_INTERVAL;
+        assert!(limiter.allow(Some(IpAddr::from([255, 255, 255, 255])), later));
+        assert_eq!(limiter.per_ip.len(), 1);
+
+        for i in 0..PRUNE_THRESHOLD as u32 {
+            assert!(limiter.allow(Some(IpAddr::from(i.to_be_bytes())), later));
+        }
+
+        // the map was just pruned, so it is not pruned on every accept while it stays full
+        let soon = later + PRUNE_INTERVAL / 2;
+        assert!(limiter.allow(Some(IpAddr::from([255, 255, 255, 254])), soon));
+        assert_eq!(limiter.per_ip.len(), PRUNE_THRESHOLD + 2);
+
+        let next = later + PRUNE_INTERVAL;
+        assert!(limiter.allow(Some(IpAddr::from([255, 255, 255, 253])), next));
+        assert_eq!(limiter.per_ip.len(), 1);
+    }
+}
@@ -0,0 +1,312 @@
+//! A bridge which lets Tokio tasks `.await` the events of a [`Server`]. See [`AsyncServer`].
+
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::sync::{mpsc, mpsc::error::TryRecvError};
+use tracing::{trace, warn};
+
+use crate::net::{Fd, RecvBuffer, Server, ServerDef, ServerEvent};
+
+/// How long the server thread waits for events when there was nothing to do. Commands are only
+/// run between waits, so this is also the most they are delayed by.
+const IDLE_WAIT: Duration = Duration::from_millis(1);
+
+/// Like [`ServerEvent`], but owning its data so it can be sent to another thread.
+#[derive(Debug)]
+pub enum OwnedServerEvent {
+    AddPlayer { fd: Fd, listener: usize },
+    RemovePlayer { fd: Fd },
+    RecvData { fd: Fd, data: Bytes },
+    RecvBuffer { fd: Fd, buffer: RecvBuffer },
+    SentData { fd: Fd },
+    Error { fd: Fd, error: io::Error },
+    CompletionOverflow { dropped: u32 },
+    AcceptsRejected { count: u32 },
+}
+
+impl From<ServerEvent<'_>> for OwnedServerEvent {
+    fn from(event: ServerEvent<'_>) -> Self {
+        match event {
+            ServerEvent::AddPlayer { fd, listener } => Self::AddPlayer { fd, listener },
+            ServerEvent::RemovePlayer { fd } => Self::RemovePlayer { fd },
+            ServerEvent::RecvData { fd, data } => Self::RecvData {
+                fd,
+                data: Bytes::copy_from_slice(data),
+            },
+            // the buffer is already owned, so it is passed on without copying the data
+            ServerEvent::RecvBuffer { fd, buffer } => Self::RecvBuffer { fd, buffer },
+            ServerEvent::SentData { fd } => Self::SentData { fd },
+            ServerEvent::Error { fd, error } => Self::Error { fd, error },
+            ServerEvent::CompletionOverflow { dropped } => Self::CompletionOverflow { dropped },
+            ServerEvent::AcceptsRejected { count } => Self::AcceptsRejected { count },
+        }
+    }
+}
+
+enum Command {
+    Run(Box<dyn FnOnce(&mut Server) + Send>),
+    CloseAfterSend(Fd),
+    Shutdown,
+}
+
+/// Runs a [`Server`] on its own thread for embedders which would rather `.await` events than poll.
+///
+/// The server itself stays poll-based. [`AsyncServer::spawn`] creates it on a dedicated thread,
+/// which drains events, submits queued operations, and runs the commands of
+/// [`AsyncServerHandle`]s in a loop. On Linux, the `io_uring` instance never leaves that thread,
+/// so every submission comes from the same thread like in the game loop.
+///
+/// Events are sent through a bounded channel. Once it is full, the server thread blocks until
+/// events are received, so it stops reading from connections and clients are slowed down by TCP
+/// instead of events piling up in memory.
+pub struct AsyncServer {
+    events: mpsc::Receiver<OwnedServerEvent>,
+    handle: AsyncServerHandle,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+/// Sends commands to the thread of an [`AsyncServer`]. Commands are run in the order they are
+/// sent, between two drains.
+///
+/// The command channel is unbounded, since the server thread never waits for more than a
+/// millisecond before running them.
+#[derive(Clone)]
+pub struct AsyncServerHandle {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl AsyncServer {
+    /// Starts a server on every address `address` resolves to on a new thread, and runs `init`
+    /// there before anything else, which should register the S2C buffers with
+    /// [`crate::net::IoBufs::init`]. Its result is returned along with the server.
+    ///
+    /// At most `capacity` events are buffered before the server thread waits for
+    /// [`AsyncServer::recv`].
+    ///
+    /// This blocks until `init` has returned.
+    ///
+    /// # Panics
+    /// If `capacity` is zero.
+    pub fn spawn<T: Send + 'static>(
+        address: impl ToSocketAddrs,
+        capacity: usize,
+        init: impl FnOnce(&mut Server) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<(Self, T)> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+
+        let (event_tx, events) = mpsc::channel(capacity);
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let (init_tx, init_rx) = std::sync::mpsc::sync_channel(1);
+
+        let thread = std::thread::Builder::new()
+            .name("server-io".to_owned())
+            .spawn(move || {
+                let started = Server::new_multi(&addresses).and_then(|mut server| {
+                    let value = init(&mut server)?;
+                    Ok((server, value))
+                });
+
+                let server = match started {
+                    Ok((server, value)) => {
+                        init_tx.send(Ok(value)).ok();
+                        server
+                    }
+                    Err(err) => {
+                        init_tx.send(Err(err)).ok();
+                        return Ok(());
+                    }
+                };
+
+                run(server, &event_tx, command_rx)
+            })?;
+
+        let value = init_rx
+            .recv()
+            .context("server thread exited before it was initialized")??;
+
+        let server = Self {
+            events,
+            handle: AsyncServerHandle { commands },
+            thread,
+        };
+
+        Ok((server, value))
+    }
+
+    /// Waits for the next event. Returns [`None`] once the server thread has stopped.
+    pub async fn recv(&mut self) -> Option<OwnedServerEvent> {
+        self.events.recv().await
+    }
+
+    #[must_use]
+    pub const fn handle(&self) -> &AsyncServerHandle {
+        &self.handle
+    }
+
+    /// Shuts the server down with [`ServerDef::shutdown`] and waits for its thread to exit.
+    /// Events which have not been received yet are discarded.
+    pub async fn shutdown(mut self) -> io::Result<()> {
+        self.handle.shutdown();
+
+        // unblocks the server thread if it is waiting for room in the channel
+        self.events.close();
+
+        let thread = self.thread;
+        tokio::task::spawn_blocking(move || thread.join())
+            .await
+            .map_err(io::Error::other)?
+            .map_err(|_| io::Error::other("server thread panicked"))?
+    }
+}
+
+impl AsyncServerHandle {
+    /// Runs `f` on the server thread, like to call [`ServerDef::write_all`] with packets which
+    /// have been appended on another thread. The rings which the packets point into must not be
+    /// written to until the writes have completed.
+    pub fn with_server(&self, f: impl FnOnce(&mut Server) + Send + 'static) {
+        self.send(Command::Run(Box::new(f)));
+    }
+
+    /// See [`ServerDef::close_after_send`].
+    pub fn close_after_send(&self, fd: Fd) {
+        self.send(Command::CloseAfterSend(fd));
+    }
+
+    /// Stops the server thread after the commands which have already been sent.
+    pub fn shutdown(&self) {
+        self.send(Command::Shutdown);
+    }
+
+    fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            warn!("server thread has already stopped");
+        }
+    }
+}
+
+fn run(
+    mut server: Server,
+    events: &mpsc::Sender<OwnedServerEvent>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) -> io::Result<()> {
+    loop {
+        loop {
+            match commands.try_recv() {
+                Ok(Command::Run(f)) => f(&mut server),
+                Ok(Command::CloseAfterSend(fd)) => server.close_after_send(fd),
+                Ok(Command::Shutdown) | Err(TryRecvError::Disconnected) => {
+                    return server.shutdown();
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        server.submit_events();
+
+        let mut received = 0_usize;
+        let mut closed = false;
+
+        server.drain(|event| {
+            received += 1;
+
+            // blocks while the channel is full, which is what propagates backpressure
+            closed |= events.blocking_send(event.into()).is_err();
+        })?;
+
+        if closed {
+            trace!("event receiver was dropped, shutting down");
+            return server.shutdown();
+        }
+
+        if received == 0 {
+            wait_for_events(&server);
+        }
+    }
+}
+
+/// Waits until the server has events to drain, or for at most [`IDLE_WAIT`].
+fn wait_for_events(server: &Server) {
+    let Some(fd) = server.registration_fd() else {
+        std::thread::sleep(IDLE_WAIT);
+        return;
+    };
+
+    let mut poll_fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    // SAFETY: `poll_fd` is a single valid pollfd
+    let ready = unsafe { libc::poll(&mut poll_fd, 1, IDLE_WAIT.as_millis() as i32) };
+
+    if ready < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            warn!("failed to poll the server: {err}");
+            std::thread::sleep(IDLE_WAIT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::net::REQUIRE_IO_URING;
+
+    #[tokio::test]
+    async fn test_recv_events() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let mut server = match AsyncServer::spawn(address, 16, |_| Ok(())) {
+            Ok((server, ())) => server,
+            Err(err) if std::env::var_os(REQUIRE_IO_URING).is_some() => {
+                panic!("the server could not be started: {err:?}")
+            }
+            Err(err) => {
+                eprintln!("skipping, the server could not be started: {err}");
+                return;
+            }
+        };
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"hello").unwrap();
+
+        let mut added = None;
+        let mut received = Vec::new();
+
+        while received.len() < 5 {
+            let event = tokio::time::timeout(Duration::from_secs(30), server.recv())
+                .await
+                .expect("timed out waiting for events")
+                .expect("server thread stopped");
+
+            match event {
+                OwnedServerEvent::AddPlayer { fd, .. } => added = Some(fd),
+                OwnedServerEvent::RecvData { fd, data } => {
+                    assert_eq!(Some(fd), added);
+                    received.extend_from_slice(&data);
+                }
+                _ => {}
+            }
+        }
+
+        assert_eq!(received, b"hello");
+
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! The compressors packets are compressed with.
+
+use libdeflater::CompressionLvl;
+
+/// Compresses packet data in the zlib format, which is what clients expect after the data length
+/// of a compressed packet.
+///
+/// This is implemented for [`libdeflater::Compressor`], which is what
+/// [`crate::net::Compressors::new`] uses. Other implementations can be swapped in with
+/// [`crate::net::Compressors::with_factory`], for example to compare compression libraries.
+pub trait PacketCompressor: Send {
+    /// Compresses `src` into `dst` and returns the number of bytes written, or [`None`] if `dst`
+    /// is too small for the compressed data.
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> Option<usize>;
+}
+
+impl PacketCompressor for libdeflater::Compressor {
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> Option<usize> {
+        self.zlib_compress(src, dst).ok()
+    }
+}
+
+/// Creates the compressor of each core with the given level. See [`PacketCompressor`].
+pub(crate) type CompressorFactory =
+    Box<dyn Fn(CompressionLvl) -> Box<dyn PacketCompressor> + Send + Sync>;
+
+/// The format compressed packets are compressed in. The framing is the same for every backend:
+/// compressed packets still start with the length of the uncompressed data.
+///
+/// The backend is the same for every connection of a server, since broadcasts and cached packets
+/// are compressed once and sent to everyone. It is set with
+/// [`crate::global::Shared::compression_backend`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CompressionBackend {
+    /// zlib, which is what vanilla clients expect.
+    #[default]
+    Zlib,
+    /// zstd, which is not understood by vanilla clients. Only use this if every connection is a
+    /// link where both ends are controlled, like to a proxy.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// A [`PacketCompressor`] which compresses packets with zstd. See [`CompressionBackend::Zstd`].
+#[cfg(feature = "zstd")]
+pub struct ZstdCompressor(zstd::bulk::Compressor<'static>);
+
+#[cfg(feature = "zstd")]
+impl ZstdCompressor {
+    /// Creates a compressor with the zstd `level`, where 0 is the default level of zstd.
+    pub fn new(level: i32) -> std::io::Result<Self> {
+        zstd::bulk::Compressor::new(level).map(Self)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl PacketCompressor for ZstdCompressor {
+    fn compress(&mut self, src: &[u8], dst: &mut [u8]) -> Option<usize> {
+        self.0.compress_to_buffer(src, dst).ok()
+    }
+}
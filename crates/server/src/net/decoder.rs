@@ -1,17 +1,234 @@
+use std::{borrow::Cow, fmt};
+
 use anyhow::{bail, ensure, Context};
 use bytes::{Buf, BytesMut};
 use more_asserts::debug_assert_ge;
+use parking_lot::Mutex;
 use valence_protocol::{
     decode::PacketFrame, var_int::VarIntDecodeError, CompressionThreshold, Decode, VarInt,
     MAX_PACKET_SIZE,
 };
 
-use crate::event::ScratchBuffer;
+use crate::{
+    event::ScratchBuffer,
+    net::{CompressionBackend, PacketDecryptor},
+};
+
+/// An error which occurs while splitting a byte stream into frames with
+/// [`PacketDecoder::decode_frames`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The length prefix of a frame is not a valid `VarInt`.
+    MalformedLength,
+    /// A frame advertised a negative length.
+    NegativeLength(i32),
+    /// A frame advertised a length larger than [`MAX_PACKET_SIZE`].
+    FrameTooLarge(i32),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedLength => write!(f, "malformed packet length VarInt"),
+            Self::NegativeLength(len) => write!(f, "packet length of {len} is negative"),
+            Self::FrameTooLarge(len) => write!(
+                f,
+                "packet length of {len} exceeds the maximum of {MAX_PACKET_SIZE}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Returns the size of the length prefix and the length of the frame at the start of `buf`, or
+/// [`None`] if the length prefix is not complete yet.
+pub(crate) fn frame_len(buf: &[u8]) -> Result<Option<(usize, usize)>, DecodeError> {
+    let mut r = buf;
+
+    let len = match VarInt::decode_partial(&mut r) {
+        Ok(len) => len,
+        Err(VarIntDecodeError::Incomplete) => return Ok(None),
+        Err(VarIntDecodeError::TooLarge) => return Err(DecodeError::MalformedLength),
+    };
+
+    if len < 0 {
+        return Err(DecodeError::NegativeLength(len));
+    }
+
+    if len > MAX_PACKET_SIZE {
+        return Err(DecodeError::FrameTooLarge(len));
+    }
+
+    let prefix_len = buf.len() - r.len();
+
+    #[expect(clippy::cast_sign_loss, reason = "we are checking if < 0")]
+    Ok(Some((prefix_len, len as usize)))
+}
+
+/// An iterator over the frames in a byte slice. See [`PacketDecoder::decode_frames`].
+pub struct Frames<'a, 'b> {
+    decoder: &'b mut PacketDecoder,
+    data: &'a [u8],
+    errored: bool,
+}
+
+impl<'a> Frames<'a, '_> {
+    fn fail(&mut self, err: DecodeError) -> DecodeError {
+        self.errored = true;
+        self.decoder.buf.clear();
+        self.data = &[];
+        err
+    }
+
+    /// Completes the frame which was partially buffered by a previous call.
+    fn next_buffered(&mut self) -> Option<Result<Cow<'a, [u8]>, DecodeError>> {
+        let buf = &mut self.decoder.buf;
+
+        loop {
+            match frame_len(buf) {
+                Ok(Some((prefix_len, len))) => {
+                    let total_len = prefix_len + len;
+
+                    if buf.len() < total_len {
+                        let take = (total_len - buf.len()).min(self.data.len());
+                        let (taken, rest) = self.data.split_at(take);
+                        buf.extend_from_slice(taken);
+                        self.data = rest;
+
+                        if buf.len() < total_len {
+                            return None;
+                        }
+                    }
+
+                    let mut frame = buf.split_to(total_len);
+                    frame.advance(prefix_len);
+
+                    return Some(Ok(Cow::Owned(frame.to_vec())));
+                }
+                Ok(None) => {
+                    // the length prefix itself is split; move bytes over one at a time
+                    let (first, rest) = self.data.split_first()?;
+                    buf.extend_from_slice(&[*first]);
+                    self.data = rest;
+                }
+                Err(err) => return Some(Err(self.fail(err))),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Frames<'a, '_> {
+    type Item = Result<Cow<'a, [u8]>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.errored {
+            return None;
+        }
+
+        if !self.decoder.buf.is_empty() {
+            return self.next_buffered();
+        }
+
+        match frame_len(self.data) {
+            Ok(Some((prefix_len, len))) if self.data.len() >= prefix_len + len => {
+                let (frame, rest) = self.data.split_at(prefix_len + len);
+                self.data = rest;
+                Some(Ok(Cow::Borrowed(&frame[prefix_len..])))
+            }
+            Ok(_) => {
+                // not enough data has arrived yet
+                self.decoder.buf.extend_from_slice(self.data);
+                self.data = &[];
+                None
+            }
+            Err(err) => Some(Err(self.fail(err))),
+        }
+    }
+}
+
+impl Drop for Frames<'_, '_> {
+    /// Buffers whatever has not been yielded so it is not lost if iteration stops early.
+    fn drop(&mut self) {
+        if !self.errored {
+            self.decoder.buf.extend_from_slice(self.data);
+        }
+    }
+}
+
+/// A [`libdeflater::Decompressor`] which is only created once a compressed packet arrives.
+///
+/// The decompressor is not [`Sync`], but components have to be. It is only reached through
+/// `&mut self`, so the [`Mutex`] is never locked and only makes the decoder [`Sync`].
+#[derive(Default)]
+struct LazyDecompressor(Mutex<Option<libdeflater::Decompressor>>);
+
+impl LazyDecompressor {
+    fn get(&mut self) -> &mut libdeflater::Decompressor {
+        self.0
+            .get_mut()
+            .get_or_insert_with(libdeflater::Decompressor::new)
+    }
+}
 
 #[derive(Default)]
 pub struct PacketDecoder {
     buf: BytesMut,
     threshold: CompressionThreshold,
+    backend: CompressionBackend,
+    decompressor: LazyDecompressor,
+    decryption: Option<PacketDecryptor>,
+}
+
+/// Inflates `compressed` into `scratch`. It must decompress to exactly `data_len` bytes, which is
+/// bounded by [`MAX_PACKET_SIZE`] so a malicious client cannot make us allocate a huge buffer.
+fn inflate<'s>(
+    decompressor: &mut LazyDecompressor,
+    backend: CompressionBackend,
+    threshold: CompressionThreshold,
+    data_len: i32,
+    compressed: &[u8],
+    scratch: &'s mut impl ScratchBuffer,
+) -> anyhow::Result<&'s [u8]> {
+    ensure!(
+        (0..MAX_PACKET_SIZE).contains(&data_len),
+        "decompressed packet length of {data_len} is out of bounds"
+    );
+
+    ensure!(
+        data_len > threshold.0,
+        "decompressed packet length of {data_len} is <= the compression threshold of {}",
+        threshold.0
+    );
+
+    let decompression_buf = scratch.obtain();
+
+    debug_assert!(decompression_buf.is_empty());
+    debug_assert_ge!(decompression_buf.capacity(), MAX_PACKET_SIZE as usize);
+
+    #[expect(clippy::cast_sign_loss, reason = "we are checking if < 0")]
+    let data_len = data_len as usize;
+
+    // valid because scratch is always large enough
+    unsafe { decompression_buf.set_len(data_len) };
+
+    let written_len = match backend {
+        CompressionBackend::Zlib => decompressor
+            .get()
+            .zlib_decompress(compressed, decompression_buf)?,
+        #[cfg(feature = "zstd")]
+        CompressionBackend::Zstd => {
+            zstd::bulk::decompress_to_buffer(compressed, decompression_buf)?
+        }
+    };
+
+    ensure!(
+        written_len == data_len,
+        "decompressed packet length of {written_len} does not match the declared length of \
+         {data_len}"
+    );
+
+    Ok(decompression_buf)
 }
 
 impl PacketDecoder {
@@ -44,7 +261,9 @@ impl PacketDecoder {
             return Ok(None);
         }
 
-        let packet_len_len = VarInt(packet_len).written_size();
+        // clients may encode VarInts with more bytes than needed, so the size of the prefix is
+        // what was read rather than `VarInt::written_size`
+        let packet_len_len = self.buf.len() - r.len();
 
         let mut data;
 
@@ -53,6 +272,7 @@ impl PacketDecoder {
             r = &r[..packet_len as usize];
 
             let data_len = VarInt::decode(&mut r)?.0;
+            let data_len_len = packet_len as usize - r.len();
 
             ensure!(
                 (0..MAX_PACKET_SIZE).contains(&data_len),
@@ -61,37 +281,18 @@ impl PacketDecoder {
 
             // Is this packet compressed?
             if data_len > 0 {
-                ensure!(
-                    data_len > self.threshold.0,
-                    "decompressed packet length of {data_len} is <= the compression threshold of \
-                     {}",
-                    self.threshold.0
-                );
-
-                let decompression_buf = scratch.obtain();
-
-                debug_assert!(decompression_buf.is_empty());
-                debug_assert_ge!(decompression_buf.capacity(), MAX_PACKET_SIZE as usize);
-
-                // decompression_buf.put_bytes(0, data_len as usize);
-
-                // valid because scratch is always large enough
-                unsafe { decompression_buf.set_len(data_len as usize) };
-
-                let written_len = {
-                    // todo: does it make sense to cache ever?
-                    let mut decompressor = libdeflater::Decompressor::new();
-
-                    decompressor.zlib_decompress(r, decompression_buf)?
-                };
-
-                debug_assert_eq!(written_len, data_len as usize);
-
-                let total_packet_len = VarInt(packet_len).written_size() + packet_len as usize;
-
-                self.buf.advance(total_packet_len);
-
-                data = BytesMut::from(decompression_buf.as_slice());
+                let decompressed = inflate(
+                    &mut self.decompressor,
+                    self.backend,
+                    self.threshold,
+                    data_len,
+                    r,
+                    scratch,
+                )?;
+
+                data = BytesMut::from(decompressed);
+
+                self.buf.advance(packet_len_len + packet_len as usize);
             } else {
                 debug_assert_eq!(data_len, 0);
 
@@ -104,7 +305,7 @@ impl PacketDecoder {
 
                 let remaining_len = r.len();
 
-                self.buf.advance(packet_len_len + 1);
+                self.buf.advance(packet_len_len + data_len_len);
 
                 data = self.buf.split_to(remaining_len);
             }
@@ -127,6 +328,70 @@ impl PacketDecoder {
         }))
     }
 
+    /// Splits `data` into length-prefixed frames, yielding the bytes after each length prefix.
+    ///
+    /// Frames which are entirely inside `data` are borrowed from it. A trailing partial frame is
+    /// buffered and completed by the next call, in which case that frame is copied. After an
+    /// error is yielded, all buffered data is discarded and the iterator ends.
+    ///
+    /// Frames are not decompressed. If encryption is enabled, every frame is copied.
+    pub fn decode_frames<'a, 'b>(&'b mut self, data: &'a [u8]) -> Frames<'a, 'b> {
+        // encrypted data has to be decrypted into our own buffer, so nothing can be borrowed
+        let data: &[u8] = if self.decryption.is_some() {
+            self.queue_slice(data);
+            &[]
+        } else {
+            data
+        };
+
+        Frames {
+            decoder: self,
+            data,
+            errored: false,
+        }
+    }
+
+    /// Returns the uncompressed bytes of a frame yielded by [`PacketDecoder::decode_frames`], which
+    /// are the encoded packet ID followed by the body. The frame is decompressed into `scratch` if
+    /// compression is enabled and the frame is compressed.
+    ///
+    /// Frames below the compression threshold are returned without their data length and without
+    /// using `scratch`.
+    pub fn decompress_frame<'a>(
+        &mut self,
+        frame: &'a [u8],
+        scratch: &'a mut impl ScratchBuffer,
+    ) -> anyhow::Result<&'a [u8]> {
+        if self.threshold.0 < 0 {
+            return Ok(frame);
+        }
+
+        let mut r = frame;
+        let data_len = VarInt::decode(&mut r)?.0;
+
+        if data_len == 0 {
+            #[expect(clippy::cast_sign_loss, reason = "we are checking if < 0")]
+            let threshold = self.threshold.0 as usize;
+
+            ensure!(
+                r.len() <= threshold,
+                "uncompressed packet length of {} exceeds compression threshold of {threshold}",
+                r.len(),
+            );
+
+            return Ok(r);
+        }
+
+        inflate(
+            &mut self.decompressor,
+            self.backend,
+            self.threshold,
+            data_len,
+            r,
+            scratch,
+        )
+    }
+
     #[must_use]
     pub const fn compression(&self) -> CompressionThreshold {
         self.threshold
@@ -136,12 +401,48 @@ impl PacketDecoder {
         self.threshold = threshold;
     }
 
-    pub fn queue_bytes(&mut self, bytes: BytesMut) {
+    #[must_use]
+    pub const fn compression_backend(&self) -> CompressionBackend {
+        self.backend
+    }
+
+    /// Decompresses packets with `backend` from now on. This must match the backend the other
+    /// end compresses with; see [`crate::global::Shared::compression_backend`].
+    pub fn set_compression_backend(&mut self, backend: CompressionBackend) {
+        self.backend = backend;
+    }
+
+    /// Decrypts everything received from now on with AES-128-CFB8. Bytes which are already
+    /// queued are assumed to be plaintext.
+    ///
+    /// # Panics
+    /// If encryption is already enabled.
+    pub fn enable_encryption(&mut self, shared_secret: &[u8; 16]) {
+        assert!(self.decryption.is_none(), "encryption is already enabled");
+        self.decryption = Some(PacketDecryptor::new(shared_secret));
+    }
+
+    pub fn queue_bytes(&mut self, mut bytes: BytesMut) {
+        if let Some(decryption) = &mut self.decryption {
+            decryption.decrypt(&mut bytes);
+        }
+
         self.buf.unsplit(bytes);
     }
 
+    /// Whether no bytes are waiting to be decoded.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
     pub fn queue_slice(&mut self, bytes: &[u8]) {
+        let start = self.buf.len();
         self.buf.extend_from_slice(bytes);
+
+        if let Some(decryption) = &mut self.decryption {
+            decryption.decrypt(&mut self.buf[start..]);
+        }
     }
 
     pub fn take_capacity(&mut self) -> BytesMut {
@@ -157,7 +458,7 @@ impl PacketDecoder {
 mod tests {
     use valence_protocol::{
         packets::{login, login::LoginHelloC2s},
-        Bounded, CompressionThreshold,
+        Bounded, CompressionThreshold, Encode,
     };
 
     use super::*;
@@ -278,6 +579,180 @@ mod tests {
         }
     }
 
+    fn encode_two_packets() -> Vec<u8> {
+        let mut encoder = valence_protocol::PacketEncoder::new();
+
+        encoder
+            .append_packet(&login::LoginHelloC2s {
+                username: Bounded("Emerald_Explorer"),
+                profile_id: None,
+            })
+            .unwrap();
+
+        encoder
+            .append_packet(&login::LoginHelloC2s {
+                username: Bounded::default(),
+                profile_id: None,
+            })
+            .unwrap();
+
+        encoder.take().to_vec()
+    }
+
+    #[test]
+    fn test_decode_frames_split_at_every_byte() {
+        let bytes = encode_two_packets();
+
+        let expected: Vec<Vec<u8>> = PacketDecoder::new()
+            .decode_frames(&bytes)
+            .map(|frame| frame.unwrap().into_owned())
+            .collect();
+
+        assert_eq!(expected.len(), 2);
+
+        for split in 0..=bytes.len() {
+            let (first, second) = bytes.split_at(split);
+
+            let mut decoder = PacketDecoder::new();
+
+            let mut frames: Vec<Vec<u8>> = decoder
+                .decode_frames(first)
+                .map(|frame| frame.unwrap().into_owned())
+                .collect();
+
+            frames.extend(
+                decoder
+                    .decode_frames(second)
+                    .map(|frame| frame.unwrap().into_owned()),
+            );
+
+            assert_eq!(frames, expected, "mismatch when splitting at {split}");
+        }
+    }
+
+    #[test]
+    fn test_decode_frames_too_large() {
+        let mut bytes = Vec::new();
+        VarInt(MAX_PACKET_SIZE + 1).encode(&mut bytes).unwrap();
+
+        let mut decoder = PacketDecoder::new();
+        let mut frames = decoder.decode_frames(&bytes);
+
+        assert_eq!(
+            frames.next(),
+            Some(Err(DecodeError::FrameTooLarge(MAX_PACKET_SIZE + 1)))
+        );
+        assert_eq!(frames.next(), None);
+    }
+
+    #[test]
+    fn test_decompress_frames() {
+        let threshold = CompressionThreshold(10);
+
+        let mut encoder = valence_protocol::PacketEncoder::new();
+        encoder.set_compression(threshold);
+
+        // one packet above the threshold and one below it
+        for username in ["Emerald_Explorer", ""] {
+            encoder
+                .append_packet(&login::LoginHelloC2s {
+                    username: Bounded(username),
+                    profile_id: None,
+                })
+                .unwrap();
+        }
+
+        let bytes = encoder.take();
+
+        let mut valence_decoder = valence_protocol::PacketDecoder::new();
+        valence_decoder.set_compression(threshold);
+        valence_decoder.queue_slice(&bytes);
+
+        let mut decoder = PacketDecoder::new();
+        decoder.set_compression(threshold);
+
+        let frames: Vec<Vec<u8>> = decoder
+            .decode_frames(&bytes)
+            .map(|frame| frame.unwrap().into_owned())
+            .collect();
+
+        assert_eq!(frames.len(), 2);
+
+        let mut scratch = Scratch::new();
+
+        for frame in &frames {
+            let data = decoder.decompress_frame(frame, &mut scratch).unwrap();
+
+            let mut r = data;
+            let id = VarInt::decode(&mut r).unwrap().0;
+
+            let expected = valence_decoder.try_next_packet().unwrap().unwrap();
+
+            assert_eq!(id, expected.id);
+            assert_eq!(r, &expected.body[..]);
+        }
+    }
+
+    #[test]
+    fn test_decode_encrypted_frames() {
+        let secret = [42; 16];
+
+        let mut bytes = encode_two_packets();
+
+        let expected: Vec<Vec<u8>> = PacketDecoder::new()
+            .decode_frames(&bytes)
+            .map(|frame| frame.unwrap().into_owned())
+            .collect();
+
+        crate::net::PacketEncryptor::new(&secret).encrypt(&mut bytes);
+
+        let mut decoder = PacketDecoder::new();
+        decoder.enable_encryption(&secret);
+
+        let (first, second) = bytes.split_at(bytes.len() / 2);
+
+        let mut frames: Vec<Vec<u8>> = decoder
+            .decode_frames(first)
+            .map(|frame| frame.unwrap().into_owned())
+            .collect();
+
+        frames.extend(
+            decoder
+                .decode_frames(second)
+                .map(|frame| frame.unwrap().into_owned()),
+        );
+
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn test_overlong_var_ints() {
+        let mut scratch = Scratch::new();
+
+        // a length of 2 encoded in 2 bytes, followed by a packet with a length of 1
+        let mut decoder = PacketDecoder::new();
+        decoder.queue_slice(&[0x82, 0x00, 0x00, 0x07, 0x01, 0x05]);
+
+        let packet = decoder.try_next_packet(&mut scratch).unwrap().unwrap();
+        assert_eq!(packet.id, 0);
+        assert_eq!(&packet.body[..], [0x07]);
+
+        let packet = decoder.try_next_packet(&mut scratch).unwrap().unwrap();
+        assert_eq!(packet.id, 5);
+        assert!(packet.body.is_empty());
+        assert!(decoder.is_empty());
+
+        // an uncompressed data length of 0 encoded in 2 bytes
+        let mut decoder = PacketDecoder::new();
+        decoder.set_compression(CompressionThreshold(256));
+        decoder.queue_slice(&[0x03, 0x80, 0x00, 0x05]);
+
+        let packet = decoder.try_next_packet(&mut scratch).unwrap().unwrap();
+        assert_eq!(packet.id, 5);
+        assert!(packet.body.is_empty());
+        assert!(decoder.is_empty());
+    }
+
     // #[test]
     // fn test_compressed() {
     //     // ... similar tests as in the encoder, but using compare_decoder ...
@@ -0,0 +1,149 @@
+//! Why the server disconnects a client, and what the client is told.
+
+use valence_protocol::text::{IntoText, Text};
+
+use crate::net::ProtocolVersion;
+
+/// Why the server disconnects a client. Pass it to [`crate::net::Compose::disconnect_with_reason`],
+/// which sends [`DisconnectReason::message`] in the packet of the connection's state, and counts
+/// the disconnect towards [`crate::net::NetMetrics::by_disconnect_reason`] under
+/// [`DisconnectReason::kind`].
+///
+/// | Reason | Kind | Message |
+/// |---|---|---|
+/// | `ServerFull` | `server_full` | The server is full. |
+/// | `TimedOut` | `timed_out` | Timed out |
+/// | `ProtocolError` | `protocol_error` | Invalid packet received. |
+/// | `Kicked(reason)` | `kicked` | `reason` |
+/// | `Banned` | `banned` | You are banned from this server. |
+/// | `AuthFailed` | `auth_failed` | Unable to verify player details. |
+/// | `NotForwarded` | `not_forwarded` | This server requires you to connect with Velocity. |
+/// | `UnsupportedVersion` | `unsupported_version` | Unsupported client version `client`. Please join with `supported`. |
+/// | `Shutdown(reason)` | `shutdown` | `reason` |
+///
+/// Connections which are still in the handshake or status state cannot be shown a message, so
+/// they are only closed, but the disconnect is counted all the same.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectReason {
+    /// More players tried to log in than [`crate::global::Global::max_connections`].
+    ServerFull,
+    /// Nothing was received for [`crate::global::Global::keep_alive_timeout`].
+    TimedOut,
+    /// The client sent something which could not be decoded or was not expected.
+    ProtocolError,
+    /// A plugin or operator kicked the player with a reason of their own.
+    Kicked(Text),
+    /// The player is banned.
+    Banned,
+    /// The identity of the player could not be verified, like a Velocity forwarding with a wrong
+    /// signature.
+    AuthFailed,
+    /// Velocity forwarding is enabled, but the client connected directly.
+    NotForwarded,
+    /// The client speaks a protocol version which is not in [`ProtocolVersion::SUPPORTED`].
+    UnsupportedVersion {
+        /// The name of the version of the client, or its protocol number if it is unknown.
+        client: String,
+    },
+    /// The server is shutting down or restarting. See [`crate::Hyperion::announce_and_drain`].
+    Shutdown(Text),
+}
+
+impl DisconnectReason {
+    /// Every [`DisconnectReason::kind`], in the order of the variants.
+    pub const KINDS: [&'static str; 9] = [
+        "server_full",
+        "timed_out",
+        "protocol_error",
+        "kicked",
+        "banned",
+        "auth_failed",
+        "not_forwarded",
+        "unsupported_version",
+        "shutdown",
+    ];
+
+    /// The name of the variant, which disconnects are counted under in logs and metrics.
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        Self::KINDS[self.kind_index()]
+    }
+
+    /// The index of [`DisconnectReason::kind`] in [`DisconnectReason::KINDS`].
+    pub(crate) const fn kind_index(&self) -> usize {
+        match self {
+            Self::ServerFull => 0,
+            Self::TimedOut => 1,
+            Self::ProtocolError => 2,
+            Self::Kicked(_) => 3,
+            Self::Banned => 4,
+            Self::AuthFailed => 5,
+            Self::NotForwarded => 6,
+            Self::UnsupportedVersion { .. } => 7,
+            Self::Shutdown(_) => 8,
+        }
+    }
+
+    /// What the client is shown on the disconnect screen.
+    #[must_use]
+    pub fn message(&self) -> Text {
+        match self {
+            Self::ServerFull => "The server is full.".into_text(),
+            Self::TimedOut => "Timed out".into_text(),
+            Self::ProtocolError => "Invalid packet received.".into_text(),
+            Self::Kicked(reason) | Self::Shutdown(reason) => reason.clone(),
+            Self::Banned => "You are banned from this server.".into_text(),
+            Self::AuthFailed => "Unable to verify player details.".into_text(),
+            Self::NotForwarded => "This server requires you to connect with Velocity.".into_text(),
+            Self::UnsupportedVersion { client } => {
+                let supported = ProtocolVersion::SUPPORTED
+                    .iter()
+                    .map(|version| version.name())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                format!("Unsupported client version {client}. Please join with {supported}.")
+                    .into_text()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kinds() {
+        let reasons = [
+            DisconnectReason::ServerFull,
+            DisconnectReason::TimedOut,
+            DisconnectReason::ProtocolError,
+            DisconnectReason::Kicked("Cheating".into_text()),
+            DisconnectReason::Banned,
+            DisconnectReason::AuthFailed,
+            DisconnectReason::NotForwarded,
+            DisconnectReason::UnsupportedVersion {
+                client: "1.8.9".to_owned(),
+            },
+            DisconnectReason::Shutdown("Restarting".into_text()),
+        ];
+
+        let kinds: Vec<_> = reasons.iter().map(DisconnectReason::kind).collect();
+        assert_eq!(kinds, DisconnectReason::KINDS);
+    }
+
+    #[test]
+    fn test_messages() {
+        let kicked = DisconnectReason::Kicked("Cheating".into_text());
+        assert_eq!(kicked.message(), "Cheating".into_text());
+
+        let message = DisconnectReason::UnsupportedVersion {
+            client: "1.8.9".to_owned(),
+        }
+        .message();
+
+        let current = ProtocolVersion::CURRENT.name();
+        assert!(message.to_legacy_lossy().contains(current));
+    }
+}
@@ -0,0 +1,217 @@
+//! Warning every player and then disconnecting them before the server shuts down, for rolling
+//! restarts.
+
+use std::time::{Duration, Instant};
+
+use valence_protocol::text::Text;
+
+use crate::net::Packets;
+
+/// How long the final packets may take to be sent after everyone was disconnected before the
+/// server shuts down anyway, so a client which stopped reading cannot keep it running.
+pub const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the caller of [`Drain::poll`] has to do in this tick.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DrainStep {
+    /// Send [`Drain::reason`] to every player as a warning. This is only returned by the first
+    /// poll.
+    Announce,
+    /// Nothing, the grace period has not passed yet.
+    Wait,
+    /// Disconnect every connection with [`Drain::reason`]. This is only returned once.
+    Disconnect,
+    /// Nothing, the disconnect packets are still being sent.
+    Flush,
+    /// Shut the server down. Every connection which is left has nothing queued or in flight.
+    Shutdown,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Phase {
+    Announce,
+    Grace,
+    Flush { since: Instant },
+    Done,
+}
+
+/// A drain of every connection: players are warned, given `grace` to finish what they are doing,
+/// and then disconnected, and the server shuts down once their disconnect packets were sent.
+///
+/// The drain is driven by calling [`Drain::poll`] every tick and doing the [`DrainStep`] it
+/// returns. Disconnected connections are closed by the egress system once everything queued for
+/// them was passed to the server, but their writes may still be in flight then, so the drain
+/// waits until [`Packets::number_sending`] is 0 for every connection. See
+/// [`crate::Hyperion::announce_and_drain`].
+#[derive(Debug, Clone)]
+pub struct Drain {
+    reason: Text,
+    deadline: Instant,
+    phase: Phase,
+}
+
+impl Drain {
+    /// A drain which disconnects everyone `grace` after `now`.
+    #[must_use]
+    pub fn new(reason: Text, grace: Duration, now: Instant) -> Self {
+        Self {
+            reason,
+            deadline: now + grace,
+            phase: Phase::Announce,
+        }
+    }
+
+    /// What players are warned and disconnected with.
+    #[must_use]
+    pub const fn reason(&self) -> &Text {
+        &self.reason
+    }
+
+    /// When players are disconnected.
+    #[must_use]
+    pub const fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Advances the drain to `now`. `players` are the [`Packets`] of every connection which has
+    /// not been removed yet.
+    pub fn poll<'a>(
+        &mut self,
+        now: Instant,
+        players: impl IntoIterator<Item = &'a Packets>,
+    ) -> DrainStep {
+        match self.phase {
+            Phase::Announce => {
+                self.phase = Phase::Grace;
+                DrainStep::Announce
+            }
+            Phase::Grace if now < self.deadline => DrainStep::Wait,
+            Phase::Grace => {
+                self.phase = Phase::Flush { since: now };
+                DrainStep::Disconnect
+            }
+            Phase::Flush { since } => {
+                let flushed = players
+                    .into_iter()
+                    .all(|packets| packets.total_len() == 0 && packets.number_sending() == 0);
+
+                if !flushed && now < since + FLUSH_TIMEOUT {
+                    return DrainStep::Flush;
+                }
+
+                self.phase = Phase::Done;
+                DrainStep::Shutdown
+            }
+            Phase::Done => DrainStep::Shutdown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use valence_protocol::{text::IntoText, CompressionThreshold};
+
+    use super::*;
+    use crate::net::{Fd, IoBuf, MockServer, ServerDef, ServerEvent, MIN_S2C_BUFFER_SIZE};
+
+    /// Does what the egress and ingress systems do in a tick: sends everything queued, closes
+    /// the connections which were disconnected, and releases the completed writes.
+    fn tick(server: &mut MockServer, players: &mut [(Fd, Packets)]) {
+        for (fd, packets) in &mut *players {
+            if packets.can_send() {
+                server.send(*fd, packets);
+            }
+            if packets.take_close() {
+                server.close_after_send(*fd);
+            }
+        }
+
+        server.submit_events();
+        server
+            .drain(|event| {
+                if let ServerEvent::SentData { fd } = event {
+                    let (_, packets) = players.iter().find(|(id, _)| *id == fd).unwrap();
+                    packets.set_successfully_sent(1);
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_announce_and_drain() {
+        let mut server = MockServer::default();
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+
+        let mut players: Vec<_> = (0..2)
+            .map(|_| (server.connect(), Packets::default()))
+            .collect();
+        server.drain(|_| {}).unwrap();
+
+        let start = Instant::now();
+        let mut drain = Drain::new("Restarting".into_text(), Duration::from_secs(10), start);
+
+        let poll = |drain: &mut Drain, players: &[(Fd, Packets)], secs| {
+            let now = start + Duration::from_secs(secs);
+            drain.poll(now, players.iter().map(|(_, packets)| packets))
+        };
+
+        assert_eq!(poll(&mut drain, &players, 0), DrainStep::Announce);
+        for (_, packets) in &players {
+            packets.append_raw(b"warning", &mut buf).unwrap();
+        }
+        tick(&mut server, &mut players);
+
+        assert_eq!(poll(&mut drain, &players, 5), DrainStep::Wait);
+        assert_eq!(poll(&mut drain, &players, 10), DrainStep::Disconnect);
+        for (_, packets) in &mut players {
+            packets.append_raw(b"bye", &mut buf).unwrap();
+            packets.close_after_send();
+        }
+
+        // the disconnect packets have not been sent yet
+        assert_eq!(poll(&mut drain, &players, 10), DrainStep::Flush);
+
+        // they are in flight, so the connections are closed but not flushed yet
+        for (fd, packets) in &mut players {
+            server.send(*fd, packets);
+            assert!(packets.take_close());
+            server.close_after_send(*fd);
+        }
+        assert_eq!(poll(&mut drain, &players, 10), DrainStep::Flush);
+
+        tick(&mut server, &mut players);
+        assert_eq!(poll(&mut drain, &players, 10), DrainStep::Shutdown);
+
+        for (fd, _) in &players {
+            assert!(server.is_closed(*fd));
+            assert_eq!(server.written(*fd), Some(&b"warningbye"[..]));
+        }
+        assert_eq!(server.connection_count(), 0);
+    }
+
+    #[test]
+    fn test_flush_timeout() {
+        let mut server = MockServer::default();
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+
+        let fd = server.connect();
+        let mut packets = Packets::default();
+
+        let start = Instant::now();
+        let mut drain = Drain::new("Restarting".into_text(), Duration::ZERO, start);
+
+        assert_eq!(drain.poll(start, [&packets]), DrainStep::Announce);
+        assert_eq!(drain.poll(start, [&packets]), DrainStep::Disconnect);
+
+        // the write never completes
+        packets.append_raw(b"bye", &mut buf).unwrap();
+        server.send(fd, &mut packets);
+
+        let later = start + FLUSH_TIMEOUT / 2;
+        assert_eq!(drain.poll(later, [&packets]), DrainStep::Flush);
+
+        let later = start + FLUSH_TIMEOUT;
+        assert_eq!(drain.poll(later, [&packets]), DrainStep::Shutdown);
+        assert_eq!(drain.poll(later, [&packets]), DrainStep::Shutdown);
+    }
+}
@@ -1,25 +1,240 @@
 use std::{
-    fmt::Debug,
+    cell::Cell,
+    fmt::{self, Debug},
     io::{Cursor, Write},
     mem::MaybeUninit,
 };
 
-use anyhow::ensure;
 use tracing::trace;
 use valence_protocol::{CompressionThreshold, Encode, Packet, VarInt};
 
-use crate::{event::ScratchBuffer, net::MAX_PACKET_SIZE, singleton::ring::Buf};
+use crate::{
+    event::{Scratch, ScratchBuffer},
+    net::{PacketCompressor, MAX_ENCODED_PACKET_SIZE, MAX_PACKET_LEN_SIZE, MAX_PACKET_SIZE},
+    singleton::ring::{Buf, VecBuf},
+};
 
 mod util;
 
+/// Why a packet could not be appended.
+#[derive(Debug)]
+pub enum AppendError {
+    /// Appending would overwrite bytes in the ring which have not been sent yet. This is
+    /// recoverable; the caller can apply backpressure and try again after the ring drains.
+    RingFull,
+    /// The packet is longer than [`MAX_PACKET_SIZE`] once encoded, so its length does not fit
+    /// into the length prefix vanilla clients read. Nothing is appended, so the caller can drop
+    /// the packet or send its contents in smaller packets instead. See
+    /// [`crate::net::Compose::cache_chunk`] for how to split a chunk.
+    TooLarge {
+        /// How many bytes the packet takes up after its length prefix without compression.
+        size: usize,
+    },
+    /// The packet itself failed to encode.
+    Encode(anyhow::Error),
+}
+
+impl fmt::Display for AppendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RingFull => write!(f, "ring buffer would overwrite unsent data"),
+            Self::TooLarge { size } => write!(
+                f,
+                "packet is {size} bytes, which exceeds the maximum length of {MAX_PACKET_SIZE} \
+                 bytes a {MAX_PACKET_LEN_SIZE} byte length prefix can hold"
+            ),
+            Self::Encode(err) => write!(f, "failed to encode packet: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for AppendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Encode(err) => Some(&**err),
+            Self::RingFull | Self::TooLarge { .. } => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for AppendError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::Encode(err)
+    }
+}
+
+impl From<std::io::Error> for AppendError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Encode(err.into())
+    }
+}
+
+/// Encoding into a cursor over a [`MAX_ENCODED_PACKET_SIZE`] slice only fails because of the size
+/// if the cursor is full. The size in the error is `overhead` bytes of framing before the packet
+/// ID plus the size of the packet, which is measured again since the cursor stopped short of it.
+fn encode_error<P>(
+    pkt: &P,
+    err: anyhow::Error,
+    cursor: &Cursor<&mut [u8]>,
+    overhead: usize,
+) -> AppendError
+where
+    P: Packet + Encode,
+{
+    if cursor.position() as usize >= cursor.get_ref().len() {
+        AppendError::TooLarge {
+            size: overhead + unframed_len(pkt),
+        }
+    } else {
+        AppendError::Encode(err)
+    }
+}
+
+/// The number of bytes `pkt` encodes to with its ID, without writing them anywhere.
+fn unframed_len<P>(pkt: &P) -> usize
+where
+    P: Packet + Encode,
+{
+    struct Counter(usize);
+
+    impl Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+
+    // this is only used after encoding failed for lack of room, so an error here is the same one
+    let _ = pkt.encode_with_id(&mut counter);
+
+    counter.0
+}
+
+/// The length prefix of a packet which is `len` bytes long after the prefix.
+///
+/// Every length prefix is created here, so no packet is encoded with a length vanilla clients
+/// cannot read.
+fn packet_len(len: usize) -> Result<VarInt, AppendError> {
+    if len > MAX_PACKET_SIZE {
+        return Err(AppendError::TooLarge { size: len });
+    }
+
+    Ok(VarInt(len as i32))
+}
+
+/// How many bytes [`CompressionPolicy`] looks at to estimate how well a packet compresses.
+const ENTROPY_SAMPLE_LEN: usize = 1024;
+
+/// Decides whether a packet above the compression threshold is sent compressed.
+///
+/// Some packets, like chunks which are already densely packed, barely get smaller when
+/// compressed. Such packets can be sent uncompressed with a data length of 0, which the protocol
+/// allows for packets of any size.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CompressionPolicy {
+    /// The fraction of bytes compression has to save, or [`None`] to always compress.
+    min_savings: Option<f32>,
+}
+
+impl CompressionPolicy {
+    /// Compresses every packet above the threshold, even if it gets larger. This is the default.
+    pub const ALWAYS: Self = Self { min_savings: None };
+
+    /// Only sends a packet compressed if that makes it at least `ratio` smaller, e.g. `0.1` for
+    /// 10%. `ratio` is clamped to `0.0..=1.0`.
+    ///
+    /// Before compressing, the byte entropy of a sample of the packet is used to estimate how
+    /// much compression would save. Packets which are estimated to save less than `ratio` are not
+    /// compressed at all. Packets which are compressed but still save less than `ratio` are sent
+    /// uncompressed as well.
+    #[must_use]
+    pub fn min_savings(ratio: f32) -> Self {
+        Self {
+            min_savings: Some(ratio.clamp(0.0, 1.0)),
+        }
+    }
+
+    /// Whether `data` looks compressible enough to be worth compressing.
+    fn should_compress(self, data: &[u8]) -> bool {
+        let Some(min_savings) = self.min_savings else {
+            return true;
+        };
+
+        1.0 - estimate_entropy(data) / 8.0 >= min_savings
+    }
+
+    /// Whether compressing `len` bytes down to `compressed_len` bytes saved enough.
+    fn keep_compressed(self, len: usize, compressed_len: usize) -> bool {
+        let Some(min_savings) = self.min_savings else {
+            return true;
+        };
+
+        compressed_len < len && 1.0 - compressed_len as f32 / len as f32 >= min_savings
+    }
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self::ALWAYS
+    }
+}
+
+/// Estimates the Shannon entropy of `data` in bits per byte from up to [`ENTROPY_SAMPLE_LEN`]
+/// bytes spread evenly over it.
+fn estimate_entropy(data: &[u8]) -> f32 {
+    if data.is_empty() {
+        return 0.0;
+    }
+
+    let step = data.len().div_ceil(ENTROPY_SAMPLE_LEN);
+
+    let mut counts = [0_u32; 256];
+    let mut total = 0_u32;
+
+    for &byte in data.iter().step_by(step) {
+        counts[byte as usize] += 1;
+        total += 1;
+    }
+
+    let total = total as f32;
+
+    let sum: f32 = counts
+        .iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| {
+            let p = count as f32 / total;
+            p * p.log2()
+        })
+        .sum();
+
+    -sum
+}
+
 pub struct PacketEncoder {
     threshold: CompressionThreshold,
+    policy: CompressionPolicy,
+    /// Whether every packet is framed without compression, regardless of `threshold`
+    compression_disabled: bool,
+    /// The bytes of packet data which were compressed since the last
+    /// [`PacketEncoder::take_compression_totals`]
+    compressed_before: Cell<u64>,
+    /// What `compressed_before` was sent as, which is the data itself where compressing it was
+    /// not kept
+    compressed_after: Cell<u64>,
 }
 
 impl Debug for PacketEncoder {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("PacketEncoder")
             .field("threshold", &self.threshold)
+            .field("policy", &self.policy)
+            .field("compression_disabled", &self.compression_disabled)
             .finish()
     }
 }
@@ -35,6 +250,30 @@ impl Debug for PacketEncoder {
 pub struct PacketWriteInfo {
     pub start_ptr: *const u8,
     pub len: u32,
+    /// The [`crate::singleton::ring::Ring::generation`] the bytes were written in. Writes from
+    /// different generations are never contiguous, even if their pointers line up.
+    pub generation: u32,
+    /// See [`Priority`].
+    pub priority: Priority,
+    /// The CRC32 of the bytes when they were queued. See [`PacketWriteInfo::verify_checksum`].
+    #[cfg(feature = "debug_checksums")]
+    pub checksum: u32,
+}
+
+/// How important the bytes of a [`PacketWriteInfo`] are to the client. Once the
+/// [`crate::net::BandwidthLimiter`] budget of a tick is used up, cosmetic writes wait for the next
+/// tick while essential writes are still sent.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Packets which are sensitive to latency, like keep alives and chat. These are sent before
+    /// every queued packet of a lower priority, so they do not wait behind a large chunk batch.
+    High,
+    /// Everything which changes what the client knows about the world, like block updates. This
+    /// is the default.
+    #[default]
+    Essential,
+    /// Packets the client can receive late, like particles and sounds.
+    Cosmetic,
 }
 
 impl PacketWriteInfo {
@@ -47,30 +286,90 @@ impl PacketWriteInfo {
     }
 }
 
-pub fn append_packet_without_compression<P, B: Buf>(
-    pkt: &P,
-    buf: &mut B,
-) -> anyhow::Result<B::Output>
+#[cfg(feature = "debug_checksums")]
+impl PacketWriteInfo {
+    /// The CRC32 of the `len` bytes at `start_ptr`.
+    ///
+    /// # Safety
+    /// The bytes must be valid for reads.
+    #[must_use]
+    pub unsafe fn checksum_of(start_ptr: *const u8, len: u32) -> u32 {
+        crc32fast::hash(std::slice::from_raw_parts(start_ptr, len as usize))
+    }
+
+    /// The checksum of the bytes of `self` followed by the bytes of `next`, without reading
+    /// either again. This is what a merged write is checksummed with.
+    #[must_use]
+    pub fn combined_checksum(&self, next: &Self) -> u32 {
+        let (checksum, len) = (self.checksum, self.len);
+        let mut hasher = crc32fast::Hasher::new_with_initial_len(checksum, u64::from(len));
+
+        let (checksum, len) = (next.checksum, next.len);
+        hasher.combine(&crc32fast::Hasher::new_with_initial_len(
+            checksum,
+            u64::from(len),
+        ));
+
+        hasher.finalize()
+    }
+
+    /// Checksums the bytes again and compares them to the checksum they were queued with. If they
+    /// differ, something like a ring wrapping too early overwrote them before they were sent.
+    ///
+    /// # Safety
+    /// The bytes must be valid for reads.
+    ///
+    /// # Errors
+    /// If the checksums differ.
+    pub unsafe fn verify_checksum(&self) -> anyhow::Result<()> {
+        let Self {
+            start_ptr,
+            len,
+            generation,
+            checksum,
+            ..
+        } = *self;
+
+        let actual = Self::checksum_of(start_ptr, len);
+
+        if actual != checksum {
+            anyhow::bail!(
+                "the {len} bytes at {start_ptr:?} (generation {generation}) were queued with \
+                 checksum {checksum:#010x} but are now {actual:#010x}"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Encodes `pkt` into `sink` without compression, prefixed with its length. This is the framing
+/// of connections which have not enabled compression.
+///
+/// A packet longer than [`MAX_PACKET_SIZE`] fails with [`AppendError::TooLarge`] before anything
+/// is committed to `sink`.
+///
+/// `sink` can be the S2C ring of an [`crate::net::IoBuf`] to queue the packet for sending, or a
+/// [`VecBuf`] to build a contiguous buffer of packets without a ring, like a login sequence or a
+/// replay file.
+pub fn encode_uncompressed_into<P, B: Buf>(pkt: &P, sink: &mut B) -> Result<B::Output, AppendError>
 where
     P: valence_protocol::Packet + Encode,
 {
-    let data_write_start = VarInt::MAX_SIZE as u64;
-    let slice = buf.get_contiguous(MAX_PACKET_SIZE);
+    let data_write_start = MAX_PACKET_LEN_SIZE as u64;
+    let slice = sink.get_contiguous(MAX_ENCODED_PACKET_SIZE)?;
 
     let mut cursor = Cursor::new(slice);
     cursor.set_position(data_write_start);
 
-    pkt.encode_with_id(&mut cursor)?;
+    if let Err(err) = pkt.encode_with_id(&mut cursor) {
+        return Err(encode_error(pkt, err, &cursor, 0));
+    }
 
     let data_len = cursor.position() as usize - data_write_start as usize;
 
-    let packet_len_size = VarInt(data_len as i32).written_size();
-
-    let packet_len = packet_len_size + data_len;
-    ensure!(
-        packet_len <= MAX_PACKET_SIZE,
-        "packet exceeds maximum length"
-    );
+    let packet_len = packet_len(data_len)?;
+    let packet_len_size = packet_len.written_size();
 
     let inner = cursor.into_inner();
 
@@ -80,7 +379,7 @@ where
     );
 
     let mut cursor = Cursor::new(inner);
-    VarInt(data_len as i32).encode(&mut cursor)?;
+    packet_len.encode(&mut cursor)?;
 
     let slice = cursor.into_inner();
     let entire_slice = &slice[..packet_len_size + data_len];
@@ -89,13 +388,81 @@ where
 
     trace!("without compression: {len} bytes");
 
-    Ok(buf.advance(len))
+    Ok(sink.advance(len))
+}
+
+/// Encodes `pkt` into the exact bytes a connection with `threshold` is sent, including the
+/// compression framing, without an [`crate::net::IoBuf`] or ring. The encoder has the default
+/// [`CompressionPolicy`].
+///
+/// This is for tests which compare the wire format of packets against known bytes. It
+/// allocates a new [`Vec<u8>`] and scratch buffer for every packet, so it is too slow for sending.
+pub fn encode_to_vec<P>(
+    pkt: &P,
+    threshold: CompressionThreshold,
+    compressor: &mut (impl PacketCompressor + ?Sized),
+) -> Result<Vec<u8>, AppendError>
+where
+    P: Packet + Encode,
+{
+    let enc = PacketEncoder::new(threshold);
+    let mut scratch = Scratch::new();
+    let mut bytes = VecBuf::new();
+
+    enc.append_packet(pkt, &mut bytes, &mut scratch, compressor)?;
+
+    Ok(bytes.into_inner())
 }
 
 impl PacketEncoder {
     #[must_use]
     pub const fn new(threshold: CompressionThreshold) -> Self {
-        Self { threshold }
+        Self {
+            threshold,
+            policy: CompressionPolicy::ALWAYS,
+            compression_disabled: false,
+            compressed_before: Cell::new(0),
+            compressed_after: Cell::new(0),
+        }
+    }
+
+    /// The bytes of packet data which were compressed since the last call, and the bytes they
+    /// were sent as, and resets both. Data which compression did not make small enough to keep
+    /// counts as sent uncompressed. Packets under the threshold are not counted.
+    pub(crate) fn take_compression_totals(&self) -> (u64, u64) {
+        (self.compressed_before.take(), self.compressed_after.take())
+    }
+
+    fn record_compression(&self, before: u64, after: u64) {
+        self.compressed_before
+            .set(self.compressed_before.get() + before);
+        self.compressed_after
+            .set(self.compressed_after.get() + after);
+    }
+
+    /// Whether packets skip compression entirely. See [`PacketEncoder::set_compression_disabled`].
+    #[must_use]
+    pub const fn compression_disabled(&self) -> bool {
+        self.compression_disabled
+    }
+
+    /// Frames every packet like [`encode_uncompressed_into`] does, no matter how large it is or
+    /// what the threshold is, so the compressor is never called. This is for benchmarks and LAN
+    /// servers, where bandwidth is free and compression is pure overhead.
+    ///
+    /// Clients only read this framing if they were sent a `LoginCompressionS2c` with a threshold
+    /// of -1 during login, or none at all.
+    pub fn set_compression_disabled(&mut self, disabled: bool) {
+        self.compression_disabled = disabled;
+    }
+
+    #[must_use]
+    pub const fn compression_policy(&self) -> CompressionPolicy {
+        self.policy
+    }
+
+    pub fn set_compression_policy(&mut self, policy: CompressionPolicy) {
+        self.policy = policy;
     }
 
     #[must_use]
@@ -108,21 +475,23 @@ impl PacketEncoder {
         pkt: &P,
         buf: &mut B,
         scratch: &mut impl ScratchBuffer,
-        compressor: &mut libdeflater::Compressor,
-    ) -> anyhow::Result<B::Output>
+        compressor: &mut (impl PacketCompressor + ?Sized),
+    ) -> Result<B::Output, AppendError>
     where
         P: valence_protocol::Packet + Encode,
     {
         const DATA_LEN_0_SIZE: usize = 1;
 
         // + 1 because data len would be 0 if not compressed
-        let data_write_start = (VarInt::MAX_SIZE + DATA_LEN_0_SIZE) as u64;
-        let slice = buf.get_contiguous(MAX_PACKET_SIZE);
+        let data_write_start = (MAX_PACKET_LEN_SIZE + DATA_LEN_0_SIZE) as u64;
+        let slice = buf.get_contiguous(MAX_ENCODED_PACKET_SIZE)?;
 
         let mut cursor = Cursor::new(&mut slice[..]);
         cursor.set_position(data_write_start);
 
-        pkt.encode_with_id(&mut cursor)?;
+        if let Err(err) = pkt.encode_with_id(&mut cursor) {
+            return Err(encode_error(pkt, err, &cursor, DATA_LEN_0_SIZE));
+        }
 
         let end_data_position_exclusive = cursor.position();
 
@@ -130,45 +499,64 @@ impl PacketEncoder {
 
         let threshold = u64::from(self.threshold.0.unsigned_abs());
 
-        if data_len > threshold {
+        let data = &slice[data_write_start as usize..end_data_position_exclusive as usize];
+
+        if data_len > threshold && self.policy.should_compress(data) {
             let scratch = scratch.obtain();
 
             debug_assert!(scratch.is_empty());
 
-            let data_slice =
-                &mut slice[data_write_start as usize..end_data_position_exclusive as usize];
-
-            {
-                // todo: I think this kinda safe maybe??? ... lol. well I know at least scratch is always large enough
-                let written = {
-                    let scratch = scratch.spare_capacity_mut();
-                    let scratch = unsafe { MaybeUninit::slice_assume_init_mut(scratch) };
+            // todo: I think this kinda safe maybe??? ... lol. well I know at least scratch is always large enough
+            let written = {
+                let scratch = scratch.spare_capacity_mut();
+                let scratch = unsafe { MaybeUninit::slice_assume_init_mut(scratch) };
 
-                    compressor.zlib_compress(data_slice, scratch)?
-                };
+                // scratch has room for MAX_PACKET_SIZE bytes, so this only fails if the
+                // compressed packet would be larger than that
+                compressor.compress(data, scratch)
+            };
 
+            // a packet which is too long compressed is sent uncompressed, which always fits
+            if let Some(written) = written {
                 unsafe {
                     scratch.set_len(scratch.len() + written);
                 }
-            }
 
-            let data_len = VarInt(data_len as u32 as i32);
+                let keep_compressed = self
+                    .policy
+                    .keep_compressed(data_len as usize, scratch.len());
+
+                let data_len_varint = VarInt(data_len as u32 as i32);
+
+                let compressed_packet_len =
+                    packet_len(data_len_varint.written_size() + scratch.len()).ok();
 
-            let packet_len = data_len.written_size() + scratch.len();
-            let packet_len = VarInt(packet_len as u32 as i32);
+                if let Some(packet_len) = compressed_packet_len.filter(|_| keep_compressed) {
+                    let mut write = Cursor::new(&mut slice[..]);
+                    packet_len.encode(&mut write)?;
+                    data_len_varint.encode(&mut write)?;
+                    write.write_all(scratch)?;
 
-            let mut write = Cursor::new(&mut slice[..]);
-            packet_len.encode(&mut write)?;
-            data_len.encode(&mut write)?;
-            write.write_all(scratch)?;
+                    let len = write.position();
 
-            let len = write.position();
+                    self.record_compression(data_len, scratch.len() as u64);
 
-            return Ok(buf.advance(len as usize));
+                    return Ok(buf.advance(len as usize));
+                }
+
+                trace!(
+                    "compressing {data_len} bytes only saved {} bytes, sending uncompressed",
+                    data_len as usize - scratch.len().min(data_len as usize)
+                );
+            } else {
+                trace!("{data_len} bytes do not fit once compressed, sending uncompressed");
+            }
+
+            self.record_compression(data_len, data_len);
         }
 
         let data_len_0 = VarInt(0);
-        let packet_len = VarInt(DATA_LEN_0_SIZE as i32 + data_len as u32 as i32); // packet_len.written_size();
+        let packet_len = packet_len(DATA_LEN_0_SIZE + data_len as usize)?;
 
         let mut cursor = Cursor::new(&mut slice[..]);
         packet_len.encode(&mut cursor)?;
@@ -191,22 +579,215 @@ impl PacketEncoder {
         pkt: &P,
         buf: &mut B,
         scratch: &mut impl ScratchBuffer,
-        compressor: &mut libdeflater::Compressor,
-    ) -> anyhow::Result<B::Output>
+        compressor: &mut (impl PacketCompressor + ?Sized),
+    ) -> Result<B::Output, AppendError>
     where
         P: Packet + Encode,
     {
-        let has_compression = self.threshold.0 >= 0;
+        let has_compression = !self.compression_disabled && self.threshold.0 >= 0;
 
         if has_compression {
             self.append_packet_with_compression(pkt, buf, scratch, compressor)
         } else {
-            append_packet_without_compression(pkt, buf)
+            encode_uncompressed_into(pkt, buf)
         }
     }
 
-    pub fn set_compression(&mut self, threshold: CompressionThreshold) {
-        self.threshold = threshold;
+    /// Sets the compression threshold, returning the previous one. To change it only for a few
+    /// packets, use [`crate::net::IoBuf::with_compression`], which restores it.
+    pub fn set_compression(&mut self, threshold: CompressionThreshold) -> CompressionThreshold {
+        std::mem::replace(&mut self.threshold, threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libdeflater::CompressionLvl;
+    use valence_protocol::{packets::play::KeepAliveS2c, PacketSide, PacketState};
+
+    use super::*;
+    use crate::{
+        net::{PacketDecoder, MIN_S2C_BUFFER_SIZE},
+        singleton::ring::Ring,
+    };
+
+    #[test]
+    fn test_encode_uncompressed_into_vec() {
+        let first = KeepAliveS2c { id: 1 };
+        let second = KeepAliveS2c { id: 2 };
+
+        let mut bytes = VecBuf::new();
+        let first_range = encode_uncompressed_into(&first, &mut bytes).unwrap();
+        let second_range = encode_uncompressed_into(&second, &mut bytes).unwrap();
+
+        // the packets are contiguous, and nothing but the packets is kept
+        assert_eq!(first_range.start, 0);
+        assert_eq!(first_range.end, second_range.start);
+        assert_eq!(second_range.end, bytes.len());
+
+        // a vec is framed the same as a ring
+        let mut ring = Ring::new(MIN_S2C_BUFFER_SIZE);
+        for (pkt, range) in [(&first, first_range), (&second, second_range)] {
+            let info = encode_uncompressed_into(pkt, &mut ring).unwrap();
+            assert_eq!(unsafe { info.as_slice() }, &bytes[range]);
+        }
+    }
+
+    /// A packet of `len` zeroes after its ID.
+    struct ZeroesPkt(usize);
+
+    impl Packet for ZeroesPkt {
+        const ID: i32 = 0;
+        const NAME: &'static str = "ZeroesPkt";
+        const SIDE: PacketSide = PacketSide::Clientbound;
+        const STATE: PacketState = PacketState::Play;
+    }
+
+    impl Encode for ZeroesPkt {
+        fn encode(&self, mut w: impl Write) -> anyhow::Result<()> {
+            w.write_all(&vec![0; self.0])?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_too_large() {
+        let pkt = ZeroesPkt(MAX_PACKET_SIZE * 2);
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        // the packet would compress to almost nothing, but it is rejected before compression
+        for (threshold, overhead) in [(-1, 0), (256, 1)] {
+            let enc = PacketEncoder::new(CompressionThreshold(threshold));
+            let mut ring = Ring::new(MAX_ENCODED_PACKET_SIZE * 2);
+
+            let result = enc.append_packet(&pkt, &mut ring, &mut scratch, &mut compressor);
+            let Err(AppendError::TooLarge { size }) = result else {
+                panic!("expected TooLarge, got {result:?}");
+            };
+            assert_eq!(size, overhead + 1 + MAX_PACKET_SIZE * 2);
+
+            // nothing was committed, so the ring is still usable
+            assert_eq!(ring.pending(), 0);
+            let info = enc
+                .append_packet(
+                    &KeepAliveS2c { id: 1 },
+                    &mut ring,
+                    &mut scratch,
+                    &mut compressor,
+                )
+                .unwrap();
+            assert_eq!(ring.pending(), { info.len } as usize);
+        }
+    }
+
+    #[test]
+    fn test_compression_disabled() {
+        let pkt = KeepAliveS2c { id: 1 };
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let mut uncompressed = VecBuf::new();
+        encode_uncompressed_into(&pkt, &mut uncompressed).unwrap();
+
+        // every packet is above a threshold of 0
+        let mut enc = PacketEncoder::new(CompressionThreshold(0));
+
+        let mut compressed = VecBuf::new();
+        enc.append_packet(&pkt, &mut compressed, &mut scratch, &mut compressor)
+            .unwrap();
+        assert_ne!(*compressed, *uncompressed);
+
+        enc.set_compression_disabled(true);
+
+        let mut bytes = VecBuf::new();
+        enc.append_packet(&pkt, &mut bytes, &mut scratch, &mut compressor)
+            .unwrap();
+        assert_eq!(*bytes, *uncompressed);
+    }
+
+    #[test]
+    fn test_compression_totals() {
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let enc = PacketEncoder::new(CompressionThreshold(256));
+        let mut bytes = VecBuf::new();
+
+        // the ID and the zeroes are compressed
+        enc.append_packet(&ZeroesPkt(1000), &mut bytes, &mut scratch, &mut compressor)
+            .unwrap();
+
+        // under the threshold, so not counted
+        enc.append_packet(
+            &KeepAliveS2c { id: 1 },
+            &mut bytes,
+            &mut scratch,
+            &mut compressor,
+        )
+        .unwrap();
+
+        let (before, after) = enc.take_compression_totals();
+        assert_eq!(before, 1001);
+        assert!(after < 100, "1000 zeroes compressed to {after} bytes");
+
+        assert_eq!(enc.take_compression_totals(), (0, 0));
+    }
+
+    /// The wire bytes of packets whose IDs are pinned by [`crate::net::PacketRegistry::server`],
+    /// so a protocol bump which changes them fails here.
+    #[test]
+    fn test_encode_to_vec_snapshots() {
+        use valence_protocol::packets::{
+            login::LoginCompressionS2c, play::BundleSplitterS2c, status::QueryPongS2c,
+        };
+
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+        let uncompressed = CompressionThreshold(-1);
+        let threshold = CompressionThreshold(256);
+
+        let pkt = LoginCompressionS2c {
+            threshold: VarInt(256),
+        };
+        let bytes = encode_to_vec(&pkt, uncompressed, &mut compressor).unwrap();
+        assert_eq!(bytes, [3, 0x03, 0x80, 0x02]);
+
+        let pkt = QueryPongS2c { payload: 42 };
+        let bytes = encode_to_vec(&pkt, uncompressed, &mut compressor).unwrap();
+        assert_eq!(bytes, [9, 0x01, 0, 0, 0, 0, 0, 0, 0, 42]);
+
+        // under the threshold, so the data length is 0
+        let pkt = KeepAliveS2c {
+            id: 0x0102_0304_0506_0708,
+        };
+        let bytes = encode_to_vec(&pkt, threshold, &mut compressor).unwrap();
+        assert_eq!(bytes, [10, 0, 0x23, 1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let bytes = encode_to_vec(&BundleSplitterS2c, threshold, &mut compressor).unwrap();
+        assert_eq!(bytes, [2, 0, 0x00]);
+    }
+
+    #[test]
+    fn test_encode_to_vec_compressed() {
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+        let mut scratch = Scratch::new();
+        let threshold = CompressionThreshold(0);
+
+        let pkt = KeepAliveS2c { id: 1234 };
+        let bytes = encode_to_vec(&pkt, threshold, &mut compressor).unwrap();
+
+        // the length of the frame, then the uncompressed length of the ID and the 8 byte id
+        assert_eq!(usize::from(bytes[0]), bytes.len() - 1);
+        assert_eq!(bytes[1], 9);
+
+        let mut decoder = PacketDecoder::new();
+        decoder.set_compression(threshold);
+        decoder.queue_slice(&bytes);
+
+        let frame = decoder.try_next_packet(&mut scratch).unwrap().unwrap();
+        assert_eq!(frame.decode::<KeepAliveS2c>().unwrap().id, 1234);
     }
 }
 
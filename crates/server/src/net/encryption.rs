@@ -0,0 +1,83 @@
+//! AES-128-CFB8 encryption which the Minecraft protocol uses once a client has authenticated.
+//!
+//! Both ciphers keep stream state, so they must live per connection.
+
+use aes::cipher::{
+    consts::U1, generic_array::GenericArray, BlockDecryptMut, BlockEncryptMut, KeyIvInit,
+};
+
+type Encryptor = cfb8::Encryptor<aes::Aes128>;
+type Decryptor = cfb8::Decryptor<aes::Aes128>;
+
+/// CFB8 works on blocks of a single byte, so any slice can be passed to the cipher at once.
+fn as_blocks(data: &mut [u8]) -> &mut [GenericArray<u8, U1>] {
+    // SAFETY: `GenericArray<u8, U1>` is a `#[repr(C)]` wrapper around `[u8; 1]`, so it has the
+    // size and alignment of a `u8`, and the returned slice covers exactly the bytes of `data`
+    unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr().cast(), data.len()) }
+}
+
+/// Encrypts S2C bytes for a single connection.
+pub struct PacketEncryptor {
+    cipher: Encryptor,
+}
+
+impl PacketEncryptor {
+    /// Minecraft uses the shared secret as both the key and the IV.
+    #[must_use]
+    pub fn new(shared_secret: &[u8; 16]) -> Self {
+        let cipher = Encryptor::new_from_slices(shared_secret, shared_secret)
+            .expect("the shared secret is always 16 bytes");
+
+        Self { cipher }
+    }
+
+    pub fn encrypt(&mut self, data: &mut [u8]) {
+        self.cipher.encrypt_blocks_mut(as_blocks(data));
+    }
+}
+
+/// Decrypts C2S bytes for a single connection.
+pub struct PacketDecryptor {
+    cipher: Decryptor,
+}
+
+impl PacketDecryptor {
+    /// Minecraft uses the shared secret as both the key and the IV.
+    #[must_use]
+    pub fn new(shared_secret: &[u8; 16]) -> Self {
+        let cipher = Decryptor::new_from_slices(shared_secret, shared_secret)
+            .expect("the shared secret is always 16 bytes");
+
+        Self { cipher }
+    }
+
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        self.cipher.decrypt_blocks_mut(as_blocks(data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_across_calls() {
+        let secret = [7; 16];
+
+        let mut encryptor = PacketEncryptor::new(&secret);
+        let mut decryptor = PacketDecryptor::new(&secret);
+
+        let plaintext = b"Hello, World! This is longer than a single AES block.";
+
+        let mut data = plaintext.to_vec();
+        encryptor.encrypt(&mut data);
+        assert_ne!(&data[..], &plaintext[..]);
+
+        // the stream state must carry over between calls
+        let (first, second) = data.split_at_mut(10);
+        decryptor.decrypt(first);
+        decryptor.decrypt(second);
+
+        assert_eq!(&data[..], &plaintext[..]);
+    }
+}
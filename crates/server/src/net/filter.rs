@@ -0,0 +1,133 @@
+//! Hooks which inspect inbound packets before they are dispatched, for anti-cheat, rate limiting
+//! specific packets, or debugging.
+
+use std::fmt;
+
+use evenio::component::Component;
+
+use crate::net::Fd;
+
+/// What happens to an inbound packet after a [`PacketFilter`] inspected it.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum FilterAction {
+    /// The packet is passed to the next filter, and dispatched if every filter allows it. This is
+    /// the default.
+    #[default]
+    Allow,
+    /// The packet is dropped without being decoded, and the next packet is inspected.
+    Drop,
+    /// The packet and everything received after it are dropped, and the player is kicked.
+    Disconnect,
+}
+
+/// Inspects every packet a player sends in the play state before it is decoded.
+///
+/// Packets sent during the handshake, status, and login states are not inspected, since the
+/// server handles those itself and their IDs mean something different.
+pub trait PacketFilter: Send + Sync {
+    /// Decides what happens to the packet with `id` sent by `fd`. `data` is the body of the packet
+    /// after its ID, already decompressed, so a filter which only looks at `id` costs next to
+    /// nothing.
+    fn inspect(&mut self, fd: Fd, id: i32, data: &[u8]) -> FilterAction;
+}
+
+impl<F> PacketFilter for F
+where
+    F: FnMut(Fd, i32, &[u8]) -> FilterAction + Send + Sync,
+{
+    fn inspect(&mut self, fd: Fd, id: i32, data: &[u8]) -> FilterAction {
+        self(fd, id, data)
+    }
+}
+
+/// The [`PacketFilter`]s which the ingress system runs on every inbound packet, in the order they
+/// were registered.
+#[derive(Component, Default)]
+pub struct PacketFilters {
+    filters: Vec<Box<dyn PacketFilter>>,
+}
+
+impl fmt::Debug for PacketFilters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacketFilters")
+            .field("filters", &self.filters.len())
+            .finish()
+    }
+}
+
+impl PacketFilters {
+    /// Adds `filter`, which only sees the packets every filter registered before it allowed.
+    pub fn register(&mut self, filter: impl PacketFilter + 'static) {
+        self.filters.push(Box::new(filter));
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Runs the filters on a packet until one of them does not allow it.
+    pub fn inspect(&mut self, fd: Fd, id: i32, data: &[u8]) -> FilterAction {
+        self.filters
+            .iter_mut()
+            .map(|filter| filter.inspect(fd, id, data))
+            .find(|&action| action != FilterAction::Allow)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::net::MockServer;
+
+    #[test]
+    fn test_first_rejection_wins() {
+        let mut server = MockServer::default();
+        let fd = server.connect();
+
+        let inspected = Arc::new(AtomicUsize::new(0));
+
+        let mut filters = PacketFilters::default();
+        filters.register(|_: Fd, id: i32, _: &[u8]| match id {
+            1 => FilterAction::Drop,
+            _ => FilterAction::Allow,
+        });
+        filters.register(|_: Fd, _: i32, data: &[u8]| {
+            if data.len() > 4 {
+                FilterAction::Disconnect
+            } else {
+                FilterAction::Allow
+            }
+        });
+        filters.register({
+            let inspected = inspected.clone();
+            move |_: Fd, _: i32, _: &[u8]| {
+                inspected.fetch_add(1, Ordering::Relaxed);
+                FilterAction::Allow
+            }
+        });
+
+        assert_eq!(filters.inspect(fd, 0, &[0; 4]), FilterAction::Allow);
+        assert_eq!(filters.inspect(fd, 1, &[0; 8]), FilterAction::Drop);
+        assert_eq!(filters.inspect(fd, 0, &[0; 8]), FilterAction::Disconnect);
+
+        // later filters only see allowed packets
+        assert_eq!(inspected.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_empty_allows() {
+        let mut server = MockServer::default();
+        let fd = server.connect();
+
+        let mut filters = PacketFilters::default();
+        assert!(filters.is_empty());
+        assert_eq!(filters.inspect(fd, 0, &[]), FilterAction::Allow);
+    }
+}
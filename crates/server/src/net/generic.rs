@@ -1,13 +1,17 @@
-// You can run this example from the root of the mio repo:
-// cargo run --example tcp_server --features="os-poll net"
+//! A portable [`ServerDef`] built on [`mio`] for platforms without `io_uring`.
+//!
+//! This is far slower than the Linux server, but it emits the same [`ServerEvent`]s so it is good
+//! enough to log in and play while developing locally (i.e., on macOS).
+
 use std::{
+    collections::VecDeque,
     hash::BuildHasherDefault,
     io::{self, Read, Write},
-    net::ToSocketAddrs,
-    time::Duration,
+    net::{Shutdown, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use anyhow::Context;
 use fxhash::FxHashMap;
 use libc::iovec;
 use mio::{
@@ -15,32 +19,182 @@ use mio::{
     net::{TcpListener, TcpStream},
     Events, Interest, Poll, Registry, Token,
 };
-use rayon_local::RayonLocal;
-use tracing::{info, warn};
+use socket2::SockRef;
+use tracing::{field, info, instrument, trace, warn, Span};
 
 use crate::{
     global::Global,
-    net::{encoder::PacketWriteInfo, Fd, RefreshItems, ServerDef, ServerEvent, MAX_PACKET_SIZE},
+    net::{
+        bind_listeners, core_index, encoder::PacketWriteInfo, local_addrs, set_connection_opts,
+        validate_resize, BufferPool, Fd, FlushResult, RefreshItems, ServerDef, ServerEvent,
+        SocketOpts, DEFAULT_MAX_CONNECTIONS, FULL_CONNECTION_HEADROOM,
+    },
 };
 
-// Setup some tokens to allow us to identify which event is for which socket.
-const SERVER: Token = Token(0);
+const EVENT_CAPACITY: usize = 1024;
+
+/// How many bytes to read from a socket at a time
+const READ_CHUNK_SIZE: usize = 4096;
 
-const EVENT_CAPACITY: usize = 128;
+/// How long [`ServerDef::close_after_send`] waits for the peer to accept the last bytes before the
+/// connection is closed anyway, so a client which stopped reading cannot keep it open.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
 struct ConnectionInfo {
-    pub to_write: RayonLocal<Vec<PacketWriteInfo>>,
-    pub connection: TcpStream,
-    pub data_to_write: Vec<u8>,
+    connection: TcpStream,
+    peer_addr: SocketAddr,
+
+    /// Bytes copied out of the S2C rings in [`ServerDef::write_all`] which have not been written
+    /// to the socket yet.
+    data_to_write: Vec<u8>,
+
+    /// The length of every [`PacketWriteInfo`] in `data_to_write` that has not been fully written.
+    /// Each one corresponds to exactly one [`ServerEvent::SentData`], like a single `WriteFixed`
+    /// on Linux, so the number of writes in flight only reaches 0 once the kernel has accepted
+    /// every byte of the batch.
+    in_flight: VecDeque<usize>,
+
+    /// Whether the connection is registered for writable events. This is only the case while
+    /// the kernel has not accepted all of `data_to_write`, so idle connections do not wake up the
+    /// poll.
+    writable_interest: bool,
+}
+
+impl ConnectionInfo {
+    /// Marks `len` bytes as written, pushing a [`ServerEvent::SentData`] for every
+    /// [`PacketWriteInfo`] which has now been completely written.
+    fn mark_written(&mut self, mut len: usize, fd: Fd, sent: &mut Vec<Fd>) {
+        while let Some(front) = self.in_flight.front_mut() {
+            if *front > len {
+                *front -= len;
+                return;
+            }
+
+            len -= *front;
+            self.in_flight.pop_front();
+            sent.push(fd);
+        }
+    }
+
+    /// Writes as much pending data as the socket accepts without blocking. If some of it is left,
+    /// the connection is registered for writable events so the rest is written once the socket
+    /// has room again.
+    fn flush(&mut self, fd: Fd, sent: &mut Vec<Fd>, registry: &Registry) -> io::Result<()> {
+        self.write_pending(fd, sent)?;
+        self.update_interest(registry, Token(fd.0))
+    }
+
+    fn write_pending(&mut self, fd: Fd, sent: &mut Vec<Fd>) -> io::Result<()> {
+        while !self.data_to_write.is_empty() {
+            match self.connection.write(&self.data_to_write) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.data_to_write.drain(..n);
+                    self.mark_written(n, fd, sent);
+                }
+                // Would block "errors" are the OS's way of saying that the
+                // connection is not actually ready to perform this I/O operation. We will get a
+                // writable event once it is.
+                Err(ref err) if would_block(err) => break,
+                // Got interrupted (how rude!), we'll try again.
+                Err(ref err) if interrupted(err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn update_interest(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let writable_interest = !self.data_to_write.is_empty();
+
+        if writable_interest == self.writable_interest {
+            return Ok(());
+        }
+
+        let interest = if writable_interest {
+            Interest::READABLE.add(Interest::WRITABLE)
+        } else {
+            Interest::READABLE
+        };
+
+        // the socket is polled again, so a writable event follows if it already has room
+        registry.reregister(&mut self.connection, token, interest)?;
+        self.writable_interest = writable_interest;
+
+        Ok(())
+    }
 }
 
 pub struct GenericServer {
     poll: Poll,
     events: Events,
-    server: TcpListener,
+    /// The listeners, whose tokens are their indices. Connections use the tokens after them.
+    listeners: Vec<TcpListener>,
+    local_addrs: Vec<SocketAddr>,
+    /// Set on every accepted connection.
+    socket_opts: SocketOpts,
     ids: Ids,
     write_iovecs: Vec<iovec>,
     connections: FxHashMap<usize, ConnectionInfo>,
+    /// See [`ServerDef::max_connections`].
+    max_connections: usize,
+
+    /// Writes which completed outside of [`ServerDef::drain`]. They are reported on the next drain
+    /// the same way `io_uring` completions are.
+    sent: Vec<Fd>,
+
+    /// Connections which errored outside of [`ServerDef::drain`] and are reported on the next
+    /// drain.
+    closed: Vec<(Fd, io::Error)>,
+
+    /// Connections closed by [`ServerDef::close_after_send`] which are reported on the next drain.
+    removed: Vec<Fd>,
+
+    /// Connections passed to [`ServerDef::close_after_send`] which still have bytes to write, with
+    /// when they are closed even if the peer did not accept those. They are no longer connected.
+    closing: FxHashMap<usize, (ConnectionInfo, Instant)>,
+
+    /// Reused between reads so we do not allocate every tick
+    received_data: Vec<u8>,
+
+    /// Keeps the memory behind `write_iovecs` alive.
+    #[expect(dead_code, reason = "this is used so there is no drop")]
+    s2c_buffers: Option<Arc<BufferPool>>,
+}
+
+impl GenericServer {
+    /// Sets [`ServerDef::max_connections`], which is [`DEFAULT_MAX_CONNECTIONS`] until this is
+    /// called. Connections which are already open are kept, even if there are more of them.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    /// Writes what is left for the connections in [`GenericServer::closing`], and closes the ones
+    /// which are done writing, failed to write, or are past their deadline at `now`.
+    fn flush_closing(&mut self, now: Instant) {
+        let registry = self.poll.registry();
+        let sent = &mut self.sent;
+        let removed = &mut self.removed;
+
+        self.closing.retain(|&token, (info, deadline)| {
+            let fd = Fd(token);
+
+            match info.flush(fd, sent, registry) {
+                Ok(()) if info.data_to_write.is_empty() => {}
+                Ok(()) if now < *deadline => return true,
+                Ok(()) => warn!(
+                    "closing {fd:?} with {} bytes which the peer did not accept in time",
+                    info.data_to_write.len()
+                ),
+                Err(err) => warn!("failed to flush {fd:?} before closing: {err}"),
+            }
+
+            shutdown_connection(registry, info);
+            removed.push(fd);
+            false
+        });
+    }
 }
 
 struct Ids {
@@ -56,238 +210,430 @@ impl Ids {
 }
 
 impl ServerDef for GenericServer {
-    fn new(address: impl ToSocketAddrs) -> anyhow::Result<Self>
+    fn new_multi_with_socket_opts(
+        addresses: &[SocketAddr],
+        opts: &SocketOpts,
+    ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        // todo: will this use kqueue on macOS? I hope it will 🥲
+        // this uses kqueue on macOS and epoll on other unix platforms
         let poll = Poll::new()?;
         // Create storage for events.
         let events = Events::with_capacity(EVENT_CAPACITY);
 
-        let address = address
-            .to_socket_addrs()?
-            .next()
-            .context("could not get first address")?;
+        let listeners = bind_listeners(addresses, opts)?;
+        let local_addrs = local_addrs(&listeners)?;
+
+        info!("using generic I/O server and listening on {local_addrs:?}");
+
+        let listeners = listeners
+            .into_iter()
+            .enumerate()
+            .map(|(token, listener)| {
+                let mut listener = TcpListener::from_std(listener.into());
 
-        info!("using generic I/O server and listening on {address}");
-        let mut server = TcpListener::bind(address)?;
+                // Register the server with poll we can receive events for it.
+                poll.registry()
+                    .register(&mut listener, Token(token), Interest::READABLE)?;
 
-        // Register the server with poll we can receive events for it.
-        poll.registry()
-            .register(&mut server, SERVER, Interest::READABLE)?;
+                Ok(listener)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
 
-        // Map of `Token` -> `TcpStream`.
-        // todo: is there a more idiomatic way to do this?
         let connections = FxHashMap::with_hasher(BuildHasherDefault::default());
 
         Ok(Self {
             poll,
-            connections,
             events,
-            ids: Ids { token_on: 1 },
-            server,
+            ids: Ids {
+                token_on: listeners.len(),
+            },
+            listeners,
+            local_addrs,
+            socket_opts: opts.clone(),
             write_iovecs: Vec::new(),
+            connections,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            sent: Vec::new(),
+            closed: Vec::new(),
+            removed: Vec::new(),
+            closing: FxHashMap::default(),
+            received_data: Vec::new(),
+            s2c_buffers: None,
         })
     }
 
+    #[instrument(
+        skip_all,
+        level = "trace",
+        name = "mio-drain-events",
+        fields(core = core_index(), events = field::Empty)
+    )]
     fn drain(&mut self, mut f: impl FnMut(ServerEvent)) -> io::Result<()> {
-        // todo: this is a bit of a hack, is there a better number? probs dont want people sending more than this
-        let mut received_data = Vec::with_capacity(MAX_PACKET_SIZE * 2);
-
-        // process the current tick
-        if let Err(err) = self
-            .poll
-            .poll(&mut self.events, Some(Duration::from_millis(10)))
-        {
+        self.flush_closing(Instant::now());
+
+        for fd in self.sent.drain(..) {
+            f(ServerEvent::SentData { fd });
+        }
+
+        for (fd, error) in self.closed.drain(..) {
+            f(ServerEvent::Error { fd, error });
+            f(ServerEvent::RemovePlayer { fd });
+        }
+
+        for fd in self.removed.drain(..) {
+            f(ServerEvent::RemovePlayer { fd });
+        }
+
+        // process the current tick without waiting; the game loop does its own sleeping
+        if let Err(err) = self.poll.poll(&mut self.events, Some(Duration::ZERO)) {
             if interrupted(&err) {
                 return Ok(());
             }
             return Err(err);
         }
 
+        Span::current().record("events", self.events.iter().count());
+
         for event in &self.events {
             match event.token() {
-                SERVER => loop {
-                    // Received an event for the TCP server socket, which
-                    // indicates we can accept an connection.
-                    let (mut connection, _) = match self.server.accept() {
-                        Ok((connection, address)) => (connection, address),
-                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            // If we get a `WouldBlock` error we know our
-                            // listener has no more incoming connections queued,
-                            // so we can return to polling and wait for some
-                            // more.
-                            break;
-                        }
-                        Err(e) => {
-                            // If it was any other kind of error, something went
-                            // wrong and we terminate with an error.
-                            return Err(e);
-                        }
-                    };
-
-                    let token = self.ids.generate_unique_token();
-                    self.poll.registry().register(
-                        &mut connection,
-                        token,
-                        Interest::READABLE.add(Interest::WRITABLE),
-                    )?;
-
-                    self.connections.insert(token.0, ConnectionInfo {
-                        to_write: RayonLocal::default(),
-                        connection,
-                        data_to_write: vec![],
-                    });
-
-                    f(ServerEvent::AddPlayer { fd: Fd(token.0) });
-                },
+                Token(listener) if listener < self.listeners.len() => accept_all(
+                    &self.listeners[listener],
+                    listener,
+                    &self.socket_opts,
+                    self.poll.registry(),
+                    &mut self.ids,
+                    &mut self.connections,
+                    self.max_connections,
+                    &mut f,
+                )?,
                 token => {
-                    // Maybe received an event for a TCP connection.
-                    let done = if let Some(connection) = self.connections.get_mut(&token.0) {
-                        received_data.clear();
-                        handle_connection_event(
-                            self.poll.registry(),
-                            connection,
-                            event,
-                            &mut received_data,
-                            token,
-                            &mut f,
-                        )?
-                    } else {
-                        // Sporadic events happen, we can safely ignore them.
-                        false
+                    // Sporadic events happen, we can safely ignore them.
+                    let Some(info) = self.connections.get_mut(&token.0) else {
+                        continue;
                     };
+
+                    let fd = Fd(token.0);
+
+                    let done = handle_connection_event(
+                        info,
+                        event,
+                        fd,
+                        self.poll.registry(),
+                        &mut self.received_data,
+                        &mut self.sent,
+                        &mut f,
+                    );
+
                     if done {
-                        if let Some(mut connection) = self.connections.remove(&token.0) {
-                            self.poll
-                                .registry()
-                                .deregister(&mut connection.connection)?;
-                        }
-                        f(ServerEvent::RemovePlayer { fd: Fd(token.0) });
+                        close(self.poll.registry(), &mut self.connections, token.0);
+                        f(ServerEvent::RemovePlayer { fd });
                     }
                 }
             }
         }
 
+        // writes which completed because of writable events
+        for fd in self.sent.drain(..) {
+            f(ServerEvent::SentData { fd });
+        }
+
+        Ok(())
+    }
+
+    fn allocate_buffers(&mut self, pool: Arc<BufferPool>) -> anyhow::Result<()> {
+        // SAFETY: the pool is kept alive for as long as the server
+        unsafe { self.allocate_buffers_raw(&pool.iovecs())? };
+        self.s2c_buffers = Some(pool);
         Ok(())
     }
 
-    // todo: make unsafe
-    fn allocate_buffers(&mut self, buffers: &[iovec]) {
+    unsafe fn allocate_buffers_raw(&mut self, buffers: &[iovec]) -> anyhow::Result<()> {
         if !self.write_iovecs.is_empty() {
             warn!("iovecs are not empty");
         }
         self.write_iovecs = buffers.to_vec();
+        Ok(())
+    }
+
+    fn replace_s2c_buffers(&mut self, pool: Arc<BufferPool>) -> anyhow::Result<()> {
+        let new_buffers = pool.iovecs();
+        validate_resize(self.write_iovecs.len(), &new_buffers)?;
+
+        // the bytes of every write were copied out of the old buffers in `write_all`
+        self.write_iovecs = new_buffers;
+        self.s2c_buffers = Some(pool);
+        Ok(())
     }
 
+    /// Data is read into `received_data`, which is not provided to the OS, so there is nothing
+    /// to resize.
+    fn resize_buffers(&mut self, _count: usize, _f: impl FnMut(ServerEvent)) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Unlike Linux, the data is copied out of the rings immediately, so the rings can be reused
+    /// as soon as this returns.
+    #[instrument(
+        skip_all,
+        level = "trace",
+        name = "mio-write-all",
+        fields(
+            core = core_index(),
+            fds = field::Empty,
+            writes = field::Empty,
+            bytes = field::Empty
+        )
+    )]
     fn write_all<'a>(
         &mut self,
-        _global: &mut Global,
+        global: &mut Global,
         writers: impl Iterator<Item = RefreshItems<'a>>,
-    ) {
+    ) -> FlushResult {
+        let mut fds = 0_usize;
+        let mut writes = 0_usize;
+        let mut bytes = 0_usize;
+
         for writer in writers {
             let RefreshItems { write, fd } = writer;
 
-            let Some(to_write) = self.connections.get_mut(&fd.0) else {
+            let Some(info) = self.connections.get_mut(&fd.0) else {
                 warn!("no connection for fd {fd:?}");
                 continue;
             };
 
+            let writes_before = writes;
+
             for (idx, write) in write.iter_mut().enumerate() {
-                let (a, b) = write.as_slices();
+                global.drain_writes(fd, write, |elem| {
+                    debug_assert!(
+                        is_within(&self.write_iovecs[idx], elem),
+                        "write for {fd:?} is not within registered buffer {idx}"
+                    );
+
+                    // SAFETY: the data lives in a registered ring which is not overwritten until
+                    // the next tick
+                    let data = unsafe { elem.as_slice() };
+                    info.data_to_write.extend_from_slice(data);
+                    info.in_flight.push_back(data.len());
+
+                    writes += 1;
+                    bytes += data.len();
+                });
+            }
 
-                let to_write = &mut to_write.to_write[idx];
-                to_write.reserve(a.len() + b.len());
+            if writes != writes_before {
+                fds += 1;
+            }
+        }
 
-                to_write.extend_from_slice(a);
-                to_write.extend_from_slice(b);
+        let span = Span::current();
+        span.record("fds", fds);
+        span.record("writes", writes);
+        span.record("bytes", bytes);
 
-                write.clear();
-            }
+        FlushResult {
+            fds_written: fds,
+            bytes_submitted: bytes,
+            iovecs: writes,
         }
     }
 
-    fn submit_events(&mut self) {
-        // todo
+    fn close_after_send(&mut self, fd: Fd) {
+        let Some(info) = self.connections.remove(&fd.0) else {
+            warn!("tried to close {fd:?} which is not connected");
+            return;
+        };
+
+        // the last packets are usually the reason for closing, so they are written first, but
+        // without waiting for a peer which does not read
+        let now = Instant::now();
+        self.closing.insert(fd.0, (info, now + CLOSE_TIMEOUT));
+        self.flush_closing(now);
     }
-}
 
-/// Returns `true` if the connection is done.
-fn handle_connection_event(
-    registry: &Registry,
-    info: &mut ConnectionInfo,
-    event: &Event,
-    received_data: &mut Vec<u8>,
-    token: Token,
-    f: &mut impl FnMut(ServerEvent),
-) -> io::Result<bool> {
-    if event.is_writable() {
-        let data = &mut info.data_to_write;
+    fn connected_fds(&self) -> impl Iterator<Item = Fd> + '_ {
+        self.connections.keys().map(|&token| Fd(token))
+    }
+
+    /// Tokens are never reused, so the token is enough to tell connections apart.
+    fn is_connected(&self, fd: Fd) -> bool {
+        self.connections.contains_key(&fd.0)
+    }
+
+    fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
 
-        for buf in &info.to_write {
-            for elem in buf {
-                data.extend_from_slice(unsafe { elem.as_slice() });
+    fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    fn peer_addr(&self, fd: Fd) -> Option<SocketAddr> {
+        self.connections.get(&fd.0).map(|info| info.peer_addr)
+    }
+
+    #[instrument(
+        skip_all,
+        level = "trace",
+        name = "mio-submit-events",
+        fields(core = core_index(), fds = self.connections.len())
+    )]
+    fn submit_events(&mut self) {
+        let mut errored = Vec::new();
+
+        for (&token, info) in &mut self.connections {
+            let fd = Fd(token);
+            if let Err(err) = info.flush(fd, &mut self.sent, self.poll.registry()) {
+                warn!("error writing to {fd:?}; closing connection: {err}");
+                errored.push((token, err));
             }
         }
 
-        if data.is_empty() {
-            // todo: I think an event cannot be both readable and writable
-            registry.reregister(
-                &mut info.connection,
-                event.token(),
-                Interest::READABLE.add(Interest::WRITABLE),
-            )?;
+        for (token, err) in errored {
+            close(self.poll.registry(), &mut self.connections, token);
+            self.closed.push((Fd(token), err));
+        }
+    }
+
+    /// Writes that are still unsent once [`CLOSE_TIMEOUT`] passed are dropped, so a peer which
+    /// does not read cannot keep the server from shutting down.
+    fn shutdown(&mut self) -> io::Result<()> {
+        info!("closing {} connections", self.connections.len());
 
-            return Ok(false);
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        for (token, info) in self.connections.drain() {
+            self.closing.insert(token, (info, deadline));
         }
 
-        // We can (maybe) write to the connection.
-        match info.connection.write(data) {
-            Ok(n) if n < data.len() => {
-                // remove {n} bytes from the data
-                // todo: is this most efficient
-                data.drain(..n);
-                registry.reregister(
-                    &mut info.connection,
-                    event.token(),
-                    Interest::READABLE.add(Interest::WRITABLE),
-                )?;
-            }
-            Ok(_) => {
-                data.drain(..);
-                registry.reregister(
-                    &mut info.connection,
-                    event.token(),
-                    Interest::READABLE.add(Interest::WRITABLE),
-                )?;
-            }
-            // Would block "errors" are the OS's way of saying that the
-            // connection is not actually ready to perform this I/O operation.
-            Err(ref err) if would_block(err) => {}
-            // Got interrupted (how rude!), we'll try again.
-            Err(ref err) if interrupted(err) => {
-                return handle_connection_event(registry, info, event, received_data, token, f)
+        loop {
+            self.flush_closing(Instant::now());
+
+            let Some(&(_, next_deadline)) = self.closing.values().min_by_key(|(_, at)| *at) else {
+                return Ok(());
+            };
+
+            // wakes up once a socket has room again, or the first deadline passed
+            let timeout = next_deadline.saturating_duration_since(Instant::now());
+            if let Err(err) = self.poll.poll(&mut self.events, Some(timeout)) {
+                if !interrupted(&err) {
+                    return Err(err);
+                }
             }
-            // Other errors we'll consider fatal.
+        }
+    }
+}
+
+#[expect(
+    clippy::too_many_arguments,
+    reason = "the fields are borrowed separately from the server"
+)]
+fn accept_all(
+    listener: &TcpListener,
+    listener_index: usize,
+    opts: &SocketOpts,
+    registry: &Registry,
+    ids: &mut Ids,
+    connections: &mut FxHashMap<usize, ConnectionInfo>,
+    max_connections: usize,
+    f: &mut impl FnMut(ServerEvent),
+) -> io::Result<()> {
+    loop {
+        // Received an event for the TCP server socket, which
+        // indicates we can accept an connection.
+        let (mut connection, peer_addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            // If we get a `WouldBlock` error we know our
+            // listener has no more incoming connections queued,
+            // so we can return to polling and wait for some
+            // more.
+            Err(ref err) if would_block(err) => return Ok(()),
+            Err(ref err) if interrupted(err) => continue,
             Err(err) => return Err(err),
+        };
+
+        if connections.len() >= max_connections + FULL_CONNECTION_HEADROOM {
+            trace!("closing connection from {peer_addr} because the server is full");
+            f(ServerEvent::AcceptsRejected { count: 1 });
+            continue;
         }
 
-        info.to_write
-            .iter_mut()
-            .flat_map(|buf| buf.drain(..))
-            .for_each(|_| {
-                f(ServerEvent::SentData { fd: Fd(token.0) });
-            });
+        // connections inherit the options of the listener, but not on every platform
+        if let Err(err) = set_connection_opts(SockRef::from(&connection), opts) {
+            warn!("failed to set the socket options of the connection from {peer_addr}: {err}");
+        }
+
+        let token = ids.generate_unique_token();
+        registry.register(&mut connection, token, Interest::READABLE)?;
+
+        connections.insert(token.0, ConnectionInfo {
+            connection,
+            peer_addr,
+            data_to_write: Vec::new(),
+            in_flight: VecDeque::new(),
+            writable_interest: false,
+        });
+
+        f(ServerEvent::AddPlayer {
+            fd: Fd(token.0),
+            listener: listener_index,
+        });
+    }
+}
+
+fn close(registry: &Registry, connections: &mut FxHashMap<usize, ConnectionInfo>, token: usize) {
+    let Some(mut info) = connections.remove(&token) else {
+        return;
+    };
+
+    if let Err(err) = registry.deregister(&mut info.connection) {
+        warn!("failed to deregister connection: {err}");
+    }
+}
+
+/// Deregisters the connection of `info` and shuts it down. The socket is closed once `info` is
+/// dropped.
+fn shutdown_connection(registry: &Registry, info: &mut ConnectionInfo) {
+    if let Err(err) = registry.deregister(&mut info.connection) {
+        warn!("failed to deregister connection: {err}");
+    }
+
+    // the peer might have already closed the connection
+    if let Err(err) = info.connection.shutdown(Shutdown::Both) {
+        warn!("failed to shutdown connection: {err}");
+    }
+}
+
+/// Returns `true` if the connection is done.
+fn handle_connection_event(
+    info: &mut ConnectionInfo,
+    event: &Event,
+    fd: Fd,
+    registry: &Registry,
+    received_data: &mut Vec<u8>,
+    sent: &mut Vec<Fd>,
+    f: &mut impl FnMut(ServerEvent),
+) -> bool {
+    if event.is_writable() {
+        if let Err(err) = info.flush(fd, sent, registry) {
+            warn!("error writing to {fd:?}; closing connection: {err}");
+            f(ServerEvent::Error { fd, error: err });
+            return true;
+        }
     }
 
     if event.is_readable() {
         let mut connection_closed = false;
+        let mut read_error = None;
         let mut bytes_read = 0;
-        // We can (maybe) read from the connection.
 
         // todo: remove setting 0's, just use MaybeUninit
-        received_data.resize(1024, 0);
+        received_data.resize(READ_CHUNK_SIZE, 0);
 
         loop {
             match info.connection.read(&mut received_data[bytes_read..]) {
@@ -300,32 +646,53 @@ fn handle_connection_event(
                 Ok(n) => {
                     bytes_read += n;
                     if bytes_read == received_data.len() {
-                        received_data.resize(received_data.len() + 1024, 0);
+                        received_data.resize(received_data.len() + READ_CHUNK_SIZE, 0);
                     }
                 }
-                // Would block "errors" are the OS's way of saying that the
-                // connection is not actually ready to perform this I/O operation.
                 Err(ref err) if would_block(err) => break,
                 Err(ref err) if interrupted(err) => continue,
-                // Other errors we'll consider fatal.
-                Err(err) => return Err(err),
+                Err(err) => {
+                    trace!("player {fd:?} disconnected during read: {err}");
+                    read_error = Some(err);
+                    connection_closed = true;
+                    break;
+                }
             }
         }
 
         if bytes_read != 0 {
-            let received_data = &received_data[..bytes_read];
             f(ServerEvent::RecvData {
-                fd: Fd(token.0),
-                data: received_data,
+                fd,
+                data: &received_data[..bytes_read],
             });
         }
 
-        if connection_closed {
-            return Ok(true);
+        if let Some(error) = read_error {
+            f(ServerEvent::Error { fd, error });
+        }
+
+        return connection_closed;
+    }
+
+    if event.is_error() {
+        if let Ok(Some(error)) = info.connection.take_error() {
+            f(ServerEvent::Error { fd, error });
         }
+        return true;
     }
 
-    Ok(false)
+    event.is_read_closed()
+}
+
+/// Whether the bytes of `elem` are entirely inside `buffer`.
+fn is_within(buffer: &iovec, elem: PacketWriteInfo) -> bool {
+    let start = buffer.iov_base as usize;
+    let end = start + buffer.iov_len;
+
+    let elem_start = elem.start_ptr as usize;
+    let elem_end = elem_start + elem.len as usize;
+
+    start <= elem_start && elem_end <= end
 }
 
 fn would_block(err: &io::Error) -> bool {
@@ -0,0 +1,264 @@
+//! A validating parser for the handshake, which is the first packet every connection sends and
+//! so the one anyone can reach without logging in.
+//!
+//! See <https://wiki.vg/Protocol#Handshake>.
+
+use std::{fmt, ops::RangeInclusive};
+
+use valence_protocol::{packets::handshaking::handshake_c2s::HandshakeNextState, Decode, VarInt};
+
+use crate::net::ProtocolVersion;
+
+/// The longest server address vanilla clients send, in characters.
+pub const MAX_ADDRESS_LEN: usize = 255;
+
+/// What separates the server address from the data Forge and proxies append to it.
+const EXTRA_SEPARATOR: char = '\0';
+
+/// A decoded handshake.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Handshake<'a> {
+    /// The protocol number the client advertised. See [`Handshake::version`].
+    pub protocol: i32,
+    /// The address the client connected to, without [`Handshake::extra`].
+    pub address: &'a str,
+    /// What was appended to the address after a NUL character, without the first NUL.
+    ///
+    /// Forge clients append a marker like `FML2\0`, and BungeeCord's IP forwarding appends the
+    /// address and UUID of the player. Vanilla clients send [`None`].
+    pub extra: Option<&'a str>,
+    pub port: u16,
+    pub next_state: HandshakeNextState,
+}
+
+impl Handshake<'_> {
+    /// The version of the client, if it is a known one.
+    #[must_use]
+    pub const fn version(&self) -> Option<ProtocolVersion> {
+        ProtocolVersion::from_handshake(self.protocol)
+    }
+
+    /// Whether the client is a Forge client, which mark their handshake with `FML`, `FML2`, or
+    /// `FML3`.
+    #[must_use]
+    pub fn is_forge(&self) -> bool {
+        self.extra
+            .is_some_and(|extra| extra.split(EXTRA_SEPARATOR).any(|s| s.starts_with("FML")))
+    }
+}
+
+/// Why a handshake was rejected by [`HandshakeConfig::decode`].
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// A field could not be decoded, like when the packet is cut off.
+    Malformed(anyhow::Error),
+    /// The next state is neither 1 (status) nor 2 (login). 3 (transfer) is only sent by clients
+    /// since 1.20.5.
+    InvalidNextState(i32),
+    /// The server address is longer than [`HandshakeConfig::max_address_len`] characters.
+    AddressTooLong { len: usize },
+    /// The protocol number is outside of [`HandshakeConfig::protocols`].
+    UnacceptedProtocol(i32),
+    /// There are bytes left after the handshake.
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(err) => write!(f, "malformed handshake: {err}"),
+            Self::InvalidNextState(state) => write!(f, "invalid next state {state}"),
+            Self::AddressTooLong { len } => {
+                write!(f, "server address is {len} characters long")
+            }
+            Self::UnacceptedProtocol(protocol) => {
+                write!(f, "protocol {protocol} is not accepted")
+            }
+            Self::TrailingBytes(count) => write!(f, "{count} bytes after the handshake"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Malformed(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for HandshakeError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::Malformed(err)
+    }
+}
+
+/// Which handshakes [`HandshakeConfig::decode`] accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeConfig {
+    /// The protocol numbers which are decoded any further. Clients outside of this range are
+    /// disconnected without a response, so they do not see the server in their server list
+    /// either. Clients inside of it which cannot join are told which versions they need instead,
+    /// so this defaults to every protocol number which is not negative.
+    pub protocols: RangeInclusive<i32>,
+    /// The longest server address accepted, in characters, not counting [`Handshake::extra`].
+    /// This defaults to [`MAX_ADDRESS_LEN`].
+    pub max_address_len: usize,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            protocols: 0..=i32::MAX,
+            max_address_len: MAX_ADDRESS_LEN,
+        }
+    }
+}
+
+impl HandshakeConfig {
+    /// Decodes the body of a handshake packet, which is everything after its ID. The protocol
+    /// number is checked before the rest is decoded.
+    pub fn decode<'a>(&self, data: &'a [u8]) -> Result<Handshake<'a>, HandshakeError> {
+        let mut r = data;
+
+        let protocol = VarInt::decode(&mut r)?.0;
+        if !self.protocols.contains(&protocol) {
+            return Err(HandshakeError::UnacceptedProtocol(protocol));
+        }
+
+        // this is bounded by the maximum string length of the protocol, which leaves room for the
+        // extra data
+        let full_address = <&str>::decode(&mut r)?;
+        let (address, extra) = match full_address.split_once(EXTRA_SEPARATOR) {
+            Some((address, extra)) => (address, Some(extra)),
+            None => (full_address, None),
+        };
+
+        let len = address.chars().count();
+        if len > self.max_address_len {
+            return Err(HandshakeError::AddressTooLong { len });
+        }
+
+        let port = u16::decode(&mut r)?;
+
+        let next_state = match VarInt::decode(&mut r)?.0 {
+            1 => HandshakeNextState::Status,
+            2 => HandshakeNextState::Login,
+            state => return Err(HandshakeError::InvalidNextState(state)),
+        };
+
+        if !r.is_empty() {
+            return Err(HandshakeError::TrailingBytes(r.len()));
+        }
+
+        Ok(Handshake {
+            protocol,
+            address,
+            extra,
+            port,
+            next_state,
+        })
+    }
+}
+
+/// Decodes the body of a handshake packet with the [`HandshakeConfig::default`] limits.
+pub fn decode_handshake(data: &[u8]) -> Result<Handshake<'_>, HandshakeError> {
+    HandshakeConfig::default().decode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use valence_protocol::{packets::handshaking::HandshakeC2s, Bounded, Encode};
+
+    use super::*;
+
+    fn encode(protocol: i32, address: &str, next_state: i32) -> Vec<u8> {
+        let mut data = Vec::new();
+        VarInt(protocol).encode(&mut data).unwrap();
+        address.encode(&mut data).unwrap();
+        25565_u16.encode(&mut data).unwrap();
+        VarInt(next_state).encode(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_vanilla() {
+        let mut data = Vec::new();
+        HandshakeC2s {
+            protocol_version: VarInt(ProtocolVersion::CURRENT.protocol()),
+            server_address: Bounded("localhost"),
+            server_port: 25565,
+            next_state: HandshakeNextState::Login,
+        }
+        .encode(&mut data)
+        .unwrap();
+
+        let handshake = decode_handshake(&data).unwrap();
+        assert_eq!(handshake.version(), Some(ProtocolVersion::CURRENT));
+        assert_eq!(handshake.address, "localhost");
+        assert_eq!(handshake.extra, None);
+        assert_eq!(handshake.port, 25565);
+        assert_eq!(handshake.next_state, HandshakeNextState::Login);
+        assert!(!handshake.is_forge());
+    }
+
+    #[test]
+    fn test_forge() {
+        // the extra data does not count towards the address length
+        let address = format!("{}\0FML3\0", "a".repeat(MAX_ADDRESS_LEN));
+        let data = encode(763, &address, 2);
+
+        let handshake = decode_handshake(&data).unwrap();
+        assert_eq!(handshake.address.len(), MAX_ADDRESS_LEN);
+        assert_eq!(handshake.extra, Some("FML3\0"));
+        assert!(handshake.is_forge());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            decode_handshake(&encode(763, "localhost", 3)),
+            Err(HandshakeError::InvalidNextState(3))
+        ));
+
+        let address = "a".repeat(MAX_ADDRESS_LEN + 1);
+        assert!(matches!(
+            decode_handshake(&encode(763, &address, 1)),
+            Err(HandshakeError::AddressTooLong { len }) if len == MAX_ADDRESS_LEN + 1
+        ));
+
+        let data = encode(763, "localhost", 1);
+        assert!(matches!(
+            decode_handshake(&data[..data.len() - 1]),
+            Err(HandshakeError::Malformed(_))
+        ));
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(matches!(
+            decode_handshake(&trailing),
+            Err(HandshakeError::TrailingBytes(1))
+        ));
+    }
+
+    #[test]
+    fn test_protocol_range() {
+        let config = HandshakeConfig {
+            protocols: 763..=765,
+            ..HandshakeConfig::default()
+        };
+
+        // the rest is not decoded, so the invalid next state is not noticed
+        assert!(matches!(
+            config.decode(&encode(47, "localhost", 3)),
+            Err(HandshakeError::UnacceptedProtocol(47))
+        ));
+        assert!(config.decode(&encode(765, "localhost", 1)).is_ok());
+
+        assert!(matches!(
+            decode_handshake(&encode(-1, "localhost", 1)),
+            Err(HandshakeError::UnacceptedProtocol(-1))
+        ));
+    }
+}
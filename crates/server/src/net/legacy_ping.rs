@@ -0,0 +1,127 @@
+//! The server list ping of clients before 1.7, which is not framed like modern packets.
+//!
+//! See <https://wiki.vg/Server_List_Ping#1.6>.
+
+use crate::net::MINECRAFT_VERSION;
+
+/// The first byte of a legacy ping. A modern handshake starts with its length instead, which is
+/// never this large in practice, so vanilla servers make the same assumption.
+const LEGACY_PING: u8 = 0xFE;
+
+/// The kick packet legacy clients expect the status in.
+const LEGACY_KICK: u8 = 0xFF;
+
+/// The protocol version reported to legacy clients. No legacy client has it, so they show the
+/// server as incompatible along with [`MINECRAFT_VERSION`].
+const LEGACY_PROTOCOL: i32 = 127;
+
+/// A server list ping from a client before 1.7.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LegacyPing {
+    /// Beta 1.8 to 1.3, which only send `0xFE`.
+    Beta,
+    /// 1.4 to 1.6, which send `0xFE 0x01`, followed by the host they connected to since 1.6.
+    V1_4,
+}
+
+impl LegacyPing {
+    /// The legacy ping `data` starts with, if any. `data` must be the first bytes received on a
+    /// connection.
+    #[must_use]
+    pub const fn detect(data: &[u8]) -> Option<Self> {
+        match data {
+            [LEGACY_PING, 0x01, ..] => Some(Self::V1_4),
+            [LEGACY_PING, ..] => Some(Self::Beta),
+            _ => None,
+        }
+    }
+
+    /// Encodes the response to this ping. Legacy clients read it from a kick packet, so the
+    /// connection should be closed once it is sent.
+    #[must_use]
+    pub fn response(self, status: &LegacyStatus<'_>) -> Vec<u8> {
+        let LegacyStatus { motd, online, max } = *status;
+
+        let text = match self {
+            // `§` separates the fields, so it cannot be part of the motd
+            Self::Beta => format!("{}§{online}§{max}", motd.replace('§', "")),
+            Self::V1_4 => format!(
+                "§1\0{LEGACY_PROTOCOL}\0{MINECRAFT_VERSION}\0{}\0{online}\0{max}",
+                motd.replace('\0', "")
+            ),
+        };
+
+        // the length is the number of UTF-16 code units
+        let units: Vec<u16> = text.encode_utf16().take(usize::from(u16::MAX)).collect();
+
+        let mut response = Vec::with_capacity(3 + 2 * units.len());
+        response.push(LEGACY_KICK);
+        response.extend_from_slice(&(units.len() as u16).to_be_bytes());
+
+        for unit in units {
+            response.extend_from_slice(&unit.to_be_bytes());
+        }
+
+        response
+    }
+}
+
+/// What a legacy client shows in its server list.
+#[derive(Debug, Copy, Clone)]
+pub struct LegacyStatus<'a> {
+    pub motd: &'a str,
+    pub online: usize,
+    pub max: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: LegacyStatus<'static> = LegacyStatus {
+        motd: "a§b",
+        online: 1,
+        max: 32,
+    };
+
+    /// Decodes the UTF-16 text of a response, checking the kick packet around it.
+    fn text(response: &[u8]) -> String {
+        assert_eq!(response[0], LEGACY_KICK);
+
+        let len = usize::from(u16::from_be_bytes([response[1], response[2]]));
+        let units: Vec<u16> = response[3..]
+            .chunks(2)
+            .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+            .collect();
+        assert_eq!(units.len(), len);
+
+        String::from_utf16(&units).unwrap()
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(LegacyPing::detect(&[0xFE]), Some(LegacyPing::Beta));
+        assert_eq!(LegacyPing::detect(&[0xFE, 0x01]), Some(LegacyPing::V1_4));
+        assert_eq!(
+            LegacyPing::detect(&[0xFE, 0x01, 0xFA, 0x00]),
+            Some(LegacyPing::V1_4)
+        );
+
+        // a modern handshake starts with its length
+        assert_eq!(LegacyPing::detect(&[0x10, 0x00]), None);
+        assert_eq!(LegacyPing::detect(&[]), None);
+    }
+
+    #[test]
+    fn test_beta_response() {
+        assert_eq!(text(&LegacyPing::Beta.response(&STATUS)), "ab§1§32");
+    }
+
+    #[test]
+    fn test_v1_4_response() {
+        let text = text(&LegacyPing::V1_4.response(&STATUS));
+        let fields: Vec<&str> = text.split('\0').collect();
+
+        assert_eq!(fields, ["§1", "127", MINECRAFT_VERSION, "a§b", "1", "32"]);
+    }
+}
@@ -6,38 +6,149 @@ use std::{
     iter::TrustedLen,
     marker::PhantomData,
     net::{SocketAddr, ToSocketAddrs},
-    os::fd::AsRawFd,
-    sync::atomic::{AtomicU16, Ordering},
+    os::fd::{AsRawFd, RawFd},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
+use anyhow::{ensure, Context};
+use fxhash::{FxHashMap, FxHashSet};
 pub use io_uring::types::Fixed;
 use io_uring::{
-    cqueue::buffer_select, squeue, squeue::SubmissionQueue, types::BufRingEntry, IoUring,
+    cqueue::buffer_select,
+    squeue,
+    squeue::SubmissionQueue,
+    types::{BufRingEntry, CancelBuilder, DestinationSlot, SubmitArgs, Timespec},
+    IoUring,
 };
 use libc::iovec;
-use socket2::Socket;
-use tracing::{error, info, instrument, trace, warn};
+use socket2::{SockAddr, Socket};
+use tracing::{debug, error, field, info, instrument, trace, warn, Span};
 
 use super::RefreshItems;
 use crate::{
     global::Global,
-    net::{encoder::PacketWriteInfo, Fd, ServerDef, ServerEvent},
+    net::{
+        accept_limit::AcceptLimiter, bind_listeners, core_index, encoder::PacketWriteInfo,
+        local_addrs, recv_buffer::RecvBufferPool, validate_resize, AcceptPolicy, BufferPool, Fd,
+        FlushResult, RecvBuffer, ServerDef, ServerEvent, SocketOpts, DEFAULT_MAX_CONNECTIONS,
+        FULL_CONNECTION_HEADROOM,
+    },
 };
 
+/// The number of completions which fit in the completion queue. Completions are only drained once
+/// per tick, so this must fit every completion of a tick: each connection posts one for every C2S
+/// buffer it fills and one for every write, and the listener posts one per accepted connection.
+///
+/// This is sized for [`DEFAULT_MAX_CONNECTIONS`], each with about one completion per tick. If it
+/// still fills up, the kernel buffers the extra completions and flushes them on the next submit if
+/// it supports `IORING_FEAT_NODROP`, and drops them otherwise, which is reported with
+/// [`ServerEvent::CompletionOverflow`].
 const COMPLETION_QUEUE_SIZE: u32 = 32768;
 const SUBMISSION_QUEUE_SIZE: u32 = 32768;
-const IO_URING_FILE_COUNT: u32 = 32768;
+/// The number of C2S buffers provided to the kernel until [`ServerDef::resize_buffers`] is called.
+/// This must be a power of two of at most [`MAX_C2S_RING_BUFFER_COUNT`].
+///
+/// Each buffer is given back to the kernel once its data was handled in [`ServerDef::drain`], so
+/// this only limits how much can be received between two drains.
 const C2S_RING_BUFFER_COUNT: usize = 16384;
-const LISTEN_BACKLOG: libc::c_int = 128;
 // const SEND_BUFFER_SIZE: usize = 128 * 1024 * 1024;
 
 /// Size of each buffer in bytes
 const C2S_RING_BUFFER_LEN: usize = 64;
 
-const LISTENER_FIXED_FD: Fixed = Fixed(0);
 const C2S_BUFFER_GROUP_ID: u16 = 0;
 
+/// The most entries a provided-buffer ring can have.
+const MAX_C2S_RING_BUFFER_COUNT: usize = 32768;
+
+/// The number of accepts in flight for each listener, which is also the most connections a
+/// listener accepts per drain. Multishot accepts cannot report the address of the peer, so every
+/// accept needs its own [`AcceptSlot`].
+const ACCEPTS_PER_LISTENER: usize = 64;
+
 const IORING_CQE_F_MORE: u32 = 1 << 1;
+/// Makes `io_uring_enter` post the finished operations to the completion queue, which it does even
+/// with a `min_complete` of 0.
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+
+/// How long [`SubmitStrategy::Spin`] polls for a completion by default.
+const DEFAULT_SPIN_TIMEOUT: Duration = Duration::from_millis(1);
+
+fn uring_builder() -> io_uring::Builder {
+    // TODO: Try to use defer taskrun
+    let mut builder = IoUring::builder();
+    builder
+        .setup_cqsize(COMPLETION_QUEUE_SIZE)
+        .setup_submit_all()
+        .setup_coop_taskrun()
+        .setup_single_issuer();
+    builder
+}
+
+/// Builds the `io_uring`, returning whether SQPOLL ended up enabled. See
+/// [`LinuxServerConfig::sqpoll`].
+fn build_uring(sqpoll: Option<Duration>) -> anyhow::Result<(IoUring, bool)> {
+    if let Some(idle) = sqpoll {
+        let idle = u32::try_from(idle.as_millis()).unwrap_or(u32::MAX);
+
+        match uring_builder()
+            .setup_sqpoll(idle)
+            .build(SUBMISSION_QUEUE_SIZE)
+        {
+            Ok(uring) => {
+                info!("io_uring SQPOLL enabled with an idle time of {idle}ms");
+                return Ok((uring, true));
+            }
+            Err(err) => {
+                warn!(
+                    "the kernel rejected io_uring SQPOLL, falling back to a regular io_uring \
+                     (this may need CAP_SYS_NICE or a higher RLIMIT_MEMLOCK): {err}"
+                );
+            }
+        }
+    }
+
+    let uring = uring_builder()
+        .build(SUBMISSION_QUEUE_SIZE)
+        .context("failed to create io_uring")?;
+
+    Ok((uring, false))
+}
+
+/// The [`Fd`] of the connection which was accepted into the slot `fd` last.
+fn fd_in(generations: &[u32], fd: Fixed) -> Fd {
+    Fd(fd, generations[fd.0 as usize])
+}
+
+/// Whether `fd` is still connected, and not an earlier connection in the same slot. See
+/// [`ServerDef::is_connected`].
+fn is_current(
+    connections: &FxHashMap<Fixed, Option<SocketAddr>>,
+    generations: &[u32],
+    fd: Fd,
+) -> bool {
+    connections.contains_key(&fd.0) && fd_in(generations, fd.0) == fd
+}
+
+/// The `user_data` of an entry for the connection `fd`. The generation is stored next to the
+/// slot, so that a completion which arrives after the slot was reused is not mistaken for one of
+/// the connection which has the slot now. See [`fd_of`].
+fn user_data(marker: u64, fd: Fd) -> u64 {
+    marker | u64::from(fd.1 & GENERATION_MASK) << 32 | u64::from(fd.0 .0)
+}
+
+/// The [`Fd`] whose entry had `user_data` from [`user_data`], without the marker.
+#[expect(clippy::cast_possible_truncation, reason = "the fields are masked")]
+const fn fd_of(user_data: u64) -> Fd {
+    Fd(
+        Fixed(user_data as u32),
+        (user_data >> 32) as u32 & GENERATION_MASK,
+    )
+}
 
 fn page_size() -> usize {
     // SAFETY: This is valid
@@ -109,9 +220,217 @@ impl<T> Drop for PageAlignedMemory<T> {
     }
 }
 
+/// Options for [`LinuxServer`] which only make sense with `io_uring`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LinuxServerConfig {
+    /// Runs the `io_uring` in SQPOLL mode, where a kernel thread polls the submission queue so
+    /// that submitting does not need a syscall. The thread goes to sleep after it has been idle for
+    /// this long, after which the next submission has to wake it up with a syscall.
+    ///
+    /// This needs `CAP_SYS_NICE` on kernels older than 5.11, and the rings are allocated from
+    /// locked memory, so `RLIMIT_MEMLOCK` may have to be raised. If the kernel rejects SQPOLL, the
+    /// server falls back to a regular `io_uring`.
+    pub sqpoll: Option<Duration>,
+    /// Limits how fast connections are accepted. Connections over the limit are closed before
+    /// they are reported, and counted with [`ServerEvent::AcceptsRejected`].
+    pub accept_policy: AcceptPolicy,
+    /// See [`ServerDef::max_connections`]. This is [`DEFAULT_MAX_CONNECTIONS`] if it is not set.
+    ///
+    /// Accepted connections are registered in the fixed file table of the `io_uring`, which is
+    /// sized for the listeners, this many connections and [`FULL_CONNECTION_HEADROOM`] more.
+    pub max_connections: Option<usize>,
+    /// The options set on every listener and connection. See
+    /// [`ServerDef::new_multi_with_socket_opts`].
+    pub socket_opts: SocketOpts,
+    /// Emits [`ServerEvent::RecvBuffer`] instead of [`ServerEvent::RecvData`], so handlers can
+    /// hold onto the C2S buffers instead of copying out of them during the drain.
+    ///
+    /// There are only [`C2S_RING_BUFFER_COUNT`] buffers of [`C2S_RING_BUFFER_LEN`] bytes for every
+    /// connection together unless they are resized with [`ServerDef::resize_buffers`], and the
+    /// kernel cannot receive into the ones which are held. See [`RecvBuffer`].
+    pub zero_copy_recv: bool,
+    /// How [`ServerDef::submit_events`] waits for completions.
+    pub submit_strategy: SubmitStrategy,
+}
+
+/// How [`ServerDef::submit_events`] waits for completions after submitting, which trades CPU
+/// time for latency.
+///
+/// The time spent waiting is part of the tick, and the game loop sleeps that much less before
+/// the next one, so waiting does not slow down the tick rate as long as the timeout is well below
+/// the 50 ms of a tick. Whatever completes while waiting is handled by the next drain instead of
+/// the one after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitStrategy {
+    /// Submits, then busy-polls for a completion for at most `timeout` by entering the kernel
+    /// without sleeping, which posts the completions which are done. This is not "submit only":
+    /// it enters the kernel even with [`LinuxServerConfig::sqpoll`], since finished operations
+    /// are not posted otherwise. There is no wakeup latency, but the thread burns its core while
+    /// it polls, and completions which are not ready by the deadline wait for the next tick.
+    ///
+    /// This is for dedicated machines, where the cores would be idle otherwise.
+    Spin {
+        /// How long to poll at most.
+        timeout: Duration,
+    },
+    /// Submits and sleeps in the kernel until at least `min_complete` completions are ready or
+    /// `timeout` passed. The thread does not use any CPU while it sleeps, but it takes a wakeup
+    /// to continue once the completions are ready.
+    ///
+    /// This is for shared machines, where other processes can use the time.
+    Block {
+        /// How many completions to wait for, counting the ones which are already ready.
+        min_complete: usize,
+        /// How long to sleep at most.
+        timeout: Duration,
+    },
+    /// Submits, then polls for a completion for `spin` by entering the kernel without sleeping,
+    /// which posts the completions which are done. If none is ready by then, it sleeps like
+    /// [`SubmitStrategy::Block`] until one is, or `timeout` passed.
+    ///
+    /// Under load, a completion is usually ready within `spin`, so completions are handled about
+    /// as soon as with [`SubmitStrategy::Block`] but without the wakeup, while an idle server
+    /// only spins for `spin` each tick.
+    Adaptive { spin: Duration, timeout: Duration },
+}
+
+impl Default for SubmitStrategy {
+    fn default() -> Self {
+        Self::Spin {
+            timeout: DEFAULT_SPIN_TIMEOUT,
+        }
+    }
+}
+
+impl SubmitStrategy {
+    /// Whether the strategy sleeps in the kernel, which needs `IORING_FEAT_EXT_ARG` for the
+    /// timeout.
+    const fn blocks(self) -> bool {
+        !matches!(self, Self::Spin { .. })
+    }
+}
+
+/// A socket option which is set on every accepted connection. See
+/// [`LinuxServer::set_connection_opts`].
+struct ConnectionOpt {
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+}
+
+impl ConnectionOpt {
+    /// The options of `opts` which apply to each connection.
+    fn from_socket_opts(opts: &SocketOpts) -> anyhow::Result<Box<[Self]>> {
+        let mut connection_opts = vec![Self {
+            level: libc::IPPROTO_TCP,
+            name: libc::TCP_NODELAY,
+            value: libc::c_int::from(opts.nodelay),
+        }];
+
+        if let Some(size) = opts.send_buffer_size {
+            connection_opts.push(Self {
+                level: libc::SOL_SOCKET,
+                name: libc::SO_SNDBUF,
+                value: libc::c_int::try_from(size).context("send_buffer_size is too large")?,
+            });
+        }
+
+        if let Some(size) = opts.recv_buffer_size {
+            connection_opts.push(Self {
+                level: libc::SOL_SOCKET,
+                name: libc::SO_RCVBUF,
+                value: libc::c_int::try_from(size).context("recv_buffer_size is too large")?,
+            });
+        }
+
+        Ok(connection_opts.into_boxed_slice())
+    }
+}
+
+/// Where an accept writes the address of the peer.
+struct AcceptSlot {
+    addr: libc::sockaddr_storage,
+    len: libc::socklen_t,
+}
+
+impl AcceptSlot {
+    fn new() -> Self {
+        Self {
+            // SAFETY: sockaddr_storage is valid in the all-zero byte-pattern.
+            addr: unsafe { std::mem::zeroed() },
+            len: 0,
+        }
+    }
+
+    /// The address written by the last accept which used this slot.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        // SAFETY: the kernel wrote an address of `len` bytes
+        unsafe { SockAddr::new(self.addr, self.len) }.as_socket()
+    }
+}
+
+/// Closes connections once none of their writes are in flight anymore.
+///
+/// Entries refer to a connection by its slot in the fixed file table, which is only resolved to
+/// the socket when the entry is issued, and the kernel accepts the next connection into a slot as
+/// soon as it is closed. A hard link only orders a write after the entry pushed before it, so a
+/// write which is still queued when its connection is closed could otherwise be issued against
+/// the connection which got the slot next.
+struct Closes {
+    /// The number of writes in flight for each slot
+    writes: Box<[u32]>,
+    /// Slots which are closed once their last write completes
+    deferred: FxHashSet<Fixed>,
+    /// The number of closes which were submitted, but did not complete yet
+    in_flight: usize,
+}
+
+impl Closes {
+    fn new(slots: usize) -> Self {
+        Self {
+            writes: vec![0; slots].into_boxed_slice(),
+            deferred: FxHashSet::default(),
+            in_flight: 0,
+        }
+    }
+
+    fn write_submitted(&mut self, fd: Fixed) {
+        self.writes[fd.0 as usize] += 1;
+    }
+
+    /// Closes `fd` once its last write completed, which may be right away.
+    fn close(&mut self, submission: &mut SubmissionQueue, fd: Fixed) {
+        if self.writes[fd.0 as usize] == 0 {
+            self.submit(submission, fd);
+        } else {
+            self.deferred.insert(fd);
+        }
+    }
+
+    fn write_completed(&mut self, submission: &mut SubmissionQueue, fd: Fixed) {
+        let writes = &mut self.writes[fd.0 as usize];
+        *writes -= 1;
+
+        if *writes == 0 && self.deferred.remove(&fd) {
+            self.submit(submission, fd);
+        }
+    }
+
+    fn submit(&mut self, submission: &mut SubmissionQueue, fd: Fixed) {
+        self.in_flight += 1;
+        LinuxServer::close(submission, fd);
+    }
+
+    /// Whether every close has completed.
+    fn is_done(&self) -> bool {
+        self.in_flight == 0 && self.deferred.is_empty()
+    }
+}
+
 pub struct LinuxServer {
     #[expect(dead_code, reason = "this is used so there is no drop")]
-    listener: Socket,
+    listeners: Vec<Socket>,
+    local_addrs: Vec<SocketAddr>,
 
     uring: IoUring,
 
@@ -119,68 +438,226 @@ pub struct LinuxServer {
     /// buffers while a reference to the data is held, which would cause undefined behavior. In
     /// addition, this field must be declared after uring so that the uring is dropped first. This
     /// is needed because registered buffers must be valid until unregistered or the uring is dropped.
-    c2s_buffer: Vec<[u8; C2S_RING_BUFFER_LEN]>,
+    ///
+    /// [`RecvBuffer`]s keep the pool alive, so buffers which are held stay valid after the server
+    /// is dropped.
+    c2s_buffer: Arc<RecvBufferPool>,
 
     /// This field must be declared after uring so that the uring is dropped first. This
     /// is needed because registered buffers must be valid until unregistered or the uring is dropped.
     c2s_buffer_entries: PageAlignedMemory<BufRingEntry>,
 
-    /// Value of `c2s_buffer_entries` tail, which is synched occasionally with the kernel
+    /// Value of `c2s_buffer_entries` tail, which is synched with the kernel at the end of every
+    /// drain. See [`LinuxServer::recycle_c2s_buffer`].
     c2s_local_tail: u16,
 
+    /// The `user_data` of every multishot recv which may still complete, including those of
+    /// connections which were closed since. See [`ServerDef::resize_buffers`].
+    armed_recvs: FxHashSet<u64>,
+
+    /// Whether recvs are requested for new connections and for recvs which stopped, which is
+    /// paused while the C2S buffers are resized
+    recvs_paused: bool,
+
+    /// See [`LinuxServerConfig::zero_copy_recv`].
+    zero_copy_recv: bool,
+
+    /// See [`LinuxServerConfig::submit_strategy`].
+    submit_strategy: SubmitStrategy,
+
     pending_writes: usize,
 
+    /// The number of S2C buffers which are registered with the uring
+    registered_buffers: usize,
+
+    /// The number of completions the kernel reported as dropped the last time the completion
+    /// queue was drained
+    dropped_completions: u32,
+
+    /// Whether a kernel thread polls the submission queue
+    sqpoll: bool,
+
+    /// All fds which have been accepted and not yet closed, with the address of their peer
+    connections: FxHashMap<Fixed, Option<SocketAddr>>,
+
+    /// The generation of the connection which was accepted into each slot of the fixed file
+    /// table last, which wraps at [`GENERATION_MASK`]. See [`Fd`].
+    generations: Box<[u32]>,
+
+    /// Fds closed by [`ServerDef::close_after_send`] which are reported as removed on the next
+    /// drain
+    closed: Vec<Fd>,
+
+    /// See [`Closes`].
+    closes: Closes,
+
+    /// `ACCEPTS_PER_LISTENER` slots for each listener, which in-flight accepts write to. This
+    /// field must be declared after uring so that the uring is dropped first.
+    accept_slots: Box<[AcceptSlot]>,
+
+    accept_limiter: AcceptLimiter,
+
+    /// The options set on every accepted connection, which in-flight setsockopts read from. This
+    /// field must be declared after uring so that the uring is dropped first.
+    connection_opts: Box<[ConnectionOpt]>,
+
+    /// Whether setting the options of a connection failed before, so it is only logged once
+    connection_opts_failed: bool,
+
+    /// Whether accepts are requested again once they complete, which stops once the server shuts
+    /// down
+    accepting: bool,
+
+    /// See [`ServerDef::max_connections`].
+    max_connections: usize,
+
+    /// The S2C buffers registered with the uring, which are registered again if replacing them
+    /// fails. This field must be declared after uring so that the uring is dropped first.
+    s2c_buffers: Option<Arc<BufferPool>>,
+
     /// Make Listener !Send and !Sync to let `io_uring` assume that it'll only be accessed by 1
     /// thread
     phantom: PhantomData<*const ()>,
 }
 
-impl ServerDef for LinuxServer {
-    fn new(address: impl ToSocketAddrs) -> anyhow::Result<Self> {
+impl LinuxServer {
+    pub fn new_with_config(
+        address: impl ToSocketAddrs,
+        config: LinuxServerConfig,
+    ) -> anyhow::Result<Self> {
         let Some(address) = address.to_socket_addrs()?.next() else {
             anyhow::bail!("no addresses specified")
         };
-        let domain = match address {
-            SocketAddr::V4(_) => socket2::Domain::IPV4,
-            SocketAddr::V6(_) => socket2::Domain::IPV6,
+
+        Self::new_multi_with_config(&[address], config)
+    }
+
+    pub fn new_multi_with_config(
+        addresses: &[SocketAddr],
+        config: LinuxServerConfig,
+    ) -> anyhow::Result<Self> {
+        let LinuxServerConfig {
+            sqpoll,
+            accept_policy,
+            max_connections,
+            socket_opts,
+            zero_copy_recv,
+            submit_strategy,
+        } = config;
+
+        let listeners = bind_listeners(addresses, &socket_opts)?;
+        let local_addrs = local_addrs(&listeners)?;
+        let connection_opts = ConnectionOpt::from_socket_opts(&socket_opts)?;
+
+        let (mut uring, sqpoll) = build_uring(sqpoll)?;
+
+        let submit_strategy = if submit_strategy.blocks() && !uring.params().is_feature_ext_arg() {
+            warn!(
+                "the kernel cannot time out waits for io_uring completions (this needs Linux \
+                 5.11), falling back to SubmitStrategy::Spin"
+            );
+            SubmitStrategy::Spin {
+                timeout: DEFAULT_SPIN_TIMEOUT,
+            }
+        } else {
+            submit_strategy
         };
 
-        let listener = Socket::new(domain, socket2::Type::STREAM, None)?;
-        listener.set_nonblocking(true)?;
-        // listener.set_send_buffer_size(SEND_BUFFER_SIZE)?;
-        listener.bind(&address.into())?;
-        listener.listen(LISTEN_BACKLOG)?;
-
-        // TODO: Try to use defer taskrun
-        let mut uring = IoUring::builder()
-            .setup_cqsize(COMPLETION_QUEUE_SIZE)
-            .setup_submit_all()
-            .setup_coop_taskrun()
-            .setup_single_issuer()
-            .build(SUBMISSION_QUEUE_SIZE)
-            .unwrap();
+        let max_connections = max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        let file_count = listeners.len() + max_connections + FULL_CONNECTION_HEADROOM;
+        let file_count = u32::try_from(file_count).context("max_connections is too large")?;
 
         let submitter = uring.submitter();
-        submitter.register_files_sparse(IO_URING_FILE_COUNT)?;
+        submitter
+            .register_files_sparse(file_count)
+            .with_context(|| format!("failed to register a fixed file table of {file_count}"))?;
+
+        let listener_fds: Vec<RawFd> = listeners.iter().map(AsRawFd::as_raw_fd).collect();
         assert_eq!(
-            submitter.register_files_update(LISTENER_FIXED_FD.0, &[listener.as_raw_fd()])?,
-            1
+            submitter.register_files_update(0, &listener_fds)?,
+            listener_fds.len()
         );
 
-        // Create the c2s buffer
-        let c2s_buffer = vec![[0u8; C2S_RING_BUFFER_LEN]; C2S_RING_BUFFER_COUNT];
-        let c2s_buffer_entries = PageAlignedMemory::from_iter(c2s_buffer.iter().enumerate().map(
-            |(buffer_id, buffer)| {
-                // SAFETY: BufRingEntry is valid in the all-zero byte-pattern.
-                let mut entry = unsafe { std::mem::zeroed::<BufRingEntry>() };
-                entry.set_addr(buffer.as_ptr() as u64);
-                entry.set_len(buffer.len() as u32);
-                entry.set_bid(buffer_id as u16);
-                entry
-            },
-        ));
+        let (c2s_buffer, c2s_buffer_entries, tail) =
+            Self::register_c2s_ring(&uring, C2S_RING_BUFFER_COUNT)?;
+
+        let mut accept_slots: Box<[AcceptSlot]> = (0..listeners.len() * ACCEPTS_PER_LISTENER)
+            .map(|_| AcceptSlot::new())
+            .collect();
+
+        for slot in 0..accept_slots.len() {
+            Self::request_accept(&mut uring.submission(), &mut accept_slots, slot);
+        }
+
+        info!("listening on {local_addrs:?}");
+
+        Ok(Self {
+            listeners,
+            local_addrs,
+            uring,
+            c2s_buffer,
+            c2s_buffer_entries,
+            c2s_local_tail: tail,
+            armed_recvs: FxHashSet::default(),
+            recvs_paused: false,
+            zero_copy_recv,
+            submit_strategy,
+            pending_writes: 0,
+            registered_buffers: 0,
+            dropped_completions: 0,
+            sqpoll,
+            connections: FxHashMap::default(),
+            generations: vec![0; file_count as usize].into_boxed_slice(),
+            closed: Vec::new(),
+            closes: Closes::new(file_count as usize),
+            accept_slots,
+            accept_limiter: AcceptLimiter::new(accept_policy),
+            connection_opts,
+            connection_opts_failed: false,
+            accepting: true,
+            max_connections,
+            s2c_buffers: None,
+            phantom: PhantomData,
+        })
+    }
 
-        let tail = C2S_RING_BUFFER_COUNT as u16;
+    /// The fd of the `io_uring`, which becomes readable once completions are ready. It can be
+    /// added to an external epoll or poll set, like the reactor of a Tokio runtime, to wait for
+    /// network events instead of draining every tick.
+    #[must_use]
+    pub fn registration_fd(&self) -> RawFd {
+        self.uring.as_raw_fd()
+    }
+
+    /// Handles the completions which are already in the completion queue, without entering the
+    /// kernel or waiting for more. Call this once [`LinuxServer::registration_fd`] is readable.
+    ///
+    /// New submissions are not flushed, so [`ServerDef::submit_events`] still has to be called
+    /// after queueing writes.
+    pub fn drain_ready(&mut self, f: impl FnMut(ServerEvent)) -> std::io::Result<()> {
+        // `drain` only reads the completion queue, so it never blocks
+        ServerDef::drain(self, f)
+    }
+}
+
+impl LinuxServer {
+    /// Creates `count` C2S buffers, provides all of them to the kernel in a new ring, and
+    /// returns them with the ring and its tail.
+    fn register_c2s_ring(
+        uring: &IoUring,
+        count: usize,
+    ) -> std::io::Result<(Arc<RecvBufferPool>, PageAlignedMemory<BufRingEntry>, u16)> {
+        let c2s_buffer = Arc::new(RecvBufferPool::new(count, C2S_RING_BUFFER_LEN));
+        let c2s_buffer_entries = PageAlignedMemory::from_iter((0..count as u16).map(|buffer_id| {
+            // SAFETY: BufRingEntry is valid in the all-zero byte-pattern.
+            let mut entry = unsafe { std::mem::zeroed::<BufRingEntry>() };
+            entry.set_addr(c2s_buffer.buffer_ptr(buffer_id) as u64);
+            entry.set_len(C2S_RING_BUFFER_LEN as u32);
+            entry.set_bid(buffer_id);
+            entry
+        }));
+
+        let tail = count as u16;
 
         // Update the tail
         // SAFETY: This is the first entry of the buffer ring
@@ -193,47 +670,201 @@ impl ServerDef for LinuxServer {
         }
 
         // Register the buffer ring
-        // SAFETY: c2s_buffer_entries is valid to write to for C2S_RING_BUFFER_COUNT BufRingEntry structs
+        // SAFETY: c2s_buffer_entries is valid to write to for `count` BufRingEntry structs, and
+        // is kept alive until the ring is unregistered or the uring is dropped
         unsafe {
-            submitter.register_buf_ring(
+            uring.submitter().register_buf_ring(
                 c2s_buffer_entries.data as u64,
-                C2S_RING_BUFFER_COUNT as u16,
+                count as u16,
                 C2S_BUFFER_GROUP_ID,
             )?;
         }
 
-        Self::request_accept(&mut uring.submission());
+        Ok((c2s_buffer, c2s_buffer_entries, tail))
+    }
 
-        Ok(Self {
-            listener,
-            uring,
-            c2s_buffer,
-            c2s_buffer_entries,
-            c2s_local_tail: tail,
-            pending_writes: 0,
-            phantom: PhantomData,
-        })
+    /// Cancels every recv and drains until all of them completed, so the kernel does not pick
+    /// buffers from the C2S ring anymore. The recvs must be paused, or they would be requested
+    /// again as they complete.
+    fn cancel_recvs(&mut self, mut f: impl FnMut(ServerEvent)) -> anyhow::Result<()> {
+        debug_assert!(self.recvs_paused);
+
+        // recvs which were requested since the last submit can only be cancelled once the
+        // kernel has them
+        self.uring.submit()?;
+
+        let armed: Vec<u64> = self.armed_recvs.iter().copied().collect();
+        for user_data in armed {
+            self.cancel(CancelBuilder::user_data(user_data))
+                .context("failed to cancel the recvs")?;
+        }
+
+        // a cancelled recv still posts a completion, after those it posted before, whose data
+        // is passed to `f` like on every drain
+        while !self.armed_recvs.is_empty() {
+            self.uring.submit_and_wait(1)?;
+            self.drain(&mut f)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the C2S ring with one of `count` buffers. No recv may be in flight. If the new
+    /// ring cannot be registered, a ring of the old size is registered instead.
+    fn replace_c2s_ring(&mut self, count: usize) -> anyhow::Result<()> {
+        self.uring
+            .submitter()
+            .unregister_buf_ring(C2S_BUFFER_GROUP_ID)
+            .context("failed to unregister the C2S buffers")?;
+
+        let (result, (buffer, entries, tail)) = match Self::register_c2s_ring(&self.uring, count) {
+            Ok(ring) => (Ok(()), ring),
+            Err(err) => {
+                // held `RecvBuffer`s keep the old buffers alive, so they cannot be provided
+                // again, but the connections still need buffers to receive into
+                let ring = Self::register_c2s_ring(&self.uring, self.c2s_buffer.count())
+                    .context("failed to register C2S buffers of the old size again")?;
+                let err = anyhow::Error::from(err).context("failed to register the C2S buffers");
+                (Err(err), ring)
+            }
+        };
+
+        // the old entries are not used by the kernel anymore, and the old buffers are freed
+        // once every `RecvBuffer` which refers to them was dropped
+        self.c2s_buffer = buffer;
+        self.c2s_buffer_entries = entries;
+        self.c2s_local_tail = tail;
+
+        result
+    }
+
+    /// Requests a recv for every connection which does not have one in flight.
+    fn resume_recvs(&mut self) {
+        let mut submission = self.uring.submission();
+
+        for &fd in self.connections.keys() {
+            let id = fd_in(&self.generations, fd);
+
+            if !self.armed_recvs.contains(&user_data(RECV_MARKER, id)) {
+                Self::request_recv(&mut submission, &mut self.armed_recvs, id);
+            }
+        }
+    }
+
+    /// Gives the C2S buffer `buffer_id` back to the kernel once its data has been handled, or its
+    /// [`RecvBuffer`] was dropped.
+    ///
+    /// Completions do not necessarily arrive in the order the kernel picked the buffers, so the
+    /// buffer is written to the slot after the tail instead of assuming that slot already refers
+    /// to it. The kernel only sees it after the tail is published at the end of the drain.
+    ///
+    /// This takes the fields it needs since the uring is borrowed while draining.
+    fn recycle_c2s_buffer(
+        entries: &PageAlignedMemory<BufRingEntry>,
+        buffers: &RecvBufferPool,
+        tail: &mut u16,
+        buffer_id: u16,
+    ) {
+        let index = usize::from(*tail) & (buffers.count() - 1);
+
+        // SAFETY: `index` is in bounds of the entries, and the kernel does not read the entry
+        // until the tail is published. This does not touch the tail, which overlaps the reserved
+        // field of the first entry.
+        let entry = unsafe { &mut *entries.data.add(index) };
+        entry.set_addr(buffers.buffer_ptr(buffer_id) as u64);
+        entry.set_len(C2S_RING_BUFFER_LEN as u32);
+        entry.set_bid(buffer_id);
+
+        *tail = tail.wrapping_add(1);
+    }
+}
+
+impl ServerDef for LinuxServer {
+    fn new_multi_with_socket_opts(
+        addresses: &[SocketAddr],
+        opts: &SocketOpts,
+    ) -> anyhow::Result<Self> {
+        let config = LinuxServerConfig {
+            socket_opts: opts.clone(),
+            ..LinuxServerConfig::default()
+        };
+
+        Self::new_multi_with_config(addresses, config)
+    }
+
+    fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    fn drain(&mut self, f: impl FnMut(ServerEvent)) -> std::io::Result<()> {
+        self.drain_budget(usize::MAX, f).map(|_| ())
     }
 
     /// `f` should never panic
-    #[instrument(skip_all, level = "trace", name = "iou-drain-events")]
-    fn drain(&mut self, mut f: impl FnMut(ServerEvent)) -> std::io::Result<()> {
+    #[instrument(
+        skip_all,
+        level = "trace",
+        name = "iou-drain-events",
+        fields(core = core_index(), completions = field::Empty)
+    )]
+    fn drain_budget(
+        &mut self,
+        max_events: usize,
+        mut f: impl FnMut(ServerEvent),
+    ) -> std::io::Result<bool> {
         let (_submitter, mut submission, mut completion) = self.uring.split();
         completion.sync();
-        if completion.overflow() > 0 {
-            error!(
-                "the io_uring completion queue overflowed, and some connection errors are likely \
-                 to occur; consider increasing COMPLETION_QUEUE_SIZE to avoid this"
+
+        // the kernel counts every completion it ever dropped
+        let dropped_completions = completion.overflow();
+        if dropped_completions != self.dropped_completions {
+            let dropped = dropped_completions.wrapping_sub(self.dropped_completions);
+            self.dropped_completions = dropped_completions;
+
+            warn!(
+                "the io_uring completion queue overflowed and {dropped} completions were lost; \
+                 consider increasing COMPLETION_QUEUE_SIZE or accepting fewer connections"
             );
+            f(ServerEvent::CompletionOverflow { dropped });
+        }
+
+        if submission.cq_overflow() {
+            // these were not lost, and are flushed by the kernel on the next submit
+            debug!("the io_uring completion queue is full, so completions are backlogged");
         }
 
-        for event in completion {
+        let mut completions = 0_usize;
+
+        // completions over the budget stay in the queue, so the next drain starts with them
+        for event in completion.by_ref().take(max_events) {
+            completions += 1;
+
             let result = event.result();
             match event.user_data() {
-                0 => {
-                    if event.flags() & IORING_CQE_F_MORE == 0 {
-                        warn!("multishot accept rerequested");
-                        Self::request_accept(&mut submission);
+                accept if accept & ACCEPT_MARKER != 0 => {
+                    let slot = (accept & !ACCEPT_MARKER) as usize;
+                    let peer_addr = self.accept_slots[slot].peer_addr();
+
+                    if !self.accepting {
+                        // the server is shutting down, so nothing is accepted anymore
+                        if result >= 0 {
+                            #[expect(clippy::cast_sign_loss, reason = "we are checking if < 0")]
+                            let fd = Fixed(result as u32);
+                            self.closes.close(&mut submission, fd);
+                        }
+                        continue;
+                    }
+
+                    // the address has been read, so the slot can be reused
+                    Self::request_accept(&mut submission, &mut self.accept_slots, slot);
+
+                    // the fixed file table is full, so the kernel closed the connection
+                    if result == -libc::ENFILE {
+                        trace!(
+                            "closing connection from {peer_addr:?} because the file table is full"
+                        );
+                        self.accept_limiter.record_rejected();
+                        continue;
                     }
 
                     if result < 0 {
@@ -241,30 +872,112 @@ impl ServerDef for LinuxServer {
                         continue;
                     }
 
+                    if peer_addr.is_none() {
+                        warn!("accept did not report the address of the peer");
+                    }
+
                     #[expect(clippy::cast_sign_loss, reason = "we are checking if < 0")]
                     let fd = Fixed(result as u32);
-                    Self::request_recv(&mut submission, fd);
-                    f(ServerEvent::AddPlayer { fd: Fd(fd) });
+
+                    if !self
+                        .accept_limiter
+                        .allow(peer_addr.map(|addr| addr.ip()), Instant::now())
+                    {
+                        trace!("closing connection from {peer_addr:?} over the accept policy");
+                        self.closes.close(&mut submission, fd);
+                        continue;
+                    }
+
+                    if self.connections.len() >= self.max_connections + FULL_CONNECTION_HEADROOM {
+                        trace!("closing connection from {peer_addr:?} because the server is full");
+                        self.accept_limiter.record_rejected();
+                        self.closes.close(&mut submission, fd);
+                        continue;
+                    }
+
+                    // the slot may have belonged to a connection which was closed before
+                    let generation = &mut self.generations[fd.0 as usize];
+                    *generation = generation.wrapping_add(1) & GENERATION_MASK;
+                    let id = fd_in(&self.generations, fd);
+
+                    self.connections.insert(fd, peer_addr);
+                    Self::set_connection_opts(&mut submission, &self.connection_opts, fd);
+
+                    // otherwise the recv is requested once the C2S buffers were resized
+                    if !self.recvs_paused {
+                        Self::request_recv(&mut submission, &mut self.armed_recvs, id);
+                    }
+                    f(ServerEvent::AddPlayer {
+                        fd: id,
+                        listener: slot / ACCEPTS_PER_LISTENER,
+                    });
                 }
-                1 => {
+                sockopt if sockopt & SOCKOPT_MARKER != 0 => {
+                    if result < 0 && !self.connection_opts_failed {
+                        self.connection_opts_failed = true;
+
+                        let fd = Fixed((sockopt & !SOCKOPT_MARKER) as u32);
+                        let err = std::io::Error::from_raw_os_error(-result);
+                        warn!(
+                            "failed to set the socket options of {fd:?}, so connections only have \
+                             the options they inherit from the listener (this needs Linux 6.7): \
+                             {err}"
+                        );
+                    }
+                }
+                close if close & CLOSE_MARKER != 0 => {
+                    self.closes.in_flight -= 1;
+
                     if result < 0 {
                         error!("there was an error in socket close: {}", result);
                     }
                 }
                 write if write & SEND_MARKER != 0 => {
-                    let fd = Fixed((write & !SEND_MARKER) as u32);
+                    // the connection may have been closed, and its slot reused, since the write
+                    // was submitted, so events are for the connection which submitted it
+                    let id = fd_of(write & !SEND_MARKER);
+                    let fd = id.0;
 
                     self.pending_writes -= 1;
+                    self.closes.write_completed(&mut submission, fd);
 
                     match result.cmp(&0) {
                         cmp::Ordering::Less => {
-                            error!("there was an error in write: {}", result);
-                            // Nothing is done here. It's assumed that if there is a write error,
-                            // read will error too, and all of the error handling occurs in read.
-                            // This code intentionally does not shutdown nor close the socket
-                            // because read may close the socket before this does, and if this code
-                            // closes the socket afterwards, it could close another player's
-                            // socket.
+                            let lost =
+                                matches!(-result, libc::EPIPE | libc::EBADF | libc::ECONNRESET);
+
+                            if lost {
+                                trace!("player {fd:?} disconnected during write (code {result})");
+                            } else {
+                                error!("there was an error in write: {}", result);
+                            }
+
+                            f(ServerEvent::Error {
+                                fd: id,
+                                error: std::io::Error::from_raw_os_error(-result),
+                            });
+
+                            // the kernel is done with the buffer even though nothing was sent, so
+                            // the write is released like a successful one. otherwise
+                            // `Packets::number_sending` never reaches zero and the ring region
+                            // stays in flight until the player is removed
+                            f(ServerEvent::SentData { fd: id });
+
+                            // the peer is gone, which read may not notice for a while if the fd
+                            // was not readable. if read already removed this fd, it is not closed
+                            // again, since its slot may belong to another player by then. the recv
+                            // of a removed fd is ignored, so the player is not removed twice. the
+                            // other writes of the fd may still be in flight, so the close waits
+                            // for them
+                            if lost && is_current(&self.connections, &self.generations, id) {
+                                self.connections.remove(&fd);
+
+                                // EBADF means the slot is already closed
+                                if result != -libc::EBADF {
+                                    self.closes.close(&mut submission, fd);
+                                }
+                                f(ServerEvent::RemovePlayer { fd: id });
+                            }
                         }
                         cmp::Ordering::Equal => {
                             // This should never happen as long as write is never passed an empty buffer:
@@ -276,14 +989,35 @@ impl ServerDef for LinuxServer {
                             // TODO: Check that write wasn't truncated
                             trace!("successful write response");
 
-                            f(ServerEvent::SentData { fd: Fd(fd) });
+                            f(ServerEvent::SentData { fd: id });
                         }
                     }
                 }
                 read if read & RECV_MARKER != 0 => {
-                    let fd = Fixed((read & !RECV_MARKER) as u32);
+                    let id = fd_of(read & !RECV_MARKER);
+                    let fd = id.0;
                     let more = event.flags() & IORING_CQE_F_MORE != 0;
 
+                    if !more {
+                        self.armed_recvs.remove(&read);
+                    }
+
+                    if !is_current(&self.connections, &self.generations, id) {
+                        // closing a fixed file does not cancel its multishot recv, which keeps
+                        // completing until the peer hangs up, and by then the slot may belong to
+                        // another connection. the data is dropped, but its buffer still has to be
+                        // given back
+                        if let Some(buffer_id) = buffer_select(event.flags()) {
+                            Self::recycle_c2s_buffer(
+                                &self.c2s_buffer_entries,
+                                &self.c2s_buffer,
+                                &mut self.c2s_local_tail,
+                                buffer_id,
+                            );
+                        }
+                        continue;
+                    }
+
                     if result == -libc::ECONNRESET || result == -libc::ETIMEDOUT || result == 0 {
                         trace!("player {fd:?} disconnected during recv (code {result})");
 
@@ -293,16 +1027,25 @@ impl ServerDef for LinuxServer {
                              check is needed to avoid removing the same player multiple times"
                         );
 
-                        f(ServerEvent::RemovePlayer { fd: Fd(fd) });
-                        Self::close(&mut submission, fd);
+                        // EOF is a clean disconnect
+                        if result != 0 {
+                            f(ServerEvent::Error {
+                                fd: id,
+                                error: std::io::Error::from_raw_os_error(-result),
+                            });
+                        }
+
+                        f(ServerEvent::RemovePlayer { fd: id });
+                        self.connections.remove(&fd);
+                        self.closes.close(&mut submission, fd);
                     } else {
                         // The player is not getting disconnected, but there still may be errors
 
-                        if !more {
+                        if !more && !self.recvs_paused {
                             // No more completion events will occur from this multishot recv. This
                             // will need to request another multishot recv.
                             warn!("socket recv rerequested");
-                            Self::request_recv(&mut submission, fd);
+                            Self::request_recv(&mut submission, &mut self.armed_recvs, id);
                         }
 
                         if result > 0 {
@@ -310,26 +1053,50 @@ impl ServerDef for LinuxServer {
                             let bytes_received = result as usize;
                             let buffer_id =
                                 buffer_select(event.flags()).expect("there should be a buffer");
-                            assert!((buffer_id as usize) < C2S_RING_BUFFER_COUNT);
-                            // SAFETY: as_mut_ptr doesn't take a reference to the slice in c2s_buffer.
-                            // buffer_id is in bounds of c2s_buffer, so all the
-                            // safety requirements for add is met.
-                            let buffer_ptr =
-                                unsafe { self.c2s_buffer.as_mut_ptr().add(buffer_id as usize) };
-                            // SAFETY: buffer_id is in bounds, so buffer_ptr is valid
-                            let buffer = unsafe { &(*buffer_ptr)[..bytes_received] };
-                            self.c2s_local_tail = self.c2s_local_tail.wrapping_add(1);
-                            f(ServerEvent::RecvData {
-                                fd: Fd(fd),
-                                data: buffer,
-                            });
+                            assert!((buffer_id as usize) < self.c2s_buffer.count());
+
+                            if self.zero_copy_recv {
+                                // SAFETY: the kernel wrote `bytes_received` bytes into the buffer,
+                                // and it is only recycled once the `RecvBuffer` was dropped
+                                let buffer = unsafe {
+                                    RecvBuffer::new(
+                                        self.c2s_buffer.clone(),
+                                        buffer_id,
+                                        bytes_received,
+                                    )
+                                };
+                                f(ServerEvent::RecvBuffer { fd: id, buffer });
+                            } else {
+                                // SAFETY: the buffer is only recycled after the slice is dropped
+                                let buffer =
+                                    unsafe { self.c2s_buffer.slice(buffer_id, bytes_received) };
+                                f(ServerEvent::RecvData {
+                                    fd: id,
+                                    data: buffer,
+                                });
+
+                                // the handler copied everything it needs out of the buffer
+                                Self::recycle_c2s_buffer(
+                                    &self.c2s_buffer_entries,
+                                    &self.c2s_buffer,
+                                    &mut self.c2s_local_tail,
+                                    buffer_id,
+                                );
+                            }
+                        } else if result == -libc::ECANCELED {
+                            // cancelled by `resize_buffers`, which requests it again
                         } else if result == -libc::ENOBUFS {
                             warn!(
                                 "ran out of c2s buffers which will negatively impact performance; \
-                                 consider increasing C2S_RING_BUFFER_COUNT"
+                                 consider resizing them with ServerDef::resize_buffers or \
+                                 dropping RecvBuffers sooner"
                             );
                         } else {
                             error!("unhandled recv error: {result}");
+                            f(ServerEvent::Error {
+                                fd: id,
+                                error: std::io::Error::from_raw_os_error(-result),
+                            });
                         }
                     }
                 }
@@ -339,59 +1106,389 @@ impl ServerDef for LinuxServer {
             }
         }
 
+        Span::current().record("completions", completions);
+
+        let remaining = !completion.is_empty();
+        // publishes the new head, so the kernel can reuse the slots of the handled completions
+        drop(completion);
+
+        let rejected = self.accept_limiter.take_rejected();
+        if rejected != 0 {
+            f(ServerEvent::AcceptsRejected { count: rejected });
+        }
+
+        for fd in self.closed.drain(..) {
+            f(ServerEvent::RemovePlayer { fd });
+        }
+
+        // buffers whose `RecvBuffer` was dropped since the last drain
+        for buffer_id in self.c2s_buffer.take_returned() {
+            Self::recycle_c2s_buffer(
+                &self.c2s_buffer_entries,
+                &self.c2s_buffer,
+                &mut self.c2s_local_tail,
+                buffer_id,
+            );
+        }
+
         // SAFETY: This is the first entry of the buffer ring
         let tail_addr = unsafe { BufRingEntry::tail(self.c2s_buffer_entries.data) };
         // Casting it into an atomic is needed since the kernel is also reading the tail
         let tail_addr: *const AtomicU16 = tail_addr.cast();
-        // SAFETY: tail_addr is valid
+        // SAFETY: tail_addr is valid. Release makes the recycled entries visible to the kernel
+        // before the new tail.
         unsafe {
-            (*tail_addr).store(self.c2s_local_tail, Ordering::Relaxed);
+            (*tail_addr).store(self.c2s_local_tail, Ordering::Release);
         }
 
+        Ok(remaining)
+    }
+
+    fn allocate_buffers(&mut self, pool: Arc<BufferPool>) -> anyhow::Result<()> {
+        // SAFETY: the pool is kept alive until after the uring is dropped
+        unsafe { self.allocate_buffers_raw(&pool.iovecs())? };
+        self.s2c_buffers = Some(pool);
         Ok(())
     }
 
+    /// Registers `buffers` as io_uring fixed buffers. Writes refer to a buffer by the index of
+    /// the core which encoded them, so at least one buffer is needed for every
+    /// [`rayon_local::RayonLocal`] slot (`rayon::current_num_threads() + 1`). io_uring allows at
+    /// most 16384 buffers of at most 1 GiB each.
     #[instrument(skip_all, level = "trace", name = "iou-allocate-buffers")]
-    fn allocate_buffers(&mut self, buffers: &[iovec]) {
+    unsafe fn allocate_buffers_raw(&mut self, buffers: &[iovec]) -> anyhow::Result<()> {
         info!("allocating buffers");
-        unsafe { self.register_buffers(buffers) };
+        unsafe { self.register_buffers(buffers) }.context("failed to register buffers")?;
+        self.registered_buffers = buffers.len();
         info!("finished allocating buffers");
+        Ok(())
+    }
+
+    /// If the new buffers cannot be registered, the old ones are registered again, unless they
+    /// were registered with [`ServerDef::allocate_buffers_raw`], since the server does not know
+    /// whether they are still valid.
+    #[instrument(skip_all, level = "trace", name = "iou-replace-s2c-buffers")]
+    fn replace_s2c_buffers(&mut self, pool: Arc<BufferPool>) -> anyhow::Result<()> {
+        let new_buffers = pool.iovecs();
+        validate_resize(self.registered_buffers, &new_buffers)?;
+
+        // a write in flight may still read from the old buffers, which are freed once the rings
+        // were moved
+        ensure!(
+            self.pending_writes == 0,
+            "{} writes are still in flight, so the buffers cannot be replaced until they complete",
+            self.pending_writes
+        );
+
+        if self.registered_buffers != 0 {
+            self.uring
+                .submitter()
+                .unregister_buffers()
+                .context("failed to unregister the old buffers")?;
+            self.registered_buffers = 0;
+        }
+
+        // SAFETY: the pool is kept alive until after the uring is dropped or the buffers are
+        // replaced again
+        if let Err(err) = unsafe { self.register_buffers(&new_buffers) } {
+            // the rings still write from the old buffers, which need to be registered for that
+            if let Some(old_buffers) = self.s2c_buffers.as_ref().map(|pool| pool.iovecs()) {
+                // SAFETY: the old pool is still kept alive
+                unsafe { self.register_buffers(&old_buffers) }
+                    .context("failed to register the old buffers again")?;
+                self.registered_buffers = old_buffers.len();
+            }
+
+            return Err(err).context("failed to register the new buffers");
+        }
+
+        self.registered_buffers = new_buffers.len();
+        self.s2c_buffers = Some(pool);
+
+        info!("replaced the S2C buffers with {}", new_buffers.len());
+        Ok(())
+    }
+
+    #[instrument(skip_all, level = "trace", name = "iou-resize-buffers")]
+    fn resize_buffers(&mut self, count: usize, f: impl FnMut(ServerEvent)) -> anyhow::Result<()> {
+        ensure!(
+            count.is_power_of_two() && count <= MAX_C2S_RING_BUFFER_COUNT,
+            "{count} C2S buffers are not a power of two of at most {MAX_C2S_RING_BUFFER_COUNT}"
+        );
+
+        self.recvs_paused = true;
+        let result = self
+            .cancel_recvs(f)
+            .and_then(|()| self.replace_c2s_ring(count));
+        self.recvs_paused = false;
+
+        // the connections get their recv back even if the ring was not replaced
+        self.resume_recvs();
+
+        if result.is_ok() {
+            info!("resized the C2S buffers to {count}");
+        }
+
+        result
     }
 
     /// Impl with local sends BEFORE broadcasting
-    #[instrument(skip_all, level = "trace", name = "iou-write-all")]
+    #[instrument(
+        skip_all,
+        level = "trace",
+        name = "iou-write-all",
+        fields(
+            core = core_index(),
+            fds = field::Empty,
+            writes = field::Empty,
+            bytes = field::Empty
+        )
+    )]
     fn write_all<'a>(
         &mut self,
-        _global: &mut Global,
+        global: &mut Global,
         writers: impl Iterator<Item = RefreshItems<'a>>,
-    ) {
+    ) -> FlushResult {
+        let mut fds = 0_usize;
+        let mut writes = 0_usize;
+        let mut bytes = 0_usize;
+
         writers.for_each(|item| {
             let RefreshItems { write, fd } = item;
 
-            let fd = fd.0;
+            // the slot may belong to another connection by now
+            if !self.is_connected(fd) {
+                warn!("no connection for fd {fd:?}");
+                return;
+            }
+
+            let writes_before = writes;
 
             for (idx, buf) in write.iter_mut().enumerate() {
-                for elem in buf.iter() {
-                    let PacketWriteInfo { start_ptr, len } = *elem;
+                global.drain_writes(fd, buf, |elem| {
+                    let PacketWriteInfo { start_ptr, len, .. } = elem;
+                    writes += 1;
+                    bytes += len as usize;
                     self.write_raw(fd, start_ptr, len, idx as u16);
-                }
-                buf.clear();
+                });
+            }
+
+            if writes != writes_before {
+                fds += 1;
             }
         });
-    }
 
-    #[instrument(skip_all, level = "trace", name = "iou-submit-events")]
-    fn submit_events(&mut self) {
-        if let Err(err) = self.uring.submit() {
-            error!("unexpected io_uring error during submit: {err}");
+        let span = Span::current();
+        span.record("fds", fds);
+        span.record("writes", writes);
+        span.record("bytes", bytes);
+
+        FlushResult {
+            fds_written: fds,
+            bytes_submitted: bytes,
+            iovecs: writes,
         }
     }
-}
 
-const RECV_MARKER: u64 = 0b1 << 63;
+    fn close_after_send(&mut self, fd: Fd) {
+        if !self.is_connected(fd) {
+            warn!("tried to close {fd:?} which is not connected");
+            return;
+        }
+
+        self.connections.remove(&fd.0);
+
+        // the writes pushed by `write_all` are sent before the connection is closed
+        self.closes.close(&mut self.uring.submission(), fd.0);
+        self.closed.push(fd);
+    }
+
+    fn connected_fds(&self) -> impl Iterator<Item = Fd> + '_ {
+        self.connections
+            .keys()
+            .map(|&fd| fd_in(&self.generations, fd))
+    }
+
+    fn is_connected(&self, fd: Fd) -> bool {
+        is_current(&self.connections, &self.generations, fd)
+    }
+
+    fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    fn peer_addr(&self, fd: Fd) -> Option<SocketAddr> {
+        if !self.is_connected(fd) {
+            return None;
+        }
+
+        self.connections.get(&fd.0).copied().flatten()
+    }
+
+    #[instrument(
+        skip_all,
+        level = "trace",
+        name = "iou-submit-events",
+        fields(core = core_index(), submitted = field::Empty)
+    )]
+    fn submit_events(&mut self) {
+        match self.submit_strategy {
+            SubmitStrategy::Spin { timeout } => {
+                self.submit();
+                self.poll_completions(timeout);
+            }
+            SubmitStrategy::Block {
+                min_complete,
+                timeout,
+            } => self.submit_and_block(min_complete, timeout),
+            SubmitStrategy::Adaptive { spin, timeout } => {
+                self.submit();
+
+                if !self.poll_completions(spin) {
+                    self.submit_and_block(1, timeout);
+                }
+            }
+        }
+    }
+
+    #[instrument(skip_all, name = "iou-shutdown")]
+    fn shutdown(&mut self) -> std::io::Result<()> {
+        // a connection accepted from now on would never be closed
+        self.accepting = false;
+        for listener in 0..self.local_addrs.len() {
+            if let Err(err) = self.cancel(CancelBuilder::fd(Fixed(listener as u32)).all()) {
+                warn!("failed to cancel the accepts, so they are closed as they complete: {err}");
+            }
+        }
+
+        info!("flushing {} pending writes", self.pending_writes);
+
+        // submits the writes which were pushed since the last submit, and waits for every write
+        // to complete. `drain` decrements `pending_writes` for each write completion.
+        while self.pending_writes > 0 {
+            self.uring.submit_and_wait(1)?;
+            self.drain(|_| {})?;
+        }
+
+        // connections the drains removed were closed by them, and are not closed again, since
+        // their slot may belong to another connection by then
+        let connections = std::mem::take(&mut self.connections);
+
+        info!("closing {} connections", connections.len());
+
+        // the recvs would keep the sockets open after they are closed. they are not connected
+        // anymore, so their completions are ignored
+        if let Err(err) = self.cancel(CancelBuilder::any().all()) {
+            warn!("failed to cancel the recvs of the connections: {err}");
+        }
+
+        {
+            let mut submission = self.uring.submission();
+            for fd in connections.into_keys() {
+                self.closes.close(&mut submission, fd);
+            }
+        }
+
+        // waits for the close completions, not only for the closes to be submitted
+        while !self.closes.is_done() {
+            self.uring.submit_and_wait(1)?;
+            self.drain(|_| {})?;
+        }
+
+        Ok(())
+    }
+}
+
+const RECV_MARKER: u64 = 0b1 << 63;
 const SEND_MARKER: u64 = 0b1 << 62;
+const ACCEPT_MARKER: u64 = 0b1 << 61;
+const SOCKOPT_MARKER: u64 = 0b1 << 60;
+const CLOSE_MARKER: u64 = 0b1 << 59;
+
+/// The bits of a generation which are kept, so that it fits in the `user_data` of an entry
+/// between the slot and the markers. See [`user_data`].
+const GENERATION_MASK: u32 = (1 << 27) - 1;
 
 impl LinuxServer {
+    /// Submits the new entries without waiting. With SQPOLL, this only enters the kernel if the
+    /// polling thread went to sleep.
+    fn submit(&mut self) {
+        if self.sqpoll {
+            let mut submission = self.uring.submission();
+            submission.sync();
+
+            // the kernel thread picks up the new entries on its own unless it went to sleep
+            if !submission.need_wakeup() {
+                Span::current().record("submitted", submission.len());
+                return;
+            }
+        }
+
+        match self.uring.submit() {
+            Ok(submitted) => {
+                Span::current().record("submitted", submitted);
+            }
+            Err(err) => error!("unexpected io_uring error during submit: {err}"),
+        }
+    }
+
+    /// Submits the new entries and sleeps until `min_complete` completions are ready or
+    /// `timeout` passed. See [`SubmitStrategy::Block`].
+    fn submit_and_block(&mut self, min_complete: usize, timeout: Duration) {
+        let timeout = Timespec::from(timeout);
+        let args = SubmitArgs::new().timespec(&timeout);
+
+        match self.uring.submitter().submit_with_args(min_complete, &args) {
+            Ok(submitted) => {
+                Span::current().record("submitted", submitted);
+            }
+            // fewer completions were ready when the timeout passed, which is fine
+            Err(err) if err.raw_os_error() == Some(libc::ETIME) => {}
+            Err(err) => error!("unexpected io_uring error during submit: {err}"),
+        }
+    }
+
+    /// Enters the kernel without sleeping until a completion is ready or `window` passed, and
+    /// returns whether one is. See [`SubmitStrategy::Spin`] and [`SubmitStrategy::Adaptive`].
+    ///
+    /// Finished operations are only posted to the completion queue when the kernel is entered,
+    /// since the uring is set up with `IORING_SETUP_COOP_TASKRUN`, so only looking at the queue
+    /// would not see them. This passes `IORING_ENTER_GETEVENTS` itself, because with SQPOLL
+    /// [`Submitter::submit`](io_uring::Submitter::submit) skips the syscall while the polling
+    /// thread is awake.
+    fn poll_completions(&mut self, window: Duration) -> bool {
+        let start = Instant::now();
+
+        loop {
+            if !self.uring.completion().is_empty() {
+                return true;
+            }
+
+            if start.elapsed() >= window {
+                return false;
+            }
+
+            // SAFETY: nothing is submitted and no argument is passed, so the kernel does not read
+            // any memory from here
+            let entered = unsafe {
+                self.uring
+                    .submitter()
+                    .enter::<libc::sigset_t>(0, 0, IORING_ENTER_GETEVENTS, None)
+            };
+
+            if let Err(err) = entered {
+                error!("unexpected io_uring error while polling for completions: {err}");
+                return false;
+            }
+
+            std::hint::spin_loop();
+        }
+    }
+
     /// # Safety
     /// The entry must be valid for the duration of the operation
     unsafe fn push_entry(submission: &mut SubmissionQueue, entry: &io_uring::squeue::Entry) {
@@ -417,25 +1514,70 @@ impl LinuxServer {
         }
     }
 
-    fn request_accept(submission: &mut SubmissionQueue) {
+    /// Accepts a connection into `slot` of `slots`, from the listener which owns the slot.
+    /// Listeners are registered as the first fixed files, so the index of a listener is also its
+    /// fixed file.
+    fn request_accept(submission: &mut SubmissionQueue, slots: &mut [AcceptSlot], slot: usize) {
+        let listener = (slot / ACCEPTS_PER_LISTENER) as u32;
+
+        let AcceptSlot { addr, len } = &mut slots[slot];
+        *len = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        // SAFETY: the slots are not moved or dropped until the uring is dropped
         unsafe {
             Self::push_entry(
                 submission,
-                &io_uring::opcode::AcceptMulti::new(LISTENER_FIXED_FD)
-                    .allocate_file_index(true)
-                    .build()
-                    .user_data(0),
+                &io_uring::opcode::Accept::new(
+                    Fixed(listener),
+                    std::ptr::from_mut(addr).cast(),
+                    len,
+                )
+                .file_index(Some(DestinationSlot::auto_target()))
+                .build()
+                .user_data(slot as u64 | ACCEPT_MARKER),
             );
         }
     }
 
-    fn request_recv(submission: &mut SubmissionQueue, fd: Fixed) {
+    /// Sets `opts` on the connection `fd` before its first recv is requested.
+    ///
+    /// Connections are accepted straight into the fixed file table, so they have no regular fd
+    /// to call setsockopt on, and this uses the `io_uring` setsockopt, which needs Linux 6.7. On
+    /// older kernels, connections still inherit the options from the listener.
+    #[expect(
+        clippy::cast_sign_loss,
+        reason = "socket option levels and names are positive"
+    )]
+    fn set_connection_opts(submission: &mut SubmissionQueue, opts: &[ConnectionOpt], fd: Fixed) {
+        for opt in opts {
+            // SAFETY: the options are not moved or dropped until the uring is dropped
+            unsafe {
+                Self::push_entry(
+                    submission,
+                    &io_uring::opcode::SetSockOpt::new(
+                        fd,
+                        opt.level as u32,
+                        opt.name as u32,
+                        std::ptr::from_ref(&opt.value).cast(),
+                        size_of::<libc::c_int>() as u32,
+                    )
+                    .build()
+                    .user_data(u64::from(fd.0) | SOCKOPT_MARKER),
+                );
+            }
+        }
+    }
+
+    fn request_recv(submission: &mut SubmissionQueue, armed_recvs: &mut FxHashSet<u64>, fd: Fd) {
+        let user_data = user_data(RECV_MARKER, fd);
+        armed_recvs.insert(user_data);
+
         unsafe {
             Self::push_entry(
                 submission,
-                &io_uring::opcode::RecvMulti::new(fd, C2S_BUFFER_GROUP_ID)
+                &io_uring::opcode::RecvMulti::new(fd.0, C2S_BUFFER_GROUP_ID)
                     .build()
-                    .user_data(u64::from(fd.0) | RECV_MARKER),
+                    .user_data(user_data),
             );
         }
     }
@@ -446,39 +1588,45 @@ impl LinuxServer {
         unsafe {
             Self::push_entry(
                 submission,
-                &io_uring::opcode::Close::new(fd).build().user_data(1),
+                &io_uring::opcode::Close::new(fd)
+                    .build()
+                    .user_data(u64::from(fd.0) | CLOSE_MARKER),
             );
         }
     }
 
-    pub fn write_raw(&mut self, fd: Fixed, buf: *const u8, len: u32, buf_index: u16) {
+    pub fn write_raw(&mut self, fd: Fd, buf: *const u8, len: u32, buf_index: u16) {
         self.pending_writes += 1;
+        self.closes.write_submitted(fd.0);
         unsafe {
             Self::push_entry(
                 &mut self.uring.submission(),
-                &io_uring::opcode::WriteFixed::new(fd, buf, len, buf_index)
+                &io_uring::opcode::WriteFixed::new(fd.0, buf, len, buf_index)
                     .build()
                     // IO_HARDLINK allows adjacent fd writes to be sequential which is SUPER important to make
                     // sure things get written in the right (or at least deterministic) order
                     .flags(squeue::Flags::IO_HARDLINK)
-                    .user_data(u64::from(fd.0) | SEND_MARKER),
+                    .user_data(user_data(SEND_MARKER, fd)),
             );
         }
     }
 
-    #[expect(dead_code, reason = "this is not used")]
-    pub fn cancel(&mut self, cancel_builder: io_uring::types::CancelBuilder) {
-        self.uring
-            .submitter()
-            .register_sync_cancel(None, cancel_builder)
-            .unwrap();
+    /// Cancels the entries in flight which `builder` matches, and waits until they are
+    /// cancelled. Their completions are still posted. Nothing matching is not an error.
+    ///
+    /// This needs Linux 6.0.
+    pub fn cancel(&mut self, builder: CancelBuilder) -> std::io::Result<()> {
+        match self.uring.submitter().register_sync_cancel(None, builder) {
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            result => result,
+        }
     }
 
     /// To register new buffers, unregister must be called first
     /// # Safety
     /// buffers must be valid
-    pub unsafe fn register_buffers(&mut self, buffers: &[iovec]) {
-        self.uring.submitter().register_buffers(buffers).unwrap();
+    pub unsafe fn register_buffers(&mut self, buffers: &[iovec]) -> std::io::Result<()> {
+        self.uring.submitter().register_buffers(buffers)
     }
 
     /// All requests in the submission queue must be finished or cancelled, or else this function
@@ -488,3 +1636,702 @@ impl LinuxServer {
         self.uring.submitter().unregister_buffers().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        time::Instant,
+    };
+
+    use socket2::SockRef;
+
+    use super::*;
+    use crate::net::REQUIRE_IO_URING;
+
+    /// The server which `result` started, or [`None`] if `io_uring` is not available and the test
+    /// is skipped.
+    ///
+    /// # Panics
+    /// If `io_uring` is not available and [`REQUIRE_IO_URING`] is set.
+    fn started(result: anyhow::Result<LinuxServer>) -> Option<LinuxServer> {
+        match result {
+            Ok(server) => Some(server),
+            Err(err) if std::env::var_os(REQUIRE_IO_URING).is_some() => {
+                panic!("io_uring is not available: {err:?}")
+            }
+            Err(err) => {
+                eprintln!("skipping, io_uring is not available: {err}");
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_recv_more_than_c2s_buffers() {
+        // more data than fits in every C2S buffer at once
+        const TOTAL: usize = 4 * C2S_RING_BUFFER_COUNT * C2S_RING_BUFFER_LEN;
+
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let Some(mut server) = started(LinuxServer::new(address)) else {
+            return;
+        };
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(&vec![7; TOTAL]).unwrap();
+            stream
+        });
+
+        let start = Instant::now();
+        let mut received = 0;
+
+        while received < TOTAL {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "only received {received} of {TOTAL} bytes"
+            );
+
+            server.uring.submit_and_wait(1).unwrap();
+            server
+                .drain(|event| match event {
+                    ServerEvent::RecvData { data, .. } => {
+                        assert!(data.iter().all(|&b| b == 7));
+                        received += data.len();
+                    }
+                    ServerEvent::RemovePlayer { .. } | ServerEvent::Error { .. } => {
+                        panic!("the connection was closed before everything was received");
+                    }
+                    _ => {}
+                })
+                .unwrap();
+        }
+
+        assert_eq!(received, TOTAL);
+        drop(client.join().unwrap());
+    }
+
+    #[test]
+    fn test_zero_copy_recv() {
+        // more data than fits in every C2S buffer at once, so dropped buffers have to be reused
+        const TOTAL: usize = 2 * C2S_RING_BUFFER_COUNT * C2S_RING_BUFFER_LEN;
+
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let config = LinuxServerConfig {
+            zero_copy_recv: true,
+            ..LinuxServerConfig::default()
+        };
+
+        let Some(mut server) = started(LinuxServer::new_with_config(address, config)) else {
+            return;
+        };
+
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(&vec![7; TOTAL]).unwrap();
+            stream
+        });
+
+        let start = Instant::now();
+        let mut received = 0;
+
+        while received < TOTAL {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "only received {received} of {TOTAL} bytes"
+            );
+
+            let mut buffers = Vec::new();
+
+            server.uring.submit_and_wait(1).unwrap();
+            server
+                .drain(|event| match event {
+                    ServerEvent::RecvBuffer { buffer, .. } => buffers.push(buffer),
+                    ServerEvent::RecvData { .. } => panic!("the data was copied"),
+                    ServerEvent::RemovePlayer { .. } | ServerEvent::Error { .. } => {
+                        panic!("the connection was closed before everything was received");
+                    }
+                    _ => {}
+                })
+                .unwrap();
+
+            // the buffers outlive the drain, and are given back by the next one
+            for buffer in buffers {
+                assert!(buffer.iter().all(|&b| b == 7));
+                received += buffer.len();
+            }
+        }
+
+        assert_eq!(received, TOTAL);
+        drop(client.join().unwrap());
+    }
+
+    #[test]
+    fn test_replace_s2c_buffers() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let Some(mut server) = started(LinuxServer::new(address)) else {
+            return;
+        };
+
+        let pool = Arc::new(BufferPool::new(2, 4096));
+        server.allocate_buffers(pool.clone()).unwrap();
+
+        let smaller = Arc::new(BufferPool::new(1, 4096));
+        assert!(server.replace_s2c_buffers(smaller).is_err());
+        assert_eq!(server.registered_buffers, 2);
+
+        // the old buffers are unregistered, so the server lets go of them
+        let larger = Arc::new(BufferPool::new(3, 8192));
+        server.replace_s2c_buffers(larger.clone()).unwrap();
+        assert_eq!(server.registered_buffers, 3);
+        assert_eq!(Arc::strong_count(&pool), 1);
+        assert_eq!(Arc::strong_count(&larger), 2);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_resize_c2s_buffers() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let config = LinuxServerConfig {
+            zero_copy_recv: true,
+            ..LinuxServerConfig::default()
+        };
+
+        let Some(mut server) = started(LinuxServer::new_with_config(address, config)) else {
+            return;
+        };
+
+        let (fd, mut client) = accept(&mut server, address);
+
+        let recv = |server: &mut LinuxServer, client: &mut TcpStream, byte: u8| {
+            client.write_all(&[byte; C2S_RING_BUFFER_LEN]).unwrap();
+
+            let start = Instant::now();
+            let mut buffers = Vec::new();
+            let mut received = 0;
+
+            while received < C2S_RING_BUFFER_LEN {
+                assert!(
+                    start.elapsed() < Duration::from_secs(30),
+                    "nothing was received"
+                );
+
+                server.uring.submit_and_wait(1).unwrap();
+                server
+                    .drain(|event| {
+                        if let ServerEvent::RecvBuffer { fd: from, buffer } = event {
+                            assert_eq!(from, fd);
+                            assert!(buffer.iter().all(|&b| b == byte));
+                            received += buffer.len();
+                            buffers.push(buffer);
+                        }
+                    })
+                    .unwrap();
+            }
+
+            buffers
+        };
+
+        let held = recv(&mut server, &mut client, 1);
+
+        // the count of a provided-buffer ring must be a power of two
+        assert!(server.resize_buffers(1000, |_| {}).is_err());
+        assert_eq!(server.c2s_buffer.count(), C2S_RING_BUFFER_COUNT);
+        drop(recv(&mut server, &mut client, 2));
+
+        server
+            .resize_buffers(1024, |event| {
+                assert!(!matches!(event, ServerEvent::RemovePlayer { .. }));
+            })
+            .unwrap();
+        assert_eq!(server.c2s_buffer.count(), 1024);
+        assert_eq!(server.armed_recvs.len(), 1);
+
+        // the connection receives into the new buffers, and held buffers of the old ones stay
+        // valid
+        drop(recv(&mut server, &mut client, 3));
+        assert!(held.iter().all(|buffer| buffer.iter().all(|&b| b == 1)));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_drain_ready_after_poll() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let Some(mut server) = started(LinuxServer::new(address)) else {
+            return;
+        };
+
+        // nothing is ready yet
+        server
+            .drain_ready(|_| panic!("no events expected"))
+            .unwrap();
+
+        server.submit_events();
+        let _client = TcpStream::connect(address).unwrap();
+
+        let start = Instant::now();
+        let mut added = false;
+
+        while !added {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "the connection was never accepted"
+            );
+
+            let mut poll_fd = libc::pollfd {
+                fd: server.registration_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+
+            // SAFETY: `poll_fd` is a single valid pollfd
+            let ready = unsafe { libc::poll(&mut poll_fd, 1, 1000) };
+            assert!(
+                ready >= 0,
+                "poll failed: {}",
+                std::io::Error::last_os_error()
+            );
+
+            server
+                .drain_ready(|event| {
+                    if let ServerEvent::AddPlayer { .. } = event {
+                        added = true;
+                    }
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_multiple_listeners() {
+        let addresses: [SocketAddr; 2] =
+            ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+
+        if TcpListener::bind(addresses[1]).is_err() {
+            eprintln!("skipping, IPv6 is not available");
+            return;
+        }
+
+        let Some(mut server) = started(LinuxServer::new_multi(&addresses)) else {
+            return;
+        };
+
+        let local_addrs = server.local_addrs().to_vec();
+        assert!(local_addrs[0].is_ipv4());
+        assert!(local_addrs[1].is_ipv6());
+
+        server.submit_events();
+        let _clients: Vec<_> = local_addrs
+            .iter()
+            .map(|address| TcpStream::connect(address).unwrap())
+            .collect();
+
+        let start = Instant::now();
+        let mut listeners = Vec::new();
+
+        while listeners.len() < 2 {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "the connections were never accepted"
+            );
+
+            server
+                .drain(|event| {
+                    if let ServerEvent::AddPlayer { listener, .. } = event {
+                        listeners.push(listener);
+                    }
+                })
+                .unwrap();
+            server.submit_events();
+        }
+
+        listeners.sort_unstable();
+        assert_eq!(listeners, [0, 1]);
+    }
+
+    #[test]
+    fn test_peer_addr() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let Some(mut server) = started(LinuxServer::new(address)) else {
+            return;
+        };
+
+        server.submit_events();
+        let client = TcpStream::connect(address).unwrap();
+
+        let start = Instant::now();
+        let mut added = None;
+
+        while added.is_none() {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "the connection was never accepted"
+            );
+
+            server
+                .drain(|event| {
+                    if let ServerEvent::AddPlayer { fd, .. } = event {
+                        added = Some(fd);
+                    }
+                })
+                .unwrap();
+            server.submit_events();
+        }
+
+        let fd = added.unwrap();
+        assert_eq!(server.peer_addr(fd), Some(client.local_addr().unwrap()));
+
+        // the address is forgotten with the connection
+        server.close_after_send(fd);
+        assert_eq!(server.peer_addr(fd), None);
+    }
+
+    #[test]
+    fn test_is_connected() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let Some(mut server) = started(LinuxServer::new(address)) else {
+            return;
+        };
+
+        let (fd, _client) = accept(&mut server, address);
+        assert!(server.is_connected(fd));
+
+        // an fd of the connection which had the slot before
+        let stale = Fd(fd.0, fd.1.wrapping_sub(1));
+        assert!(!server.is_connected(stale));
+        assert_eq!(server.peer_addr(stale), None);
+
+        // closing it does not close the connection which has the slot now
+        server.close_after_send(stale);
+        assert!(server.is_connected(fd));
+
+        server.close_after_send(fd);
+        assert!(!server.is_connected(fd));
+    }
+
+    #[test]
+    fn test_slot_reuse() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        // a small file table, so the slot comes around again quickly
+        let config = LinuxServerConfig {
+            max_connections: Some(0),
+            ..LinuxServerConfig::default()
+        };
+
+        let Some(mut server) = started(LinuxServer::new_with_config(address, config)) else {
+            return;
+        };
+
+        let (first, first_client) = accept(&mut server, address);
+        let mut fd = first;
+        let mut clients = Vec::new();
+
+        // connections are closed until the next one is accepted into the slot of the first
+        for _ in 0..server.generations.len() {
+            server.close_after_send(fd);
+            server.uring.submit_and_wait(1).unwrap();
+            server.drain(|_| {}).unwrap();
+
+            let (next, client) = accept(&mut server, address);
+            fd = next;
+            clients.push(client);
+
+            if fd.0 == first.0 {
+                break;
+            }
+        }
+
+        assert_eq!(
+            fd.0, first.0,
+            "the slot of the first connection was never reused"
+        );
+
+        // the stale fd does not alias the connection which has its slot now
+        assert_ne!(fd, first);
+        assert!(!server.is_connected(first));
+        assert!(server.is_connected(fd));
+
+        let lookup: FxHashMap<Fd, ()> = [(fd, ())].into_iter().collect();
+        assert!(!lookup.contains_key(&first));
+
+        // the recvs of the closed connections are still armed, so their hangups complete now,
+        // and must not be mistaken for the connection which has the slot
+        let _client = clients.pop().unwrap();
+        drop(first_client);
+        drop(clients);
+
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(500) {
+            server.uring.submit().unwrap();
+            server
+                .drain(|event| match event {
+                    ServerEvent::RemovePlayer { fd: removed } => {
+                        assert_ne!(removed, fd, "the new connection was removed");
+                    }
+                    ServerEvent::Error { fd: errored, .. } => {
+                        assert_ne!(errored, fd, "an error was reported for the new connection");
+                    }
+                    _ => {}
+                })
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(server.is_connected(fd));
+    }
+
+    #[test]
+    fn test_close_waits_for_writes() {
+        const WRITES: usize = 16;
+        const LEN: usize = 1024;
+
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let Some(mut server) = started(LinuxServer::new(address)) else {
+            return;
+        };
+
+        let pool = Arc::new(BufferPool::new(1, WRITES * LEN));
+        server.allocate_buffers(pool.clone()).unwrap();
+        let base = pool.iovecs()[0].iov_base.cast::<u8>().cast_const();
+
+        let write = |server: &mut LinuxServer, fd: Fd| {
+            for i in 0..WRITES {
+                // SAFETY: the writes are in bounds of the registered buffer
+                server.write_raw(fd, unsafe { base.add(i * LEN) }, LEN as u32, 0);
+            }
+        };
+
+        // the close of a connection waits for the writes which are still queued
+        let (fd, mut client) = accept(&mut server, address);
+        write(&mut server, fd);
+        server.close_after_send(fd);
+        assert!(server.closes.deferred.contains(&fd.0));
+
+        wait_for_writes(&mut server);
+        assert!(server.closes.deferred.is_empty());
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        let mut received = vec![0; WRITES * LEN];
+        client.read_exact(&mut received).unwrap();
+
+        // a write which fails because the peer reset the connection removes it, but the close
+        // still waits for the other writes of the connection
+        let (fd, client) = accept(&mut server, address);
+        SockRef::from(&client)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(client);
+
+        let start = Instant::now();
+        let mut removed = false;
+
+        while !removed {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "the reset connection was never removed"
+            );
+
+            if server.is_connected(fd) {
+                write(&mut server, fd);
+            }
+
+            server.uring.submit_and_wait(1).unwrap();
+            server
+                .drain(|event| {
+                    if let ServerEvent::RemovePlayer { fd: removed_fd } = event {
+                        assert_eq!(removed_fd, fd);
+                        removed = true;
+                    }
+                })
+                .unwrap();
+
+            let writes = server.closes.writes[fd.0 .0 as usize];
+            assert!(
+                server.is_connected(fd) || writes == 0 || server.closes.deferred.contains(&fd.0),
+                "the slot was closed with {writes} writes in flight"
+            );
+        }
+
+        wait_for_writes(&mut server);
+        assert!(server.closes.deferred.is_empty());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        const LEN: usize = 4096;
+
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let Some(mut server) = started(LinuxServer::new(address)) else {
+            return;
+        };
+
+        let pool = Arc::new(BufferPool::new(1, LEN));
+        server.allocate_buffers(pool.clone()).unwrap();
+        let base = pool.iovecs()[0].iov_base.cast::<u8>().cast_const();
+
+        let (fd, mut client) = accept(&mut server, address);
+        let (_, mut closed_client) = accept(&mut server, address);
+
+        // the write is only pushed, so the shutdown has to submit it
+        server.write_raw(fd, base, LEN as u32, 0);
+
+        server.shutdown().unwrap();
+        assert_eq!(server.connection_count(), 0);
+        assert!(server.closes.is_done());
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), LEN);
+
+        // every connection is closed, not only the ones which were written to
+        closed_client
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        assert_eq!(closed_client.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    /// Drains `server` until none of its writes are in flight.
+    fn wait_for_writes(server: &mut LinuxServer) {
+        let start = Instant::now();
+
+        while server.pending_writes > 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "{} writes never completed",
+                server.pending_writes
+            );
+
+            server.uring.submit_and_wait(1).unwrap();
+            server.drain(|_| {}).unwrap();
+        }
+    }
+
+    /// Connects to `address` and drains `server` until it accepted the connection.
+    fn accept(server: &mut LinuxServer, address: SocketAddr) -> (Fd, TcpStream) {
+        server.submit_events();
+        let client = TcpStream::connect(address).unwrap();
+
+        let start = Instant::now();
+        let mut added = None;
+
+        while added.is_none() {
+            assert!(
+                start.elapsed() < Duration::from_secs(30),
+                "the connection was never accepted"
+            );
+
+            server
+                .drain(|event| {
+                    if let ServerEvent::AddPlayer { fd, .. } = event {
+                        added = Some(fd);
+                    }
+                })
+                .unwrap();
+            server.submit_events();
+        }
+
+        (added.unwrap(), client)
+    }
+
+    #[test]
+    fn test_submit_strategies() {
+        const TIMEOUT: Duration = Duration::from_millis(50);
+
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let config = LinuxServerConfig {
+            submit_strategy: SubmitStrategy::Block {
+                min_complete: 1,
+                timeout: TIMEOUT,
+            },
+            ..LinuxServerConfig::default()
+        };
+
+        let Some(mut server) = started(LinuxServer::new_with_config(address, config)) else {
+            return;
+        };
+
+        if !server.submit_strategy.blocks() {
+            eprintln!("skipping, the kernel cannot time out waits for completions");
+            return;
+        }
+
+        // spinning enters the kernel until the deadline even though nothing completes
+        server.submit_strategy = SubmitStrategy::Spin { timeout: TIMEOUT };
+        let start = Instant::now();
+        server.submit_events();
+        assert!(start.elapsed() >= TIMEOUT);
+
+        server.submit_strategy = SubmitStrategy::Block {
+            min_complete: 1,
+            timeout: TIMEOUT,
+        };
+
+        // nothing completes, so this sleeps until the timeout
+        let start = Instant::now();
+        server.submit_events();
+        assert!(start.elapsed() >= TIMEOUT);
+
+        server.submit_strategy = SubmitStrategy::Adaptive {
+            spin: Duration::from_millis(1),
+            timeout: Duration::from_secs(30),
+        };
+
+        let _client = TcpStream::connect(address).unwrap();
+
+        // the accept completes long before the timeout
+        let start = Instant::now();
+        server.submit_events();
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        let mut added = false;
+        server
+            .drain(|event| {
+                if let ServerEvent::AddPlayer { .. } = event {
+                    added = true;
+                }
+            })
+            .unwrap();
+        assert!(added);
+    }
+}
@@ -0,0 +1,386 @@
+//! Counters and gauges for the networking hot path.
+//!
+//! [`NetMetrics`] is always kept up to date so it can be scraped from the ECS. With the `metrics`
+//! feature, every update is also exported through the [`metrics`](https://docs.rs/metrics) crate.
+//! Counters which are updated for every write, like the coalesce hits and misses, only exist with
+//! the feature.
+
+use std::cell::Cell;
+
+use evenio::component::Component;
+use fxhash::FxHashMap;
+
+use crate::{
+    event::Scratches,
+    net::{DisconnectReason, IoBufs, Packets},
+};
+
+/// The number of [`DisconnectReason::KINDS`].
+const DISCONNECT_KINDS: usize = DisconnectReason::KINDS.len();
+
+/// Counters for a single core. These live on the core's [`crate::net::IoBuf`], so incrementing
+/// them never touches another core's cache lines.
+#[derive(Debug, Default)]
+pub struct CoreMetrics {
+    packets_appended: Cell<u64>,
+    bytes_appended: Cell<u64>,
+    #[cfg(feature = "metrics")]
+    coalesce_hits: Cell<u64>,
+    #[cfg(feature = "metrics")]
+    coalesce_misses: Cell<u64>,
+    /// The number of disconnects of each [`DisconnectReason::kind`].
+    disconnects: [Cell<u64>; DISCONNECT_KINDS],
+    /// See [`crate::net::IoBufs::set_track_packet_types`].
+    pub(crate) track_packet_types: bool,
+    /// The number of packets and bytes of each packet ID which were encoded since the last tick.
+    packet_types: FxHashMap<i32, (u64, u64)>,
+}
+
+impl CoreMetrics {
+    pub(crate) fn record_append(&self, bytes: usize) {
+        self.packets_appended.set(self.packets_appended.get() + 1);
+        self.bytes_appended
+            .set(self.bytes_appended.get() + bytes as u64);
+    }
+
+    /// Counts a write which was queued after another one, and whether it could be merged into it.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn record_coalesce(&self, merged: bool) {
+        let counter = if merged {
+            &self.coalesce_hits
+        } else {
+            &self.coalesce_misses
+        };
+
+        counter.set(counter.get() + 1);
+    }
+
+    /// Counts a connection which was disconnected because of `reason`.
+    pub(crate) fn record_disconnect(&self, reason: &DisconnectReason) {
+        let counter = &self.disconnects[reason.kind_index()];
+        counter.set(counter.get() + 1);
+    }
+
+    /// Counts a packet with `id` which was encoded into `bytes`, if packet types are tracked.
+    pub(crate) fn record_packet_type(&mut self, id: i32, bytes: usize) {
+        if !self.track_packet_types {
+            return;
+        }
+
+        let (count, total) = self.packet_types.entry(id).or_default();
+        *count += 1;
+        *total += bytes as u64;
+    }
+
+    /// The number of packets appended on this core since the server started.
+    #[must_use]
+    pub fn packets_appended(&self) -> u64 {
+        self.packets_appended.get()
+    }
+
+    /// The number of bytes appended on this core since the server started.
+    #[must_use]
+    pub fn bytes_appended(&self) -> u64 {
+        self.bytes_appended.get()
+    }
+
+    /// The number of writes appended on this core since the server started which were merged
+    /// into the write queued before them, so they did not need an iovec or SQE of their own.
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn coalesce_hits(&self) -> u64 {
+        self.coalesce_hits.get()
+    }
+
+    /// The number of writes appended on this core since the server started which could not be
+    /// merged into the write queued before them, because the ring wrapped in between or another
+    /// connection's packets were appended to the ring in between.
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn coalesce_misses(&self) -> u64 {
+        self.coalesce_misses.get()
+    }
+
+    /// The number of connections disconnected on this core since the server started with a
+    /// reason of each of the [`DisconnectReason::KINDS`], in that order.
+    #[must_use]
+    pub fn disconnects(&self) -> [u64; DISCONNECT_KINDS] {
+        self.disconnects.each_ref().map(Cell::get)
+    }
+}
+
+/// A snapshot of the networking metrics, updated every tick by the egress system.
+#[derive(Component, Debug, Default, Clone)]
+pub struct NetMetrics {
+    /// The number of packets appended by each core since the server started.
+    pub packets_appended: Vec<u64>,
+    /// The number of bytes appended by each core since the server started.
+    pub bytes_appended: Vec<u64>,
+    /// See [`CoreMetrics::coalesce_hits`].
+    #[cfg(feature = "metrics")]
+    pub coalesce_hits: Vec<u64>,
+    /// See [`CoreMetrics::coalesce_misses`].
+    #[cfg(feature = "metrics")]
+    pub coalesce_misses: Vec<u64>,
+    /// The most bytes which have been pending at once in the [`crate::singleton::ring::Ring`] of
+    /// each core.
+    pub ring_high_water_mark: Vec<usize>,
+    /// The capacity of the scratch buffer of each core which packets are encoded in.
+    pub scratch_capacity: Vec<usize>,
+    /// The number of times the scratch buffer of each core had to grow. If this is not zero, the
+    /// scratch buffers reallocate while encoding.
+    pub scratch_grow_count: Vec<u64>,
+    /// The bytes of packet data each core compressed in the last tick. Packets under the
+    /// compression threshold are not counted. See [`NetMetrics::compression_ratio`].
+    pub compressed_before: Vec<u64>,
+    /// The bytes [`NetMetrics::compressed_before`] of each core were sent as in the last tick.
+    pub compressed_after: Vec<u64>,
+    /// The number of connections which have been registered and not removed yet.
+    pub connections: usize,
+    /// See [`crate::global::Global::max_connections`]. Connections past this are told the server
+    /// is full when they log in.
+    pub max_connections: usize,
+    /// The number of writes which are in flight, summed over every connection.
+    pub number_sending: usize,
+    /// The number of writes which have completed since the server started.
+    pub writes_completed: u64,
+    /// The number of IO completions the kernel dropped because the completion queue was full.
+    /// See [`crate::net::ServerEvent::CompletionOverflow`].
+    pub completion_overflows: u64,
+    /// The number of connections which were closed at accept because they were over the
+    /// [`crate::net::AcceptPolicy`] or the server was full. See
+    /// [`crate::net::ServerEvent::AcceptsRejected`].
+    pub accepts_rejected: u64,
+    /// See [`NetMetrics::by_packet_type`].
+    packet_types: FxHashMap<i32, (u64, u64)>,
+    /// See [`NetMetrics::by_disconnect_reason`].
+    disconnects: [u64; DISCONNECT_KINDS],
+}
+
+impl NetMetrics {
+    /// Updates the per-core counters and the gauges.
+    pub(crate) fn update<'a>(
+        &mut self,
+        io: &IoBufs,
+        scratches: &Scratches,
+        packets: impl IntoIterator<Item = &'a Packets>,
+    ) {
+        self.packets_appended.clear();
+        self.bytes_appended.clear();
+        #[cfg(feature = "metrics")]
+        {
+            self.coalesce_hits.clear();
+            self.coalesce_misses.clear();
+        }
+        self.ring_high_water_mark.clear();
+        self.compressed_before.clear();
+        self.compressed_after.clear();
+
+        self.scratch_capacity.clear();
+        self.scratch_capacity.extend(scratches.capacities());
+        self.scratch_grow_count.clear();
+        self.scratch_grow_count.extend(scratches.grow_counts());
+
+        self.packet_types.clear();
+        self.disconnects = [0; DISCONNECT_KINDS];
+
+        for buf in io.iter() {
+            let mut buf = buf.lock();
+
+            let metrics = buf.metrics();
+            self.packets_appended.push(metrics.packets_appended());
+            self.bytes_appended.push(metrics.bytes_appended());
+            #[cfg(feature = "metrics")]
+            {
+                self.coalesce_hits.push(metrics.coalesce_hits());
+                self.coalesce_misses.push(metrics.coalesce_misses());
+            }
+
+            let (before, after) = buf.enc().take_compression_totals();
+            self.compressed_before.push(before);
+            self.compressed_after.push(after);
+
+            for (total, count) in self.disconnects.iter_mut().zip(metrics.disconnects()) {
+                *total += count;
+            }
+
+            for (id, (count, bytes)) in buf.metrics.packet_types.drain() {
+                let (total_count, total_bytes) = self.packet_types.entry(id).or_default();
+                *total_count += count;
+                *total_bytes += bytes;
+            }
+
+            self.ring_high_water_mark
+                .push(buf.buf_mut().high_water_mark());
+        }
+
+        self.connections = 0;
+        self.number_sending = 0;
+
+        for pkts in packets {
+            self.connections += 1;
+            self.number_sending += pkts.number_sending();
+        }
+
+        #[cfg(feature = "metrics")]
+        self.publish();
+    }
+
+    /// The share of writes on every core which could not be merged into the write before them,
+    /// from 0 to 1. A high rate means the rings are fragmented, so every connection needs many
+    /// iovecs or SQEs per flush. Flushing less often or larger rings can help.
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn coalesce_miss_rate(&self) -> f64 {
+        let hits: u64 = self.coalesce_hits.iter().sum();
+        let misses: u64 = self.coalesce_misses.iter().sum();
+
+        if misses == 0 {
+            return 0.0;
+        }
+
+        misses as f64 / (hits + misses) as f64
+    }
+
+    /// How many times smaller compression made the packet data it was used on in the last tick,
+    /// over every core, like 4.0 if the data was sent at a quarter of its size. This is 1.0 if
+    /// nothing was compressed.
+    ///
+    /// A ratio which barely changes with a higher [`libdeflater::CompressionLvl`] means the extra
+    /// CPU time is wasted. A ratio close to 1.0 means most packets over the threshold hardly
+    /// compress, so the threshold may be too low.
+    #[must_use]
+    pub fn compression_ratio(&self) -> f64 {
+        let before = self.compressed_before.iter().sum();
+        let after = self.compressed_after.iter().sum();
+
+        compression_ratio(before, after)
+    }
+
+    /// Like [`NetMetrics::compression_ratio`], but for each core.
+    pub fn compression_ratio_by_core(&self) -> impl Iterator<Item = f64> + '_ {
+        self.compressed_before
+            .iter()
+            .zip(&self.compressed_after)
+            .map(|(&before, &after)| compression_ratio(before, after))
+    }
+
+    /// The number of packets and encoded bytes of each packet ID which were appended in the last
+    /// tick, summed over every core. Packets which are copied into the rings after being encoded
+    /// once, like [`crate::net::PrecompressedPacket`]s and raw bytes, are not counted.
+    ///
+    /// This is empty unless [`crate::global::Global::track_packet_types`] is set.
+    #[must_use]
+    pub const fn by_packet_type(&self) -> &FxHashMap<i32, (u64, u64)> {
+        &self.packet_types
+    }
+
+    /// The number of connections which were disconnected since the server started with each
+    /// [`DisconnectReason::kind`], summed over every core, to break down why players leave.
+    ///
+    /// Only disconnects which went through [`crate::net::Compose::disconnect_with_reason`] or
+    /// the login handling of the ingress system are counted. Clients which close the connection
+    /// themselves are not.
+    pub fn by_disconnect_reason(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        DisconnectReason::KINDS
+            .into_iter()
+            .zip(self.disconnects.iter().copied())
+    }
+
+    /// Records writes which completed.
+    pub(crate) fn record_sent(&mut self, count: usize) {
+        self.writes_completed += count as u64;
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("hyperion_writes_completed").increment(count as u64);
+    }
+
+    /// Records IO completions which were lost.
+    pub(crate) fn record_completion_overflow(&mut self, dropped: u32) {
+        self.completion_overflows += u64::from(dropped);
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("hyperion_completion_overflows").increment(u64::from(dropped));
+    }
+
+    /// Records connections which were closed at accept.
+    pub(crate) fn record_accepts_rejected(&mut self, count: u32) {
+        self.accepts_rejected += u64::from(count);
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("hyperion_accepts_rejected").increment(u64::from(count));
+    }
+
+    #[cfg(feature = "metrics")]
+    fn publish(&self) {
+        let cores = self
+            .packets_appended
+            .iter()
+            .zip(&self.bytes_appended)
+            .zip(&self.ring_high_water_mark);
+
+        for (core, ((&packets, &bytes), &high_water_mark)) in cores.enumerate() {
+            let core = core.to_string();
+
+            metrics::counter!("hyperion_packets_appended", "core" => core.clone())
+                .absolute(packets);
+            metrics::counter!("hyperion_bytes_appended", "core" => core.clone()).absolute(bytes);
+            metrics::gauge!("hyperion_ring_high_water_mark", "core" => core)
+                .set(high_water_mark as f64);
+        }
+
+        let coalesces = self.coalesce_hits.iter().zip(&self.coalesce_misses);
+
+        for (core, (&hits, &misses)) in coalesces.enumerate() {
+            let core = core.to_string();
+
+            metrics::counter!("hyperion_coalesce_hits", "core" => core.clone()).absolute(hits);
+            metrics::counter!("hyperion_coalesce_misses", "core" => core).absolute(misses);
+        }
+
+        let scratches = self.scratch_capacity.iter().zip(&self.scratch_grow_count);
+
+        for (core, (&capacity, &grow_count)) in scratches.enumerate() {
+            let core = core.to_string();
+
+            metrics::gauge!("hyperion_scratch_capacity", "core" => core.clone())
+                .set(capacity as f64);
+            metrics::counter!("hyperion_scratch_grow_count", "core" => core).absolute(grow_count);
+        }
+
+        let compressed = self.compressed_before.iter().zip(&self.compressed_after);
+
+        for (core, (&before, &after)) in compressed.enumerate() {
+            let core = core.to_string();
+
+            metrics::counter!("hyperion_compressed_bytes_before", "core" => core.clone())
+                .increment(before);
+            metrics::counter!("hyperion_compressed_bytes_after", "core" => core).increment(after);
+        }
+
+        metrics::gauge!("hyperion_compression_ratio").set(self.compression_ratio());
+        metrics::gauge!("hyperion_connections").set(self.connections as f64);
+        metrics::gauge!("hyperion_max_connections").set(self.max_connections as f64);
+        metrics::gauge!("hyperion_number_sending").set(self.number_sending as f64);
+
+        for (reason, count) in self.by_disconnect_reason() {
+            metrics::counter!("hyperion_disconnects", "reason" => reason).absolute(count);
+        }
+
+        for (&id, &(count, bytes)) in &self.packet_types {
+            let id = format!("{id:#04x}");
+
+            metrics::counter!("hyperion_packet_type_count", "id" => id.clone()).increment(count);
+            metrics::counter!("hyperion_packet_type_bytes", "id" => id).increment(bytes);
+        }
+    }
+}
+
+/// How many times smaller `before` bytes got by being sent as `after` bytes.
+fn compression_ratio(before: u64, after: u64) -> f64 {
+    if after == 0 {
+        return 1.0;
+    }
+
+    before as f64 / after as f64
+}
@@ -0,0 +1,490 @@
+//! An in-memory [`ServerDef`] for tests which should not need real sockets, root, or `io_uring`.
+
+use std::{collections::VecDeque, io, net::SocketAddr, sync::Arc};
+
+use fxhash::{FxHashMap, FxHashSet};
+use libc::iovec;
+
+use crate::{
+    global::Global,
+    net::{
+        encoder::PacketWriteInfo, validate_resize, BandwidthLimiter, BufferPool, Fd, FlushResult,
+        Packets, RefreshItems, ServerDef, ServerEvent, SocketOpts, DEFAULT_MAX_CONNECTIONS,
+    },
+};
+
+/// An owned [`ServerEvent`] which has been injected but not drained yet.
+enum Pending {
+    Add(Fd),
+    Remove(Fd),
+    Recv(Fd, Vec<u8>),
+    Sent(Fd),
+    Error(Fd, io::Error),
+}
+
+/// A [`ServerDef`] which keeps everything written to a connection in a [`Vec<u8>`] instead of
+/// sending it over a socket.
+///
+/// Connections and received data are injected with [`MockServer::connect`] and
+/// [`MockServer::recv`], and show up as events the next time the server is drained. Writes
+/// complete on [`ServerDef::submit_events`], so their [`ServerEvent::SentData`] events are
+/// drained after that, like with the real servers.
+#[derive(Default)]
+pub struct MockServer {
+    next_id: u32,
+    /// Everything written to each connection, until the client disconnects
+    written: FxHashMap<Fd, Vec<u8>>,
+    /// Writes which have been copied into `written` and complete on the next submit
+    in_flight: Vec<Fd>,
+    pending: VecDeque<Pending>,
+    /// Connections closed by [`ServerDef::close_after_send`], which keep what was written to them
+    closed: FxHashSet<Fd>,
+    /// Closed connections which are removed after the next submit
+    closing: Vec<Fd>,
+    /// Connections whose writes fail with the error on the next submit, after which they are
+    /// removed
+    broken: FxHashMap<Fd, io::ErrorKind>,
+    buffers: Vec<iovec>,
+    #[expect(dead_code, reason = "this is used so there is no drop")]
+    s2c_buffers: Option<Arc<BufferPool>>,
+}
+
+impl MockServer {
+    /// Opens a new connection, which is announced with [`ServerEvent::AddPlayer`].
+    pub fn connect(&mut self) -> Fd {
+        let fd = fd(self.next_id);
+        self.next_id += 1;
+
+        self.written.insert(fd, Vec::new());
+        self.pending.push_back(Pending::Add(fd));
+
+        fd
+    }
+
+    /// Makes `data` arrive on `fd` as a single [`ServerEvent::RecvData`].
+    pub fn recv(&mut self, fd: Fd, data: &[u8]) {
+        self.pending.push_back(Pending::Recv(fd, data.to_vec()));
+    }
+
+    /// Closes `fd` as if the client disconnected.
+    pub fn disconnect(&mut self, fd: Fd) {
+        if self.written.remove(&fd).is_some() {
+            self.pending.push_back(Pending::Remove(fd));
+        }
+    }
+
+    /// Closes `fd` as if an IO operation on it failed with `error`.
+    pub fn fail(&mut self, fd: Fd, error: io::Error) {
+        if self.written.remove(&fd).is_some() {
+            self.pending.push_back(Pending::Error(fd, error));
+            self.pending.push_back(Pending::Remove(fd));
+        }
+    }
+
+    /// Makes the writes to `fd` which are in flight or written before the next submit fail with
+    /// `error`, as if the peer reset the connection before they were submitted. Like with the
+    /// real servers, every failed write completes with a [`ServerEvent::Error`] followed by a
+    /// [`ServerEvent::SentData`], and `fd` is removed once.
+    pub fn break_writes(&mut self, fd: Fd, error: io::ErrorKind) {
+        if self.written.contains_key(&fd) {
+            self.broken.insert(fd, error);
+        }
+    }
+
+    /// Whether `fd` was closed with [`ServerDef::close_after_send`].
+    #[must_use]
+    pub fn is_closed(&self, fd: Fd) -> bool {
+        self.closed.contains(&fd)
+    }
+
+    /// Everything written to `fd` so far, or [`None`] if `fd` is not connected.
+    #[must_use]
+    pub fn written(&self, fd: Fd) -> Option<&[u8]> {
+        self.written.get(&fd).map(Vec::as_slice)
+    }
+
+    /// Takes everything written to `fd` so far, so the next call only returns newer writes.
+    pub fn take_written(&mut self, fd: Fd) -> Vec<u8> {
+        self.written
+            .get_mut(&fd)
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Sends everything queued in `packets` to `fd`, like the egress system would, returning the
+    /// number of writes.
+    pub fn send(&mut self, fd: Fd, packets: &mut Packets) -> usize {
+        let count = packets.prepare_for_send();
+
+        let mut limiter = BandwidthLimiter::default();
+        for queue in packets.get_write_mut().iter_mut() {
+            limiter.drain_allowed(queue, |elem| self.write(fd, elem));
+        }
+
+        packets.requeue_deferred();
+        count
+    }
+
+    fn write(&mut self, fd: Fd, elem: PacketWriteInfo) {
+        assert!(
+            self.buffers.is_empty() || self.is_registered(elem),
+            "write for {fd:?} is not within a registered buffer"
+        );
+
+        // writes to closed connections still complete, but nothing is kept
+        if let Some(written) = self.written.get_mut(&fd) {
+            // SAFETY: the data lives in a ring which is not overwritten until it is released
+            // after this write completes
+            written.extend_from_slice(unsafe { elem.as_slice() });
+        }
+
+        self.in_flight.push(fd);
+    }
+
+    fn is_registered(&self, elem: PacketWriteInfo) -> bool {
+        let elem_start = elem.start_ptr as usize;
+        let elem_end = elem_start + elem.len as usize;
+
+        self.buffers.iter().any(|buffer| {
+            let start = buffer.iov_base as usize;
+            start <= elem_start && elem_end <= start + buffer.iov_len
+        })
+    }
+}
+
+/// IDs are never reused, so every connection is in the first generation of its slot.
+#[cfg(target_os = "linux")]
+const fn fd(id: u32) -> Fd {
+    Fd(super::linux::Fixed(id), 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+const fn fd(id: u32) -> Fd {
+    Fd(id as usize)
+}
+
+impl ServerDef for MockServer {
+    fn new_multi_with_socket_opts(
+        _addresses: &[SocketAddr],
+        _opts: &SocketOpts,
+    ) -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn local_addrs(&self) -> &[SocketAddr] {
+        &[]
+    }
+
+    /// Mock connections have no peer.
+    fn peer_addr(&self, _fd: Fd) -> Option<SocketAddr> {
+        None
+    }
+
+    fn drain(&mut self, f: impl FnMut(ServerEvent)) -> io::Result<()> {
+        self.drain_budget(usize::MAX, f).map(|_| ())
+    }
+
+    /// Each event which was made to happen counts as one completion.
+    fn drain_budget(
+        &mut self,
+        max_events: usize,
+        mut f: impl FnMut(ServerEvent),
+    ) -> io::Result<bool> {
+        for _ in 0..max_events {
+            let Some(event) = self.pending.pop_front() else {
+                break;
+            };
+
+            match event {
+                Pending::Add(fd) => f(ServerEvent::AddPlayer { fd, listener: 0 }),
+                Pending::Remove(fd) => f(ServerEvent::RemovePlayer { fd }),
+                Pending::Recv(fd, data) => f(ServerEvent::RecvData { fd, data: &data }),
+                Pending::Sent(fd) => f(ServerEvent::SentData { fd }),
+                Pending::Error(fd, error) => f(ServerEvent::Error { fd, error }),
+            }
+        }
+
+        Ok(!self.pending.is_empty())
+    }
+
+    fn allocate_buffers(&mut self, pool: Arc<BufferPool>) -> anyhow::Result<()> {
+        self.buffers = pool.iovecs();
+        self.s2c_buffers = Some(pool);
+        Ok(())
+    }
+
+    unsafe fn allocate_buffers_raw(&mut self, buffers: &[iovec]) -> anyhow::Result<()> {
+        self.buffers = buffers.to_vec();
+        Ok(())
+    }
+
+    fn replace_s2c_buffers(&mut self, pool: Arc<BufferPool>) -> anyhow::Result<()> {
+        let new_buffers = pool.iovecs();
+        validate_resize(self.buffers.len(), &new_buffers)?;
+
+        self.buffers = new_buffers;
+        self.s2c_buffers = Some(pool);
+        Ok(())
+    }
+
+    fn resize_buffers(&mut self, _count: usize, _f: impl FnMut(ServerEvent)) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn write_all<'a>(
+        &mut self,
+        global: &mut Global,
+        writers: impl Iterator<Item = RefreshItems<'a>>,
+    ) -> FlushResult {
+        let mut result = FlushResult::default();
+
+        for RefreshItems { write, fd } in writers {
+            let writes_before = result.iovecs;
+
+            for queue in write.iter_mut() {
+                global.drain_writes(fd, queue, |elem| {
+                    result.iovecs += 1;
+                    result.bytes_submitted += elem.len as usize;
+                    self.write(fd, elem);
+                });
+            }
+
+            if result.iovecs != writes_before {
+                result.fds_written += 1;
+            }
+        }
+
+        result
+    }
+
+    fn close_after_send(&mut self, fd: Fd) {
+        if self.closed.insert(fd) {
+            self.closing.push(fd);
+        }
+    }
+
+    fn connected_fds(&self) -> impl Iterator<Item = Fd> + '_ {
+        self.written
+            .keys()
+            .copied()
+            .filter(|fd| !self.closed.contains(fd))
+    }
+
+    fn is_connected(&self, fd: Fd) -> bool {
+        self.written.contains_key(&fd) && !self.closed.contains(&fd)
+    }
+
+    fn connection_count(&self) -> usize {
+        self.connected_fds().count()
+    }
+
+    fn max_connections(&self) -> usize {
+        DEFAULT_MAX_CONNECTIONS
+    }
+
+    fn submit_events(&mut self) {
+        for fd in self.in_flight.drain(..) {
+            if let Some(&error) = self.broken.get(&fd) {
+                self.pending.push_back(Pending::Error(fd, error.into()));
+            }
+            self.pending.push_back(Pending::Sent(fd));
+        }
+
+        for (fd, _) in self.broken.drain() {
+            // nothing written to a broken connection arrives
+            self.written.remove(&fd);
+            self.pending.push_back(Pending::Remove(fd));
+        }
+
+        self.pending
+            .extend(self.closing.drain(..).map(Pending::Remove));
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.in_flight.clear();
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libdeflater::CompressionLvl;
+    use valence_protocol::{packets::play::KeepAliveS2c, CompressionThreshold};
+
+    use super::*;
+    use crate::{
+        event::Scratch,
+        net::{encoded_len, IoBuf, MIN_S2C_BUFFER_SIZE},
+    };
+
+    fn event_name(event: &ServerEvent) -> String {
+        match event {
+            ServerEvent::AddPlayer { .. } => "add".to_owned(),
+            ServerEvent::RemovePlayer { .. } => "remove".to_owned(),
+            ServerEvent::RecvData { data, .. } => format!("recv {data:?}"),
+            ServerEvent::RecvBuffer { buffer, .. } => format!("recv {:?}", &*buffer),
+            ServerEvent::SentData { .. } => "sent".to_owned(),
+            ServerEvent::Error { error, .. } => format!("error {}", error.kind()),
+            ServerEvent::CompletionOverflow { dropped } => format!("overflow {dropped}"),
+            ServerEvent::AcceptsRejected { count } => format!("rejected {count}"),
+        }
+    }
+
+    fn drain(server: &mut MockServer) -> Vec<String> {
+        let mut events = Vec::new();
+        server
+            .drain(|event| events.push(event_name(&event)))
+            .unwrap();
+        events
+    }
+
+    #[test]
+    fn test_injected_events() {
+        let mut server = MockServer::default();
+
+        let fd = server.connect();
+        server.recv(fd, &[1, 2, 3]);
+        assert_eq!(drain(&mut server), ["add", "recv [1, 2, 3]"]);
+        assert!(drain(&mut server).is_empty());
+
+        let other = server.connect();
+        assert_eq!(server.connection_count(), 2);
+
+        server.close_after_send(other);
+        assert_eq!(server.connected_fds().collect::<Vec<_>>(), [fd]);
+
+        server.fail(fd, io::ErrorKind::ConnectionReset.into());
+        assert_eq!(drain(&mut server), [
+            "add",
+            "error connection reset",
+            "remove"
+        ]);
+        assert_eq!(server.written(fd), None);
+        assert_eq!(server.connection_count(), 0);
+    }
+
+    #[test]
+    fn test_drain_budget() {
+        let mut server = MockServer::default();
+
+        let fd = server.connect();
+        server.recv(fd, &[1]);
+        server.recv(fd, &[2]);
+
+        let mut events = Vec::new();
+        let mut drain_budget = |server: &mut MockServer, max_events| {
+            server
+                .drain_budget(max_events, |event| events.push(event_name(&event)))
+                .unwrap()
+        };
+
+        assert!(drain_budget(&mut server, 2));
+        assert!(!drain_budget(&mut server, 2));
+        assert!(!drain_budget(&mut server, 2));
+
+        // the leftover event is handled by the next drain, in order
+        assert_eq!(events, ["add", "recv [1]", "recv [2]"]);
+    }
+
+    #[test]
+    fn test_send() {
+        let mut server = MockServer::default();
+
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let fd = server.connect();
+        drain(&mut server);
+
+        let pkt = KeepAliveS2c { id: 1234 };
+        let len = encoded_len(&pkt, &mut buf, &mut scratch, &mut compressor).unwrap();
+
+        packets
+            .append_to(&pkt, None, &mut buf, &mut scratch, &mut compressor)
+            .unwrap();
+        packets.append_raw(b"hello", &mut buf).unwrap();
+
+        let count = server.send(fd, &mut packets);
+
+        // nothing completes before submitting
+        assert!(drain(&mut server).is_empty());
+
+        server.submit_events();
+        assert_eq!(drain(&mut server), vec!["sent"; count]);
+
+        let written = server.take_written(fd);
+        assert_eq!(written.len(), len + 5);
+        assert!(written.ends_with(b"hello"));
+        assert_eq!(server.written(fd), Some(&[][..]));
+    }
+
+    #[test]
+    fn test_write_to_closed_fd() {
+        let mut server = MockServer::default();
+
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let mut packets = Packets::default();
+
+        let fd = server.connect();
+        drain(&mut server);
+
+        packets.append_raw(b"hello", &mut buf).unwrap();
+        packets.append_raw(b"world", &mut buf).unwrap();
+
+        let count = server.send(fd, &mut packets);
+        assert_eq!(count, 2);
+        assert!(packets.oldest_unsent(0).is_some());
+
+        // the peer resets the connection before the writes are submitted
+        server.break_writes(fd, io::ErrorKind::BrokenPipe);
+        server.submit_events();
+
+        let mut events = Vec::new();
+        server
+            .drain(|event| {
+                // release the writes like the ingress system does
+                if let ServerEvent::SentData { .. } = event {
+                    packets.set_successfully_sent(1);
+                }
+                events.push(event_name(&event));
+            })
+            .unwrap();
+
+        assert_eq!(events, [
+            "error broken pipe",
+            "sent",
+            "error broken pipe",
+            "sent",
+            "remove"
+        ]);
+
+        // nothing is left in flight, so the ring can be reused
+        assert_eq!(packets.number_sending(), 0);
+        assert_eq!(packets.oldest_unsent(0), None);
+        assert_eq!(server.written(fd), None);
+        assert_eq!(server.connection_count(), 0);
+    }
+
+    #[test]
+    fn test_replace_s2c_buffers() {
+        let mut server = MockServer::default();
+        server
+            .allocate_buffers(Arc::new(BufferPool::new(2, 64)))
+            .unwrap();
+
+        let smaller = Arc::new(BufferPool::new(1, 64));
+        let larger = Arc::new(BufferPool::new(3, 128));
+
+        // a core would be left without a buffer
+        assert!(server.replace_s2c_buffers(smaller).is_err());
+        assert_eq!(server.buffers.len(), 2);
+
+        server.replace_s2c_buffers(larger.clone()).unwrap();
+        assert_eq!(server.buffers.len(), 3);
+        assert!(server.buffers.iter().all(|buffer| buffer.iov_len == 128));
+        assert_eq!(Arc::strong_count(&larger), 2);
+    }
+}
@@ -0,0 +1,100 @@
+//! Plugin messages, which mods and proxies like BungeeCord use to talk to the server over named
+//! channels.
+//!
+//! See <https://wiki.vg/Plugin_channels>.
+
+use anyhow::ensure;
+use valence_protocol::{packets::play::CustomPayloadS2c, Bounded, RawBytes};
+use valence_server::Ident;
+
+/// The most bytes of data a plugin message can have. This is the limit of messages sent by
+/// clients, which proxies like BungeeCord also hold messages from the server to.
+pub const MAX_PLUGIN_MESSAGE_LEN: usize = 32767;
+
+/// Builds the plugin message sending `data` on `channel`.
+///
+/// # Errors
+/// If `channel` is not a `namespace:path` identifier, or `data` is longer than
+/// [`MAX_PLUGIN_MESSAGE_LEN`].
+pub fn plugin_message<'a>(
+    channel: &'a str,
+    data: &'a [u8],
+) -> anyhow::Result<CustomPayloadS2c<'a>> {
+    validate_channel(channel)?;
+
+    ensure!(
+        data.len() <= MAX_PLUGIN_MESSAGE_LEN,
+        "plugin message on {channel} is {} bytes, which is more than the maximum of \
+         {MAX_PLUGIN_MESSAGE_LEN}",
+        data.len()
+    );
+
+    Ok(CustomPayloadS2c {
+        channel: Ident::new_unchecked(channel).into(),
+        data: Bounded(RawBytes(data)),
+    })
+}
+
+/// Checks that `channel` is a namespaced key. Unlike other identifiers, the namespace cannot be
+/// left out, since clients do not assume `minecraft` for channels.
+fn validate_channel(channel: &str) -> anyhow::Result<()> {
+    let Some((namespace, path)) = channel.split_once(':') else {
+        anyhow::bail!("channel {channel:?} is not in the namespace:path format");
+    };
+
+    ensure!(
+        !namespace.is_empty() && !path.is_empty(),
+        "channel {channel:?} has an empty namespace or path"
+    );
+
+    let valid = |c: u8, extra: &[u8]| {
+        c.is_ascii_lowercase() || c.is_ascii_digit() || b"_-.".contains(&c) || extra.contains(&c)
+    };
+
+    ensure!(
+        namespace.bytes().all(|c| valid(c, b"")),
+        "namespace of channel {channel:?} may only contain [a-z0-9_.-]"
+    );
+    ensure!(
+        path.bytes().all(|c| valid(c, b"/")),
+        "path of channel {channel:?} may only contain [a-z0-9_.-/]"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_channels() {
+        for channel in ["bungeecord:main", "minecraft:brand", "my_mod:sub/path.v-2"] {
+            let pkt = plugin_message(channel, b"data").unwrap();
+            assert_eq!(pkt.channel.as_str(), channel);
+            assert_eq!(pkt.data.0 .0, b"data");
+        }
+    }
+
+    #[test]
+    fn test_invalid_channels() {
+        for channel in [
+            "brand",
+            ":brand",
+            "minecraft:",
+            "Minecraft:brand",
+            "minecraft:bra nd",
+            "name/space:brand",
+        ] {
+            assert!(plugin_message(channel, &[]).is_err(), "{channel}");
+        }
+    }
+
+    #[test]
+    fn test_payload_len() {
+        let data = vec![0; MAX_PLUGIN_MESSAGE_LEN + 1];
+
+        assert!(plugin_message("bungeecord:main", &data[..MAX_PLUGIN_MESSAGE_LEN]).is_ok());
+        assert!(plugin_message("bungeecord:main", &data).is_err());
+    }
+}
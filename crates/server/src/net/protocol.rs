@@ -0,0 +1,252 @@
+//! The Minecraft protocol versions clients can advertise in their handshake, and the protocol
+//! states of a [`LoginState`].
+
+use std::fmt;
+
+use anyhow::{bail, ensure};
+use valence_protocol::{decode::PacketFrame, Decode, Packet, PacketSide, PacketState};
+
+use crate::components::LoginState;
+
+/// A Minecraft protocol version.
+///
+/// Clients advertise their version in the handshake, so one listener can tell apart the versions
+/// it sees. Packets are encoded with the IDs of `valence_protocol`, so only the versions in
+/// [`ProtocolVersion::SUPPORTED`] can actually join. Other known versions are still recognized so
+/// that their clients can be disconnected with a readable message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ProtocolVersion {
+    /// 1.20.1, protocol 763.
+    V1_20_1,
+    /// 1.20.3 and 1.20.4, protocol 765.
+    V1_20_4,
+}
+
+impl ProtocolVersion {
+    /// The version this library targets.
+    pub const CURRENT: Self = Self::V1_20_1;
+    /// Every version clients can join with.
+    pub const SUPPORTED: &'static [Self] = &[Self::V1_20_1];
+
+    /// Parses the protocol number a client advertised in its handshake.
+    #[must_use]
+    pub const fn from_handshake(protocol: i32) -> Option<Self> {
+        match protocol {
+            763 => Some(Self::V1_20_1),
+            765 => Some(Self::V1_20_4),
+            _ => None,
+        }
+    }
+
+    /// The protocol number sent in the handshake and the status response.
+    #[must_use]
+    pub const fn protocol(self) -> i32 {
+        match self {
+            Self::V1_20_1 => 763,
+            Self::V1_20_4 => 765,
+        }
+    }
+
+    /// The stringified name of the Minecraft version.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::V1_20_1 => "1.20.1",
+            Self::V1_20_4 => "1.20.4",
+        }
+    }
+
+    /// Whether clients with this version can join.
+    #[must_use]
+    pub fn is_supported(self) -> bool {
+        Self::SUPPORTED.contains(&self)
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl LoginState {
+    /// The packets a connection in this state sends and receives, which decides what the ID of a
+    /// packet means in both directions. `None` once the connection is being closed, since nothing
+    /// it sends is read anymore.
+    ///
+    /// 1.20.2 adds a configuration state between login and play, which clients can also be sent
+    /// back to from play. Versions which have it are not [`ProtocolVersion::SUPPORTED`] yet.
+    #[must_use]
+    pub const fn packet_state(&self) -> Option<PacketState> {
+        match self {
+            Self::Handshake => Some(PacketState::Handshaking),
+            Self::Status => Some(PacketState::Status),
+            Self::Login | Self::Forwarding { .. } | Self::Queued { .. } => Some(PacketState::Login),
+            Self::TransitioningPlay { .. } | Self::Play => Some(PacketState::Play),
+            Self::Terminate => None,
+        }
+    }
+
+    /// Whether a connection in this state can move to `next`.
+    ///
+    /// Every connection starts in [`LoginState::Handshake`] and can only move forward:
+    ///
+    /// - the handshake moves it to [`LoginState::Status`] or [`LoginState::Login`]
+    /// - a finished login moves it to [`LoginState::Play`], possibly through forwarding and the
+    ///   login queue
+    /// - any state can be terminated
+    #[must_use]
+    pub const fn can_transition_to(&self, next: &Self) -> bool {
+        matches!(
+            (self, next),
+            (_, Self::Terminate)
+                | (Self::Handshake, Self::Status | Self::Login)
+                | (
+                    Self::Login,
+                    Self::Forwarding { .. } | Self::Queued { .. } | Self::TransitioningPlay { .. }
+                )
+                | (
+                    Self::Forwarding { .. },
+                    Self::Queued { .. } | Self::TransitioningPlay { .. }
+                )
+                | (Self::Queued { .. }, Self::TransitioningPlay { .. })
+                | (Self::TransitioningPlay { .. }, Self::Play)
+        )
+    }
+
+    /// Moves to `next`, or fails without changing anything if this state cannot move there.
+    pub fn transition(&mut self, next: Self) -> anyhow::Result<()> {
+        ensure!(
+            self.can_transition_to(&next),
+            "a connection cannot go from {self:?} to {next:?}"
+        );
+
+        *self = next;
+        Ok(())
+    }
+
+    /// Checks that `frame` is a `P`, which has to be a serverbound packet of this state. Packet
+    /// IDs are only unique within a state, so this catches frames read as a packet of another
+    /// state which happens to have the same ID.
+    pub fn expect<P: Packet>(&self, frame: &PacketFrame) -> anyhow::Result<()> {
+        if P::SIDE != PacketSide::Serverbound {
+            bail!("{} is not sent by clients", P::NAME);
+        }
+
+        ensure!(
+            self.packet_state() == Some(P::STATE),
+            "{} cannot be received in the {self:?} state",
+            P::NAME
+        );
+
+        ensure!(
+            frame.id == P::ID,
+            "expected {} ({:#x}), but got packet {:#x}",
+            P::NAME,
+            P::ID,
+            frame.id
+        );
+
+        Ok(())
+    }
+
+    /// Decodes `frame` as `P` after checking it with [`LoginState::expect`].
+    pub fn decode<'a, P>(&self, frame: &'a PacketFrame) -> anyhow::Result<P>
+    where
+        P: Packet + Decode<'a>,
+    {
+        self.expect::<P>(frame)?;
+        frame.decode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use valence_protocol::packets::{
+        handshaking::HandshakeC2s,
+        status::{QueryPingC2s, QueryPongS2c, QueryRequestC2s},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_handshake_roundtrip() {
+        for version in [ProtocolVersion::V1_20_1, ProtocolVersion::V1_20_4] {
+            assert_eq!(
+                ProtocolVersion::from_handshake(version.protocol()),
+                Some(version)
+            );
+        }
+
+        assert_eq!(ProtocolVersion::from_handshake(0), None);
+    }
+
+    #[test]
+    fn test_transitions() {
+        let mut state = LoginState::Handshake;
+
+        assert!(state.transition(LoginState::Play).is_err());
+        assert_eq!(state, LoginState::Handshake);
+
+        state.transition(LoginState::Login).unwrap();
+        assert!(state.transition(LoginState::Status).is_err());
+        assert!(state.transition(LoginState::Login).is_err());
+
+        state
+            .transition(LoginState::Forwarding {
+                username: Box::from("a"),
+            })
+            .unwrap();
+        state
+            .transition(LoginState::TransitioningPlay {
+                packets_to_transition: 1,
+            })
+            .unwrap();
+        assert!(state.transition(LoginState::Login).is_err());
+
+        state.transition(LoginState::Play).unwrap();
+        assert!(state.transition(LoginState::Handshake).is_err());
+        state.transition(LoginState::Terminate).unwrap();
+        assert!(state.transition(LoginState::Play).is_err());
+
+        let mut state = LoginState::Handshake;
+        state.transition(LoginState::Status).unwrap();
+        assert!(state.transition(LoginState::Play).is_err());
+    }
+
+    #[test]
+    fn test_decode_checks_state() {
+        // the query request has the same ID as the handshake, but is only sent in the status state
+        assert_eq!(QueryRequestC2s::ID, HandshakeC2s::ID);
+
+        let frame = PacketFrame {
+            id: QueryRequestC2s::ID,
+            body: BytesMut::new(),
+        };
+
+        assert!(LoginState::Status.decode::<QueryRequestC2s>(&frame).is_ok());
+        assert!(LoginState::Handshake
+            .decode::<QueryRequestC2s>(&frame)
+            .is_err());
+        assert!(LoginState::Status.expect::<HandshakeC2s>(&frame).is_err());
+        assert!(LoginState::Terminate
+            .expect::<QueryRequestC2s>(&frame)
+            .is_err());
+
+        // the state has to match the ID as well
+        assert!(LoginState::Status.expect::<QueryPingC2s>(&frame).is_err());
+
+        // clientbound packets are never decoded
+        assert!(LoginState::Status.decode::<QueryPongS2c>(&frame).is_err());
+    }
+
+    #[test]
+    fn test_current_is_supported() {
+        assert!(ProtocolVersion::CURRENT.is_supported());
+        assert_eq!(
+            ProtocolVersion::CURRENT.protocol(),
+            crate::net::PROTOCOL_VERSION
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Spreading the encoding of many packets across ticks, so a burst like the chunks of a player who
+//! just joined does not stall a single tick.
+
+use std::collections::VecDeque;
+
+use crate::net::{AppendError, Packets};
+
+/// The number of bytes [`PacketQueue::flush`] appends per connection by default. This is enough
+/// for a few chunks per tick.
+pub const DEFAULT_ENCODE_BUDGET: usize = 256 * 1024;
+
+/// Packets which have not been encoded yet, described by a `T` like the position of a chunk.
+///
+/// [`BandwidthLimiter`](crate::net::BandwidthLimiter) bounds how much is written every tick, but
+/// everything it holds back was already encoded. This bounds the encoding instead: each
+/// [`PacketQueue::flush`] only encodes packets until [`PacketQueue::budget`] bytes were
+/// appended, and carries the rest forward to the next tick.
+#[derive(Debug, Clone)]
+pub struct PacketQueue<T> {
+    pending: VecDeque<T>,
+    budget: usize,
+}
+
+impl<T> Default for PacketQueue<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENCODE_BUDGET)
+    }
+}
+
+impl<T> Extend<T> for PacketQueue<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.pending.extend(iter);
+    }
+}
+
+impl<T> PacketQueue<T> {
+    /// An empty queue which appends up to `budget` bytes per flush.
+    #[must_use]
+    pub const fn new(budget: usize) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            budget,
+        }
+    }
+
+    /// How many bytes [`PacketQueue::flush`] appends at most. A flush always encodes at least one
+    /// packet, so a packet larger than this still goes out, just on its own.
+    #[must_use]
+    pub const fn budget(&self) -> usize {
+        self.budget
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+    }
+
+    /// Queues `item` after everything which is already queued.
+    pub fn push(&mut self, item: T) {
+        self.pending.push_back(item);
+    }
+
+    /// The number of packets which have not been encoded yet.
+    #[must_use]
+    pub fn backlog(&self) -> usize {
+        self.pending.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Keeps only the queued packets for which `f` returns `true`, like the chunks which are still
+    /// in view after a player moved.
+    pub fn retain(&mut self, f: impl FnMut(&T) -> bool) {
+        self.pending.retain(f);
+    }
+
+    /// Drops every queued packet.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Encodes queued packets in order with `encode`, which appends the packet `T` describes to
+    /// `packets`, until [`PacketQueue::budget`] bytes were appended or nothing is queued anymore.
+    /// Returns the number of bytes which were appended.
+    ///
+    /// If `encode` fails with [`AppendError::RingFull`], the packet stays at the front of the
+    /// queue to be retried by the next flush.
+    ///
+    /// # Errors
+    /// The error of `encode` if it failed with anything else. The packet is dropped in that case,
+    /// since encoding it again would fail again.
+    pub fn flush(
+        &mut self,
+        packets: &Packets,
+        mut encode: impl FnMut(&T) -> Result<(), AppendError>,
+    ) -> Result<usize, AppendError> {
+        let start = packets.queued_bytes();
+        let appended = || packets.queued_bytes() - start;
+
+        while appended() < self.budget {
+            let Some(item) = self.pending.front() else {
+                break;
+            };
+
+            match encode(item) {
+                // the ring only has room again once what is queued was sent
+                Err(AppendError::RingFull) => break,
+                result => {
+                    self.pending.pop_front();
+                    result?;
+                }
+            }
+        }
+
+        Ok(appended())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use valence_protocol::CompressionThreshold;
+
+    use super::*;
+    use crate::net::{IoBuf, MIN_S2C_BUFFER_SIZE};
+
+    #[test]
+    fn test_budget() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+
+        let mut queue = PacketQueue::new(10);
+        queue.extend([*b"abcd", *b"efgh", *b"ijkl", *b"mnop", *b"qrst"]);
+
+        let mut encode = |data: &[u8; 4]| packets.append_raw(data, &mut buf);
+
+        // the packet which crosses the budget is still appended
+        assert_eq!(queue.flush(&packets, &mut encode).unwrap(), 12);
+        assert_eq!(queue.backlog(), 2);
+
+        assert_eq!(queue.flush(&packets, &mut encode).unwrap(), 8);
+        assert!(queue.is_empty());
+
+        assert_eq!(queue.flush(&packets, &mut encode).unwrap(), 0);
+        assert_eq!(packets.total_len(), 20);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut buf = IoBuf::new(CompressionThreshold(-1), MIN_S2C_BUFFER_SIZE, 0);
+        let packets = Packets::default();
+
+        let mut queue = PacketQueue::new(DEFAULT_ENCODE_BUDGET);
+        queue.extend([1, 2, 3]);
+
+        // a full ring keeps the packet for the next flush
+        let appended = queue.flush(&packets, |_| Err(AppendError::RingFull));
+        assert_eq!(appended.unwrap(), 0);
+        assert_eq!(queue.backlog(), 3);
+
+        // anything else drops it
+        let result = queue.flush(&packets, |&item| match item {
+            2 => Err(AppendError::TooLarge { size: 0 }),
+            _ => packets.append_raw(b"data", &mut buf),
+        });
+        assert!(matches!(result, Err(AppendError::TooLarge { .. })));
+        assert_eq!(queue.backlog(), 1);
+
+        queue.retain(|&item| item != 3);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,472 @@
+//! Records the exact bytes sent to connections, for debugging protocol issues and building
+//! regression corpora. This is only compiled with the `record` feature.
+//!
+//! A recording is a header followed by one record for every write passed to the OS:
+//!
+//! - the magic bytes `HYPREC`, and the format version as one byte, which is [`VERSION`]
+//! - the compression threshold in effect when the recording started, as a big-endian `i32`
+//! - whether the connection was still logging in when the recording started, as one byte which
+//!   is 0 or 1
+//! - for every write, its length as a big-endian `u32` followed by its bytes
+//!
+//! Writes are recorded in the order they are sent, but before encryption: the bytes of a
+//! connection which enabled encryption are decrypted again with
+//! [`Recorder::enable_encryption`], so a recording can be decoded without the shared secret.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use anyhow::{ensure, Context};
+use fxhash::FxHashMap;
+use tracing::warn;
+use valence_protocol::{
+    decode::PacketFrame,
+    packets::login::{LoginCompressionS2c, LoginSuccessS2c},
+    CompressionThreshold, Packet,
+};
+
+use crate::{
+    event::ScratchBuffer,
+    net::{Fd, PacketDecoder, PacketDecryptor},
+};
+
+const MAGIC: &[u8; 6] = b"HYPREC";
+
+/// The version of the format, which is incremented whenever it changes.
+pub const VERSION: u8 = 2;
+
+/// Writes a recording to `W`.
+struct Tap<W: Write> {
+    out: W,
+    /// Decrypts the writes once the connection enabled encryption, after the bytes which are
+    /// still sent as plaintext. See [`Recorder::enable_encryption`].
+    decryption: Option<(usize, PacketDecryptor)>,
+    /// Holds a decrypted write, so the bytes in the ring are not touched
+    decrypted: Vec<u8>,
+}
+
+impl<W: Write> fmt::Debug for Tap<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tap")
+            .field("encrypted", &self.decryption.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> Tap<W> {
+    fn new(mut out: W, threshold: CompressionThreshold, logging_in: bool) -> std::io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        out.write_all(&threshold.0.to_be_bytes())?;
+        out.write_all(&[u8::from(logging_in)])?;
+
+        Ok(Self {
+            out,
+            decryption: None,
+            decrypted: Vec::new(),
+        })
+    }
+
+    fn record(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let len = u32::try_from(data.len())
+            .map_err(|_| std::io::Error::new(ErrorKind::InvalidInput, "write is too large"))?;
+
+        self.out.write_all(&len.to_be_bytes())?;
+
+        let Some((plaintext, decryption)) = &mut self.decryption else {
+            return self.out.write_all(data);
+        };
+
+        let skip = (*plaintext).min(data.len());
+        *plaintext -= skip;
+
+        self.decrypted.clear();
+        self.decrypted.extend_from_slice(data);
+        decryption.decrypt(&mut self.decrypted[skip..]);
+
+        self.out.write_all(&self.decrypted)
+    }
+}
+
+/// The connections whose writes are recorded, each to its own file.
+///
+/// [`ServerDef::write_all`](crate::net::ServerDef::write_all) records writes through
+/// [`crate::global::Global::drain_writes`], so only the bytes which are actually passed to the OS
+/// are recorded, in the order they are sent.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    taps: FxHashMap<Fd, Tap<BufWriter<File>>>,
+}
+
+impl Recorder {
+    /// Starts recording everything sent to `fd` to a new file at `path`, replacing any recording
+    /// of `fd` which is in progress.
+    ///
+    /// `threshold` is the compression threshold of the connection at this point, so it must be -1
+    /// if the connection has not been sent a `LoginCompressionS2c` yet. `logging_in` is whether
+    /// the connection has not been sent a `LoginSuccessS2c` yet, in which case the
+    /// [`ReplayDecoder`] enables compression when it decodes the `LoginCompressionS2c`, like a
+    /// client.
+    ///
+    /// If the connection already enabled encryption, [`Recorder::enable_encryption`] has to be
+    /// called again after this.
+    pub fn start(
+        &mut self,
+        fd: Fd,
+        path: impl AsRef<Path>,
+        threshold: CompressionThreshold,
+        logging_in: bool,
+    ) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("failed to create recording {}", path.display()))?;
+
+        let tap = Tap::new(BufWriter::new(file), threshold, logging_in)?;
+        self.stop(fd)?;
+        self.taps.insert(fd, tap);
+
+        Ok(())
+    }
+
+    /// Stops recording `fd` and flushes what was recorded. This is called when the connection is
+    /// removed, since its fd may be reused.
+    pub fn stop(&mut self, fd: Fd) -> anyhow::Result<()> {
+        if let Some(mut tap) = self.taps.remove(&fd) {
+            tap.out.flush().context("failed to flush recording")?;
+        }
+
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn is_recording(&self, fd: Fd) -> bool {
+        self.taps.contains_key(&fd)
+    }
+
+    /// Decrypts the writes to `fd` before they are recorded, like the client does, once the
+    /// first `plaintext_len` bytes were recorded. Call this with
+    /// [`Packets::enable_encryption`](crate::net::Packets::enable_encryption), passing
+    /// [`Packets::plaintext_len`](crate::net::Packets::plaintext_len) right after it. This does
+    /// nothing if `fd` is not recorded.
+    pub fn enable_encryption(&mut self, fd: Fd, shared_secret: &[u8; 16], plaintext_len: usize) {
+        if let Some(tap) = self.taps.get_mut(&fd) {
+            tap.decryption = Some((plaintext_len, PacketDecryptor::new(shared_secret)));
+        }
+    }
+
+    /// Records `data` being sent to `fd`, if it is recorded. If the recording cannot be written,
+    /// it is stopped.
+    pub(crate) fn record(&mut self, fd: Fd, data: &[u8]) {
+        let Some(tap) = self.taps.get_mut(&fd) else {
+            return;
+        };
+
+        if let Err(err) = tap.record(data) {
+            warn!("failed to record write to {fd:?}, so the recording is stopped: {err}");
+            self.taps.remove(&fd);
+        }
+    }
+}
+
+/// A recording made by a [`Recorder`], which can be decoded with [`Replay::decoder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    threshold: CompressionThreshold,
+    logging_in: bool,
+    writes: Vec<Vec<u8>>,
+}
+
+impl Replay {
+    /// Reads the recording at `path`.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("failed to open recording {}", path.display()))?;
+
+        Self::read(BufReader::new(file))
+            .with_context(|| format!("invalid recording {}", path.display()))
+    }
+
+    /// Reads a recording from `reader`. A recording which ends in the middle of a write, like
+    /// when the server crashed, is an error.
+    pub fn read(mut reader: impl Read) -> anyhow::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        let mut version = [0; 1];
+        let mut threshold = [0; 4];
+        let mut logging_in = [0; 1];
+
+        for field in [
+            &mut magic[..],
+            &mut version[..],
+            &mut threshold[..],
+            &mut logging_in[..],
+        ] {
+            reader.read_exact(field).context("recording is too short")?;
+        }
+
+        ensure!(&magic == MAGIC, "recording does not start with {MAGIC:?}");
+
+        let [version] = version;
+        ensure!(
+            version == VERSION,
+            "recording has version {version}, but only version {VERSION} is supported"
+        );
+
+        let threshold = CompressionThreshold(i32::from_be_bytes(threshold));
+
+        let logging_in = match logging_in {
+            [0] => false,
+            [1] => true,
+            [other] => anyhow::bail!("recording has an invalid login flag of {other}"),
+        };
+
+        let mut writes = Vec::new();
+
+        loop {
+            let mut len = [0; 4];
+            match reader.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            }
+
+            // the length is untrusted, so only allocate as much as the recording actually contains
+            let len = u64::from(u32::from_be_bytes(len));
+            let mut write = Vec::new();
+            let read = (&mut reader)
+                .take(len)
+                .read_to_end(&mut write)
+                .with_context(|| format!("failed to read write {}", writes.len()))?;
+            ensure!(read as u64 == len, "write {} is cut off", writes.len());
+
+            writes.push(write);
+        }
+
+        Ok(Self {
+            threshold,
+            logging_in,
+            writes,
+        })
+    }
+
+    /// The compression threshold in effect when the recording started.
+    #[must_use]
+    pub const fn threshold(&self) -> CompressionThreshold {
+        self.threshold
+    }
+
+    /// Whether the connection was still logging in when the recording started.
+    #[must_use]
+    pub const fn logging_in(&self) -> bool {
+        self.logging_in
+    }
+
+    /// Every recorded write, in the order they were sent.
+    #[must_use]
+    pub fn writes(&self) -> &[Vec<u8>] {
+        &self.writes
+    }
+
+    /// A decoder with every recorded byte queued, which inflates packets with
+    /// [`Replay::threshold`] until the connection is sent a `LoginCompressionS2c`.
+    #[must_use]
+    pub fn decoder(&self) -> ReplayDecoder {
+        let mut decoder = PacketDecoder::new();
+        decoder.set_compression(self.threshold);
+
+        for write in &self.writes {
+            decoder.queue_slice(write);
+        }
+
+        ReplayDecoder {
+            decoder,
+            logging_in: self.logging_in,
+        }
+    }
+}
+
+/// Decodes the packets of a [`Replay`] like a client would: while the connection is logging in,
+/// the threshold of a `LoginCompressionS2c` applies to the packets after it.
+#[derive(Debug)]
+pub struct ReplayDecoder {
+    decoder: PacketDecoder,
+    /// Whether a `LoginSuccessS2c` has not been decoded yet, since the IDs of the login packets
+    /// are used by other packets once the connection is playing
+    logging_in: bool,
+}
+
+impl ReplayDecoder {
+    /// The next packet, or `None` once every recorded byte was decoded. See
+    /// [`PacketDecoder::try_next_packet`].
+    pub fn try_next_packet(
+        &mut self,
+        scratch: &mut impl ScratchBuffer,
+    ) -> anyhow::Result<Option<PacketFrame>> {
+        let Some(frame) = self.decoder.try_next_packet(scratch)? else {
+            return Ok(None);
+        };
+
+        if self.logging_in {
+            match frame.id {
+                LoginCompressionS2c::ID => {
+                    let pkt: LoginCompressionS2c = frame.decode()?;
+                    self.decoder
+                        .set_compression(CompressionThreshold(pkt.threshold.0));
+                }
+                LoginSuccessS2c::ID => self.logging_in = false,
+                _ => {}
+            }
+        }
+
+        Ok(Some(frame))
+    }
+
+    /// Whether every recorded byte was decoded.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.decoder.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libdeflater::CompressionLvl;
+    use valence_protocol::{packets::play::KeepAliveS2c, VarInt};
+
+    use super::*;
+    use crate::{
+        event::Scratch,
+        net::{encoder::PacketEncoder, PacketEncryptor, VecBuf},
+    };
+
+    fn record(threshold: CompressionThreshold, writes: &[&[u8]]) -> Vec<u8> {
+        let mut tap = Tap::new(Vec::new(), threshold, false).unwrap();
+
+        for write in writes {
+            tap.record(write).unwrap();
+        }
+
+        tap.out
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let recording = record(CompressionThreshold(256), &[b"hello", b"", b"world"]);
+        let replay = Replay::read(&recording[..]).unwrap();
+
+        assert_eq!(replay.threshold(), CompressionThreshold(256));
+        assert_eq!(replay.writes(), [&b"hello"[..], &b""[..], &b"world"[..]]);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let recording = record(CompressionThreshold(-1), &[b"hello"]);
+
+        assert!(Replay::read(&recording[..recording.len() - 1]).is_err());
+        assert!(Replay::read(&recording[..5]).is_err());
+        assert!(Replay::read(&b"NOTREC\x01\0\0\0\0"[..]).is_err());
+
+        // a write claiming to be far larger than the recording is rejected without allocating it
+        let mut recording = record(CompressionThreshold(-1), &[]);
+        recording.extend_from_slice(&u32::MAX.to_be_bytes());
+        recording.extend_from_slice(b"hello");
+        assert!(Replay::read(&recording[..]).is_err());
+    }
+
+    #[test]
+    fn test_decode_compressed() {
+        let threshold = CompressionThreshold(0);
+        let enc = PacketEncoder::new(threshold);
+
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+
+        let mut bytes = VecBuf::new();
+        for id in 0..3 {
+            enc.append_packet(
+                &KeepAliveS2c { id },
+                &mut bytes,
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap();
+        }
+
+        // the packets are split over writes in a different place than they start
+        let (first, second) = bytes.split_at(bytes.len() / 2);
+        let replay = Replay::read(&record(threshold, &[first, second])[..]).unwrap();
+
+        let mut decoder = replay.decoder();
+        for id in 0..3 {
+            let frame = decoder.try_next_packet(&mut scratch).unwrap().unwrap();
+            assert_eq!(frame.id, KeepAliveS2c::ID);
+            assert_eq!(frame.decode::<KeepAliveS2c>().unwrap().id, id);
+        }
+        assert!(decoder.try_next_packet(&mut scratch).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_login_compression() {
+        let mut scratch = Scratch::new();
+        let mut compressor = libdeflater::Compressor::new(CompressionLvl::default());
+        let mut bytes = VecBuf::new();
+
+        PacketEncoder::new(CompressionThreshold(-1))
+            .append_packet(
+                &LoginCompressionS2c {
+                    threshold: VarInt(0),
+                },
+                &mut bytes,
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap();
+
+        PacketEncoder::new(CompressionThreshold(0))
+            .append_packet(
+                &KeepAliveS2c { id: 7 },
+                &mut bytes,
+                &mut scratch,
+                &mut compressor,
+            )
+            .unwrap();
+
+        let mut recording = Tap::new(Vec::new(), CompressionThreshold(-1), true).unwrap();
+        recording.record(&bytes).unwrap();
+        let replay = Replay::read(&recording.out[..]).unwrap();
+        assert!(replay.logging_in());
+
+        let mut decoder = replay.decoder();
+        let frame = decoder.try_next_packet(&mut scratch).unwrap().unwrap();
+        assert_eq!(frame.id, LoginCompressionS2c::ID);
+
+        // the keep alive is only decoded correctly with the threshold of the packet before it
+        let frame = decoder.try_next_packet(&mut scratch).unwrap().unwrap();
+        assert_eq!(frame.id, KeepAliveS2c::ID);
+        assert_eq!(frame.decode::<KeepAliveS2c>().unwrap().id, 7);
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn test_record_before_encryption() {
+        let secret = [7; 16];
+
+        let mut sent = b"plainsecret".to_vec();
+        PacketEncryptor::new(&secret).encrypt(&mut sent[5..]);
+
+        let mut tap = Tap::new(Vec::new(), CompressionThreshold(-1), false).unwrap();
+        tap.decryption = Some((5, PacketDecryptor::new(&secret)));
+
+        // the plaintext ends in the middle of a write
+        let (first, second) = sent.split_at(3);
+        tap.record(first).unwrap();
+        tap.record(second).unwrap();
+
+        let replay = Replay::read(&tap.out[..]).unwrap();
+        assert_eq!(replay.writes(), [&b"pla"[..], &b"insecret"[..]]);
+    }
+}
@@ -0,0 +1,178 @@
+//! C2S buffers which a handler can hold onto after [`crate::net::ServerDef::drain`] returns.
+
+#![cfg_attr(
+    not(target_os = "linux"),
+    allow(dead_code, reason = "buffers are only provided to the kernel on linux")
+)]
+
+use std::{cell::UnsafeCell, fmt, ops::Deref, sync::Arc};
+
+use crossbeam_queue::ArrayQueue;
+
+/// The memory C2S data is received into, split into `count` buffers of `buffer_len` bytes which
+/// are referred to by their ID like in a provided-buffer ring.
+///
+/// The kernel writes into every buffer which is provided to it, so the memory is only accessed
+/// through raw pointers, and a buffer is only read while it is not provided.
+pub(crate) struct RecvBufferPool {
+    memory: Box<[UnsafeCell<u8>]>,
+    count: usize,
+    buffer_len: usize,
+    /// IDs of the buffers whose [`RecvBuffer`] was dropped, which have not been provided again
+    returned: ArrayQueue<u16>,
+}
+
+// SAFETY: a buffer is only read through a `RecvBuffer`, and the kernel only writes into buffers
+// which no `RecvBuffer` refers to
+unsafe impl Sync for RecvBufferPool {}
+
+impl RecvBufferPool {
+    pub(crate) fn new(count: usize, buffer_len: usize) -> Self {
+        assert!(count <= usize::from(u16::MAX) + 1, "buffer IDs are u16");
+
+        Self {
+            memory: (0..count * buffer_len)
+                .map(|_| UnsafeCell::new(0))
+                .collect(),
+            count,
+            buffer_len,
+            returned: ArrayQueue::new(count.max(1)),
+        }
+    }
+
+    pub(crate) const fn count(&self) -> usize {
+        self.count
+    }
+
+    pub(crate) const fn buffer_len(&self) -> usize {
+        self.buffer_len
+    }
+
+    /// The start of the buffer `id`, which the kernel can write into.
+    ///
+    /// The pointer is derived from the whole of `memory` rather than from its first byte, so it
+    /// may be used for all `buffer_len` bytes of the buffer.
+    pub(crate) fn buffer_ptr(&self, id: u16) -> *mut u8 {
+        let offset = usize::from(id) * self.buffer_len;
+        assert!(offset < self.memory.len(), "buffer {id} is out of bounds");
+
+        // SAFETY: `offset` is in bounds of `memory`
+        unsafe { UnsafeCell::raw_get(self.memory.as_ptr()).add(offset) }
+    }
+
+    /// The first `len` bytes of the buffer `id`.
+    ///
+    /// # Safety
+    /// The buffer must not be provided to the kernel while the slice is alive.
+    pub(crate) unsafe fn slice(&self, id: u16, len: usize) -> &[u8] {
+        assert!(len <= self.buffer_len);
+        // SAFETY: the buffer is in bounds of `memory`, and the caller guarantees that nothing
+        // writes to it
+        unsafe { std::slice::from_raw_parts(self.buffer_ptr(id), len) }
+    }
+
+    /// Takes the IDs of the buffers whose [`RecvBuffer`] was dropped, so they can be provided
+    /// again.
+    pub(crate) fn take_returned(&self) -> impl Iterator<Item = u16> + '_ {
+        std::iter::from_fn(|| self.returned.pop())
+    }
+}
+
+/// Data which was received into a C2S buffer, owned by the handler instead of borrowed for the
+/// duration of the [`crate::net::ServerDef::drain`] callback. See
+/// [`crate::net::ServerEvent::RecvBuffer`].
+///
+/// This holds on to the buffer itself instead of a copy of the data, so it can be sent to
+/// another thread to be decoded there. Once this is dropped, the buffer is given back to the
+/// kernel by the next drain. It stays valid even if the server was dropped before.
+///
+/// The pool of buffers is small and shared by every connection. While buffers are held, the
+/// kernel has fewer buffers to receive into, and once every buffer is held, nothing is received
+/// from any connection until some are dropped. Drop each buffer as soon as it was decoded, and
+/// do not hold buffers across ticks.
+pub struct RecvBuffer {
+    pool: Arc<RecvBufferPool>,
+    id: u16,
+    len: usize,
+}
+
+impl RecvBuffer {
+    /// # Safety
+    /// The first `len` bytes of the buffer `id` must have been written, and the buffer must not
+    /// be provided to the kernel until this is dropped.
+    pub(crate) unsafe fn new(pool: Arc<RecvBufferPool>, id: u16, len: usize) -> Self {
+        assert!(len <= pool.buffer_len());
+        Self { pool, id, len }
+    }
+
+    /// The ID of the buffer in the provided-buffer ring.
+    #[must_use]
+    pub const fn id(&self) -> u16 {
+        self.id
+    }
+}
+
+impl Deref for RecvBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the buffer is not provided until this is dropped
+        unsafe { self.pool.slice(self.id, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for RecvBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for RecvBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvBuffer")
+            .field("id", &self.id)
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl Drop for RecvBuffer {
+    fn drop(&mut self) {
+        // there is room for every buffer, and each one is only held by one `RecvBuffer` at a time
+        let returned = self.pool.returned.push(self.id);
+        debug_assert!(returned.is_ok(), "buffer {} was returned twice", self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_return_on_drop() {
+        let pool = Arc::new(RecvBufferPool::new(4, 8));
+
+        // SAFETY: nothing else refers to the buffer
+        unsafe {
+            std::ptr::copy_nonoverlapping(b"hello".as_ptr(), pool.buffer_ptr(2), 5);
+        }
+
+        // SAFETY: the first 5 bytes were written
+        let buffer = unsafe { RecvBuffer::new(pool.clone(), 2, 5) };
+        assert_eq!(pool.take_returned().count(), 0);
+
+        // the buffer can be decoded on another thread
+        let buffer = std::thread::spawn(move || {
+            assert_eq!(&*buffer, b"hello");
+            buffer
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(buffer.id(), 2);
+        drop(buffer);
+
+        assert_eq!(pool.take_returned().collect::<Vec<_>>(), [2]);
+        assert_eq!(pool.take_returned().count(), 0);
+    }
+}
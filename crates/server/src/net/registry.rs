@@ -0,0 +1,243 @@
+//! A startup check that the packets the server sends have the IDs the active protocol expects.
+
+use std::fmt::Write;
+
+use valence_protocol::{
+    packets::{login, play, status},
+    Packet, PacketSide, PacketState,
+};
+
+use crate::{net::ProtocolVersion, packets::vanilla};
+
+/// A packet type which was registered in a [`PacketRegistry`].
+#[derive(Debug, Copy, Clone)]
+struct RegisteredPacket {
+    name: &'static str,
+    side: PacketSide,
+    state: PacketState,
+    id: i32,
+    /// The ID the packet must have in the version of the registry, if it is known.
+    expected: Option<i32>,
+}
+
+/// Every packet type the server sends, together with the IDs they are encoded with.
+///
+/// The IDs come from [`Packet::ID`], which is generated by `valence_protocol` for a single
+/// protocol version. When `valence_protocol` is bumped without updating
+/// [`ProtocolVersion::CURRENT`] (or the other way around), packets would silently be sent with the
+/// wrong opcode. [`PacketRegistry::validate`] catches this at startup instead.
+#[derive(Debug)]
+pub struct PacketRegistry {
+    version: ProtocolVersion,
+    packets: Vec<RegisteredPacket>,
+}
+
+impl PacketRegistry {
+    /// Creates an empty registry for `version`.
+    #[must_use]
+    pub const fn new(version: ProtocolVersion) -> Self {
+        Self {
+            version,
+            packets: Vec::new(),
+        }
+    }
+
+    /// The registry of every packet the server sends for [`ProtocolVersion::CURRENT`].
+    ///
+    /// Packets whose IDs are known for the version are pinned to them.
+    #[must_use]
+    pub fn server() -> Self {
+        let mut registry = Self::new(ProtocolVersion::CURRENT);
+
+        if registry.version == ProtocolVersion::V1_20_1 {
+            registry
+                .expect::<login::LoginDisconnectS2c>(0x00)
+                .expect::<login::LoginSuccessS2c>(0x02)
+                .expect::<login::LoginCompressionS2c>(0x03)
+                .expect::<status::QueryResponseS2c>(0x00)
+                .expect::<status::QueryPongS2c>(0x01)
+                .expect::<play::DisconnectS2c>(0x1A)
+                .expect::<play::KeepAliveS2c>(0x23)
+                .expect::<play::GameJoinS2c>(0x28);
+        }
+
+        registry
+            .register::<play::BlockUpdateS2c>()
+            .register::<play::ChunkDataS2c>()
+            .register::<play::ChunkRenderDistanceCenterS2c>()
+            .register::<play::ClearTitleS2c>()
+            .register::<play::CommandTreeS2c>()
+            .register::<play::CustomPayloadS2c>()
+            .register::<play::EntitiesDestroyS2c>()
+            .register::<play::EntityAnimationS2c>()
+            .register::<play::EntityDamageS2c>()
+            .register::<play::EntityEquipmentUpdateS2c>()
+            .register::<play::EntityPositionS2c>()
+            .register::<play::EntitySetHeadYawS2c>()
+            .register::<play::EntitySpawnS2c>()
+            .register::<play::EntityStatusEffectS2c>()
+            .register::<play::EntityTrackerUpdateS2c>()
+            .register::<play::EntityVelocityUpdateS2c>()
+            .register::<play::GameMessageS2c>()
+            .register::<play::GameStateChangeS2c>()
+            .register::<play::HealthUpdateS2c>()
+            .register::<play::MoveRelativeS2c>()
+            .register::<play::PlayerActionResponseS2c>()
+            .register::<play::PlayerListHeaderS2c>()
+            .register::<play::PlayerListS2c>()
+            .register::<play::PlayerPositionLookS2c>()
+            .register::<play::PlayerRemoveS2c>()
+            .register::<play::PlayerRespawnS2c>()
+            .register::<play::PlayerSpawnPositionS2c>()
+            .register::<play::PlayerSpawnS2c>()
+            .register::<play::RotateAndMoveRelativeS2c>()
+            .register::<play::RotateS2c>()
+            .register::<play::SynchronizeTagsS2c>()
+            .register::<play::TeamS2c>()
+            .register::<play::WorldBorderCenterChangedS2c>()
+            .register::<play::WorldBorderInitializeS2c>()
+            .register::<play::WorldBorderSizeChangedS2c>()
+            .register::<play::WorldTimeUpdateS2c>()
+            .register::<vanilla::EntityEquipmentUpdateS2c>();
+
+        registry
+    }
+
+    /// Registers `P` without knowing which ID it should have.
+    pub fn register<P: Packet>(&mut self) -> &mut Self {
+        self.push::<P>(None)
+    }
+
+    /// Registers `P`, which must have `id` in the version of this registry.
+    pub fn expect<P: Packet>(&mut self, id: i32) -> &mut Self {
+        self.push::<P>(Some(id))
+    }
+
+    fn push<P: Packet>(&mut self, expected: Option<i32>) -> &mut Self {
+        self.packets.push(RegisteredPacket {
+            name: P::NAME,
+            side: P::SIDE,
+            state: P::STATE,
+            id: P::ID,
+            expected,
+        });
+        self
+    }
+
+    /// Every problem with the registered packets, in registration order.
+    fn errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        for (i, packet) in self.packets.iter().enumerate() {
+            let RegisteredPacket {
+                name,
+                side,
+                state,
+                id,
+                expected,
+            } = *packet;
+
+            if side != PacketSide::Clientbound {
+                errors.push(format!("{name} is sent by the server but is {side:?}"));
+            }
+
+            if id < 0 {
+                errors.push(format!("{name} has negative ID {id}"));
+            }
+
+            if let Some(expected) = expected {
+                if id != expected {
+                    errors.push(format!(
+                        "{name} has ID {id:#04x} but must be {expected:#04x} in {}",
+                        self.version
+                    ));
+                }
+            }
+
+            // the same packet may be registered more than once, for example when it has a custom
+            // encoder, but it must always have the same ID
+            for other in &self.packets[..i] {
+                if other.state != state || other.side != side {
+                    continue;
+                }
+
+                if other.name == name && other.id != id {
+                    errors.push(format!(
+                        "{name} is registered with IDs {:#04x} and {id:#04x}",
+                        other.id
+                    ));
+                }
+
+                if other.name != name && other.id == id {
+                    errors.push(format!(
+                        "{name} and {} both have ID {id:#04x} in {state:?}",
+                        other.name
+                    ));
+                }
+            }
+        }
+
+        errors
+    }
+
+    /// Checks that every registered packet is clientbound, has the ID it must have in the
+    /// version of this registry, and does not share its ID with another packet.
+    ///
+    /// # Panics
+    /// If any of these checks fails. This usually means `valence_protocol` does not target the
+    /// same version as [`ProtocolVersion::CURRENT`].
+    pub fn validate(&self) {
+        let errors = self.errors();
+
+        if errors.is_empty() {
+            return;
+        }
+
+        let mut msg = format!(
+            "packet IDs do not match protocol {} ({}):",
+            self.version.protocol(),
+            self.version
+        );
+
+        for error in errors {
+            let _ = write!(msg, "\n  - {error}");
+        }
+
+        panic!("{msg}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use valence_protocol::packets::handshaking::handshake_c2s::HandshakeC2s;
+
+    use super::*;
+
+    #[test]
+    fn test_server_registry_is_valid() {
+        PacketRegistry::server().validate();
+    }
+
+    #[test]
+    fn test_mismatches() {
+        let mut registry = PacketRegistry::new(ProtocolVersion::CURRENT);
+        registry
+            .expect::<play::KeepAliveS2c>(play::KeepAliveS2c::ID + 1)
+            .register::<HandshakeC2s>()
+            .register::<status::QueryResponseS2c>()
+            .register::<login::LoginDisconnectS2c>();
+
+        let errors = registry.errors();
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(errors[0].starts_with("KeepAliveS2c has ID"));
+        assert!(errors[1].starts_with("HandshakeC2s is sent by the server"));
+    }
+
+    #[test]
+    #[should_panic(expected = "packet IDs do not match protocol")]
+    fn test_validate_panics() {
+        let mut registry = PacketRegistry::new(ProtocolVersion::CURRENT);
+        registry.expect::<play::KeepAliveS2c>(-1);
+        registry.validate();
+    }
+}
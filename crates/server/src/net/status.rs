@@ -0,0 +1,282 @@
+//! The response to a server list ping, which clients show in their multiplayer menu.
+//!
+//! See <https://wiki.vg/Server_List_Ping#Status_Response>.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::{ensure, Context};
+use base64::Engine;
+use parking_lot::Mutex;
+use serde::{Serialize, Serializer};
+use uuid::Uuid;
+
+use crate::net::{MINECRAFT_VERSION, PROTOCOL_VERSION};
+
+/// The width and height of a favicon. Clients do not show favicons of any other size.
+pub const FAVICON_SIZE: u32 = 64;
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// A player shown when hovering over the player count.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SamplePlayer {
+    pub name: String,
+    #[serde(serialize_with = "serialize_uuid")]
+    pub id: Uuid,
+}
+
+fn serialize_uuid<S: Serializer>(id: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&id.hyphenated())
+}
+
+#[derive(Serialize)]
+struct Version {
+    name: &'static str,
+    protocol: i32,
+}
+
+#[derive(Serialize)]
+struct Players<'a> {
+    max: usize,
+    online: usize,
+    sample: &'a [SamplePlayer],
+}
+
+#[derive(Serialize)]
+struct StatusJson<'a> {
+    version: Version,
+    players: Players<'a>,
+    description: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    favicon: Option<&'a str>,
+}
+
+/// The JSON sent in `QueryResponseS2c`.
+///
+/// Everything except the number of players online is set when it is built, so the JSON is cached
+/// and only serialized again when that number changes.
+#[derive(Debug)]
+pub struct StatusResponse {
+    description: String,
+    max_players: usize,
+    sample: Vec<SamplePlayer>,
+    /// The favicon as a `data:image/png;base64,` URI.
+    favicon: Option<String>,
+    /// The last JSON and the number of players online it was serialized with.
+    cache: Mutex<Option<(usize, Arc<str>)>>,
+}
+
+impl Default for StatusResponse {
+    fn default() -> Self {
+        Self::new("something")
+    }
+}
+
+impl StatusResponse {
+    /// A response with `description` as the MOTD, a maximum of 32 players, no sample players and
+    /// no favicon.
+    #[must_use]
+    pub fn new(description: impl Into<String>) -> Self {
+        Self {
+            description: description.into(),
+            max_players: 32,
+            sample: Vec::new(),
+            favicon: None,
+            cache: Mutex::new(None),
+        }
+    }
+
+    #[must_use]
+    pub const fn with_max_players(mut self, max_players: usize) -> Self {
+        self.max_players = max_players;
+        self
+    }
+
+    /// Sets the players shown when hovering over the player count. Vanilla servers show up to
+    /// 12 random players who are online.
+    #[must_use]
+    pub fn with_sample(mut self, sample: Vec<SamplePlayer>) -> Self {
+        self.sample = sample;
+        self
+    }
+
+    /// Loads the favicon from the PNG at `path`.
+    ///
+    /// # Errors
+    /// If the file cannot be read, or it is not a PNG of [`FAVICON_SIZE`] by [`FAVICON_SIZE`].
+    pub fn with_favicon(self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let png = std::fs::read(path)
+            .with_context(|| format!("failed to read favicon {}", path.display()))?;
+
+        self.with_favicon_png(&png)
+            .with_context(|| format!("invalid favicon {}", path.display()))
+    }
+
+    /// Sets the favicon to the encoded `png`.
+    ///
+    /// # Errors
+    /// If `png` is not a PNG of [`FAVICON_SIZE`] by [`FAVICON_SIZE`].
+    pub fn with_favicon_png(mut self, png: &[u8]) -> anyhow::Result<Self> {
+        let (width, height) = png_size(png)?;
+
+        ensure!(
+            width == FAVICON_SIZE && height == FAVICON_SIZE,
+            "favicon is {width}x{height}, but it must be {FAVICON_SIZE}x{FAVICON_SIZE}"
+        );
+
+        let base64 = base64::engine::general_purpose::STANDARD.encode(png);
+        self.favicon = Some(format!("data:image/png;base64,{base64}"));
+
+        Ok(self)
+    }
+
+    #[must_use]
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    #[must_use]
+    pub const fn max_players(&self) -> usize {
+        self.max_players
+    }
+
+    /// The JSON with `online` players online, which is only serialized if `online` differs from
+    /// the last call.
+    pub fn json(&self, online: usize) -> anyhow::Result<Arc<str>> {
+        let mut cache = self.cache.lock();
+
+        if let Some((cached_online, json)) = &*cache {
+            if *cached_online == online {
+                return Ok(json.clone());
+            }
+        }
+
+        let json = StatusJson {
+            version: Version {
+                name: MINECRAFT_VERSION,
+                protocol: PROTOCOL_VERSION,
+            },
+            players: Players {
+                max: self.max_players,
+                online,
+                sample: &self.sample,
+            },
+            description: &self.description,
+            favicon: self.favicon.as_deref(),
+        };
+
+        let json: Arc<str> = serde_json::to_string(&json)?.into();
+        *cache = Some((online, json.clone()));
+
+        Ok(json)
+    }
+}
+
+/// The width and height in the `IHDR` chunk, which is always the first chunk of a PNG.
+fn png_size(png: &[u8]) -> anyhow::Result<(u32, u32)> {
+    let (signature, rest) = png
+        .split_first_chunk::<8>()
+        .context("favicon is not a PNG")?;
+    ensure!(signature == PNG_SIGNATURE, "favicon is not a PNG");
+
+    // 4 bytes of length, then the chunk type, width and height
+    let Some([_, _, _, _, b'I', b'H', b'D', b'R', w0, w1, w2, w3, h0, h1, h2, h3, ..]) =
+        rest.first_chunk::<16>().copied()
+    else {
+        anyhow::bail!("favicon does not start with an IHDR chunk");
+    };
+
+    Ok((
+        u32::from_be_bytes([w0, w1, w2, w3]),
+        u32::from_be_bytes([h0, h1, h2, h3]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend_from_slice(&13_u32.to_be_bytes());
+        png.extend_from_slice(b"IHDR");
+        png.extend_from_slice(&width.to_be_bytes());
+        png.extend_from_slice(&height.to_be_bytes());
+        png.extend_from_slice(&[8, 6, 0, 0, 0]);
+        png
+    }
+
+    #[test]
+    fn test_json() {
+        let status = StatusResponse::new("a motd")
+            .with_max_players(100)
+            .with_sample(vec![SamplePlayer {
+                name: "Notch".to_owned(),
+                id: Uuid::from_u128(0x069a_79f4_44e9_4726_a5be_fca9_0e38_aaf5),
+            }]);
+
+        let json: serde_json::Value = serde_json::from_str(&status.json(3).unwrap()).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "version": {
+                    "name": MINECRAFT_VERSION,
+                    "protocol": PROTOCOL_VERSION,
+                },
+                "players": {
+                    "max": 100,
+                    "online": 3,
+                    "sample": [{
+                        "name": "Notch",
+                        "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5",
+                    }],
+                },
+                "description": "a motd",
+            })
+        );
+    }
+
+    #[test]
+    fn test_cached_until_online_changes() {
+        let status = StatusResponse::default();
+
+        let first = status.json(1).unwrap();
+        assert!(Arc::ptr_eq(&first, &status.json(1).unwrap()));
+
+        let second = status.json(2).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(second.contains(r#""online":2"#));
+    }
+
+    #[test]
+    fn test_favicon() {
+        let png = png(64, 64);
+        let status = StatusResponse::default().with_favicon_png(&png).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&status.json(0).unwrap()).unwrap();
+        let favicon = json["favicon"].as_str().unwrap();
+        let encoded = favicon.strip_prefix("data:image/png;base64,").unwrap();
+
+        assert_eq!(
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .unwrap(),
+            png
+        );
+    }
+
+    #[test]
+    fn test_invalid_favicon() {
+        assert!(StatusResponse::default()
+            .with_favicon_png(&png(32, 64))
+            .is_err());
+        assert!(StatusResponse::default()
+            .with_favicon_png(&png(64, 64)[..20])
+            .is_err());
+        assert!(StatusResponse::default()
+            .with_favicon_png(b"GIF89a not a png")
+            .is_err());
+    }
+}
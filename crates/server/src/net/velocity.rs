@@ -0,0 +1,274 @@
+//! Velocity's modern forwarding, which tells a server behind the proxy who is actually joining.
+//!
+//! Players log in to the proxy, so the server only sees the address of the proxy and a username
+//! it cannot verify. With modern forwarding, the server asks the proxy for the player with a
+//! login plugin request on [`CHANNEL`], and the proxy answers with the address, UUID, and profile
+//! of the player, signed with a secret both of them are configured with.
+//!
+//! See <https://docs.papermc.io/velocity/player-information-forwarding>.
+
+use std::{borrow::Cow, fmt, net::IpAddr};
+
+use anyhow::{ensure, Context};
+use sha2::{Digest, Sha256};
+use valence_protocol::{
+    packets::login::{LoginQueryRequestS2c, LoginQueryResponseC2s},
+    profile::Property,
+    Bounded, Decode, RawBytes, VarInt,
+};
+use valence_server::Ident;
+
+/// The channel of the login plugin request the proxy answers.
+const CHANNEL: &str = "velocity:player_info";
+
+/// The message ID of the request, which the response has to echo.
+const MESSAGE_ID: i32 = 0x48_59_50;
+
+/// The forwarding version without the chat signing key, which is all this server reads.
+const MODERN_DEFAULT: u8 = 1;
+
+/// The length of an HMAC-SHA256 signature.
+const SIGNATURE_LEN: usize = 32;
+
+/// The longest username the protocol allows.
+const MAX_USERNAME_LEN: usize = 16;
+
+/// The secret shared with the proxy. It is not printed by [`fmt::Debug`].
+#[derive(Clone)]
+pub struct VelocityForwarding {
+    secret: Box<[u8]>,
+}
+
+impl fmt::Debug for VelocityForwarding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VelocityForwarding").finish_non_exhaustive()
+    }
+}
+
+/// The player a proxy forwarded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardedPlayer {
+    /// The address the player connected to the proxy from.
+    pub address: IpAddr,
+    pub uuid: uuid::Uuid,
+    pub username: String,
+    /// The profile properties of the player, like their skin.
+    pub properties: Vec<Property>,
+}
+
+impl VelocityForwarding {
+    /// Forwarding with `secret`, which is the contents of the `forwarding.secret` file of the
+    /// proxy.
+    #[must_use]
+    pub fn new(secret: impl Into<Box<[u8]>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    /// The request which is sent after the client sent its `LoginHelloC2s`.
+    #[must_use]
+    pub fn request() -> LoginQueryRequestS2c<'static> {
+        LoginQueryRequestS2c {
+            message_id: VarInt(MESSAGE_ID),
+            channel: Ident::new_unchecked(CHANNEL).into(),
+            data: Bounded(RawBytes(&[MODERN_DEFAULT])),
+        }
+    }
+
+    /// Whether `response` answers [`VelocityForwarding::request`], which it does if it echoes the
+    /// message ID of the request.
+    #[must_use]
+    pub fn is_response(response: &LoginQueryResponseC2s<'_>) -> bool {
+        response.message_id.0 == MESSAGE_ID
+    }
+
+    /// Checks the signature of the data of the response to [`VelocityForwarding::request`] and
+    /// decodes the player it forwards.
+    pub fn verify(&self, data: &[u8]) -> anyhow::Result<ForwardedPlayer> {
+        ensure!(
+            data.len() >= SIGNATURE_LEN,
+            "forwarding data is too short to be signed"
+        );
+
+        let (signature, mut r) = data.split_at(SIGNATURE_LEN);
+
+        let expected = hmac_sha256(&self.secret, r);
+        ensure!(
+            constant_time_eq(signature, &expected),
+            "forwarding data has an invalid signature, so the proxy has a different secret"
+        );
+
+        let version = VarInt::decode(&mut r)?.0;
+        ensure!(
+            version >= i32::from(MODERN_DEFAULT),
+            "unknown forwarding version {version}"
+        );
+
+        let address = <&str>::decode(&mut r)?;
+        // IPv6 addresses can have a scope, which `IpAddr` does not
+        let address = address.split('%').next().unwrap_or_default();
+        let address = address
+            .parse()
+            .with_context(|| format!("invalid forwarded address {address:?}"))?;
+
+        let uuid = uuid::Uuid::from_u128(u128::decode(&mut r)?);
+
+        let username = <&str>::decode(&mut r)?;
+        ensure!(
+            username.chars().count() <= MAX_USERNAME_LEN,
+            "forwarded username {username:?} is too long"
+        );
+
+        let properties = Vec::<Property>::decode(&mut r)?;
+
+        // newer versions append the chat signing key, which is not needed
+
+        Ok(ForwardedPlayer {
+            address,
+            uuid,
+            username: username.to_owned(),
+            properties,
+        })
+    }
+}
+
+impl ForwardedPlayer {
+    /// The properties in the form `LoginSuccessS2c` sends them.
+    #[must_use]
+    pub fn properties(&self) -> Cow<'_, [Property]> {
+        Cow::Borrowed(&self.properties)
+    }
+}
+
+/// HMAC-SHA256 as in RFC 2104.
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; SIGNATURE_LEN] {
+    const BLOCK_LEN: usize = 64;
+
+    // keys longer than a block are hashed first
+    let mut block = [0; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..SIGNATURE_LEN].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner = Sha256::new()
+        .chain_update(block.map(|b| b ^ 0x36))
+        .chain_update(data)
+        .finalize();
+
+    Sha256::new()
+        .chain_update(block.map(|b| b ^ 0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// Compares without returning early, so how long this takes does not tell how much of a forged
+/// signature is correct.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use valence_protocol::Encode;
+
+    use super::*;
+
+    fn forwarding_data(secret: &[u8], username: &str) -> Vec<u8> {
+        let mut payload = Vec::new();
+        VarInt(i32::from(MODERN_DEFAULT))
+            .encode(&mut payload)
+            .unwrap();
+        "127.0.0.1".encode(&mut payload).unwrap();
+        0x069a_79f4_44e9_4726_a5be_fca9_0e38_aaf5_u128
+            .encode(&mut payload)
+            .unwrap();
+        username.encode(&mut payload).unwrap();
+        vec![Property {
+            name: "textures".to_owned(),
+            value: "skin".to_owned(),
+            signature: Some("signature".to_owned()),
+        }]
+        .encode(&mut payload)
+        .unwrap();
+
+        let mut data = hmac_sha256(secret, &payload).to_vec();
+        data.extend_from_slice(&payload);
+        data
+    }
+
+    #[test]
+    fn test_hmac() {
+        // test cases 2 and 6 of RFC 4231
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex::encode(hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let forwarding = VelocityForwarding::new(&b"secret"[..]);
+        let data = forwarding_data(b"secret", "Emerald_Explorer");
+
+        let player = forwarding.verify(&data).unwrap();
+        assert_eq!(player.address, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(
+            player.uuid,
+            uuid::Uuid::from_u128(0x069a_79f4_44e9_4726_a5be_fca9_0e38_aaf5)
+        );
+        assert_eq!(player.username, "Emerald_Explorer");
+        assert_eq!(player.properties.len(), 1);
+        assert_eq!(player.properties[0].signature.as_deref(), Some("signature"));
+    }
+
+    #[test]
+    fn test_reject_forged() {
+        let forwarding = VelocityForwarding::new(&b"secret"[..]);
+
+        // signed with another secret
+        let data = forwarding_data(b"other", "Emerald_Explorer");
+        assert!(forwarding.verify(&data).is_err());
+
+        // tampered with after signing
+        let mut data = forwarding_data(b"secret", "Emerald_Explorer");
+        *data.last_mut().unwrap() ^= 1;
+        assert!(forwarding.verify(&data).is_err());
+
+        assert!(forwarding.verify(&data[..SIGNATURE_LEN - 1]).is_err());
+        assert!(forwarding.verify(&[]).is_err());
+    }
+
+    #[test]
+    fn test_is_response() {
+        let response = |message_id| LoginQueryResponseC2s {
+            message_id: VarInt(message_id),
+            data: None,
+        };
+
+        let request = VelocityForwarding::request();
+        assert!(VelocityForwarding::is_response(&response(
+            request.message_id.0
+        )));
+        assert!(!VelocityForwarding::is_response(&response(
+            request.message_id.0 + 1
+        )));
+    }
+
+    #[test]
+    fn test_username_too_long() {
+        let forwarding = VelocityForwarding::new(&b"secret"[..]);
+        let data = forwarding_data(b"secret", "a_username_too_long");
+        assert!(forwarding.verify(&data).is_err());
+    }
+}
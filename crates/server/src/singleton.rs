@@ -2,7 +2,9 @@
 
 pub mod bounding_box;
 pub mod broadcast;
+pub mod fd_activity;
 pub mod fd_lookup;
+pub mod login_queue;
 pub mod player_aabb_lookup;
 pub mod player_id_lookup;
 pub mod player_uuid_lookup;
@@ -0,0 +1,69 @@
+//! Track when each connection was last active so idle ones can be closed.
+use std::time::{Duration, Instant};
+
+use evenio::prelude::Component;
+use fxhash::FxHashMap;
+
+use crate::net::Fd;
+
+/// See [`crate::singleton::fd_activity`].
+#[derive(Component, Default, Debug)]
+pub struct FdActivity {
+    /// When data was last received from each connection
+    last_activity: FxHashMap<Fd, Instant>,
+}
+
+impl FdActivity {
+    /// Marks `fd` as active at `now`.
+    pub fn record(&mut self, fd: Fd, now: Instant) {
+        self.last_activity.insert(fd, now);
+    }
+
+    /// Stops tracking `fd`. This must be called when a connection is removed, or the map grows
+    /// forever.
+    pub fn remove(&mut self, fd: Fd) {
+        self.last_activity.remove(&fd);
+    }
+
+    /// Every connection which has not been active for longer than `timeout`.
+    #[must_use]
+    pub fn timed_out(&self, timeout: Duration) -> Vec<Fd> {
+        self.timed_out_at(Instant::now(), timeout)
+    }
+
+    fn timed_out_at(&self, now: Instant, timeout: Duration) -> Vec<Fd> {
+        self.last_activity
+            .iter()
+            .filter(|(_, &last)| now.saturating_duration_since(last) > timeout)
+            .map(|(&fd, _)| fd)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::MockServer;
+
+    #[test]
+    fn test_timed_out() {
+        let mut server = MockServer::default();
+        let idle = server.connect();
+        let active = server.connect();
+
+        let start = Instant::now();
+        let timeout = Duration::from_secs(20);
+
+        let mut activity = FdActivity::default();
+        activity.record(idle, start);
+        activity.record(active, start);
+        activity.record(active, start + Duration::from_secs(15));
+
+        let now = start + Duration::from_secs(30);
+        assert_eq!(activity.timed_out_at(now, timeout), [idle]);
+
+        activity.remove(idle);
+        assert!(activity.timed_out_at(now, timeout).is_empty());
+        assert_eq!(activity.last_activity.len(), 1);
+    }
+}
@@ -0,0 +1,177 @@
+//! Holding connections which finished logging in until they may join, so a join storm after a
+//! restart does not spend every tick on logins.
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use evenio::prelude::Component;
+use fxhash::FxHashMap;
+use valence_protocol::{packets::login::LoginQueryRequestS2c, Bounded, RawBytes, VarInt};
+use valence_server::Ident;
+
+use crate::net::Fd;
+
+/// How often queued connections are sent [`LoginQueue::keep_alive`].
+pub const QUEUE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The channel of the login plugin request which keeps queued connections alive. Clients do not
+/// understand it, so they answer that they did not.
+const CHANNEL: &str = "hyperion:queue";
+
+/// The message ID of [`LoginQueue::keep_alive`].
+const MESSAGE_ID: i32 = 0x51_55_45;
+
+/// Connections which finished logging in, in the order they did, while they wait in
+/// [`crate::components::LoginState::Queued`] to be let into the world.
+///
+/// The queue is only used if [`crate::global::Global::logins_per_tick`] is set, and that many
+/// connections are let in at the start of every tick. Clients cannot be sent chat during login,
+/// so they are kept alive with login plugin requests, which they answer to.
+#[derive(Component, Default, Debug)]
+pub struct LoginQueue {
+    /// The waiting connections by the order they were queued in
+    waiting: BTreeMap<u64, Fd>,
+    /// The key of every waiting connection in [`LoginQueue::waiting`], so a disconnect does not
+    /// have to search the whole queue
+    sequences: FxHashMap<Fd, u64>,
+    /// The key of the next connection which is queued
+    next_sequence: u64,
+    /// When [`LoginQueue::keep_alive`] was last sent to every queued connection
+    last_keep_alive: Option<Instant>,
+}
+
+impl LoginQueue {
+    /// Queues `fd` after every connection which is already waiting, and returns its
+    /// [`LoginQueue::position`]. If `fd` is already queued, it keeps its place.
+    pub fn push(&mut self, fd: Fd) -> usize {
+        if let Some(position) = self.position(fd) {
+            return position;
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        self.waiting.insert(sequence, fd);
+        self.sequences.insert(fd, sequence);
+        self.waiting.len() - 1
+    }
+
+    /// Stops `fd` from waiting, like when it disconnected. Every connection behind it moves up
+    /// by one. Returns whether `fd` was queued.
+    pub fn remove(&mut self, fd: Fd) -> bool {
+        let Some(sequence) = self.sequences.remove(&fd) else {
+            return false;
+        };
+
+        self.waiting.remove(&sequence);
+        true
+    }
+
+    /// How many connections are ahead of `fd`, so 0 is let in next, or [`None`] if `fd` is not
+    /// queued.
+    #[must_use]
+    pub fn position(&self, fd: Fd) -> Option<usize> {
+        let sequence = *self.sequences.get(&fd)?;
+        Some(self.waiting.range(..sequence).count())
+    }
+
+    /// The number of connections which are waiting.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    /// The waiting connections, from the one which is let in next to the one which joined last.
+    pub fn iter(&self) -> impl Iterator<Item = Fd> + '_ {
+        self.waiting.values().copied()
+    }
+
+    /// Removes the first `count` connections from the queue, which may join now. Each connection
+    /// is removed when the iterator yields it.
+    pub fn admit(&mut self, count: usize) -> impl Iterator<Item = Fd> + '_ {
+        std::iter::from_fn(move || {
+            let (_, fd) = self.waiting.pop_first()?;
+            self.sequences.remove(&fd);
+            Some(fd)
+        })
+        .take(count)
+    }
+
+    /// Whether [`LoginQueue::keep_alive`] has to be sent to every queued connection at `now`.
+    /// Once this returned `true`, it returns `false` until [`QUEUE_KEEP_ALIVE_INTERVAL`] passed.
+    pub fn keep_alive_due(&mut self, now: Instant) -> bool {
+        let due = self
+            .last_keep_alive
+            .is_none_or(|last| now.saturating_duration_since(last) >= QUEUE_KEEP_ALIVE_INTERVAL);
+
+        if due {
+            self.last_keep_alive = Some(now);
+        }
+
+        due
+    }
+
+    /// The request which keeps a queued connection from timing out. The answer to it does not
+    /// matter, and is ignored.
+    #[must_use]
+    pub fn keep_alive() -> LoginQueryRequestS2c<'static> {
+        LoginQueryRequestS2c {
+            message_id: VarInt(MESSAGE_ID),
+            channel: Ident::new_unchecked(CHANNEL).into(),
+            data: Bounded(RawBytes(&[])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::MockServer;
+
+    #[test]
+    fn test_positions() {
+        let mut server = MockServer::default();
+        let [a, b, c, d] = [(); 4].map(|()| server.connect());
+
+        let mut queue = LoginQueue::default();
+        assert_eq!(queue.push(a), 0);
+        assert_eq!(queue.push(b), 1);
+        assert_eq!(queue.push(c), 2);
+        assert_eq!(queue.push(d), 3);
+        assert_eq!(queue.push(b), 1);
+
+        // a disconnect moves everyone behind up
+        assert!(queue.remove(b));
+        assert!(!queue.remove(b));
+        assert_eq!(queue.position(c), Some(1));
+
+        assert_eq!(queue.admit(2).collect::<Vec<_>>(), [a, c]);
+        assert_eq!(queue.position(a), None);
+        assert_eq!(queue.position(d), Some(0));
+        assert_eq!(queue.len(), 1);
+
+        assert_eq!(queue.admit(10).collect::<Vec<_>>(), [d]);
+        assert!(queue.is_empty());
+
+        // a connection which was let in can be queued again, behind everyone else
+        assert_eq!(queue.push(b), 0);
+        assert_eq!(queue.push(a), 1);
+        assert_eq!(queue.iter().collect::<Vec<_>>(), [b, a]);
+    }
+
+    #[test]
+    fn test_keep_alive_due() {
+        let mut queue = LoginQueue::default();
+        let start = Instant::now();
+
+        assert!(queue.keep_alive_due(start));
+        assert!(!queue.keep_alive_due(start + Duration::from_secs(1)));
+        assert!(queue.keep_alive_due(start + QUEUE_KEEP_ALIVE_INTERVAL));
+    }
+}
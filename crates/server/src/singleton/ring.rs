@@ -1,34 +1,416 @@
-use std::mem::MaybeUninit;
+use std::{
+    alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout},
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut, Range},
+    ptr::NonNull,
+    sync::Arc,
+};
 
 use libc::iovec;
-use tracing::debug;
+use tracing::{debug, warn};
 
-use crate::net::{encoder::PacketWriteInfo, ServerDef};
+use crate::net::{
+    encoder::{PacketWriteInfo, Priority},
+    AppendError,
+};
+
+/// The smallest page size of the platforms the server runs on. Larger pages are just touched more
+/// than once by [`Ring::prefault`].
+const PAGE_SIZE: usize = 4096;
+
+/// The memory of the S2C rings. The server reads from this memory while the rings write to it, so
+/// both keep the pool alive with an [`Arc`]. This way the memory cannot be freed while the server
+/// still refers to it.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Box<[NonNull<u8>]>,
+    layout: Layout,
+    /// The length of the mapping of each buffer if the buffers were mapped for
+    /// [`HugePages`] rather than allocated.
+    #[cfg_attr(
+        not(target_os = "linux"),
+        expect(dead_code, reason = "huge pages are only mapped on linux")
+    )]
+    mapped_len: Option<usize>,
+}
+
+/// Whether the buffers of a [`BufferPool`] are backed by 2 MiB huge pages instead of 4 KiB pages.
+///
+/// Every page a core writes to needs a TLB entry, and a [`crate::net::S2C_BUFFER_SIZE`] ring spans
+/// 32768 normal pages but only 64 huge pages. Huge pages are only used on Linux. If they are not
+/// available, the buffers silently use normal pages.
+///
+/// Whether this helps depends on the hardware, and in a VM on how the host backs guest memory.
+/// Measure on the machine the server runs on, for example by comparing
+/// `perf stat -e dTLB-loads,dTLB-load-misses,dTLB-stores,dTLB-store-misses -p <pid>` of the
+/// server under a broadcast-heavy load, like many bots in view of each other, with and without
+/// huge pages. `AnonHugePages` (transparent) or `HugePages_Free` (explicit) in `/proc/meminfo`
+/// shows whether they are used.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum HugePages {
+    /// Normal pages. This is the default.
+    #[default]
+    Off,
+    /// Asks for transparent huge pages with `madvise(MADV_HUGEPAGE)`. This needs
+    /// `/sys/kernel/mm/transparent_hugepage/enabled` to be `madvise` or `always`, and the kernel
+    /// uses normal pages wherever it cannot find a free huge page.
+    Transparent,
+    /// Maps the buffers with `MAP_HUGETLB` from the huge pages reserved with
+    /// `sysctl vm.nr_hugepages`. Every buffer is rounded up to whole huge pages, so
+    /// `vm.nr_hugepages` has to be at least `cores * ceil(buffer size / 2 MiB)`.
+    Explicit,
+}
+
+/// The size of a huge page on x86-64, and on aarch64 with 4 KiB base pages.
+#[cfg(target_os = "linux")]
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// Maps `count` zeroed buffers of `len` bytes rounded up to whole huge pages, returning them and
+/// the length of each mapping. Returns [`None`] without leaking anything if a mapping fails.
+#[cfg(target_os = "linux")]
+fn map_huge(
+    count: usize,
+    len: usize,
+    huge_pages: HugePages,
+) -> Option<(Box<[NonNull<u8>]>, usize)> {
+    let mapped_len = len.next_multiple_of(HUGE_PAGE_SIZE);
+
+    let mut flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    if huge_pages == HugePages::Explicit {
+        flags |= libc::MAP_HUGETLB;
+    }
+
+    let mut buffers = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        // SAFETY: this creates a new anonymous mapping, which does not alias any memory
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                -1,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            let err = std::io::Error::last_os_error();
+            warn!("failed to map {huge_pages:?} huge pages, falling back to normal pages: {err}");
+
+            for ptr in buffers {
+                // SAFETY: `ptr` was mapped above with `mapped_len`
+                unsafe { libc::munmap(NonNull::as_ptr(ptr).cast(), mapped_len) };
+            }
+
+            return None;
+        }
+
+        // SAFETY: the range was just mapped
+        if huge_pages == HugePages::Transparent
+            && unsafe { libc::madvise(ptr, mapped_len, libc::MADV_HUGEPAGE) } != 0
+        {
+            let err = std::io::Error::last_os_error();
+            warn!("transparent huge pages are not available, using normal pages: {err}");
+        }
+
+        buffers.push(NonNull::new(ptr.cast()).expect("mmap never returns null"));
+    }
+
+    Some((buffers.into_boxed_slice(), mapped_len))
+}
+
+// SAFETY: the pool only hands out raw pointers, and each buffer is written by a single ring
+unsafe impl Send for BufferPool {}
+unsafe impl Sync for BufferPool {}
+
+impl BufferPool {
+    /// Allocates `count` zeroed buffers of `len` bytes each.
+    ///
+    /// # Panics
+    /// If `len` is zero.
+    #[must_use]
+    pub fn new(count: usize, len: usize) -> Self {
+        Self::with_huge_pages(count, len, HugePages::Off)
+    }
+
+    /// Like [`BufferPool::new`], but backs the buffers with huge pages if they are available.
+    ///
+    /// # Panics
+    /// If `len` is zero.
+    #[must_use]
+    pub fn with_huge_pages(count: usize, len: usize, huge_pages: HugePages) -> Self {
+        assert!(len != 0, "buffers must not be empty");
+
+        let layout = Layout::array::<u8>(len).expect("buffer is too large");
+
+        #[cfg(target_os = "linux")]
+        if huge_pages != HugePages::Off {
+            if let Some((buffers, mapped_len)) = map_huge(count, len, huge_pages) {
+                return Self {
+                    buffers,
+                    layout,
+                    mapped_len: Some(mapped_len),
+                };
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        if huge_pages != HugePages::Off {
+            debug!("huge pages are only used on Linux");
+        }
+
+        let buffers = (0..count)
+            .map(|_| {
+                // SAFETY: `layout` has a non-zero size
+                let ptr = unsafe { alloc_zeroed(layout) };
+                NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(layout))
+            })
+            .collect();
+
+        Self {
+            buffers,
+            layout,
+            mapped_len: None,
+        }
+    }
+
+    /// The number of buffers.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    /// The size of each buffer in bytes.
+    #[must_use]
+    pub const fn buffer_len(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// The buffers as `iovec`s, which stay valid for as long as `self` is alive.
+    #[must_use]
+    pub fn iovecs(&self) -> Vec<iovec> {
+        self.buffers
+            .iter()
+            .map(|ptr| iovec {
+                iov_base: ptr.as_ptr().cast(),
+                iov_len: self.buffer_len(),
+            })
+            .collect()
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        for ptr in &*self.buffers {
+            #[cfg(target_os = "linux")]
+            if let Some(mapped_len) = self.mapped_len {
+                // SAFETY: every buffer was mapped with `mapped_len`
+                unsafe { libc::munmap(ptr.as_ptr().cast(), mapped_len) };
+                continue;
+            }
+
+            // SAFETY: every buffer was allocated with `self.layout`
+            unsafe { dealloc(ptr.as_ptr(), self.layout) };
+        }
+    }
+}
+
+/// One buffer of a [`BufferPool`].
+#[derive(Debug)]
+struct RingData {
+    ptr: NonNull<u8>,
+    len: usize,
+    _pool: Arc<BufferPool>,
+}
+
+// SAFETY: the buffer is only ever written through the ring which owns this
+unsafe impl Send for RingData {}
+
+impl Deref for RingData {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: the pool is kept alive by `_pool`
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for RingData {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: the pool is kept alive by `_pool`, and only this ring writes to the buffer
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+/// What a [`Ring`] does when a write does not fit because too many bytes are still pending.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum RingMode {
+    /// Appending fails with [`AppendError::RingFull`]. This is the default.
+    #[default]
+    Fixed,
+    /// The ring grows, up to `max` bytes in total, instead of failing.
+    ///
+    /// Queued [`PacketWriteInfo`]s hold raw pointers into the ring, and io_uring can only write
+    /// from the buffers registered when the server started, so the ring can never be moved to a
+    /// new allocation. Instead, its [`BufferPool`] buffer is allocated with `max` bytes up front,
+    /// and growing only extends how much of it the ring uses. Nothing is moved, so no pointer is
+    /// invalidated.
+    ///
+    /// This only works while the pending bytes do not wrap around the end of the ring. Otherwise,
+    /// bytes after the old end could only be reached by writing over pending bytes, so the write
+    /// fails with [`AppendError::RingFull`] just like in [`RingMode::Fixed`].
+    ///
+    /// The memory behind `max` is reserved whether or not the ring grows. On Linux, registering
+    /// it with io_uring also pins it, so this bounds how much of the buffer is touched rather
+    /// than how much memory is used.
+    Growable { max: usize },
+}
+
+impl RingMode {
+    /// The size of the [`BufferPool`] buffers needed for rings which start with `capacity` bytes.
+    #[must_use]
+    pub const fn buffer_len(self, capacity: usize) -> usize {
+        match self {
+            Self::Fixed => capacity,
+            Self::Growable { max } => {
+                if max > capacity {
+                    max
+                } else {
+                    capacity
+                }
+            }
+        }
+    }
+}
 
 // todo: see if it makes sense to use MaybeUninit
 #[derive(Debug)]
 pub struct Ring {
-    data: Box<[u8]>, // sad we have to box this so no stackoverflow
+    data: RingData,
     head: usize,
+    /// The number of bytes of `data` which are used. Only a [`RingMode::Growable`] ring uses less
+    /// than all of it.
     max_len: usize,
+    mode: RingMode,
+
+    /// The total number of bytes ever advanced past, including the bytes skipped when rotating.
+    written: u64,
+    /// Everything before this position has been sent and can be overwritten.
+    released: u64,
+    /// The most bytes which have been pending at once.
+    high_water_mark: usize,
+    /// Incremented every time `head` moves back to the start of the buffer.
+    generation: u32,
 }
 
+/// A sink packets are encoded into, like with [`crate::net::encoder::encode_uncompressed_into`].
+///
+/// The encoder asks for a contiguous region which is large enough for any packet, encodes into
+/// it, and then commits the bytes it used.
 pub trait Buf {
+    /// What committing bytes returns, like where they were written.
     type Output;
-    fn get_contiguous(&mut self, len: usize) -> &mut [u8];
+
+    /// A contiguous region of at least `len` bytes to write into. Nothing is committed until
+    /// [`Buf::advance`] is called.
+    fn get_contiguous(&mut self, len: usize) -> Result<&mut [u8], AppendError>;
+
+    /// Commits the first `len` bytes of the region returned by the last
+    /// [`Buf::get_contiguous`].
     fn advance(&mut self, len: usize) -> Self::Output;
 }
 
+/// A growable buffer which packets are appended to, to build a contiguous buffer of packets
+/// without a [`Ring`], like a login sequence or a replay file.
+///
+/// The region [`Buf::get_contiguous`] returns is zeroed only the first time the buffer grows into
+/// it, so appending many packets does not zero [`crate::net::MAX_ENCODED_PACKET_SIZE`] bytes for
+/// each of them.
+#[derive(Debug, Default, Clone)]
+pub struct VecBuf {
+    /// The appended bytes, followed by zeroes which were handed out but not committed.
+    bytes: Vec<u8>,
+    /// The number of bytes which were committed by [`Buf::advance`].
+    len: usize,
+}
+
+impl VecBuf {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Appends `data` after the bytes which were committed so far.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.bytes.truncate(self.len);
+        self.bytes.extend_from_slice(data);
+        self.len = self.bytes.len();
+    }
+
+    /// The appended bytes.
+    #[must_use]
+    pub fn into_inner(mut self) -> Vec<u8> {
+        self.bytes.truncate(self.len);
+        self.bytes
+    }
+}
+
+impl Deref for VecBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Buf for VecBuf {
+    /// The range of the buffer the bytes were written to.
+    type Output = Range<usize>;
+
+    fn get_contiguous(&mut self, len: usize) -> Result<&mut [u8], AppendError> {
+        let end = self.len + len;
+
+        if self.bytes.len() < end {
+            self.bytes.resize(end, 0);
+        }
+
+        Ok(&mut self.bytes[self.len..end])
+    }
+
+    fn advance(&mut self, len: usize) -> Self::Output {
+        let start = self.len;
+
+        assert!(
+            start + len <= self.bytes.len(),
+            "advancing {len} bytes past the {} bytes handed out",
+            self.bytes.len() - start
+        );
+
+        self.len += len;
+        start..self.len
+    }
+}
+
 impl Buf for bytes::BytesMut {
     type Output = Self;
 
-    fn get_contiguous(&mut self, len: usize) -> &mut [u8] {
+    fn get_contiguous(&mut self, len: usize) -> Result<&mut [u8], AppendError> {
         // self.resize(len, 0);
         // self
         self.reserve(len);
         let cap = self.spare_capacity_mut();
         let cap = unsafe { MaybeUninit::slice_assume_init_mut(cap) };
-        cap
+        Ok(cap)
     }
 
     fn advance(&mut self, len: usize) -> Self::Output {
@@ -42,76 +424,272 @@ impl Ring {
         self.max_len - self.head
     }
 
-    pub fn append(&mut self, data: &[u8]) -> *const u8 {
-        debug_assert!(data.len() <= self.max_len);
+    /// Copies `data` into the ring, returning where it was written.
+    ///
+    /// The bytes are always contiguous: if they do not fit before the end of the ring, the rest
+    /// of the ring is skipped and they are written at the start, which begins a new
+    /// [`Ring::generation`]. `start_ptr + len` never goes past [`Ring::capacity`] bytes from the
+    /// start of the buffer, and a write which ends exactly at the end also begins a new
+    /// generation.
+    ///
+    /// The returned pointer is never invalidated while the ring or its [`BufferPool`] is alive,
+    /// since the buffer is never moved, not even when a [`RingMode::Growable`] ring grows. The
+    /// bytes behind it stay as they were written until [`Ring::release_until`] is called with a
+    /// position past them. After that, any later write may overwrite them.
+    ///
+    /// # Errors
+    /// [`AppendError::RingFull`] if this would overwrite bytes which have not been released yet.
+    /// Nothing is written in that case.
+    pub fn append(&mut self, data: &[u8]) -> Result<PacketWriteInfo, AppendError> {
         let len = data.len();
-        let contiguous = self.get_contiguous(len);
+        let contiguous = self.get_contiguous(len)?;
         contiguous.copy_from_slice(data);
-        let ptr = contiguous.as_ptr();
-        self.advance(len);
-        ptr
+        Ok(self.advance(len))
+    }
+
+    /// The offset in the buffer the next write starts at, unless it has to rotate.
+    #[must_use]
+    pub const fn head(&self) -> usize {
+        self.head
+    }
+
+    /// The position the next byte will be written at. Positions only ever increase, so unlike
+    /// pointers they can be compared to find out which bytes were written first.
+    #[must_use]
+    pub const fn position(&self) -> u64 {
+        self.written
+    }
+
+    /// The position of the first byte of `info`, which must be the last thing that was advanced.
+    #[must_use]
+    pub fn position_of_last(&self, info: &PacketWriteInfo) -> u64 {
+        let len = info.len;
+        self.written - u64::from(len)
+    }
+
+    /// The position of the first byte of `info`, which must have been written to this ring and
+    /// not released yet.
+    #[must_use]
+    pub fn position_of(&self, info: &PacketWriteInfo) -> u64 {
+        let offset = info.start_ptr as usize - self.data.ptr.as_ptr() as usize;
+
+        debug_assert!(offset < self.max_len, "{info:?} is not in this ring");
+
+        // unreleased bytes are at most one generation old, and the ring only grows while none of
+        // them are, so the last generation ended at the current end of the ring
+        let behind_head = if info.generation == self.generation {
+            self.head - offset
+        } else {
+            self.head + self.max_len - offset
+        };
+
+        self.written - behind_head as u64
+    }
+
+    /// The number of times the ring has wrapped back to the start of its buffer, wrapping on
+    /// overflow. Pointers from different generations must not be compared.
+    #[must_use]
+    pub const fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Marks every byte before `position` as sent so it can be overwritten.
+    pub fn release_until(&mut self, position: u64) {
+        debug_assert!(
+            position <= self.written,
+            "released position {position} has not been written yet ({})",
+            self.written
+        );
+
+        self.released = self.released.max(position);
+    }
+
+    /// The number of bytes which have been written but not released.
+    #[must_use]
+    pub const fn pending(&self) -> usize {
+        (self.written - self.released) as usize
+    }
+
+    /// The most bytes which have been pending at once. If this gets close to the size of the ring,
+    /// clients are not keeping up.
+    #[must_use]
+    pub const fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    /// Touches every page of the buffer behind this ring, including the part a
+    /// [`RingMode::Growable`] ring has not grown into yet, so the OS maps the pages now instead of
+    /// on the first write to each of them. The bytes are not changed.
+    ///
+    /// The pages are mapped on the NUMA node of the calling thread, so this should run on the core
+    /// which appends to the ring.
+    pub fn prefault(&mut self) {
+        for byte in self.data.iter_mut().step_by(PAGE_SIZE) {
+            // SAFETY: `byte` is a valid reference. The accesses are volatile so they are not
+            // optimized out for writing back what was read.
+            unsafe { std::ptr::write_volatile(byte, std::ptr::read_volatile(byte)) };
+        }
+    }
+
+    /// Grows a [`RingMode::Growable`] ring so `len` bytes fit at `head` without rotating. Returns
+    /// whether it grew.
+    fn try_grow(&mut self, len: usize) -> bool {
+        let RingMode::Growable { max } = self.mode else {
+            return false;
+        };
+
+        // if the pending bytes wrap, some of them are at the old end of the ring
+        if self.pending() > self.head {
+            return false;
+        }
+
+        let needed = self.head + len;
+        if needed > max {
+            return false;
+        }
+
+        let new_len = (self.max_len * 2).clamp(needed, max);
+        debug!("growing ring from {} to {new_len} bytes", self.max_len);
+        self.max_len = new_len;
+
+        true
     }
 }
 
 impl Buf for Ring {
     type Output = PacketWriteInfo;
 
-    fn get_contiguous(&mut self, len: usize) -> &mut [u8] {
+    fn get_contiguous(&mut self, len: usize) -> Result<&mut [u8], AppendError> {
         debug_assert!(
-            len <= self.max_len,
-            "requested contiguous length of {} exceeds max_len of {}",
+            len <= self.data.len(),
+            "requested contiguous length of {} exceeds the buffer length of {}",
             len,
-            self.max_len
+            self.data.len()
         );
 
         let len_until_end = self.len_until_end();
-        if len_until_end < len {
+        let skipped = if len_until_end < len {
+            len_until_end
+        } else {
+            0
+        };
+
+        // if nothing is pending, the entire ring is free
+        let pending = self.pending();
+        let full = len > self.max_len || (pending != 0 && pending + skipped + len > self.max_len);
+
+        if full && !self.try_grow(len) {
+            return Err(AppendError::RingFull);
+        }
+
+        // growing makes room before the end
+        let skipped = if full { 0 } else { skipped };
+
+        if skipped != 0 {
             let ptr = self.data.as_ptr();
             debug!("rotating buffer {ptr:?} because {len_until_end} < {len}");
             self.head = 0;
-            &mut self.data[..len]
+            self.written += skipped as u64;
+            self.generation = self.generation.wrapping_add(1);
+            Ok(&mut self.data[..len])
         } else {
             let start = self.head;
-            &mut self.data[start..start + len]
+            Ok(&mut self.data[start..start + len])
         }
     }
 
     /// **Does not advice head unless it needs to move to the beginning**
     fn advance(&mut self, len: usize) -> Self::Output {
-        debug_assert!(len <= self.max_len);
+        debug_assert!(
+            self.head + len <= self.max_len,
+            "advancing {len} bytes from {} goes past the end of the ring at {}",
+            self.head,
+            self.max_len
+        );
 
         let start_ptr = unsafe { self.data.as_ptr().add(self.head) };
+        let generation = self.generation;
 
         self.head = (self.head + len) % self.max_len;
+        self.written += len as u64;
+        self.high_water_mark = self.high_water_mark.max(self.pending());
+
+        if self.head == 0 {
+            self.generation = self.generation.wrapping_add(1);
+        }
 
         let len = len as u32;
-        PacketWriteInfo { start_ptr, len }
+        PacketWriteInfo {
+            start_ptr,
+            len,
+            generation,
+            priority: Priority::Essential,
+            // SAFETY: the bytes were just written and are in the ring
+            #[cfg(feature = "debug_checksums")]
+            checksum: unsafe { PacketWriteInfo::checksum_of(start_ptr, len) },
+        }
     }
 }
 
 impl Ring {
+    /// Creates a ring backed by its own [`BufferPool`].
     pub fn new(max_len: usize) -> Self {
-        Self {
-            data: vec![0; max_len].into_boxed_slice(),
-            head: 0,
-            max_len,
-        }
+        Self::from_pool(Arc::new(BufferPool::new(1, max_len)), 0)
     }
 
-    fn as_iovec(&mut self) -> iovec {
-        iovec {
-            iov_base: self.data.as_mut_ptr().cast(),
-            iov_len: self.data.len(),
-        }
+    /// The size of the ring in bytes. A [`RingMode::Growable`] ring can grow past this.
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        self.max_len
     }
-}
 
-pub fn register_rings<'a>(
-    server_def: &mut impl ServerDef,
-    io_buf: impl Iterator<Item = &'a mut Ring>,
-) {
-    let vec = io_buf.map(Ring::as_iovec).collect::<Vec<_>>();
-    server_def.allocate_buffers(&vec);
+    #[must_use]
+    pub const fn mode(&self) -> RingMode {
+        self.mode
+    }
+
+    /// Creates a ring backed by buffer `index` of `pool`. No two rings may use the same buffer.
+    pub fn from_pool(pool: Arc<BufferPool>, index: usize) -> Self {
+        let capacity = pool.buffer_len();
+        Self::from_pool_with_mode(pool, index, capacity, RingMode::Fixed)
+    }
+
+    /// Like [`Ring::from_pool`], but the ring starts out using only `capacity` bytes of the
+    /// buffer, and handles writes which do not fit according to `mode`.
+    ///
+    /// # Panics
+    /// If `capacity` is zero, or the buffers of `pool` are smaller than `mode` needs. See
+    /// [`RingMode::buffer_len`].
+    pub fn from_pool_with_mode(
+        pool: Arc<BufferPool>,
+        index: usize,
+        capacity: usize,
+        mode: RingMode,
+    ) -> Self {
+        let ptr = pool.buffers[index];
+        let len = pool.buffer_len();
+
+        assert!(capacity != 0, "ring must not be empty");
+        assert!(
+            mode.buffer_len(capacity) <= len,
+            "{mode:?} with a capacity of {capacity} does not fit in buffers of {len} bytes"
+        );
+
+        Self {
+            data: RingData {
+                ptr,
+                len,
+                _pool: pool,
+            },
+            head: 0,
+            max_len: capacity,
+            mode,
+            written: 0,
+            released: 0,
+            high_water_mark: 0,
+            generation: 0,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -127,6 +705,36 @@ mod tests {
         assert_eq!(ring.max_len, max_len);
     }
 
+    #[test]
+    fn test_huge_pages() {
+        // explicit huge pages are usually not reserved, in which case normal pages are used
+        for huge_pages in [HugePages::Off, HugePages::Transparent, HugePages::Explicit] {
+            let pool = Arc::new(BufferPool::with_huge_pages(2, 100, huge_pages));
+            assert_eq!(pool.buffer_len(), 100);
+
+            for index in 0..2 {
+                let mut ring = Ring::from_pool(pool.clone(), index);
+                assert!(ring.data.iter().all(|&byte| byte == 0));
+
+                let info = ring.append(&[index as u8 + 1; 100]).unwrap();
+                assert_eq!(unsafe { info.as_slice() }, &[index as u8 + 1; 100]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_prefault_keeps_bytes() {
+        let mut ring = Ring::new(PAGE_SIZE * 3);
+
+        let data: Vec<u8> = (0..=u8::MAX).cycle().take(PAGE_SIZE * 2 + 1).collect();
+        let info = ring.append(&data).unwrap();
+
+        ring.prefault();
+
+        assert_eq!(unsafe { info.as_slice() }, &data[..]);
+        assert_eq!(ring.pending(), data.len());
+    }
+
     #[test]
     fn test_len_until_end() {
         let max_len = 100;
@@ -143,18 +751,42 @@ mod tests {
 
         // Test when len <= len_until_end
         let len = 50;
-        let slice = ring.get_contiguous(len);
+        let slice = ring.get_contiguous(len).unwrap();
         assert_eq!(slice.len(), len);
         assert_eq!(ring.head, 0);
 
         // Test when len > len_until_end
         ring.head = 80;
         let len = 30;
-        let slice = ring.get_contiguous(len);
+        let slice = ring.get_contiguous(len).unwrap();
         assert_eq!(slice.len(), len);
         assert_eq!(ring.head, 0);
     }
 
+    #[test]
+    fn test_vec_buf() {
+        let mut buf = VecBuf::new();
+
+        buf.get_contiguous(100).unwrap()[..3].copy_from_slice(b"abc");
+        assert_eq!(buf.advance(3), 0..3);
+
+        // the zeroes which were not committed are handed out again instead of growing
+        buf.get_contiguous(97).unwrap()[..2].copy_from_slice(b"de");
+        assert_eq!(buf.bytes.len(), 100);
+        assert_eq!(buf.advance(2), 3..5);
+
+        assert_eq!(&*buf, b"abcde");
+        assert_eq!(buf.into_inner(), b"abcde");
+    }
+
+    #[test]
+    #[should_panic(expected = "advancing")]
+    fn test_vec_buf_advance_past_region() {
+        let mut buf = VecBuf::new();
+        buf.get_contiguous(10).unwrap();
+        buf.advance(11);
+    }
+
     #[test]
     fn test_advance() {
         let max_len = 100;
@@ -165,11 +797,10 @@ mod tests {
         ring.advance(len);
         assert_eq!(ring.head, len);
 
-        // Test when head + len >= max_len
-        let len = 60;
-        let prev_head = ring.head;
+        // Test when head + len == max_len
+        let len = 50;
         ring.advance(len);
-        assert_eq!(ring.head, (len + prev_head) % max_len);
+        assert_eq!(ring.head, 0);
     }
 
     #[test]
@@ -179,16 +810,193 @@ mod tests {
 
         // Test appending data
         let data = b"Hello, World!";
-        let ptr = ring.append(data);
+        let ptr = ring.append(data).unwrap().start_ptr;
         let appended_data = unsafe { std::slice::from_raw_parts(ptr, data.len()) };
         assert_eq!(appended_data, data);
         assert_eq!(ring.head, data.len());
 
         // Test appending data that wraps around
         let data2 = b"This is a longer string that will wrap around.";
-        let ptr2 = ring.append(data2);
+        let ptr2 = ring.append(data2).unwrap().start_ptr;
         let appended_data2 = unsafe { std::slice::from_raw_parts(ptr2, data2.len()) };
         assert_eq!(appended_data2, data2);
         assert_eq!(ring.head, (data.len() + data2.len()) % max_len);
     }
+
+    #[test]
+    fn test_generation() {
+        let mut ring = Ring::new(100);
+
+        let first = ring.advance(60);
+        assert_eq!({ first.generation }, 0);
+
+        // rotating skips the last 40 bytes
+        ring.release_until(ring.position());
+        ring.get_contiguous(50).unwrap();
+        let second = ring.advance(50);
+        assert_eq!({ second.generation }, 1);
+
+        // ending exactly at the end of the buffer also wraps
+        let third = ring.advance(50);
+        assert_eq!({ third.generation }, 1);
+        assert_eq!(ring.generation(), 2);
+    }
+
+    #[test]
+    fn test_ring_keeps_pool_alive() {
+        let pool = Arc::new(BufferPool::new(2, 16));
+        let iovecs = pool.iovecs();
+
+        let mut ring = Ring::from_pool(pool.clone(), 1);
+        drop(pool);
+
+        let ptr = ring.append(b"hello").unwrap().start_ptr;
+        assert_eq!(ptr, iovecs[1].iov_base.cast_const().cast());
+        assert_eq!(iovecs[1].iov_len, 16);
+    }
+
+    #[test]
+    fn test_append_past_capacity() {
+        let max_len = 100;
+        let mut ring = Ring::new(max_len);
+
+        let first = ring.append(&[1; 40]).unwrap().start_ptr;
+        ring.append(&[2; 40]).unwrap();
+
+        // only 20 bytes are left at the end, and the first 40 bytes have not been sent yet
+        assert!(matches!(ring.append(&[3; 30]), Err(AppendError::RingFull)));
+        assert!(matches!(ring.append(&[3; 21]), Err(AppendError::RingFull)));
+        assert_eq!(ring.high_water_mark(), 80);
+
+        // the unsent bytes were not touched
+        let unsent = unsafe { std::slice::from_raw_parts(first, 40) };
+        assert!(unsent.iter().all(|&b| b == 1));
+
+        // once the first packet is sent, the ring can rotate over it
+        ring.release_until(40);
+        let ptr = ring.append(&[3; 30]).unwrap().start_ptr;
+        assert_eq!(ptr, ring.data.as_ptr());
+        assert_eq!(ring.pending(), 40 + 20 + 30);
+        assert_eq!(ring.high_water_mark(), 90);
+
+        // once everything is sent, the whole ring is free again
+        ring.release_until(ring.position());
+        assert_eq!(ring.pending(), 0);
+        ring.append(&[4; 100]).unwrap();
+    }
+
+    #[test]
+    fn test_growable() {
+        let pool = Arc::new(BufferPool::new(1, 200));
+        let mut ring = Ring::from_pool_with_mode(pool, 0, 100, RingMode::Growable { max: 200 });
+
+        let first = ring.append(&[1; 40]).unwrap().start_ptr;
+        ring.append(&[2; 40]).unwrap();
+
+        // instead of rotating over the unsent bytes, the ring grows past its old end
+        let ptr = ring.append(&[3; 30]).unwrap().start_ptr;
+        assert_eq!(ptr, unsafe { first.add(80) });
+        assert_eq!(ring.capacity(), 200);
+        assert_eq!(ring.generation(), 0);
+
+        // the unsent bytes were not touched
+        let unsent = unsafe { std::slice::from_raw_parts(first, 40) };
+        assert!(unsent.iter().all(|&b| b == 1));
+
+        // it cannot grow past `max`
+        assert!(matches!(ring.append(&[4; 100]), Err(AppendError::RingFull)));
+
+        // once it has wrapped, it cannot grow either
+        ring.release_until(110);
+        ring.append(&[4; 100]).unwrap();
+        assert_eq!(ring.generation(), 1);
+        assert!(matches!(ring.append(&[5; 100]), Err(AppendError::RingFull)));
+        assert_eq!(ring.capacity(), 200);
+    }
+
+    /// Asserts that `info` lies within the part of the buffer the ring uses, and holds `data`.
+    fn assert_written(ring: &Ring, info: PacketWriteInfo, data: &[u8]) {
+        let start = ring.data.as_ptr();
+        let end = unsafe { start.add(ring.capacity()) };
+
+        let ptr = info.start_ptr;
+        let len = info.len as usize;
+
+        assert!(ptr >= start, "{ptr:?} starts before the ring at {start:?}");
+        assert!(
+            unsafe { ptr.add(len) } <= end,
+            "{ptr:?} + {len} ends after the ring at {end:?}"
+        );
+        assert_eq!(unsafe { std::slice::from_raw_parts(ptr, len) }, data);
+    }
+
+    #[test]
+    fn test_append_until_exactly_full() {
+        let mut ring = Ring::new(100);
+
+        let first = ring.append(&[1; 90]).unwrap();
+        assert_written(&ring, first, &[1; 90]);
+
+        // the last 10 bytes fit exactly
+        let last = ring.append(&[2; 10]).unwrap();
+        assert_written(&ring, last, &[2; 10]);
+        assert_eq!({ last.start_ptr }, unsafe { first.start_ptr.add(90) });
+        assert_eq!({ last.generation }, 0);
+
+        // which moves the head back to the start in a new generation
+        assert_eq!(ring.head(), 0);
+        assert_eq!(ring.generation(), 1);
+        assert_eq!(ring.pending(), 100);
+
+        // nothing fits until something is released, not even a single byte
+        assert!(matches!(ring.append(&[3]), Err(AppendError::RingFull)));
+        assert_written(&ring, first, &[1; 90]);
+
+        ring.release_until(10);
+        let wrapped = ring.append(&[3; 10]).unwrap();
+        assert_written(&ring, wrapped, &[3; 10]);
+        assert_eq!({ wrapped.start_ptr }, first.start_ptr);
+        assert_eq!({ wrapped.generation }, 1);
+    }
+
+    #[test]
+    fn test_append_the_whole_ring() {
+        let mut ring = Ring::new(100);
+
+        let info = ring.append(&[1; 100]).unwrap();
+        assert_written(&ring, info, &[1; 100]);
+        assert_eq!(ring.generation(), 1);
+
+        // once it is sent, the whole ring is free again
+        ring.release_until(ring.position());
+        let info = ring.append(&[2; 100]).unwrap();
+        assert_written(&ring, info, &[2; 100]);
+    }
+
+    #[test]
+    fn test_append_across_wrap_boundary() {
+        let mut ring = Ring::new(100);
+
+        let first = ring.append(&[1; 60]).unwrap();
+        ring.release_until(ring.position());
+
+        // 40 bytes are left at the end, so the write is moved to the start instead of being split
+        let second = ring.append(&[2; 50]).unwrap();
+        assert_written(&ring, second, &[2; 50]);
+        assert_eq!({ second.start_ptr }, first.start_ptr);
+        assert_eq!({ second.generation }, 1);
+
+        // the skipped bytes count as pending until they are released
+        assert_eq!(ring.position(), 150);
+        assert_eq!(ring.pending(), 90);
+        assert_eq!(ring.position_of_last(&second), 100);
+
+        // every write stays in bounds, however the ring wraps
+        for len in 1..=100 {
+            ring.release_until(ring.position());
+            let data = vec![len as u8; len];
+            let info = ring.append(&data).unwrap();
+            assert_written(&ring, info, &data);
+        }
+    }
 }
@@ -5,7 +5,9 @@
 mod block_update;
 mod chat_message;
 mod despawn_player;
+mod disconnect_idle;
 mod disguise_player;
+mod drain;
 mod egress;
 mod entity_detect_collisions;
 mod entity_move_logic;
@@ -37,7 +39,9 @@ mod voice_chat;
 pub use block_update::block_update;
 pub use chat_message::chat_message;
 pub use despawn_player::despawn_player;
+pub use disconnect_idle::disconnect_idle;
 pub use disguise_player::disguise_player;
+pub use drain::{announce_drain, drain};
 pub use egress::egress;
 pub use entity_detect_collisions::entity_detect_collisions;
 pub use entity_move_logic::entity_move_logic;
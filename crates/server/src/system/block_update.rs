@@ -1,7 +1,8 @@
 use evenio::{event::Receiver, fetch::Single};
+use tracing::error;
 use valence_protocol::{packets::play, VarInt};
 
-use crate::{event, net::Compose};
+use crate::{components::chunks::Chunks, event, net::Compose};
 
 #[allow(
     clippy::needless_pass_by_value,
@@ -10,10 +11,19 @@ use crate::{event, net::Compose};
 pub fn block_update(
     r: Receiver<event::UpdateBlock>,
     broadcast: Single<&crate::net::Broadcast>,
+    chunks: Single<&Chunks>,
     encode: Compose,
 ) {
     let event = r.event;
 
+    // the cached packet still has the old block
+    if let Err(err) = chunks.set_block(event.position, event.id, &encode) {
+        error!(
+            "failed to update the chunk of {:?}: {err:?}",
+            event.position
+        );
+    }
+
     let pkt = play::BlockUpdateS2c {
         position: event.position,
         block_id: event.id,
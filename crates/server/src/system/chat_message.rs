@@ -3,7 +3,7 @@ use valence_protocol::packets::play;
 
 use crate::{
     event,
-    net::{Compose, Packets},
+    net::{Compose, Packets, Priority},
 };
 
 #[allow(
@@ -19,5 +19,8 @@ pub fn chat_message(r: ReceiverMut<event::ChatMessage, &mut Packets>, compose: C
         overlay: false,
     };
 
-    packets.append(&pkt, &compose).unwrap();
+    packets
+        .append_priority(&pkt, &compose, Priority::High)
+        .unwrap();
+    packets.flush_now();
 }
@@ -0,0 +1,60 @@
+use std::time::Instant;
+
+use evenio::prelude::*;
+use tracing::{info, instrument, warn};
+
+use crate::{
+    components::LoginState,
+    event::Gametick,
+    global::Global,
+    net::{Compose, DisconnectReason, Packets},
+    singleton::{fd_activity::FdActivity, fd_lookup::FdLookup},
+};
+
+/// Disconnects connections which have not sent anything for [`Global::keep_alive_timeout`].
+/// Unlike [`super::keep_alive`], this also covers connections which never finished logging in.
+///
+/// Only received data counts as activity, so a client which stopped reading cannot stay connected
+/// by having writes complete. Connections which were already disconnected but are still idle after
+/// the timeout, usually because their disconnect packet cannot be sent, are closed without sending
+/// what is left. Connections disconnected here get another timeout for that.
+#[instrument(skip_all, level = "trace")]
+pub fn disconnect_idle(
+    _: Receiver<Gametick>,
+    global: Single<&Global>,
+    mut activity: Single<&mut FdActivity>,
+    fd_lookup: Single<&FdLookup>,
+    mut players: Fetcher<(&mut Packets, &mut LoginState)>,
+    compose: Compose,
+) {
+    let timeout = global.keep_alive_timeout;
+    let now = Instant::now();
+
+    for fd in activity.timed_out(timeout) {
+        let Some(&id) = fd_lookup.get(&fd) else {
+            continue;
+        };
+
+        let Ok((packets, login_state)) = players.get_mut(id) else {
+            continue;
+        };
+
+        if *login_state == LoginState::Terminate {
+            info!("closing {id:?} which is still idle after being disconnected");
+            packets.drop_pending();
+            packets.close_after_send();
+            continue;
+        }
+
+        info!("disconnecting {id:?} after being idle for {timeout:?}");
+
+        let reason = DisconnectReason::TimedOut;
+
+        if let Err(err) = compose.disconnect_with_reason(packets, login_state, &reason) {
+            warn!("failed to send disconnect packet to {id:?}: {err}");
+        }
+
+        // the disconnect packet gets the same time to be sent
+        activity.record(fd, now);
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Instant;
+
+use evenio::{
+    event::{EventMut, ReceiverMut},
+    prelude::*,
+};
+use tracing::{info, instrument, warn};
+use valence_protocol::packets::play;
+
+use crate::{
+    components::LoginState,
+    event::{AnnounceDrain, Gametick},
+    global::Global,
+    net::{Compose, DisconnectReason, Drain, DrainStep, Packets, Priority},
+    SHUTDOWN,
+};
+
+#[instrument(skip_all)]
+pub fn announce_drain(r: ReceiverMut<AnnounceDrain>, mut global: Single<&mut Global>) {
+    let AnnounceDrain { reason, grace } = EventMut::take(r.event);
+
+    if global.drain.is_some() {
+        warn!("the server is already draining");
+        return;
+    }
+
+    info!("draining every connection in {grace:?}");
+
+    global.drain = Some(Drain::new(reason, grace, Instant::now()));
+}
+
+/// Drives [`Global::drain`]: players are warned when it starts, disconnected once its grace
+/// period passed, and the game loop is stopped once their disconnect packets were sent.
+#[instrument(skip_all, level = "trace")]
+pub fn drain(
+    _: Receiver<Gametick>,
+    mut global: Single<&mut Global>,
+    mut players: Fetcher<(&mut Packets, &mut LoginState)>,
+    compose: Compose,
+) {
+    let Some(drain) = &mut global.drain else {
+        return;
+    };
+
+    let step = drain.poll(Instant::now(), players.iter().map(|(packets, _)| packets));
+
+    match step {
+        DrainStep::Wait | DrainStep::Flush => {}
+        DrainStep::Announce => {
+            let pkt = play::GameMessageS2c {
+                chat: drain.reason().into(),
+                overlay: false,
+            };
+
+            for (packets, login_state) in &mut players {
+                if *login_state != LoginState::Play {
+                    continue;
+                }
+
+                if let Err(err) = packets.append_priority(&pkt, &compose, Priority::High) {
+                    warn!("failed to send drain warning: {err}");
+                }
+                packets.flush_now();
+            }
+        }
+        DrainStep::Disconnect => {
+            info!("disconnecting every connection");
+
+            let reason = DisconnectReason::Shutdown(drain.reason().clone());
+
+            for (packets, login_state) in &mut players {
+                if *login_state == LoginState::Terminate {
+                    continue;
+                }
+
+                if let Err(err) = compose.disconnect_with_reason(packets, login_state, &reason) {
+                    warn!("failed to send disconnect packet: {err}");
+                }
+                packets.flush_now();
+            }
+        }
+        DrainStep::Shutdown => {
+            info!("every connection was drained, shutting down");
+            SHUTDOWN.store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
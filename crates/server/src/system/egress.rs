@@ -1,14 +1,16 @@
+use std::time::Instant;
+
 use evenio::{
     event::ReceiverMut,
     fetch::{Fetcher, Single},
 };
-use tracing::instrument;
+use tracing::{instrument, warn};
 
 use crate::{
     components::LoginState,
-    event::Egress,
+    event::{Egress, Scratches},
     global::Global,
-    net::{Broadcast, Fd, Packets, RefreshItems, ServerDef},
+    net::{Broadcast, Fd, IoBufs, NetMetrics, Packets, RefreshItems, ServerDef},
 };
 
 #[instrument(skip_all, level = "trace")]
@@ -17,37 +19,69 @@ pub fn egress(
     mut players: Fetcher<(&mut Packets, &Fd, &LoginState)>,
     mut broadcast: Single<&mut Broadcast>,
     mut global: Single<&mut Global>,
+    io: Single<&IoBufs>,
+    scratches: Single<&Scratches>,
+    mut metrics: Single<&mut NetMetrics>,
 ) {
     // todo: idk how inefficient this is
     tracing::span!(tracing::Level::TRACE, "extend-from-broadcast").in_scope(|| {
-        for (pkts, _, login_state) in &mut players {
+        for (pkts, fd, login_state) in &mut players {
             if *login_state == LoginState::Play {
-                let broadcast = &mut ***broadcast;
-                pkts.extend(broadcast);
+                broadcast.extend_into(pkts, *fd);
             }
         }
     });
 
+    io.set_compression_disabled(global.disable_compression);
+    if io.track_packet_types() != global.track_packet_types {
+        io.set_track_packet_types(global.track_packet_types);
+    }
+
     let mut total_items = 0;
 
-    let local_items =
-        tracing::span!(tracing::Level::TRACE, "generate-refresh-items").in_scope(|| {
-            players
-                .iter_mut()
-                .filter(|(pkts, ..)| pkts.can_send())
-                .map(|(pkts, fd, _)| {
-                    total_items += pkts.prepare_for_send(); // todo: should we not do this in a map for clarity?
-                    RefreshItems {
-                        write: pkts.get_write_mut(),
-                        fd: *fd,
-                    }
-                })
-        });
+    let flush_policy = global.flush_policy;
+    let now = Instant::now();
 
     let mut event = r.event;
     let server = &mut *event.server;
 
-    server.write_all(&mut global, local_items);
+    {
+        // encrypted packets are copied into the ring of the current core
+        let mut local_io = io.get_local().lock();
+
+        let local_items =
+            tracing::span!(tracing::Level::TRACE, "generate-refresh-items").in_scope(|| {
+                players
+                    .iter_mut()
+                    .filter(|(pkts, ..)| pkts.can_send())
+                    .filter(|(pkts, ..)| flush_policy.should_flush(pkts, now))
+                    .filter_map(|(pkts, fd, _)| {
+                        if let Err(err) = pkts.encrypt_pending(&mut local_io) {
+                            // try again next tick
+                            warn!("failed to encrypt packets for {fd:?}: {err}");
+                            return None;
+                        }
+
+                        total_items += pkts.prepare_for_send(); // todo: should we not do this in a map for clarity?
+                        Some(RefreshItems {
+                            write: pkts.get_write_mut(),
+                            fd: *fd,
+                        })
+                    })
+            });
+
+        global.bandwidth.start_tick();
+        server.write_all(&mut global, local_items);
+    }
+
+    // connections which were disconnected are closed once their last packets are written
+    for (pkts, fd, _) in &mut players {
+        pkts.requeue_deferred();
+
+        if pkts.take_close() {
+            server.close_after_send(*fd);
+        }
+    }
 
     let player_count = players.iter_mut().len();
     let per_player = total_items as f64 / player_count as f64;
@@ -66,4 +100,12 @@ pub fn egress(
     tracing::span!(tracing::Level::TRACE, "clear-broadcast").in_scope(|| {
         broadcast.clear();
     });
+
+    // everything which is not queued or being sent anymore can be overwritten
+    tracing::span!(tracing::Level::TRACE, "release-sent").in_scope(|| {
+        io.release_sent(players.iter().map(|(pkts, ..)| pkts));
+    });
+
+    metrics.max_connections = global.max_connections;
+    metrics.update(&io, &scratches, players.iter().map(|(pkts, ..)| pkts));
 }
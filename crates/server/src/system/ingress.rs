@@ -1,3 +1,6 @@
+use std::{io::ErrorKind, sync::atomic::Ordering, time::Instant};
+
+use anyhow::{bail, ensure, Context};
 use evenio::{
     event::{Despawn, Event, Insert, Receiver, Sender, Spawn},
     fetch::{Fetcher, Single},
@@ -6,28 +9,32 @@ use evenio::{
 };
 use fxhash::FxHashMap;
 use rayon_local::RayonLocal;
-use serde_json::json;
-use tracing::{info, instrument, trace, warn};
+use tracing::{debug, info, instrument, trace, warn};
 use valence_protocol::{
     decode::PacketFrame,
     packets,
-    packets::{handshaking::handshake_c2s::HandshakeNextState, login, login::LoginCompressionS2c},
-    Packet, VarInt,
+    packets::{handshaking::handshake_c2s::HandshakeNextState, login},
+    text::IntoText,
+    Packet,
 };
 
 use crate::{
     event,
     global::Global,
     net::{Server, ServerDef, ServerEvent},
-    singleton::fd_lookup::FdLookup,
+    singleton::{fd_activity::FdActivity, fd_lookup::FdLookup, login_queue::LoginQueue},
 };
 
 mod player_packet_buffer;
 
 use crate::{
     components::{FullEntityPose, ImmuneStatus, KeepAlive, LoginState, Vitals},
-    event::BumpScratch,
-    net::{Fd, IoBuf, IoBufs, Packets, MINECRAFT_VERSION, PROTOCOL_VERSION},
+    event::{BumpScratch, Gametick},
+    net::{
+        Compose, DisconnectReason, Fd, FilterAction, ForwardedPlayer, HandshakeConfig, IoBuf,
+        IoBufs, LegacyPing, LegacyStatus, NetMetrics, PacketFilters, Packets, ProtocolVersion,
+        VelocityForwarding,
+    },
     packets::PacketSwitchQuery,
     singleton::player_id_lookup::EntityIdLookup,
     system::ingress::player_packet_buffer::DecodeBuffer,
@@ -64,6 +71,14 @@ pub struct RemovePlayer {
     fd: Fd,
 }
 
+/// An IO error on a connection. If the connection is closed because of it, this is sent before
+/// the [`RemovePlayer`] for the same `fd`.
+#[derive(Event)]
+pub struct ConnectionError {
+    fd: Fd,
+    error: std::io::Error,
+}
+
 // todo: do we really need three different lifetimes here?
 #[derive(Event)]
 pub struct RecvData<'a, 'b, 'c> {
@@ -72,6 +87,18 @@ pub struct RecvData<'a, 'b, 'c> {
     scratch: &'b mut RayonLocal<BumpScratch<'a>>,
 }
 
+/// The server lost `dropped` IO completions. See [`ServerEvent::CompletionOverflow`].
+#[derive(Event)]
+pub struct CompletionOverflow {
+    dropped: u32,
+}
+
+/// `count` connections were closed at accept. See [`ServerEvent::AcceptsRejected`].
+#[derive(Event)]
+pub struct AcceptsRejected {
+    count: u32,
+}
+
 #[derive(Event)]
 pub struct SentData {
     decrease_count: FxHashMap<Fd, usize>,
@@ -82,12 +109,13 @@ pub fn generate_ingress_events(
     world: &mut World,
     server: &mut Server,
     scratch: &mut RayonLocal<BumpScratch>,
+    max_events: usize,
 ) {
     let mut decrease_count = FxHashMap::default();
 
-    server
-        .drain(|event| match event {
-            ServerEvent::AddPlayer { fd } => {
+    let remaining = server
+        .drain_budget(max_events, |event| match event {
+            ServerEvent::AddPlayer { fd, .. } => {
                 world.send(AddPlayer { fd });
             }
             ServerEvent::RemovePlayer { fd } => {
@@ -96,15 +124,35 @@ pub fn generate_ingress_events(
             ServerEvent::RecvData { fd, data } => {
                 world.send(RecvData { fd, data, scratch });
             }
+            ServerEvent::RecvBuffer { fd, buffer } => {
+                world.send(RecvData {
+                    fd,
+                    data: &buffer,
+                    scratch,
+                });
+            }
             ServerEvent::SentData { fd } => {
                 decrease_count
                     .entry(fd)
                     .and_modify(|x| *x += 1)
                     .or_insert(1);
             }
+            ServerEvent::Error { fd, error } => {
+                world.send(ConnectionError { fd, error });
+            }
+            ServerEvent::CompletionOverflow { dropped } => {
+                world.send(CompletionOverflow { dropped });
+            }
+            ServerEvent::AcceptsRejected { count } => {
+                world.send(AcceptsRejected { count });
+            }
         })
         .unwrap();
 
+    if remaining {
+        trace!("completions are left for the next tick");
+    }
+
     world.send(SentData { decrease_count });
 }
 
@@ -114,6 +162,8 @@ pub fn generate_ingress_events(
 pub fn add_player(
     r: ReceiverMut<AddPlayer>,
     mut fd_lookup: Single<&mut FdLookup>,
+    mut activity: Single<&mut FdActivity>,
+    global: Single<&Global>,
     mut sender: IngressSender,
 ) {
     let event = r.event;
@@ -122,25 +172,70 @@ pub fn add_player(
     sender.insert(new_player, LoginState::Handshake);
     sender.insert(new_player, DecodeBuffer::default());
 
-    sender.insert(new_player, Packets::default());
+    let mut packets = Packets::default();
+    if global.pin_connections {
+        // spreads connections over the cores in the order they connect
+        packets.pin_to_core(fd_lookup.len() % rayon_local::count());
+    }
+
+    sender.insert(new_player, packets);
     let fd = event.fd;
     sender.insert(new_player, fd);
 
     fd_lookup.insert(fd, new_player);
+    activity.record(fd, Instant::now());
     trace!("got a player with fd {:?}", fd);
 }
 
+#[instrument(skip_all, level = "trace")]
+pub fn connection_error(
+    r: Receiver<ConnectionError>,
+    fd_lookup: Single<&FdLookup>,
+    mut players: Fetcher<&mut Packets>,
+) {
+    let ConnectionError { fd, error } = r.event;
+
+    let Some(&id) = fd_lookup.get(fd) else {
+        warn!("io error for fd {fd:?} which is not in the fd lookup: {error}");
+        return;
+    };
+
+    // nothing queued can be sent anymore, so free up the ring until the player is removed
+    if let Ok(pkts) = players.get_mut(id) {
+        pkts.drop_pending();
+    }
+
+    match error.kind() {
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+            info!("player {id:?} lost connection: {error}");
+        }
+        _ => warn!("io error for player {id:?}: {error}"),
+    }
+}
+
 // The `Receiver<Tick>` parameter tells our handler to listen for the `Tick` event.
 #[instrument(skip_all, level = "trace")]
 #[allow(clippy::too_many_arguments, reason = "todo")]
 pub fn remove_player(
     r: ReceiverMut<RemovePlayer>,
     mut fd_lookup: Single<&mut FdLookup>,
+    mut activity: Single<&mut FdActivity>,
+    mut login_queue: Single<&mut LoginQueue>,
+    #[cfg(feature = "record")] mut global: Single<&mut Global>,
     mut sender: IngressSender,
 ) {
     let event = r.event;
 
     let fd = event.fd;
+    activity.remove(fd);
+    login_queue.remove(fd);
+
+    // the fd may be reused by another connection
+    #[cfg(feature = "record")]
+    if let Err(err) = global.recorder.stop(fd) {
+        warn!("failed to stop recording {fd:?}: {err}");
+    }
+
     let Some(id) = fd_lookup.remove(&fd) else {
         warn!("tried to remove player with fd {fd:?} but it seemed to already be removed",);
         return;
@@ -151,12 +246,35 @@ pub fn remove_player(
     trace!("removed a player with fd {:?}", fd);
 }
 
+#[instrument(skip_all, level = "trace")]
+pub fn completion_overflow(r: Receiver<CompletionOverflow>, mut metrics: Single<&mut NetMetrics>) {
+    let CompletionOverflow { dropped } = *r.event;
+
+    warn!("lost {dropped} io completions, so some connections may stop receiving packets");
+    metrics.record_completion_overflow(dropped);
+}
+
+#[instrument(skip_all, level = "trace")]
+pub fn accepts_rejected(r: Receiver<AcceptsRejected>, mut metrics: Single<&mut NetMetrics>) {
+    let AcceptsRejected { count } = *r.event;
+
+    warn!("closed {count} connections which were over the accept policy or the connection limit");
+    metrics.record_accepts_rejected(count);
+}
+
 // The `Receiver<Tick>` parameter tells our handler to listen for the `Tick` event.
 #[instrument(skip_all, level = "trace")]
 #[allow(clippy::too_many_arguments, reason = "todo")]
-pub fn sent_data(r: Receiver<SentData>, players: Fetcher<&Packets>, fd_lookup: Single<&FdLookup>) {
+pub fn sent_data(
+    r: Receiver<SentData>,
+    players: Fetcher<&Packets>,
+    fd_lookup: Single<&FdLookup>,
+    mut metrics: Single<&mut NetMetrics>,
+) {
     let event = r.event;
 
+    metrics.record_sent(event.decrease_count.values().sum());
+
     // todo: par iter
     event.decrease_count.iter().for_each(|(fd, count)| {
         let Some(&id) = fd_lookup.get(fd) else {
@@ -184,6 +302,7 @@ pub fn sent_data(r: Receiver<SentData>, players: Fetcher<&Packets>, fd_lookup: S
 pub fn recv_data(
     r: ReceiverMut<RecvData>,
     mut fd_lookup: Single<&mut FdLookup>,
+    mut activity: Single<&mut FdActivity>,
     mut sender: IngressSender,
     global: Single<&Global>,
     mut players: Fetcher<(
@@ -198,6 +317,8 @@ pub fn recv_data(
     )>,
     id_lookup: Single<&EntityIdLookup>,
     mut io: Single<&mut IoBufs>,
+    mut filters: Single<&mut PacketFilters>,
+    mut login_queue: Single<&mut LoginQueue>,
 ) {
     let mut event = r.event;
 
@@ -212,22 +333,85 @@ pub fn recv_data(
         return;
     };
 
+    activity.record(fd, Instant::now());
+
     let (login_state, decoder, packets, _, mut pose, mut vitals, mut keep_alive, mut immunity) =
         players.get_mut(id).expect("player with fd not found");
 
+    if decoder.discard {
+        trace!(
+            "dropping {} bytes from {fd:?} which is being closed",
+            data.len()
+        );
+        return;
+    }
+
+    let io = match packets.core() {
+        Some(core) => &mut io.get_all_mut()[core],
+        None => io.one(),
+    };
+
+    // legacy pings are not framed, so they have to be detected before decoding
+    if *login_state == LoginState::Handshake && global.legacy_ping && decoder.is_empty() {
+        if let Some(ping) = LegacyPing::detect(data) {
+            let io = io.get_mut();
+
+            // the ping is answered on a best effort basis, and the connection is closed either way
+            if let Err(err) = process_legacy_ping(ping, login_state, packets, &global, io) {
+                info!("closing {fd:?} whose legacy ping could not be answered: {err}");
+                *login_state = LoginState::Terminate;
+                packets.close_after_send();
+            }
+
+            decoder.discard = true;
+            return;
+        }
+    }
+
     decoder.queue_slice(data);
 
     let scratch = scratch.one();
 
-    let io = io.one();
-
     // todo: error  on low compression: "decompressed packet length of 2 is <= the compression threshold of 2"
     while let Some(frame) = decoder.try_next_packet(scratch).unwrap() {
         match *login_state {
-            LoginState::Handshake => process_handshake(login_state, &frame).unwrap(),
+            LoginState::Handshake => {
+                let io = io.get_mut();
+                let rejection = login_rejection(&global, fd_lookup.len());
+                let processed = process_handshake(
+                    login_state,
+                    &frame,
+                    packets,
+                    rejection.as_ref(),
+                    &global.handshake,
+                    io,
+                );
+
+                // anyone can send a handshake, so an invalid one only closes the connection
+                if let Err(err) = processed {
+                    info!("closing {fd:?} which sent an invalid handshake: {err}");
+                    io.metrics()
+                        .record_disconnect(&DisconnectReason::ProtocolError);
+                    *login_state = LoginState::Terminate;
+                    packets.close_after_send();
+                    decoder.discard = true;
+                    return;
+                }
+            }
             LoginState::Status => {
                 let io = io.get_mut();
-                process_status(login_state, &frame, packets, io).unwrap();
+                let processed = process_status(login_state, &frame, packets, &global, io);
+
+                // the status state cannot show a message, so the connection is only closed
+                if let Err(err) = processed {
+                    info!("closing {fd:?} which sent an invalid status packet: {err}");
+                    io.metrics()
+                        .record_disconnect(&DisconnectReason::ProtocolError);
+                    *login_state = LoginState::Terminate;
+                    packets.close_after_send();
+                    decoder.discard = true;
+                    return;
+                }
             }
             LoginState::Terminate => {
                 // todo: does this properly terminate the connection? I don't think so probably
@@ -239,7 +423,8 @@ pub fn recv_data(
             }
             LoginState::Login => {
                 let io = io.get_mut();
-                process_login(
+                let processed = process_login(
+                    fd,
                     id,
                     login_state,
                     &frame,
@@ -248,8 +433,37 @@ pub fn recv_data(
                     &global,
                     io,
                     &mut sender,
-                )
-                .unwrap();
+                    &mut login_queue,
+                );
+
+                if let Err(err) = processed {
+                    reject_login(fd, &err, login_state, packets, decoder, io);
+                    return;
+                }
+            }
+            LoginState::Forwarding { .. } => {
+                let io = io.get_mut();
+                let processed = process_forwarding(
+                    fd,
+                    id,
+                    login_state,
+                    &frame,
+                    packets,
+                    decoder,
+                    &global,
+                    io,
+                    &mut sender,
+                    &mut login_queue,
+                );
+
+                if let Err(err) = processed {
+                    reject_login(fd, &err, login_state, packets, decoder, io);
+                    return;
+                }
+            }
+            LoginState::Queued { .. } => {
+                // queued clients only answer the keep alives of the queue, which do not matter
+                trace!("ignoring packet {:#x} from queued {fd:?}", frame.id);
             }
             LoginState::TransitioningPlay { .. } | LoginState::Play => {
                 if let LoginState::TransitioningPlay {
@@ -263,6 +477,23 @@ pub fn recv_data(
                     }
                 }
 
+                match filters.inspect(fd, frame.id, &frame.body) {
+                    FilterAction::Allow => {}
+                    FilterAction::Drop => {
+                        trace!("filter dropped packet {:#x} from {fd:?}", frame.id);
+                        continue;
+                    }
+                    FilterAction::Disconnect => {
+                        info!("filter disconnected {fd:?} for packet {:#x}", frame.id);
+                        decoder.discard = true;
+                        sender.send(event::KickPlayer {
+                            target: id,
+                            reason: "Sent a disallowed packet".to_owned(),
+                        });
+                        return;
+                    }
+                }
+
                 if let Some((pose, vitals, keep_alive, immunity)) =
                     itertools::izip!(&mut pose, &mut vitals, &mut keep_alive, &mut immunity).next()
                 {
@@ -284,66 +515,416 @@ pub fn recv_data(
     // this is important so broadcast order is not before player gets change to play
 }
 
-fn process_handshake(login_state: &mut LoginState, packet: &PacketFrame) -> anyhow::Result<()> {
+/// Why a new login is rejected, if it is: during a [`Global::drain`], or while there are more
+/// than [`Global::max_connections`] connections.
+fn login_rejection(global: &Global, connections: usize) -> Option<DisconnectReason> {
+    if let Some(drain) = &global.drain {
+        return Some(DisconnectReason::Shutdown(drain.reason().clone()));
+    }
+
+    (connections > global.max_connections).then_some(DisconnectReason::ServerFull)
+}
+
+/// Logins are disconnected with `rejection` if it is set. Status requests are still answered,
+/// since the status shows that the server is full.
+fn process_handshake(
+    login_state: &mut LoginState,
+    packet: &PacketFrame,
+    packets: &mut Packets,
+    rejection: Option<&DisconnectReason>,
+    config: &HandshakeConfig,
+    io: &mut IoBuf,
+) -> anyhow::Result<()> {
     debug_assert!(*login_state == LoginState::Handshake);
 
-    let handshake: packets::handshaking::HandshakeC2s = packet.decode()?;
+    login_state.expect::<packets::handshaking::HandshakeC2s>(packet)?;
 
-    trace!("received handshake: {:?}", handshake);
+    let handshake = config.decode(&packet.body)?;
 
-    // todo: check version is correct
+    trace!("received handshake: {:?}", handshake);
 
     match handshake.next_state {
         HandshakeNextState::Status => {
-            *login_state = LoginState::Status;
+            // the status response tells the client which version we expect
+            login_state.transition(LoginState::Status)?;
         }
         HandshakeNextState::Login => {
-            *login_state = LoginState::Login;
+            // an unsupported client is disconnected with a login packet, so it is in the login
+            // state either way
+            login_state.transition(LoginState::Login)?;
+
+            if let Some(reason) = rejection {
+                info!(
+                    "disconnecting client whose login is rejected: {}",
+                    reason.kind()
+                );
+                return disconnect_during_login(reason, login_state, packets, io);
+            }
+
+            let protocol = handshake.protocol;
+            let version = handshake.version();
+
+            if version.is_some_and(ProtocolVersion::is_supported) {
+                return Ok(());
+            }
+
+            let client = version.map_or_else(
+                || format!("protocol {protocol}"),
+                |version| version.name().to_owned(),
+            );
+
+            info!("disconnecting client with unsupported version {client}");
+
+            let reason = DisconnectReason::UnsupportedVersion { client };
+            disconnect_during_login(&reason, login_state, packets, io)?;
         }
     }
 
     Ok(())
 }
 
+/// Disconnects a client whose login failed with `err`. Anyone can start a login, so a failure
+/// only ends this connection, which is just closed if even the disconnect cannot be sent.
+fn reject_login(
+    fd: Fd,
+    err: &anyhow::Error,
+    login_state: &mut LoginState,
+    packets: &mut Packets,
+    decoder: &mut DecodeBuffer,
+    io: &mut IoBuf,
+) {
+    info!("disconnecting {fd:?} whose login failed: {err}");
+
+    let reason = DisconnectReason::ProtocolError;
+    if let Err(err) = disconnect_during_login(&reason, login_state, packets, io) {
+        warn!("failed to send the disconnect to {fd:?}, so it is only closed: {err}");
+        *login_state = LoginState::Terminate;
+        packets.close_after_send();
+    }
+
+    decoder.discard = true;
+}
+
+/// Sends a [`login::LoginDisconnectS2c`] with the message of `reason` and closes the connection
+/// once it is sent. Compression has not been negotiated yet, so the packet is not compressed.
+fn disconnect_during_login(
+    reason: &DisconnectReason,
+    login_state: &mut LoginState,
+    packets: &mut Packets,
+    io: &mut IoBuf,
+) -> anyhow::Result<()> {
+    io.metrics().record_disconnect(reason);
+
+    let pkt = login::LoginDisconnectS2c {
+        reason: reason.message().into_cow_text(),
+    };
+
+    packets.append_pre_compression_packet(&pkt, io)?;
+    packets.close_after_send();
+
+    *login_state = LoginState::Terminate;
+
+    Ok(())
+}
+
+fn process_legacy_ping(
+    ping: LegacyPing,
+    login_state: &mut LoginState,
+    packets: &mut Packets,
+    global: &Global,
+    io: &mut IoBuf,
+) -> anyhow::Result<()> {
+    debug_assert!(*login_state == LoginState::Handshake);
+
+    trace!("received legacy ping: {ping:?}");
+
+    // the same as the modern status response
+    let status = LegacyStatus {
+        motd: global.status.description(),
+        online: global.shared.player_count.load(Ordering::Relaxed) as usize,
+        max: global.status.max_players(),
+    };
+
+    packets.append_raw(&ping.response(&status), io)?;
+    packets.close_after_send();
+
+    *login_state = LoginState::Terminate;
+
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments, reason = "todo del")]
 fn process_login(
+    fd: Fd,
     id: EntityId,
     login_state: &mut LoginState,
     packet: &PacketFrame,
-    packets: &Packets,
+    packets: &mut Packets,
     decoder: &mut DecodeBuffer,
     global: &Global,
     io: &mut IoBuf,
     sender: &mut IngressSender,
+    login_queue: &mut LoginQueue,
 ) -> anyhow::Result<()> {
     debug_assert!(*login_state == LoginState::Login);
 
-    let login::LoginHelloC2s { username, .. } = packet.decode()?;
+    let login::LoginHelloC2s { username, .. } = login_state.decode(packet)?;
 
     trace!("received LoginHello for {username}");
 
-    let username = username.0;
+    let username = Box::from(username.0);
 
-    let pkt = LoginCompressionS2c {
-        threshold: VarInt(global.shared.compression_threshold.0),
+    if global.velocity.is_some() {
+        // the player is only known once the proxy answers
+        packets.append_pre_compression_packet(&VelocityForwarding::request(), io)?;
+        login_state.transition(LoginState::Forwarding { username })?;
+        return Ok(());
+    }
+
+    finish_login(
+        fd,
+        id,
+        login_state,
+        username,
+        None,
+        packets,
+        decoder,
+        global,
+        io,
+        sender,
+        login_queue,
+    )
+}
+
+/// Handles the response of a Velocity proxy to the request sent by [`process_login`].
+#[allow(clippy::too_many_arguments, reason = "todo del")]
+fn process_forwarding(
+    fd: Fd,
+    id: EntityId,
+    login_state: &mut LoginState,
+    packet: &PacketFrame,
+    packets: &mut Packets,
+    decoder: &mut DecodeBuffer,
+    global: &Global,
+    io: &mut IoBuf,
+    sender: &mut IngressSender,
+    login_queue: &mut LoginQueue,
+) -> anyhow::Result<()> {
+    let response: login::LoginQueryResponseC2s = login_state.decode(packet)?;
+
+    ensure!(
+        VelocityForwarding::is_response(&response),
+        "expected the response to the forwarding request, but got message {}",
+        response.message_id.0
+    );
+
+    let LoginState::Forwarding { username } = login_state else {
+        bail!("expected to be waiting for forwarding, but in {login_state:?}");
     };
 
-    packets.append_pre_compression_packet(&pkt, io)?;
+    let velocity = global
+        .velocity
+        .as_ref()
+        .context("velocity forwarding was disabled during login")?;
 
-    decoder.set_compression(global.shared.compression_threshold);
+    // a client which connected directly does not know the channel, so it sends no data
+    let Some(data) = response.data else {
+        info!("disconnecting {username} which did not connect through velocity");
+        return disconnect_during_login(&DisconnectReason::NotForwarded, login_state, packets, io);
+    };
+
+    let forwarded = match velocity.verify(data.0 .0) {
+        Ok(forwarded) => forwarded,
+        Err(err) => {
+            warn!("disconnecting {username} whose forwarding could not be verified: {err}");
+            return disconnect_during_login(
+                &DisconnectReason::AuthFailed,
+                login_state,
+                packets,
+                io,
+            );
+        }
+    };
+
+    trace!("velocity forwarded {forwarded:?}");
 
-    let username = Box::from(username);
+    let username = Box::from(forwarded.username.as_str());
 
-    sender.send(event::PlayerInit {
+    finish_login(
+        fd,
+        id,
+        login_state,
+        username,
+        Some(forwarded),
+        packets,
+        decoder,
+        global,
+        io,
+        sender,
+        login_queue,
+    )
+}
+
+/// Lets the player join the world, or queues them in the [`LoginQueue`] if
+/// [`Global::logins_per_tick`] is set. Logins which were started before a [`Global::drain`] are
+/// rejected here.
+#[allow(clippy::too_many_arguments, reason = "todo del")]
+fn finish_login(
+    fd: Fd,
+    id: EntityId,
+    login_state: &mut LoginState,
+    username: Box<str>,
+    forwarded: Option<ForwardedPlayer>,
+    packets: &mut Packets,
+    decoder: &mut DecodeBuffer,
+    global: &Global,
+    io: &mut IoBuf,
+    sender: &mut IngressSender,
+    login_queue: &mut LoginQueue,
+) -> anyhow::Result<()> {
+    if let Some(drain) = &global.drain {
+        info!("disconnecting {username} which logged in while the server is draining");
+        let reason = DisconnectReason::Shutdown(drain.reason().clone());
+        return disconnect_during_login(&reason, login_state, packets, io);
+    }
+
+    if global.logins_per_tick.is_some() {
+        let position = login_queue.push(fd);
+        debug!("{username} is number {position} in the login queue");
+
+        login_state.transition(LoginState::Queued {
+            username,
+            forwarded,
+        })?;
+        return Ok(());
+    }
+
+    join_world(
+        id,
+        login_state,
+        username,
+        forwarded,
+        packets,
+        decoder,
+        global,
+        io,
+        |init| sender.send(init),
+    )
+}
+
+/// Lets up to [`Global::logins_per_tick`] connections from the [`LoginQueue`] join the world at
+/// the start of every tick, in the order they logged in, and keeps the rest alive. Nobody joins
+/// during a [`Global::drain`], which disconnects the queue with everyone else.
+#[instrument(skip_all, level = "trace")]
+pub fn admit_queued(
+    _: Receiver<Gametick>,
+    global: Single<&Global>,
+    mut login_queue: Single<&mut LoginQueue>,
+    fd_lookup: Single<&FdLookup>,
+    mut players: Fetcher<(&mut LoginState, &mut DecodeBuffer, &mut Packets)>,
+    compose: Compose,
+    mut sender: Sender<event::PlayerInit>,
+) {
+    // everyone joins once the queue is turned off
+    let count = if global.drain.is_some() {
+        0
+    } else {
+        global.logins_per_tick.unwrap_or(usize::MAX)
+    };
+    let admitted: Vec<_> = login_queue.admit(count).collect();
+
+    for fd in admitted {
+        let Some(&id) = fd_lookup.get(&fd) else {
+            continue;
+        };
+
+        let Ok((login_state, decoder, packets)) = players.get_mut(id) else {
+            continue;
+        };
+
+        // the connection may have been disconnected while it waited
+        let LoginState::Queued {
+            username,
+            forwarded,
+        } = login_state
+        else {
+            continue;
+        };
+
+        let username = std::mem::take(username);
+        let forwarded = forwarded.take();
+        let mut io = compose.buf_of(packets);
+
+        let joined = join_world(
+            id,
+            login_state,
+            username,
+            forwarded,
+            packets,
+            decoder,
+            &global,
+            &mut io,
+            |init| sender.send(init),
+        );
+
+        // the connection left the queue, so it would never be let in or closed otherwise
+        if let Err(err) = joined {
+            reject_login(fd, &err, login_state, packets, decoder, &mut io);
+        }
+    }
+
+    if login_queue.is_empty() || !login_queue.keep_alive_due(Instant::now()) {
+        return;
+    }
+
+    let keep_alive = LoginQueue::keep_alive();
+
+    for fd in login_queue.iter() {
+        let Some(&id) = fd_lookup.get(&fd) else {
+            continue;
+        };
+
+        let Ok((_, _, packets)) = players.get_mut(id) else {
+            continue;
+        };
+
+        let mut io = compose.buf_of(packets);
+        let appended = packets.append_pre_compression_packet(&keep_alive, &mut io);
+
+        if let Err(err) = appended {
+            warn!("failed to send the queue keep alive to {fd:?}: {err}");
+        }
+    }
+}
+
+/// Turns on compression, spawns the player with [`event::PlayerInit`], and moves the connection
+/// to the play state.
+#[allow(clippy::too_many_arguments, reason = "todo del")]
+fn join_world(
+    id: EntityId,
+    login_state: &mut LoginState,
+    username: Box<str>,
+    forwarded: Option<ForwardedPlayer>,
+    packets: &mut Packets,
+    decoder: &mut DecodeBuffer,
+    global: &Global,
+    io: &mut IoBuf,
+    send_init: impl FnOnce(event::PlayerInit),
+) -> anyhow::Result<()> {
+    packets.send_set_compression(global.compression_threshold(), io, decoder)?;
+    decoder.set_compression_backend(global.shared.compression_backend);
+
+    send_init(event::PlayerInit {
         target: id,
         username,
         pose: FullEntityPose::player(),
+        forwarded,
     });
 
     // todo: impl rest
-    *login_state = LoginState::TransitioningPlay {
+    login_state.transition(LoginState::TransitioningPlay {
         packets_to_transition: 5,
-    };
+    })?;
 
     Ok(())
 }
@@ -352,6 +933,7 @@ fn process_status(
     login_state: &mut LoginState,
     packet: &PacketFrame,
     packets: &Packets,
+    global: &Global,
     io: &mut IoBuf,
 ) -> anyhow::Result<()> {
     debug_assert!(*login_state == LoginState::Status);
@@ -359,23 +941,10 @@ fn process_status(
     #[allow(clippy::single_match, reason = "todo del")]
     match packet.id {
         packets::status::QueryRequestC2s::ID => {
-            let query_request: packets::status::QueryRequestC2s = packet.decode()?;
-
-            // https://wiki.vg/Server_List_Ping#Response
-            let json = json!({
-                "version": {
-                    "name": MINECRAFT_VERSION,
-                    "protocol": PROTOCOL_VERSION,
-                },
-                "players": {
-                    "online": 1,
-                    "max": 32,
-                    "sample": [],
-                },
-                "description": "something"
-            });
-
-            let json = serde_json::to_string_pretty(&json)?;
+            let query_request: packets::status::QueryRequestC2s = login_state.decode(packet)?;
+
+            let online = global.shared.player_count.load(Ordering::Relaxed) as usize;
+            let json = global.status.json(online)?;
 
             let send = packets::status::QueryResponseS2c { json: &json };
 
@@ -393,7 +962,7 @@ fn process_status(
         }
 
         packets::status::QueryPingC2s::ID => {
-            let query_ping: packets::status::QueryPingC2s = packet.decode()?;
+            let query_ping: packets::status::QueryPingC2s = login_state.decode(packet)?;
 
             let payload = query_ping.payload;
 
@@ -405,10 +974,225 @@ fn process_status(
             *login_state = LoginState::Terminate;
         }
 
-        _ => panic!("unexpected packet id: {}", packet.id),
+        _ => bail!("unexpected packet {:#x} in the status state", packet.id),
     }
 
     // todo: check version is correct
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::AtomicU32, Arc},
+        time::Duration,
+    };
+
+    use bumpalo::Bump;
+    use evenio::component::Component;
+    use libdeflater::CompressionLvl;
+    use valence_protocol::{
+        packets::handshaking::HandshakeC2s, Bounded, CompressionThreshold, PacketEncoder, VarInt,
+    };
+
+    use super::*;
+    use crate::{
+        event::{AnnounceDrain, Scratch, Scratches},
+        global::Shared,
+        net::{CompressionBackend, Compressors, MockServer, PacketDecoder, MIN_S2C_BUFFER_SIZE},
+        singleton::player_id_lookup::EntityIdLookup,
+        system::{announce_drain, drain},
+    };
+
+    fn insert_singleton(world: &mut World, component: impl Component) -> EntityId {
+        let id = world.spawn();
+        world.insert(id, component);
+        id
+    }
+
+    /// A world with the ingress and drain systems, and a connection for every `fd`. This also
+    /// returns the entity of the [`FdLookup`].
+    fn world_with(fds: &[Fd], logins_per_tick: Option<usize>) -> (World, EntityId) {
+        let mut world = World::new();
+
+        world.add_handler(add_player);
+        world.add_handler(remove_player);
+        world.add_handler(recv_data);
+        world.add_handler(admit_queued);
+        world.add_handler(announce_drain);
+        world.add_handler(drain);
+
+        let shared = Arc::new(Shared {
+            player_count: AtomicU32::new(0),
+            compression_threshold: CompressionThreshold(-1),
+            compression_level: CompressionLvl::default(),
+            compression_backend: CompressionBackend::Zlib,
+        });
+
+        let bufs = IoBufs::init(
+            CompressionThreshold(-1),
+            MIN_S2C_BUFFER_SIZE,
+            &mut MockServer::default(),
+        )
+        .unwrap();
+
+        let mut global = Global::new(shared);
+        global.logins_per_tick = logins_per_tick;
+
+        insert_singleton(&mut world, global);
+        insert_singleton(&mut world, bufs);
+        insert_singleton(&mut world, Compressors::new(CompressionLvl::default()));
+        insert_singleton(&mut world, Scratches::default());
+        let fd_lookup = insert_singleton(&mut world, FdLookup::default());
+        insert_singleton(&mut world, FdActivity::default());
+        insert_singleton(&mut world, EntityIdLookup::default());
+        insert_singleton(&mut world, LoginQueue::default());
+        insert_singleton(&mut world, PacketFilters::default());
+
+        for &fd in fds {
+            world.send(AddPlayer { fd });
+        }
+
+        (world, fd_lookup)
+    }
+
+    fn recv(world: &mut World, fd: Fd, data: &[u8]) {
+        let bump = RayonLocal::init(Bump::new);
+        let mut scratch = bump.map_ref(Scratch::from);
+
+        world.send(RecvData {
+            fd,
+            data,
+            scratch: &mut scratch,
+        });
+    }
+
+    fn tick(world: &mut World) {
+        let bump = RayonLocal::init(Bump::new);
+        let mut scratch = bump.map_ref(Scratch::from);
+
+        world.send(Gametick {
+            bump: &bump,
+            scratch: &mut scratch,
+        });
+    }
+
+    fn handshake() -> Vec<u8> {
+        let mut encoder = PacketEncoder::new();
+        encoder
+            .append_packet(&HandshakeC2s {
+                protocol_version: VarInt(ProtocolVersion::CURRENT.protocol()),
+                server_address: Bounded("localhost"),
+                server_port: 25565,
+                next_state: HandshakeNextState::Login,
+            })
+            .unwrap();
+        encoder.take().to_vec()
+    }
+
+    fn login_hello() -> Vec<u8> {
+        let mut encoder = PacketEncoder::new();
+        encoder
+            .append_packet(&login::LoginHelloC2s {
+                username: Bounded("Emerald_Explorer"),
+                profile_id: None,
+            })
+            .unwrap();
+        encoder.take().to_vec()
+    }
+
+    /// The login state and packets of the connection `fd`.
+    fn connection(world: &World, fd_lookup: EntityId, fd: Fd) -> (&LoginState, &Packets) {
+        let id = world.get::<FdLookup>(fd_lookup).unwrap()[&fd];
+        let login_state = world.get::<LoginState>(id).unwrap();
+        let packets = world.get::<Packets>(id).unwrap();
+        (login_state, packets)
+    }
+
+    /// Sends everything queued for `fd` through `server`, and returns the IDs of the packets.
+    fn flush(world: &mut World, fd_lookup: EntityId, server: &mut MockServer, fd: Fd) -> Vec<i32> {
+        let id = world.get::<FdLookup>(fd_lookup).unwrap()[&fd];
+        server.send(fd, world.get_mut::<Packets>(id).unwrap());
+
+        let mut decoder = PacketDecoder::default();
+        decoder.queue_slice(&server.take_written(fd));
+
+        let mut scratch = Scratch::new();
+        let mut ids = Vec::new();
+        while let Some(frame) = decoder.try_next_packet(&mut scratch).unwrap() {
+            ids.push(frame.id);
+        }
+        ids
+    }
+
+    #[test]
+    fn test_drain_rejects_logins() {
+        let mut server = MockServer::default();
+        let (started, late) = (server.connect(), server.connect());
+        let (mut world, fd_lookup) = world_with(&[started, late], None);
+
+        // this login started before the drain
+        recv(&mut world, started, &handshake());
+        let (login_state, packets) = connection(&world, fd_lookup, started);
+        assert_eq!(*login_state, LoginState::Login);
+        assert_eq!(packets.total_len(), 0);
+
+        world.send(AnnounceDrain {
+            reason: "Restarting".into_text(),
+            grace: Duration::from_secs(60),
+        });
+        tick(&mut world);
+
+        // both are disconnected with the reason of the drain instead of joining
+        recv(&mut world, started, &login_hello());
+        recv(&mut world, late, &handshake());
+
+        for fd in [started, late] {
+            let (login_state, packets) = connection(&world, fd_lookup, fd);
+            assert_eq!(*login_state, LoginState::Terminate);
+            assert!(packets.total_len() > 0);
+        }
+    }
+
+    #[test]
+    fn test_login_queue() {
+        let mut server = MockServer::default();
+        let [first, left, second] = [(); 3].map(|()| server.connect());
+        let (mut world, fd_lookup) = world_with(&[first, left, second], Some(1));
+
+        for fd in [first, left, second] {
+            recv(&mut world, fd, &handshake());
+            recv(&mut world, fd, &login_hello());
+
+            let (login_state, _) = connection(&world, fd_lookup, fd);
+            assert!(matches!(login_state, LoginState::Queued { .. }));
+            flush(&mut world, fd_lookup, &mut server, fd);
+        }
+
+        // a queued player which disconnects gives up its place
+        server.disconnect(left);
+        world.send(RemovePlayer { fd: left });
+
+        // one player joins every tick, and everyone still waiting is kept alive
+        tick(&mut world);
+
+        let (login_state, _) = connection(&world, fd_lookup, first);
+        assert!(matches!(login_state, LoginState::TransitioningPlay { .. }));
+        let sent = flush(&mut world, fd_lookup, &mut server, first);
+        assert_eq!(sent, [login::LoginCompressionS2c::ID]);
+
+        let (login_state, _) = connection(&world, fd_lookup, second);
+        assert!(matches!(login_state, LoginState::Queued { .. }));
+        let sent = flush(&mut world, fd_lookup, &mut server, second);
+        assert_eq!(sent, [login::LoginQueryRequestS2c::ID]);
+
+        // the keep alive is not due again yet
+        tick(&mut world);
+
+        let (login_state, _) = connection(&world, fd_lookup, second);
+        assert!(matches!(login_state, LoginState::TransitioningPlay { .. }));
+        let sent = flush(&mut world, fd_lookup, &mut server, second);
+        assert_eq!(sent, [login::LoginCompressionS2c::ID]);
+    }
+}
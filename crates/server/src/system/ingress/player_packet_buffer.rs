@@ -5,5 +5,10 @@ use crate::net::PacketDecoder;
 
 #[derive(Component, Deref, DerefMut, Default)]
 pub struct DecodeBuffer {
+    #[deref]
+    #[deref_mut]
     decoder: PacketDecoder,
+    /// Whether the connection is only waiting to be closed, so anything it sends is dropped
+    /// instead of decoded. Legacy pings are not framed like packets and would fail to decode.
+    pub discard: bool,
 }
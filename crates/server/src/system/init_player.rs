@@ -9,7 +9,7 @@ use valence_protocol::{packets::login, Bounded};
 use crate::{
     components::{
         AiTargetable, EntityReaction, FullEntityPose, ImmuneStatus, InGameName, KeepAlive,
-        LastSentChunk, Player, Uuid, Vitals,
+        LastSentChunk, PendingChunks, Player, Uuid, Vitals,
     },
     event::{PlayerInit, PlayerJoinWorld},
     net::{Compose, Packets},
@@ -42,6 +42,7 @@ pub fn init_player(
         Insert<Prev<Vitals>>,
         Insert<KeepAlive>,
         Insert<LastSentChunk>,
+        Insert<PendingChunks>,
         Insert<AiTargetable>,
         Insert<InGameName>,
         PlayerJoinWorld,
@@ -54,14 +55,18 @@ pub fn init_player(
         target: entity,
         username,
         pose,
+        forwarded,
     } = event;
 
-    let uuid = offline_uuid(&username).unwrap();
+    let (uuid, properties) = match &forwarded {
+        Some(forwarded) => (forwarded.uuid, forwarded.properties()),
+        None => (offline_uuid(&username).unwrap(), Cow::default()),
+    };
 
     let pkt = login::LoginSuccessS2c {
         uuid,
         username: Bounded(&username),
-        properties: Cow::default(),
+        properties,
     };
 
     let packets = r.query;
@@ -87,6 +92,7 @@ pub fn init_player(
 
     s.insert(entity, FullEntityPose::player());
     s.insert(entity, LastSentChunk { chunk });
+    s.insert(entity, PendingChunks::default());
 
     s.insert(entity, EntityReaction::default());
 
@@ -2,7 +2,6 @@ use std::{borrow::Cow, collections::BTreeSet};
 
 use anyhow::{bail, Context};
 use evenio::prelude::*;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use serde::Deserialize;
 use tracing::{debug, info, instrument, trace, warn};
 use valence_nbt::{value::ValueRef, Value};
@@ -21,7 +20,8 @@ use valence_protocol::{
         },
     },
     text::IntoText,
-    ByteAngle, ChunkPos, GameMode, Ident, ItemKind, ItemStack, PacketEncoder, VarInt,
+    ByteAngle, ChunkPos, CompressionThreshold, Encode, GameMode, Ident, ItemKind, ItemStack,
+    Packet, VarInt,
 };
 use valence_registry::{
     biome::{Biome, BiomeEffects},
@@ -30,15 +30,15 @@ use valence_registry::{
 
 use crate::{
     components::{
-        chunks::Chunks, Display, FullEntityPose, InGameName, Player, Uuid, PLAYER_SPAWN_POSITION,
+        Display, FullEntityPose, InGameName, PendingChunks, Player, Uuid, PLAYER_SPAWN_POSITION,
     },
     config,
     config::CONFIG,
     event::PlayerJoinWorld,
     global::Global,
-    net::{Broadcast, Compose, Packets},
+    net::{encoder::PacketEncoder, Broadcast, Compose, Packets, Priority, VecBuf},
     singleton::{player_id_lookup::EntityIdLookup, player_uuid_lookup::PlayerUuidLookup},
-    system::init_entity::spawn_entity_packet,
+    system::{init_entity::spawn_entity_packet, send_chunk_updates::queue_nearest_first},
 };
 
 #[derive(Query, Debug)]
@@ -55,6 +55,7 @@ pub(crate) struct PlayerJoinWorldQuery<'a> {
     uuid: &'a Uuid,
     pose: &'a FullEntityPose,
     packets: &'a mut Packets,
+    pending_chunks: &'a mut PendingChunks,
     name: &'a InGameName,
     _player: With<&'static Player>,
 }
@@ -79,28 +80,49 @@ pub fn player_join_world(
     mut uuid_lookup: Single<&mut PlayerUuidLookup>,
     mut id_lookup: Single<&mut EntityIdLookup>,
     broadcast: Single<&Broadcast>,
-    chunks: Single<&Chunks>,
     compose: Compose,
 ) {
     static CACHED_DATA: once_cell::sync::OnceCell<bytes::Bytes> = once_cell::sync::OnceCell::new();
 
-    let compression_level = global.0.shared.compression_threshold;
+    let compression_level = global.0.compression_threshold();
 
     let cached_data = CACHED_DATA.get_or_init(|| {
-        let mut encoder = PacketEncoder::new();
-        encoder.set_compression(compression_level);
+        let mut encoder = JoinEncoder::new(compression_level, &compose);
 
         info!("caching world data for new players");
-        inner(&mut encoder, &chunks, &compose).unwrap();
+        inner(&mut encoder).unwrap();
 
-        let bytes = encoder.take();
-        bytes.freeze()
+        encoder.take()
     });
 
     trace!("got cached data");
 
     let query = r.query;
 
+    {
+        let mut buf = compose.buf_of(query.packets);
+
+        // the world data is most of what is sent on join, so the ring may not have room for it
+        if let Err(err) = query.packets.append_raw(cached_data, &mut buf) {
+            warn!(
+                "disconnecting {} because the world data could not be queued: {err}",
+                query.name
+            );
+            query.packets.close_after_send();
+            return;
+        }
+    }
+
+    trace!("appending cached data");
+
+    // the chunks are sent by `send_chunk_updates` over the next ticks, so a burst of joins does
+    // not stall a tick encoding them
+    let center = query.pose.chunk_pos();
+    let radius = CONFIG.view_distance;
+    let in_view = (-radius..=radius)
+        .flat_map(|x| (-radius..=radius).map(move |z| ChunkPos::new(center.x + x, center.z + z)));
+    queue_nearest_first(query.pending_chunks, center, in_view);
+
     uuid_lookup.insert(query.uuid.0, query.id);
     id_lookup.insert(query.id.index().0 as i32, query.id);
 
@@ -159,13 +181,6 @@ pub fn player_join_world(
     broadcast.append(&text, &compose).unwrap();
 
     let local = query.packets;
-    {
-        let mut buf = compose.bufs.get_local().borrow_mut();
-        let buf = &mut *buf;
-        local.append_raw(cached_data, buf);
-    }
-
-    trace!("appending cached data");
 
     local
         .append(
@@ -337,7 +352,8 @@ pub fn send_keep_alive(packets: &Packets, compose: &Compose) -> anyhow::Result<(
         id: 0,
     };
 
-    packets.append(&pkt, compose)?;
+    packets.append_priority(&pkt, compose, Priority::High)?;
+    packets.flush_now();
 
     Ok(())
 }
@@ -434,7 +450,44 @@ pub fn generate_biome_registry() -> anyhow::Result<BiomeRegistry> {
     Ok(biome_registry)
 }
 
-pub fn send_game_join_packet(encoder: &mut PacketEncoder) -> anyhow::Result<()> {
+/// Encodes the packets which are cached for every new player with the compressor of the server,
+/// so they are compressed with its [`crate::net::CompressionBackend`] like every other packet.
+pub(crate) struct JoinEncoder<'a> {
+    enc: PacketEncoder,
+    bytes: VecBuf,
+    compose: &'a Compose<'a>,
+}
+
+impl<'a> JoinEncoder<'a> {
+    fn new(threshold: CompressionThreshold, compose: &'a Compose<'a>) -> Self {
+        Self {
+            enc: PacketEncoder::new(threshold),
+            bytes: VecBuf::new(),
+            compose,
+        }
+    }
+
+    fn append_packet<P>(&mut self, pkt: &P) -> anyhow::Result<()>
+    where
+        P: Packet + Encode,
+    {
+        // borrowed for each packet, so nothing else on this thread is locked out while the
+        // packets are built in between
+        let mut scratch = self.compose.scratch.get_local().borrow_mut();
+        let mut compressor = self.compose.compressor.get_local().borrow_mut();
+
+        self.enc
+            .append_packet(pkt, &mut self.bytes, &mut *scratch, &mut **compressor)?;
+
+        Ok(())
+    }
+
+    fn take(self) -> bytes::Bytes {
+        self.bytes.into_inner().into()
+    }
+}
+
+pub fn send_game_join_packet(encoder: &mut JoinEncoder<'_>) -> anyhow::Result<()> {
     // recv ack
 
     let registry_codec = registry_codec_raw()?;
@@ -475,7 +528,7 @@ pub fn send_game_join_packet(encoder: &mut PacketEncoder) -> anyhow::Result<()>
     Ok(())
 }
 
-fn send_commands(encoder: &mut PacketEncoder) -> anyhow::Result<()> {
+fn send_commands(encoder: &mut JoinEncoder<'_>) -> anyhow::Result<()> {
     // https://wiki.vg/Command_Data
     use valence_protocol::packets::play::command_tree_s2c::{
         CommandTreeS2c, Node, NodeData, Parser,
@@ -539,7 +592,7 @@ fn send_commands(encoder: &mut PacketEncoder) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn send_sync_tags(encoder: &mut PacketEncoder) -> anyhow::Result<()> {
+fn send_sync_tags(encoder: &mut JoinEncoder<'_>) -> anyhow::Result<()> {
     let bytes = include_bytes!("tags.json");
 
     let groups = serde_json::from_slice(bytes)?;
@@ -551,11 +604,12 @@ fn send_sync_tags(encoder: &mut PacketEncoder) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn inner(encoder: &mut PacketEncoder, chunks: &Chunks, compose: &Compose) -> anyhow::Result<()> {
+fn inner(encoder: &mut JoinEncoder<'_>) -> anyhow::Result<()> {
     send_game_join_packet(encoder)?;
     send_sync_tags(encoder)?;
 
-    let center_chunk = PLAYER_SPAWN_POSITION.as_ivec3() / 16;
+    // the chunks around it are queued for every player in `player_join_world`
+    let center_chunk = FullEntityPose::player().chunk_pos();
 
     // TODO: Do we need to send this else where?
     encoder.append_packet(&play::ChunkRenderDistanceCenterS2c {
@@ -563,29 +617,6 @@ fn inner(encoder: &mut PacketEncoder, chunks: &Chunks, compose: &Compose) -> any
         chunk_z: center_chunk.z.into(),
     })?;
 
-    let radius = CONFIG.view_distance;
-
-    // todo: right number?
-    let number_chunks = (radius * 2 + 1) * (radius * 2 + 1);
-    let bytes_to_append = crossbeam_queue::ArrayQueue::new(usize::try_from(number_chunks).unwrap());
-
-    (0..number_chunks).into_par_iter().for_each(|i| {
-        let x = i % (radius * 2 + 1);
-        let z = i / (radius * 2 + 1);
-
-        let x = center_chunk.x + x - radius;
-        let z = center_chunk.z + z - radius;
-
-        let chunk = ChunkPos::new(x, z);
-        if let Ok(Some(chunk)) = chunks.get(chunk, compose) {
-            bytes_to_append.push(chunk).unwrap();
-        }
-    });
-
-    for elem in bytes_to_append {
-        encoder.append_bytes(&elem);
-    }
-
     send_commands(encoder)?;
 
     encoder.append_packet(&play::PlayerSpawnPositionS2c {
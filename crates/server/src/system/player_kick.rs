@@ -1,26 +1,22 @@
 use evenio::prelude::*;
-use tracing::instrument;
-use valence_protocol::{
-    packets::play,
-    text::{Color, IntoText},
-};
+use tracing::{instrument, warn};
+use valence_protocol::text::{Color, IntoText};
 
 use crate::{
-    components::Uuid,
+    components::{LoginState, Uuid},
     event::KickPlayer,
-    net::{Compose, Packets},
+    net::{Compose, DisconnectReason, Packets},
     singleton::{player_id_lookup::EntityIdLookup, player_uuid_lookup::PlayerUuidLookup},
 };
 
 #[instrument(skip_all)]
 pub fn player_kick(
-    r: Receiver<KickPlayer, (EntityId, &Uuid, &mut Packets)>,
+    r: Receiver<KickPlayer, (EntityId, &Uuid, &mut Packets, &mut LoginState)>,
     mut uuid_lookup: Single<&mut PlayerUuidLookup>,
     mut id_lookup: Single<&mut EntityIdLookup>,
-    send_info: Compose,
-    mut s: Sender<Despawn>,
+    compose: Compose,
 ) {
-    let (id, uuid, packets) = r.query;
+    let (id, uuid, packets, login_state) = r.query;
 
     uuid_lookup.remove(&uuid.0);
     // todo: also remove on socket close
@@ -28,17 +24,10 @@ pub fn player_kick(
 
     let reason = &r.event.reason;
 
-    let reason = reason.into_text().color(Color::RED);
-
-    // todo: remove
-    packets
-        .append(
-            &play::DisconnectS2c {
-                reason: reason.into(),
-            },
-            &send_info,
-        )
-        .unwrap();
+    let reason = DisconnectReason::Kicked(reason.into_text().color(Color::RED));
 
-    s.send(Despawn(id));
+    // the player is despawned once the connection is closed
+    if let Err(err) = compose.disconnect_with_reason(packets, login_state, &reason) {
+        warn!("failed to send disconnect packet to {id:?}: {err}");
+    }
 }
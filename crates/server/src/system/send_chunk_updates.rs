@@ -1,10 +1,10 @@
 use evenio::prelude::*;
 use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
-use tracing::{instrument, trace};
+use tracing::{instrument, trace, warn};
 use valence_protocol::{packets::play, ChunkPos};
 
 use crate::{
-    components::{chunks::Chunks, FullEntityPose, LastSentChunk},
+    components::{chunks::Chunks, FullEntityPose, LastSentChunk, PendingChunks},
     config::CONFIG,
     event::Gametick,
     net::{Compose, Packets},
@@ -13,57 +13,131 @@ use crate::{
 #[instrument(skip_all, level = "trace")]
 pub fn send_chunk_updates(
     _: Receiver<Gametick>,
-    mut fetcher: Fetcher<(&mut LastSentChunk, &mut FullEntityPose, &Packets)>,
+    mut fetcher: Fetcher<(
+        &mut LastSentChunk,
+        &mut PendingChunks,
+        &mut FullEntityPose,
+        &Packets,
+    )>,
     chunks: Single<&Chunks>,
     compose: Compose,
 ) {
-    let radius = CONFIG.view_distance;
     // chunk updates yay
     fetcher
         .par_iter_mut()
-        .for_each(|(last_sent, pose, packets)| {
+        .for_each(|(last_sent, pending, pose, packets)| {
             let last_sent_chunk = last_sent.chunk;
 
             let current_chunk = pose.chunk_pos();
 
-            if last_sent_chunk == current_chunk {
-                return;
+            if last_sent_chunk != current_chunk {
+                queue_chunk_updates(last_sent, pending, current_chunk, packets, &compose);
             }
 
-            // center chunk
-            let center_chunk = play::ChunkRenderDistanceCenterS2c {
-                chunk_x: current_chunk.x.into(),
-                chunk_z: current_chunk.z.into(),
-            };
+            let sent = pending.flush(packets, |&chunk| {
+                let Ok(Some(raw)) = chunks.get(chunk, &compose) else {
+                    return Ok(());
+                };
+
+                let mut io_buf = compose.buf_of(packets);
+                packets.append_precompressed(&raw, &mut io_buf)?;
 
-            packets.append(&center_chunk, &compose).unwrap();
+                trace!("appended chunk {chunk:?}");
+                Ok(())
+            });
 
-            last_sent.chunk = current_chunk;
+            if let Err(err) = sent {
+                warn!("failed to append chunk: {err}");
+            }
+        });
+}
 
-            trace!("sending chunk updates {last_sent:?} -> {current_chunk:?}");
+/// Queues the chunks which came into view after the player moved from [`LastSentChunk`] to
+/// `current_chunk`, and drops the queued ones which went out of view.
+fn queue_chunk_updates(
+    last_sent: &mut LastSentChunk,
+    pending: &mut PendingChunks,
+    current_chunk: ChunkPos,
+    packets: &Packets,
+    compose: &Compose,
+) {
+    let radius = CONFIG.view_distance;
+    let last_sent_chunk = last_sent.chunk;
 
-            let last_sent_x_range = last_sent_chunk.x - radius..last_sent_chunk.x + radius;
-            let last_sent_z_range = last_sent_chunk.z - radius..last_sent_chunk.z + radius;
+    // center chunk
+    let center_chunk = play::ChunkRenderDistanceCenterS2c {
+        chunk_x: current_chunk.x.into(),
+        chunk_z: current_chunk.z.into(),
+    };
 
-            let current_x_range = current_chunk.x - radius..current_chunk.x + radius;
-            let current_z_range = current_chunk.z - radius..current_chunk.z + radius;
+    packets.append(&center_chunk, compose).unwrap();
 
-            // todo: how to do without par bridge
-            let added_chunks = current_x_range
-                .flat_map(move |x| current_z_range.clone().map(move |z| ChunkPos::new(x, z)))
-                .filter(|x| !last_sent_x_range.contains(&x.x) || !last_sent_z_range.contains(&x.z));
-            // .par_bridge();
+    last_sent.chunk = current_chunk;
 
-            added_chunks.for_each(|chunk| {
-                let Ok(Some(raw)) = chunks.get(chunk, &compose) else {
-                    return;
-                };
+    trace!("queueing chunk updates {last_sent_chunk:?} -> {current_chunk:?}");
 
-                let mut io_buf = compose.bufs.get_local().borrow_mut();
-                let io_buf = &mut *io_buf;
-                packets.append_raw(&raw, io_buf);
+    let last_sent_x_range = last_sent_chunk.x - radius..last_sent_chunk.x + radius;
+    let last_sent_z_range = last_sent_chunk.z - radius..last_sent_chunk.z + radius;
 
-                trace!("appended chunk {chunk:?}");
-            });
-        });
+    let current_x_range = current_chunk.x - radius..current_chunk.x + radius;
+    let current_z_range = current_chunk.z - radius..current_chunk.z + radius;
+
+    pending
+        .retain(|chunk| current_x_range.contains(&chunk.x) && current_z_range.contains(&chunk.z));
+
+    let added_chunks = current_x_range
+        .flat_map(move |x| current_z_range.clone().map(move |z| ChunkPos::new(x, z)))
+        .filter(|x| !last_sent_x_range.contains(&x.x) || !last_sent_z_range.contains(&x.z));
+
+    queue_nearest_first(pending, current_chunk, added_chunks);
+}
+
+/// Queues `chunks` after the ones which are already pending, the ones nearest to `center` first,
+/// so the ground around the player loads before the edge of the view distance.
+pub(crate) fn queue_nearest_first(
+    pending: &mut PendingChunks,
+    center: ChunkPos,
+    chunks: impl IntoIterator<Item = ChunkPos>,
+) {
+    let mut chunks: Vec<_> = chunks.into_iter().collect();
+
+    chunks.sort_by_key(|chunk| {
+        let x = chunk.x - center.x;
+        let z = chunk.z - center.z;
+        x * x + z * z
+    });
+
+    pending.extend(chunks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_nearest_first() {
+        let mut pending = PendingChunks::default();
+        pending.push(ChunkPos::new(9, 9));
+
+        let center = ChunkPos::new(-4, 2);
+        let chunks = (-1..=1).flat_map(|x| (-1..=1).map(move |z| ChunkPos::new(-4 + x, 2 + z)));
+        queue_nearest_first(&mut pending, center, chunks);
+
+        let mut order = Vec::new();
+        let packets = Packets::default();
+        pending
+            .flush(&packets, |&chunk| {
+                order.push(chunk);
+                Ok(())
+            })
+            .unwrap();
+
+        // what was already pending goes first
+        assert_eq!(order[0], ChunkPos::new(9, 9));
+        assert_eq!(order[1], center);
+
+        let distance = |chunk: &ChunkPos| (chunk.x - center.x).abs() + (chunk.z - center.z).abs();
+        assert!(order[2..6].iter().all(|chunk| distance(chunk) == 1));
+        assert!(order[6..].iter().all(|chunk| distance(chunk) == 2));
+    }
 }